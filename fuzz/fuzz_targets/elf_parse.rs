@@ -0,0 +1,50 @@
+//! Feed arbitrary bytes to the ELF parser and everything built on it, none
+//! of it may panic
+//!
+//! Run with ´cargo fuzz run elf_parse´ from the root of the repository
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// NOTE(patrik): kira is only a binary, so the modules are pulled in
+// directly
+#[path = "../../src/elf.rs"]
+mod elf;
+
+#[path = "../../src/loader.rs"]
+mod loader;
+
+#[path = "../../src/dwarf.rs"]
+mod dwarf;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(elf) = elf::Elf::parse(data) else {
+        return;
+    };
+
+    for program_header in elf.program_header_iter() {
+        let _ = elf.program_header_data(program_header);
+    }
+
+    for section_header in elf.section_header_iter() {
+        let _ = elf.section_name(section_header);
+        let _ = elf.section_header_data(section_header);
+    }
+
+    if let Ok(symbol_table) = elf.symbol_table() {
+        let _ = symbol_table.by_name("tohost");
+        let _ = symbol_table.format_address(elf.entry());
+    }
+
+    if let Ok(Some(attributes)) = elf.riscv_attributes() {
+        let _ = attributes.extensions();
+    }
+
+    if let Ok(line_table) = dwarf::LineTable::parse(&elf) {
+        let _ = line_table.lookup(elf.entry());
+    }
+
+    let _ = loader::load_elf(&elf, loader::SegmentAddress::Physical,
+                             0x80000000);
+});
@@ -0,0 +1,162 @@
+//! Module to write the state of the guest as an ELF core file
+//!
+//! The core has a ´PT_NOTE´ segment with a ´NT_PRSTATUS´ note for every
+//! hart, in the layout of riscv64 Linux, and a ´PT_LOAD´ segment with the
+//! contents of the RAM. GDB reads it with ´target core´, each hart is a
+//! thread.
+
+use std::io::Write;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 0xf3;
+
+/// ´e_flags´ for a hart with the C extension and the soft-float ABI
+const EF_RISCV_RVC: u32 = 0x1;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+
+/// Size of ´struct elf_prstatus´ on riscv64 Linux
+const PRSTATUS_SIZE: usize = 376;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+
+/// Offset of the RAM in the file, page aligned for tools that map it
+const RAM_OFFSET: u64 = 0x1000;
+
+const SIGILL: u16 = 4;
+const SIGTRAP: u16 = 5;
+const SIGBUS: u16 = 7;
+const SIGSEGV: u16 = 11;
+const SIGSYS: u16 = 31;
+
+/// Registers of a hart at the time of the dump
+#[derive(Clone, Debug)]
+pub struct HartState {
+    pub hartid: u64,
+    pub pc: u64,
+
+    /// x0 to x31
+    pub registers: [u64; 32],
+}
+
+/// Signal a Linux kernel would kill a process with for the exception
+/// ´cause´, 0 for interrupts
+pub fn signal_for_cause(cause: u64) -> u16 {
+    if cause & (1 << 63) != 0 {
+        return 0;
+    }
+
+    match cause {
+        2 => SIGILL,
+        3 => SIGTRAP,
+        0 | 4 | 6 => SIGBUS,
+        8 | 9 | 11 => SIGSYS,
+        _ => SIGSEGV,
+    }
+}
+
+/// Exit status of a process killed by ´signal´, like a shell reports it
+pub fn signal_status(signal: u16) -> u64 {
+    128 + signal as u64
+}
+
+/// Build the ´NT_PRSTATUS´ note of a hart
+fn prstatus_note(hart: &HartState, signal: u16) -> Vec<u8> {
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2]
+        .copy_from_slice(&signal.to_le_bytes());
+
+    // NOTE(patrik): The pid is the thread id in GDB, it can't be zero
+    let pid = (hart.hartid + 1) as u32;
+    desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&pid.to_le_bytes());
+
+    // NOTE(patrik): The register set starts with the pc where x0 would be
+    let mut registers = hart.registers;
+    registers[0] = hart.pc;
+    for (index, value) in registers.iter().enumerate() {
+        let offset = PRSTATUS_REGS + index * 8;
+        desc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let name = b"CORE\0\0\0\0";
+    let mut note = Vec::new();
+    note.extend_from_slice(&5u32.to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(name);
+    note.extend_from_slice(&desc);
+
+    note
+}
+
+fn program_header(out: &mut Vec<u8>, typ: u32, flags: u32, offset: u64,
+                  addr: u64, size: u64, alignment: u64)
+{
+    out.extend_from_slice(&typ.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&alignment.to_le_bytes());
+}
+
+/// Write a core file of ´harts´ and the RAM at ´ram_base´ to ´out´,
+/// ´signal´ is the signal GDB reports the program stopped with
+pub fn write<W>(out: &mut W, harts: &[HartState], signal: u16,
+                ram_base: u64, ram: &[u8])
+    -> std::io::Result<()>
+    where W: Write
+{
+    let notes: Vec<u8> = harts.iter()
+        .flat_map(|hart| prstatus_note(hart, signal))
+        .collect();
+
+    let notes_offset = (ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE) as u64;
+    let ram_offset = (notes_offset + notes.len() as u64)
+        .next_multiple_of(RAM_OFFSET);
+
+    let mut header = Vec::with_capacity(ram_offset as usize);
+    header.extend_from_slice(b"\x7fELF");
+    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE
+    header.extend_from_slice(&[2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_RISCV.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // Entry, program header offset and section header offset
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&EF_RISCV_RVC.to_le_bytes());
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    // No section headers
+    header.extend_from_slice(&[0; 6]);
+
+    program_header(&mut header, PT_NOTE, 0, notes_offset, 0,
+                   notes.len() as u64, 4);
+    program_header(&mut header, PT_LOAD, PF_R | PF_W | PF_X, ram_offset,
+                   ram_base, ram.len() as u64, RAM_OFFSET);
+
+    header.extend_from_slice(&notes);
+    header.resize(ram_offset as usize, 0);
+
+    out.write_all(&header)?;
+    out.write_all(ram)?;
+
+    Ok(())
+}
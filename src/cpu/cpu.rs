@@ -39,6 +39,7 @@ pub enum Reg {
 }
 
 impl Reg {
+    #[allow(clippy::needless_return)]
     pub fn index(&self) -> usize {
         return match self {
             Reg::X0 => 0,
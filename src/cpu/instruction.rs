@@ -1,11 +1,13 @@
 //! Module to handle CPU instructions
 
 use super::Reg;
+use crate::memory::TypeWidth;
 
 #[derive(Debug)]
 pub enum Error {
     UnknownOpcode(u32),
     UnknownInstruction(Opcode, u32),
+    UnknownCompressedInstruction(u16),
     Test
 }
 
@@ -26,12 +28,14 @@ pub enum Opcode {
     Op32,
     MiscMem,
     System,
+    Amo,
 }
 
 impl TryFrom<u32> for Opcode {
+    #[allow(clippy::needless_return)]
     type Error = Error;
     fn try_from(value: u32) -> Result<Self> {
-        return match value {
+        match value {
             0b0110111 => Ok(Self::Lui),
             0b0010111 => Ok(Self::Auipc),
             0b1101111 => Ok(Self::Jal),
@@ -45,12 +49,27 @@ impl TryFrom<u32> for Opcode {
             0b0111011 => Ok(Self::Op32),
             0b0001111 => Ok(Self::MiscMem),
             0b1110011 => Ok(Self::System),
+            0b0101111 => Ok(Self::Amo),
 
             _ => Err(Error::UnknownOpcode(value)),
-        };
+        }
     }
 }
 
+/// Operation of an AMO instruction
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
 #[derive(Debug)]
 pub enum Instruction {
     /// Opcode: LUI
@@ -117,6 +136,16 @@ pub enum Instruction {
     Or   { rd: Reg, rs1: Reg, rs2: Reg },
     And  { rd: Reg, rs1: Reg, rs2: Reg },
 
+    /// Opcode: OP (M extension)
+    Mul    { rd: Reg, rs1: Reg, rs2: Reg },
+    Mulh   { rd: Reg, rs1: Reg, rs2: Reg },
+    Mulhsu { rd: Reg, rs1: Reg, rs2: Reg },
+    Mulhu  { rd: Reg, rs1: Reg, rs2: Reg },
+    Div    { rd: Reg, rs1: Reg, rs2: Reg },
+    Divu   { rd: Reg, rs1: Reg, rs2: Reg },
+    Rem    { rd: Reg, rs1: Reg, rs2: Reg },
+    Remu   { rd: Reg, rs1: Reg, rs2: Reg },
+
     /// Opcode: OP-32
     Addw { rd: Reg, rs1: Reg, rs2: Reg },
     Subw { rd: Reg, rs1: Reg, rs2: Reg },
@@ -124,14 +153,29 @@ pub enum Instruction {
     Srlw { rd: Reg, rs1: Reg, rs2: Reg },
     Sraw { rd: Reg, rs1: Reg, rs2: Reg },
 
+    /// Opcode: OP-32 (M extension)
+    Mulw  { rd: Reg, rs1: Reg, rs2: Reg },
+    Divw  { rd: Reg, rs1: Reg, rs2: Reg },
+    Divuw { rd: Reg, rs1: Reg, rs2: Reg },
+    Remw  { rd: Reg, rs1: Reg, rs2: Reg },
+    Remuw { rd: Reg, rs1: Reg, rs2: Reg },
+
+    /// Opcode: AMO, ´width´ is either a word or a double word
+    Lr  { rd: Reg, rs1: Reg, width: TypeWidth },
+    Sc  { rd: Reg, rs1: Reg, rs2: Reg, width: TypeWidth },
+    Amo { op: AmoOp, rd: Reg, rs1: Reg, rs2: Reg, width: TypeWidth },
+
     /// Opcode: MISC-MEM
     Fence {}, // TODO(patrik): Fill in
+    FenceI,
 
     /// Opcode: SYSTEM
     Ecall,
     Ebreak,
     Sret,
     Mret,
+    Wfi,
+    SfenceVma { rs1: Reg, rs2: Reg },
     Csrrw { rd: Reg, rs1: Reg, csr: u16 },
     Csrrs { rd: Reg, rs1: Reg, csr: u16 },
     Csrrc { rd: Reg, rs1: Reg, csr: u16 },
@@ -140,6 +184,7 @@ pub enum Instruction {
     Csrrci { rd: Reg, uimm: u32, csr: u16 },
 }
 
+#[allow(clippy::needless_return)]
 impl Instruction {
     pub fn decode(inst: u32) -> Result<Self> {
         let opcode = inst & 0x7f;
@@ -175,6 +220,7 @@ impl Instruction {
             Opcode::Op32 => Self::decode_op_32(inst),
             Opcode::MiscMem => Self::decode_misc_mem(inst),
             Opcode::System => Self::decode_system(inst),
+            Opcode::Amo => Self::decode_amo(inst),
         };
     }
 
@@ -241,8 +287,10 @@ impl Instruction {
         let rs1 = data.rs1;
         let imm = data.imm;
 
+        // NOTE(patrik): RV64 has 6-bit shift amounts so the mode is only
+        // the upper 6 bits of the immediate
         let shamt = imm & 0x3f;
-        let mode = (imm >> 5) & 0x3f;
+        let mode = (imm >> 6) & 0x3f;
 
         return match data.funct3 {
             0b000 => Ok(Self::Addi  { rd, rs1, imm }),
@@ -254,8 +302,8 @@ impl Instruction {
             0b001 => Ok(Self::Slli  { rd, rs1, shamt }),
             0b101 => {
                 match mode {
-                    0b0000000 => Ok(Self::Srli  { rd, rs1, shamt }),
-                    0b0100000 => Ok(Self::Srai  { rd, rs1, shamt }),
+                    0b000000 => Ok(Self::Srli  { rd, rs1, shamt }),
+                    0b010000 => Ok(Self::Srai  { rd, rs1, shamt }),
 
                     // TODO(patrik): Diffrent error?
                     _ => Err(Error::UnknownInstruction(Opcode::OpImm, inst)),
@@ -273,8 +321,8 @@ impl Instruction {
         let rs1 = data.rs1;
         let imm = data.imm;
 
-        let shamt = imm & 0x3f;
-        let mode = (imm >> 5) & 0x3f;
+        let shamt = imm & 0x1f;
+        let mode = (imm >> 5) & 0x7f;
 
         return match data.funct3 {
             0b000 => Ok(Self::Addiw { rd, rs1, imm }),
@@ -312,6 +360,15 @@ impl Instruction {
             (0b110, 0b0000000) => Ok(Self::Or   { rd, rs1, rs2 }),
             (0b111, 0b0000000) => Ok(Self::And  { rd, rs1, rs2 }),
 
+            (0b000, 0b0000001) => Ok(Self::Mul    { rd, rs1, rs2 }),
+            (0b001, 0b0000001) => Ok(Self::Mulh   { rd, rs1, rs2 }),
+            (0b010, 0b0000001) => Ok(Self::Mulhsu { rd, rs1, rs2 }),
+            (0b011, 0b0000001) => Ok(Self::Mulhu  { rd, rs1, rs2 }),
+            (0b100, 0b0000001) => Ok(Self::Div    { rd, rs1, rs2 }),
+            (0b101, 0b0000001) => Ok(Self::Divu   { rd, rs1, rs2 }),
+            (0b110, 0b0000001) => Ok(Self::Rem    { rd, rs1, rs2 }),
+            (0b111, 0b0000001) => Ok(Self::Remu   { rd, rs1, rs2 }),
+
             // TODO(patrik): Diffrent error?
             _ => Err(Error::UnknownInstruction(Opcode::Op, inst)),
         };
@@ -331,6 +388,12 @@ impl Instruction {
             (0b101, 0b0000000) => Ok(Self::Srlw { rd, rs1, rs2 }),
             (0b101, 0b0100000) => Ok(Self::Sraw { rd, rs1, rs2 }),
 
+            (0b000, 0b0000001) => Ok(Self::Mulw  { rd, rs1, rs2 }),
+            (0b100, 0b0000001) => Ok(Self::Divw  { rd, rs1, rs2 }),
+            (0b101, 0b0000001) => Ok(Self::Divuw { rd, rs1, rs2 }),
+            (0b110, 0b0000001) => Ok(Self::Remw  { rd, rs1, rs2 }),
+            (0b111, 0b0000001) => Ok(Self::Remuw { rd, rs1, rs2 }),
+
             // TODO(patrik): Diffrent error?
             _ => Err(Error::UnknownInstruction(Opcode::Op32, inst)),
         };
@@ -340,6 +403,7 @@ impl Instruction {
         let data = IType::from(inst);
         return match data.funct3 {
             0b000 => Ok(Self::Fence { }),
+            0b001 => Ok(Self::FenceI),
 
             _ => Err(Error::UnknownInstruction(Opcode::MiscMem, inst)),
         };
@@ -365,6 +429,11 @@ impl Instruction {
                     (0b00001, 0b0000000) => Ok(Self::Ebreak {}),
                     (0b00010, 0b0001000) => Ok(Self::Sret {}),
                     (0b00010, 0b0011000) => Ok(Self::Mret {}),
+                    (0b00101, 0b0001000) => Ok(Self::Wfi {}),
+                    (_, 0b0001001) => Ok(Self::SfenceVma {
+                        rs1: rdata.rs1,
+                        rs2: rdata.rs2,
+                    }),
 
                     _ => Err(Error::UnknownInstruction(Opcode::System, inst)),
                 }
@@ -382,6 +451,226 @@ impl Instruction {
     }
 }
 
+impl Instruction {
+    fn decode_amo(inst: u32) -> Result<Self> {
+        let data = RType::from(inst);
+
+        let rd = data.rd;
+        let rs1 = data.rs1;
+        let rs2 = data.rs2;
+
+        let width = match data.funct3 {
+            0b010 => TypeWidth::Word,
+            0b011 => TypeWidth::DoubleWord,
+
+            _ => return Err(Error::UnknownInstruction(Opcode::Amo, inst)),
+        };
+
+        // NOTE(patrik): The aq and rl bits are ignored, the harts run one
+        // instruction at a time so every access is already ordered
+        let funct5 = data.funct7 >> 2;
+        let op = match funct5 {
+            0b00010 if rs2 == Reg::X0 => {
+                return Ok(Self::Lr { rd, rs1, width });
+            }
+            0b00011 => return Ok(Self::Sc { rd, rs1, rs2, width }),
+
+            0b00001 => AmoOp::Swap,
+            0b00000 => AmoOp::Add,
+            0b00100 => AmoOp::Xor,
+            0b01100 => AmoOp::And,
+            0b01000 => AmoOp::Or,
+            0b10000 => AmoOp::Min,
+            0b10100 => AmoOp::Max,
+            0b11000 => AmoOp::Minu,
+            0b11100 => AmoOp::Maxu,
+
+            _ => return Err(Error::UnknownInstruction(Opcode::Amo, inst)),
+        };
+
+        Ok(Self::Amo { op, rd, rs1, rs2, width })
+    }
+
+    /// Decode a 16-bit instruction from the C extension into the 32-bit
+    /// instruction it expands to
+    pub fn decode_compressed(inst: u16) -> Result<Self> {
+        let inst = inst as u32;
+        let error = Error::UnknownCompressedInstruction(inst as u16);
+
+        let funct3 = (inst >> 13) & 0x7;
+
+        // Full registers at [11:7] and [6:2], the popular registers x8 to
+        // x15 at [9:7] and [4:2]
+        let rd = Reg::from((inst >> 7) & 0x1f);
+        let rs2 = Reg::from((inst >> 2) & 0x1f);
+        let rd_short = Reg::from(8 + ((inst >> 7) & 0x7));
+        let rs2_short = Reg::from(8 + ((inst >> 2) & 0x7));
+
+        // 6-bit immediate at [12|6:2] used by most of quadrant 1 and 2
+        let imm6 = sign_extend(((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f), 6);
+        let shamt = (((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f)) as i32;
+
+        // Offsets for the word and double word loads and stores
+        let offset_w = (((inst >> 7) & 0x38) | ((inst >> 4) & 0x4) |
+                        ((inst << 1) & 0x40)) as i32;
+        let offset_d = (((inst >> 7) & 0x38) | ((inst << 1) & 0xc0)) as i32;
+
+        match (inst & 0b11, funct3) {
+            // C.ADDI4SPN
+            (0b00, 0b000) => {
+                let imm = ((inst >> 7) & 0x30) | ((inst >> 1) & 0x3c0) |
+                    ((inst >> 4) & 0x4) | ((inst >> 2) & 0x8);
+                if imm == 0 {
+                    return Err(error);
+                }
+
+                Ok(Self::Addi { rd: rs2_short, rs1: Reg::X2, imm: imm as i32 })
+            }
+            // C.LW
+            (0b00, 0b010) => {
+                Ok(Self::Lw { rd: rs2_short, rs1: rd_short, imm: offset_w })
+            }
+            // C.LD
+            (0b00, 0b011) => {
+                Ok(Self::Ld { rd: rs2_short, rs1: rd_short, imm: offset_d })
+            }
+            // C.SW
+            (0b00, 0b110) => {
+                Ok(Self::Sw { rs1: rd_short, rs2: rs2_short, imm: offset_w })
+            }
+            // C.SD
+            (0b00, 0b111) => {
+                Ok(Self::Sd { rs1: rd_short, rs2: rs2_short, imm: offset_d })
+            }
+
+            // C.ADDI and C.NOP
+            (0b01, 0b000) => Ok(Self::Addi { rd, rs1: rd, imm: imm6 }),
+            // C.ADDIW
+            (0b01, 0b001) if rd != Reg::X0 => {
+                Ok(Self::Addiw { rd, rs1: rd, imm: imm6 })
+            }
+            // C.LI
+            (0b01, 0b010) => Ok(Self::Addi { rd, rs1: Reg::X0, imm: imm6 }),
+            // C.ADDI16SP
+            (0b01, 0b011) if rd == Reg::X2 => {
+                let imm = ((inst >> 3) & 0x200) | ((inst >> 2) & 0x10) |
+                    ((inst << 1) & 0x40) | ((inst << 4) & 0x180) |
+                    ((inst << 3) & 0x20);
+                if imm == 0 {
+                    return Err(error);
+                }
+
+                Ok(Self::Addi { rd, rs1: rd, imm: sign_extend(imm, 10) })
+            }
+            // C.LUI
+            (0b01, 0b011) => {
+                if imm6 == 0 {
+                    return Err(error);
+                }
+
+                Ok(Self::Lui { rd, imm: imm6 << 12 })
+            }
+            (0b01, 0b100) => {
+                let rd = rd_short;
+                let rs2 = rs2_short;
+
+                let funct2 = (inst >> 10) & 0b11;
+                let word = (inst >> 12) & 1;
+                match (funct2, word, (inst >> 5) & 0b11) {
+                    (0b00, _, _) => Ok(Self::Srli { rd, rs1: rd, shamt }),
+                    (0b01, _, _) => Ok(Self::Srai { rd, rs1: rd, shamt }),
+                    (0b10, _, _) => Ok(Self::Andi { rd, rs1: rd, imm: imm6 }),
+                    (0b11, 0, 0b00) => Ok(Self::Sub { rd, rs1: rd, rs2 }),
+                    (0b11, 0, 0b01) => Ok(Self::Xor { rd, rs1: rd, rs2 }),
+                    (0b11, 0, 0b10) => Ok(Self::Or  { rd, rs1: rd, rs2 }),
+                    (0b11, 0, 0b11) => Ok(Self::And { rd, rs1: rd, rs2 }),
+                    (0b11, 1, 0b00) => Ok(Self::Subw { rd, rs1: rd, rs2 }),
+                    (0b11, 1, 0b01) => Ok(Self::Addw { rd, rs1: rd, rs2 }),
+
+                    _ => Err(error),
+                }
+            }
+            // C.J
+            (0b01, 0b101) => {
+                let imm = ((inst >> 1) & 0x800) | ((inst >> 7) & 0x10) |
+                    ((inst >> 1) & 0x300) | ((inst << 2) & 0x400) |
+                    ((inst >> 1) & 0x40) | ((inst << 1) & 0x80) |
+                    ((inst >> 2) & 0xe) | ((inst << 3) & 0x20);
+
+                Ok(Self::Jal { rd: Reg::X0, imm: sign_extend(imm, 12) })
+            }
+            // C.BEQZ and C.BNEZ
+            (0b01, 0b110) | (0b01, 0b111) => {
+                let imm = ((inst >> 4) & 0x100) | ((inst >> 7) & 0x18) |
+                    ((inst << 1) & 0xc0) | ((inst >> 2) & 0x6) |
+                    ((inst << 3) & 0x20);
+                let imm = sign_extend(imm, 9);
+
+                let rs1 = rd_short;
+                if funct3 == 0b110 {
+                    Ok(Self::Beq { rs1, rs2: Reg::X0, imm })
+                } else {
+                    Ok(Self::Bne { rs1, rs2: Reg::X0, imm })
+                }
+            }
+
+            // C.SLLI
+            (0b10, 0b000) => Ok(Self::Slli { rd, rs1: rd, shamt }),
+            // C.LWSP
+            (0b10, 0b010) if rd != Reg::X0 => {
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1c) |
+                    ((inst << 4) & 0xc0);
+                Ok(Self::Lw { rd, rs1: Reg::X2, imm: imm as i32 })
+            }
+            // C.LDSP
+            (0b10, 0b011) if rd != Reg::X0 => {
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18) |
+                    ((inst << 4) & 0x1c0);
+                Ok(Self::Ld { rd, rs1: Reg::X2, imm: imm as i32 })
+            }
+            (0b10, 0b100) => {
+                let bit12 = (inst >> 12) & 1;
+                match (bit12, rd, rs2) {
+                    // C.JR
+                    (0, Reg::X0, Reg::X0) => Err(error),
+                    (0, rs1, Reg::X0) => {
+                        Ok(Self::Jalr { rd: Reg::X0, rs1, imm: 0 })
+                    }
+                    // C.MV
+                    (0, rd, rs2) => Ok(Self::Add { rd, rs1: Reg::X0, rs2 }),
+                    // C.EBREAK
+                    (1, Reg::X0, Reg::X0) => Ok(Self::Ebreak {}),
+                    // C.JALR
+                    (1, rs1, Reg::X0) => {
+                        Ok(Self::Jalr { rd: Reg::X1, rs1, imm: 0 })
+                    }
+                    // C.ADD
+                    (_, rd, rs2) => Ok(Self::Add { rd, rs1: rd, rs2 }),
+                }
+            }
+            // C.SWSP
+            (0b10, 0b110) => {
+                let imm = ((inst >> 7) & 0x3c) | ((inst >> 1) & 0xc0);
+                Ok(Self::Sw { rs1: Reg::X2, rs2, imm: imm as i32 })
+            }
+            // C.SDSP
+            (0b10, 0b111) => {
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 1) & 0x1c0);
+                Ok(Self::Sd { rs1: Reg::X2, rs2, imm: imm as i32 })
+            }
+
+            // TODO(patrik): The floating point loads and stores
+            _ => Err(error),
+        }
+    }
+}
+
+/// Sign extend the low ´bits´ bits of ´value´
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
 #[derive(Copy, Clone, Debug)]
 struct RType {
     funct7: u32,
@@ -1,38 +1,1011 @@
 //! CPU Module
 
-use crate::memory::Mmu;
+use crate::memory::{ Mmu, TypeWidth };
+use crate::devices::{ Semihosting, Clock };
+use crate::sbi::{ Sbi, SbiOutcome };
+use crate::linux::syscall::Linux;
+use crate::devices::semihosting::{ SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT };
 
-use instruction::Instruction;
+use instruction::{ Instruction, AmoOp };
 pub use cpu::{ Hart, Reg };
 
 mod instruction;
+#[allow(clippy::module_inception)]
 mod cpu;
 
+/// ISA string of the harts, used in the device tree
+pub const ISA: &str = "rv64imac_zicsr_zifencei_sstc";
+
+/// Virtual memory scheme of the harts, used in the device tree
+pub const MMU_TYPE: &str = "riscv,sv39";
+
 const MAX_CONTROL_REGISTERS: usize = 4096;
 
+/// Encoding of the uncompressed EBREAK
+const EBREAK: u32 = 0x00100073;
+
+/// A trap the hart has nowhere to take, the hart stops at it
+#[derive(Copy, Clone, Debug)]
+pub struct Fault {
+    pub cause: u64,
+    pub epc: u64,
+    pub tval: u64,
+}
+
+const EXCEPTION_INSTRUCTION_ACCESS_FAULT: u64 = 1;
+const EXCEPTION_ILLEGAL_INSTRUCTION: u64 = 2;
+const EXCEPTION_BREAKPOINT: u64 = 3;
+const EXCEPTION_LOAD_MISALIGNED: u64 = 4;
+const EXCEPTION_LOAD_ACCESS_FAULT: u64 = 5;
+const EXCEPTION_STORE_MISALIGNED: u64 = 6;
+const EXCEPTION_STORE_ACCESS_FAULT: u64 = 7;
+const EXCEPTION_ECALL_U: u64 = 8;
+const EXCEPTION_ECALL_S: u64 = 9;
+const EXCEPTION_ECALL_M: u64 = 11;
+const EXCEPTION_INSTRUCTION_PAGE_FAULT: u64 = 12;
+const EXCEPTION_LOAD_PAGE_FAULT: u64 = 13;
+const EXCEPTION_STORE_PAGE_FAULT: u64 = 15;
+
+/// Set in ´mcause´ when the trap was caused by an interrupt
+const INTERRUPT_BIT: u64 = 1 << 63;
+const INTERRUPT_SUPERVISOR_SOFTWARE: u64 = 1;
+const INTERRUPT_MACHINE_SOFTWARE: u64 = 3;
+const INTERRUPT_SUPERVISOR_TIMER: u64 = 5;
+const INTERRUPT_MACHINE_TIMER: u64 = 7;
+const INTERRUPT_SUPERVISOR_EXTERNAL: u64 = 9;
+const INTERRUPT_MACHINE_EXTERNAL: u64 = 11;
+
+/// Interrupts in the order they are taken when several are pending
+const INTERRUPT_PRIORITY: [u64; 6] = [
+    INTERRUPT_MACHINE_EXTERNAL,
+    INTERRUPT_MACHINE_SOFTWARE,
+    INTERRUPT_MACHINE_TIMER,
+    INTERRUPT_SUPERVISOR_EXTERNAL,
+    INTERRUPT_SUPERVISOR_SOFTWARE,
+    INTERRUPT_SUPERVISOR_TIMER,
+];
+
+const CSR_SSTATUS: u16 = 0x100;
+const CSR_SIE: u16 = 0x104;
+const CSR_STVEC: u16 = 0x105;
+const CSR_SEPC: u16 = 0x141;
+const CSR_SCAUSE: u16 = 0x142;
+const CSR_STVAL: u16 = 0x143;
+const CSR_SIP: u16 = 0x144;
+const CSR_STIMECMP: u16 = 0x14d;
+const CSR_SATP: u16 = 0x180;
+const CSR_MSTATUS: u16 = 0x300;
+const CSR_MISA: u16 = 0x301;
+const CSR_MEDELEG: u16 = 0x302;
+const CSR_MIDELEG: u16 = 0x303;
+const CSR_MIE: u16 = 0x304;
+const CSR_MTVEC: u16 = 0x305;
+const CSR_MENVCFG: u16 = 0x30a;
+const CSR_MEPC: u16 = 0x341;
+const CSR_MCAUSE: u16 = 0x342;
+const CSR_MTVAL: u16 = 0x343;
+const CSR_MIP: u16 = 0x344;
+const CSR_MCYCLE: u16 = 0xb00;
+const CSR_MINSTRET: u16 = 0xb02;
+const CSR_CYCLE: u16 = 0xc00;
+const CSR_TIME: u16 = 0xc01;
+const CSR_INSTRET: u16 = 0xc02;
+const CSR_MHARTID: u16 = 0xf14;
+
+const MSTATUS_SIE: u64 = 1 << 1;
+const MSTATUS_MIE: u64 = 1 << 3;
+const MSTATUS_SPIE: u64 = 1 << 5;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_SPP: u64 = 1 << 8;
+const MSTATUS_MPP: u64 = 0b11 << 11;
+const MSTATUS_MPRV: u64 = 1 << 17;
+const MSTATUS_SUM: u64 = 1 << 18;
+const MSTATUS_MXR: u64 = 1 << 19;
+const MSTATUS_UXL: u64 = 0b11 << 32;
+const MSTATUS_SXL: u64 = 0b11 << 34;
+
+/// Bits of ´mstatus´ visible through ´sstatus´
+const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP |
+    (0b11 << 13) | (0b11 << 15) | (1 << 18) | (1 << 19) | MSTATUS_UXL |
+    (1 << 63);
+
+/// Both UXL and SXL are fixed to 64-bit
+const MSTATUS_XLEN_64: u64 = (2 << 32) | (2 << 34);
+
+pub const MIP_SSIP: u64 = 1 << INTERRUPT_SUPERVISOR_SOFTWARE;
+pub const MIP_MSIP: u64 = 1 << INTERRUPT_MACHINE_SOFTWARE;
+pub const MIP_STIP: u64 = 1 << INTERRUPT_SUPERVISOR_TIMER;
+pub const MIP_MTIP: u64 = 1 << INTERRUPT_MACHINE_TIMER;
+pub const MIP_SEIP: u64 = 1 << INTERRUPT_SUPERVISOR_EXTERNAL;
+pub const MIP_MEIP: u64 = 1 << INTERRUPT_MACHINE_EXTERNAL;
+
+/// Bits of ´mip´ that software can write
+const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Interrupts that can be delegated to S-mode
+const MIDELEG_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Exceptions that can be delegated to S-mode, ECALL from M-mode can't
+const MEDELEG_WRITABLE: u64 = 0xffff & !(1 << EXCEPTION_ECALL_M);
+
+/// RV64 with the A, C, I, M, S and U extensions
+pub const MISA: u64 = (2 << 62) | (1 << 0) | (1 << 2) | (1 << 8) | (1 << 12) |
+    (1 << 18) | (1 << 20);
+
+/// Lets S-mode use ´stimecmp´ (Sstc)
+const MENVCFG_STCE: u64 = 1 << 63;
+
+const SATP_MODE_BARE: u64 = 0;
+const SATP_MODE_SV39: u64 = 8;
+const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Number of page table levels of Sv39
+const SV39_LEVELS: u64 = 3;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// Exceptions delegated to S-mode when running with the built-in SBI, the
+/// same set OpenSBI delegates plus illegal instructions since there is no
+/// M-mode code to emulate them
+const SBI_MEDELEG: u64 = (1 << 0) | (1 << 2) | (1 << 3) | (1 << 4) |
+    (1 << 6) | (1 << EXCEPTION_ECALL_U) | (1 << 12) | (1 << 13) | (1 << 15);
+const SBI_MIDELEG: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Kind of memory access, decides the permission a page needs and the
+/// exception raised when the access fails
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(&self) -> u64 {
+        match self {
+            Access::Fetch => EXCEPTION_INSTRUCTION_PAGE_FAULT,
+            Access::Load => EXCEPTION_LOAD_PAGE_FAULT,
+            Access::Store => EXCEPTION_STORE_PAGE_FAULT,
+        }
+    }
+
+    fn access_fault(&self) -> u64 {
+        match self {
+            Access::Fetch => EXCEPTION_INSTRUCTION_ACCESS_FAULT,
+            Access::Load => EXCEPTION_LOAD_ACCESS_FAULT,
+            Access::Store => EXCEPTION_STORE_ACCESS_FAULT,
+        }
+    }
+}
+
+/// Privilege levels, the values are the encodings used in ´mstatus´
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
 pub struct SimpleHart {
     registers: [u64; 33],
     csr: [u64; MAX_CONTROL_REGISTERS],
     pub mmu: Box<dyn Mmu>,
+
+    hartid: u64,
+    mode: Privilege,
+
+    /// Bits of ´mip´ raised by the devices and the timers, kept apart from
+    /// the bits software writes
+    mip_hardware: u64,
+
+    /// Raw bits of the instruction being executed, the ´tval´ of illegal
+    /// instruction exceptions
+    inst: u32,
+
+    /// Number of retired instructions
+    instret: u64,
+
+    /// Source of the ´time´ CSR, reads as 0 when None
+    clock: Option<Clock>,
+
+    /// Handles semihosting calls, when None the semihosting sequence is
+    /// treated as a normal EBREAK
+    semihosting: Option<Semihosting>,
+
+    /// Built-in SBI firmware, when set ECALLs from S-mode are handled by it
+    /// instead of trapping to M-mode
+    sbi: Option<Sbi>,
+
+    /// Set when the hart has been stopped through the SBI
+    stopped: bool,
+
+    /// Linux system calls of a user-mode process, when set ECALLs from
+    /// U-mode are handled by it and there is nothing above U-mode
+    linux: Option<Linux>,
+
+    /// Set when the hart took a trap it can't handle, it doesn't run any
+    /// more after it
+    fault: Option<Fault>,
 }
 
 impl SimpleHart {
     pub fn new(mmu: Box<dyn Mmu>) -> Self {
+        let mut csr = [0u64; MAX_CONTROL_REGISTERS];
+        csr[CSR_MSTATUS as usize] = MSTATUS_XLEN_64;
+        csr[CSR_STIMECMP as usize] = u64::MAX;
+
         Self {
             registers: [0u64; 33],
-            csr: [0u64; MAX_CONTROL_REGISTERS],
+            csr,
             mmu,
+
+            hartid: 0,
+            mode: Privilege::Machine,
+
+            mip_hardware: 0,
+            inst: 0,
+
+            instret: 0,
+
+            clock: None,
+
+            semihosting: None,
+
+            sbi: None,
+            stopped: false,
+            linux: None,
+            fault: None,
+        }
+    }
+
+    pub fn set_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = Some(clock);
+    }
+
+    pub fn set_hartid(&mut self, hartid: u64) {
+        self.hartid = hartid;
+    }
+
+    /// Use the built-in SBI, the exceptions and interrupts the kernel
+    /// handles are delegated to S-mode
+    pub fn set_sbi(&mut self, sbi: Sbi) {
+        self.csr[CSR_MEDELEG as usize] = SBI_MEDELEG;
+        self.csr[CSR_MIDELEG as usize] = SBI_MIDELEG;
+        self.csr[CSR_MENVCFG as usize] = MENVCFG_STCE;
+
+        // NOTE(patrik): Only the boot hart runs, the others wait for the
+        // kernel to start them through HSM
+        self.stopped = sbi.is_stopped(self.hartid as usize);
+        self.sbi = Some(sbi);
+    }
+
+    /// Start executing at ´pc´ in S-mode, like firmware jumping to the
+    /// kernel
+    pub fn enter_supervisor(&mut self, pc: u64) {
+        self.mode = Privilege::Supervisor;
+        self.set_reg(Reg::Pc, pc);
+    }
+
+    /// Run a user-mode process, its system calls are handled by ´linux´
+    pub fn set_linux(&mut self, linux: Linux) {
+        self.linux = Some(linux);
+    }
+
+    /// Start executing at ´pc´ in U-mode, like a kernel starting a process
+    pub fn enter_user(&mut self, pc: u64) {
+        self.mode = Privilege::User;
+        self.set_reg(Reg::Pc, pc);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// The trap the hart stopped at, None while it's running
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    fn time(&self) -> u64 {
+        self.clock.as_ref().map_or(0, |clock| clock.timebase_ticks())
+    }
+
+    /// Take a trap, the trap goes to S-mode if it's delegated and the hart
+    /// isn't running in M-mode
+    fn trap(&mut self, cause: u64, epc: u64, tval: u64) {
+        // NOTE(patrik): A user-mode process has no kernel to take its
+        // traps, the runner ends the process at it
+        if self.linux.is_some() {
+            self.fault = Some(Fault {
+                cause,
+                epc,
+                tval,
+            });
+            return;
+        }
+
+        // NOTE(patrik): A trap vector that can't be fetched from would trap
+        // to itself forever, the hart stops at the trap that went there
+        // instead
+        let vectors = [
+            (CSR_MTVEC, CSR_MCAUSE, CSR_MEPC, CSR_MTVAL),
+            (CSR_STVEC, CSR_SCAUSE, CSR_SEPC, CSR_STVAL),
+        ];
+        let vector = vectors.into_iter()
+            .find(|&(tvec, ..)| self.csr[tvec as usize] & !0b11 == epc);
+        if let Some((_, cause_csr, epc_csr, tval_csr)) = vector
+            .filter(|_| cause == EXCEPTION_INSTRUCTION_ACCESS_FAULT)
+        {
+            self.fault = Some(Fault {
+                cause: self.csr[cause_csr as usize],
+                epc: self.csr[epc_csr as usize],
+                tval: self.csr[tval_csr as usize],
+            });
+            return;
+        }
+
+        let interrupt = cause & INTERRUPT_BIT != 0;
+        let code = cause & !INTERRUPT_BIT;
+
+        let delegated = if interrupt {
+            self.csr[CSR_MIDELEG as usize]
+        } else {
+            self.csr[CSR_MEDELEG as usize]
+        };
+
+        let to_supervisor = self.mode <= Privilege::Supervisor &&
+            code < 64 && delegated & (1 << code) != 0;
+
+        let mstatus = self.csr[CSR_MSTATUS as usize];
+
+        // NOTE(patrik): Save the interrupt enable bit and the previous
+        // privilege and disable interrupts while the trap is handled
+        let tvec = if to_supervisor {
+            self.csr[CSR_SEPC as usize] = epc;
+            self.csr[CSR_SCAUSE as usize] = cause;
+            self.csr[CSR_STVAL as usize] = tval;
+
+            let spie = if mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if self.mode == Privilege::Supervisor { MSTATUS_SPP } else { 0 };
+            self.csr[CSR_MSTATUS as usize] =
+                (mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) |
+                spie | spp;
+
+            self.mode = Privilege::Supervisor;
+            self.csr[CSR_STVEC as usize]
+        } else {
+            if self.sbi.is_some() {
+                // NOTE(patrik): There is no M-mode code with the built-in
+                // SBI so a trap to M-mode can't be handled
+                self.fault = Some(Fault {
+                    cause,
+                    epc,
+                    tval,
+                });
+                return;
+            }
+
+            self.csr[CSR_MEPC as usize] = epc;
+            self.csr[CSR_MCAUSE as usize] = cause;
+            self.csr[CSR_MTVAL as usize] = tval;
+
+            let mpie = if mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            let mpp = (self.mode as u64) << 11;
+            self.csr[CSR_MSTATUS as usize] =
+                (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) |
+                mpie | mpp;
+
+            self.mode = Privilege::Machine;
+            self.csr[CSR_MTVEC as usize]
+        };
+
+        let base = tvec & !0b11;
+        let vectored = tvec & 0b11 == 1;
+
+        let pc = if vectored && interrupt {
+            base.wrapping_add(code * 4)
+        } else {
+            base
+        };
+
+        self.set_reg(Reg::Pc, pc);
+    }
+
+    /// Update ´mip´ from the devices and the SBI and take a pending
+    /// interrupt if it's enabled, returns true if an interrupt was taken
+    fn check_interrupts(&mut self) -> bool {
+        let mut hardware = self.mmu.interrupts(self.hartid);
+
+        if self.csr[CSR_MENVCFG as usize] & MENVCFG_STCE != 0 &&
+            self.time() >= self.csr[CSR_STIMECMP as usize]
+        {
+            hardware |= MIP_STIP;
+        }
+
+        if let Some(sbi) = &self.sbi {
+            let hartid = self.hartid as usize;
+            if sbi.timer_pending(hartid, self.time()) {
+                hardware |= MIP_STIP;
+            }
+
+            if sbi.take_ipi(hartid) {
+                self.csr[CSR_MIP as usize] |= MIP_SSIP;
+            }
+        }
+
+        self.mip_hardware = hardware;
+        let mip = self.csr[CSR_MIP as usize] | hardware;
+
+        let pending = mip & self.csr[CSR_MIE as usize];
+        if pending == 0 {
+            return false;
+        }
+
+        let mstatus = self.csr[CSR_MSTATUS as usize];
+        let mideleg = self.csr[CSR_MIDELEG as usize];
+
+        // NOTE(patrik): Interrupts for a higher privilege are always
+        // enabled, for the current privilege they depend on xIE
+        let m_enabled = self.mode < Privilege::Machine ||
+            mstatus & MSTATUS_MIE != 0;
+        let s_enabled = self.mode < Privilege::Supervisor ||
+            (self.mode == Privilege::Supervisor && mstatus & MSTATUS_SIE != 0);
+
+        for code in INTERRUPT_PRIORITY {
+            if pending & (1 << code) == 0 {
+                continue;
+            }
+
+            let enabled = if mideleg & (1 << code) != 0 {
+                s_enabled
+            } else {
+                m_enabled
+            };
+
+            if enabled {
+                let pc = self.reg(Reg::Pc);
+                self.trap(INTERRUPT_BIT | code, pc, 0);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn read_csr(&self, csr: u16) -> u64 {
+        let mideleg = self.csr[CSR_MIDELEG as usize];
+
+        match csr {
+            CSR_SSTATUS => self.csr[CSR_MSTATUS as usize] & SSTATUS_MASK,
+            CSR_SIE => self.csr[CSR_MIE as usize] & mideleg,
+            CSR_SIP => self.read_csr(CSR_MIP) & mideleg,
+            CSR_MIP => self.csr[CSR_MIP as usize] | self.mip_hardware,
+            CSR_MISA => MISA,
+            CSR_MHARTID => self.hartid,
+            CSR_CYCLE | CSR_INSTRET | CSR_MCYCLE | CSR_MINSTRET => self.instret,
+            CSR_TIME => self.time(),
+
+            _ => self.csr[csr as usize],
+        }
+    }
+
+    fn write_csr(&mut self, csr: u16, value: u64) {
+        let mideleg = self.csr[CSR_MIDELEG as usize];
+
+        match csr {
+            CSR_SSTATUS => {
+                let mstatus = self.csr[CSR_MSTATUS as usize];
+                let writable = SSTATUS_MASK & !MSTATUS_UXL;
+                self.csr[CSR_MSTATUS as usize] =
+                    (mstatus & !writable) | (value & writable);
+            }
+            CSR_SIE => {
+                let mie = self.csr[CSR_MIE as usize];
+                self.csr[CSR_MIE as usize] = (mie & !mideleg) | (value & mideleg);
+            }
+            CSR_SIP => {
+                let writable = mideleg & MIP_SSIP;
+                let mip = self.csr[CSR_MIP as usize];
+                self.csr[CSR_MIP as usize] = (mip & !writable) | (value & writable);
+            }
+            CSR_MSTATUS => {
+                // NOTE(patrik): The XLEN fields are read-only
+                let fixed = MSTATUS_UXL | MSTATUS_SXL;
+                self.csr[CSR_MSTATUS as usize] =
+                    (value & !fixed) | MSTATUS_XLEN_64;
+            }
+            CSR_MIP => {
+                let mip = self.csr[CSR_MIP as usize];
+                self.csr[CSR_MIP as usize] =
+                    (mip & !MIP_WRITABLE) | (value & MIP_WRITABLE);
+            }
+            CSR_MIDELEG => {
+                self.csr[CSR_MIDELEG as usize] = value & MIDELEG_WRITABLE;
+            }
+            CSR_MEDELEG => {
+                self.csr[CSR_MEDELEG as usize] = value & MEDELEG_WRITABLE;
+            }
+            CSR_SATP => {
+                // NOTE(patrik): Writes selecting an unsupported mode are
+                // ignored, kernels probe for the modes this way
+                let mode = value >> 60;
+                if mode == SATP_MODE_BARE || mode == SATP_MODE_SV39 {
+                    self.csr[CSR_SATP as usize] = value;
+                }
+            }
+            CSR_MISA | CSR_MHARTID => {}
+
+            _ => self.csr[csr as usize] = value,
+        }
+    }
+
+    /// Execute a CSR instruction, ´op´ gets the old value and returns the
+    /// value to write or None if the CSR isn't written. Raises an illegal
+    /// instruction exception for CSRs the current privilege can't access
+    fn csr_instruction<F>(&mut self, pc: u64, rd: Reg, csr: u16, op: F)
+        where F: Fn(u64) -> Option<u64>
+    {
+        let required = Privilege::from_bits((csr >> 8) as u64);
+        let read_only = (csr >> 10) & 0b11 == 0b11;
+
+        let old = self.read_csr(csr);
+        let new = op(old);
+
+        if self.mode < required || (read_only && new.is_some()) {
+            self.trap(EXCEPTION_ILLEGAL_INSTRUCTION, pc, self.inst as u64);
+            return;
+        }
+
+        if let Some(new) = new {
+            self.write_csr(csr, new);
+        }
+
+        self.set_reg(rd, old);
+    }
+
+    /// Do the Linux system call in a7 with the arguments in a0 to a5
+    fn linux_call(&mut self) {
+        let number = self.reg(Reg::X17);
+        let args = [
+            self.reg(Reg::X10), self.reg(Reg::X11), self.reg(Reg::X12),
+            self.reg(Reg::X13), self.reg(Reg::X14), self.reg(Reg::X15),
+        ];
+
+        let Some(linux) = self.linux.as_mut() else {
+            return;
+        };
+
+        let result = linux.syscall(number, args);
+        self.set_reg(Reg::X10, result);
+    }
+
+    /// Handle an ECALL from S-mode with the built-in SBI
+    fn sbi_call(&mut self, sbi: &Sbi) {
+        let mut args = [0u64; 8];
+        for (index, arg) in args.iter_mut().enumerate() {
+            *arg = self.reg(Reg::from(10 + index as u32));
+        }
+
+        match sbi.call(self.hartid as usize, args) {
+            SbiOutcome::Return { error, value } => {
+                self.set_reg(Reg::X10, error as u64);
+                self.set_reg(Reg::X11, value);
+            }
+
+            SbiOutcome::Legacy(value) => self.set_reg(Reg::X10, value),
+
+            SbiOutcome::Stop => self.stopped = true,
+        }
+    }
+
+    /// Wait in the stopped state until the SBI starts the hart again
+    fn resume_stopped(&mut self) -> bool {
+        let Some(sbi) = &self.sbi else {
+            return false;
+        };
+
+        let Some((addr, opaque)) = sbi.take_start(self.hartid as usize) else {
+            return false;
+        };
+
+        // NOTE(patrik): The hart starts in S-mode with translation and
+        // interrupts off, a0 is the hartid and a1 the opaque value
+        self.stopped = false;
+        self.csr[CSR_SATP as usize] = 0;
+        self.csr[CSR_MSTATUS as usize] &= !MSTATUS_SIE;
+        self.set_reg(Reg::X10, self.hartid);
+        self.set_reg(Reg::X11, opaque);
+        self.enter_supervisor(addr);
+
+        true
+    }
+
+    /// Check if the EBREAK at ´pc´ is part of the semihosting sequence
+    fn is_semihosting_call(&mut self, pc: u64) -> bool {
+        // NOTE(patrik): The sequence is three uncompressed instructions on
+        // the same page, a C.EBREAK is never part of it
+        let start = pc.wrapping_sub(4);
+        let end = pc.wrapping_add(8);
+        if self.semihosting.is_none() || self.inst != EBREAK ||
+            start / PAGE_SIZE != (end - 1) / PAGE_SIZE
+        {
+            return false;
+        }
+
+        // NOTE(patrik): The neighbours are fetched like instructions, if
+        // they can't be it's a normal EBREAK
+        let mut fetch = |addr: u64| {
+            let phys = self.translate(addr, Access::Fetch).ok()?;
+            self.mmu.read(phys, TypeWidth::Word)
+        };
+
+        fetch(start) == Some(SEMIHOSTING_ENTRY as u64) &&
+            fetch(pc.wrapping_add(4)) == Some(SEMIHOSTING_EXIT as u64)
+    }
+
+    /// Translate the virtual address ´addr´ to a physical address, returns
+    /// the cause of the exception if the access causes a page fault or the
+    /// page table can't be read
+    fn translate(&mut self, addr: u64, access: Access)
+        -> Result<u64, u64>
+    {
+        let mstatus = self.csr[CSR_MSTATUS as usize];
+
+        // NOTE(patrik): With MPRV set loads and stores from M-mode are
+        // translated as if they were done in the mode in MPP
+        let mode = if access != Access::Fetch && mstatus & MSTATUS_MPRV != 0 {
+            Privilege::from_bits(mstatus >> 11)
+        } else {
+            self.mode
+        };
+
+        let satp = self.csr[CSR_SATP as usize];
+        if mode == Privilege::Machine || satp >> 60 != SATP_MODE_SV39 {
+            return Ok(addr);
+        }
+
+        // Bits 63 to 39 has to be copies of bit 38
+        if (((addr as i64) << 25) >> 25) as u64 != addr {
+            return Err(access.page_fault());
+        }
+
+        // TODO(patrik): Cache the translations in a TLB, every access walks
+        // the page table right now
+        let mut table = (satp & SATP_PPN_MASK) * PAGE_SIZE;
+        for level in (0..SV39_LEVELS).rev() {
+            let vpn = (addr >> (12 + level * 9)) & 0x1ff;
+            let pte_addr = table + vpn * 8;
+            let Some(pte) = self.mmu.read(pte_addr, TypeWidth::DoubleWord)
+            else {
+                return Err(access.access_fault());
+            };
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault());
+            }
+
+            let ppn = (pte >> 10) & SATP_PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn * PAGE_SIZE;
+                continue;
+            }
+
+            let user = pte & PTE_U != 0;
+            let allowed_mode = match mode {
+                Privilege::User => user,
+                Privilege::Supervisor => {
+                    let sum = mstatus & MSTATUS_SUM != 0;
+                    !user || (access != Access::Fetch && sum)
+                }
+                Privilege::Machine => true,
+            };
+
+            let readable = pte & PTE_R != 0 ||
+                (pte & PTE_X != 0 && mstatus & MSTATUS_MXR != 0);
+            let allowed_access = match access {
+                Access::Fetch => pte & PTE_X != 0,
+                Access::Load => readable,
+                Access::Store => pte & PTE_W != 0,
+            };
+
+            if !allowed_mode || !allowed_access {
+                return Err(access.page_fault());
+            }
+
+            // A superpage has to be aligned to its size
+            let offset_bits = 12 + level * 9;
+            let superpage_mask = (1 << (level * 9)) - 1;
+            if ppn & superpage_mask != 0 {
+                return Err(access.page_fault());
+            }
+
+            // NOTE(patrik): The accessed and dirty bits are updated by the
+            // hart instead of raising a page fault
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+
+            if updated != pte &&
+                !self.mmu.write(pte_addr, updated, TypeWidth::DoubleWord)
+            {
+                return Err(access.access_fault());
+            }
+
+            let offset_mask = (1 << offset_bits) - 1;
+            let base = (ppn * PAGE_SIZE) & !offset_mask;
+            return Ok(base | (addr & offset_mask));
+        }
+
+        Err(access.page_fault())
+    }
+
+    /// Translate ´addr´ and raise an exception for the instruction at ´pc´
+    /// if the translation fails
+    fn translate_or_fault(&mut self, pc: u64, addr: u64, access: Access)
+        -> Option<u64>
+    {
+        match self.translate(addr, access) {
+            Ok(phys) => Some(phys),
+            Err(cause) => {
+                self.trap(cause, pc, addr);
+                None
+            }
+        }
+    }
+
+    /// Read the physical address ´phys´, the translation of ´addr´, and
+    /// raise an access fault for the instruction at ´pc´ if nothing is
+    /// mapped there
+    fn read_physical(&mut self, pc: u64, addr: u64, phys: u64,
+                     width: TypeWidth, access: Access)
+        -> Option<u64>
+    {
+        let value = self.mmu.read(phys, width);
+        if value.is_none() {
+            self.trap(access.access_fault(), pc, addr);
+        }
+
+        value
+    }
+
+    /// Write the physical address ´phys´, the translation of ´addr´, and
+    /// raise an access fault for the instruction at ´pc´ if nothing is
+    /// mapped there. Returns false if the access fault was raised
+    fn write_physical(&mut self, pc: u64, addr: u64, phys: u64, value: u64,
+                      width: TypeWidth)
+        -> bool
+    {
+        let written = self.mmu.write(phys, value, width);
+        if !written {
+            self.trap(EXCEPTION_STORE_ACCESS_FAULT, pc, addr);
+        }
+
+        written
+    }
+
+    /// Read from the virtual address ´addr´ for the instruction at ´pc´,
+    /// returns None if the access raised an exception
+    fn read_virtual(&mut self, pc: u64, addr: u64, width: TypeWidth,
+                    access: Access)
+        -> Option<u64>
+    {
+        let size = width.size();
+        if (addr % PAGE_SIZE) + size <= PAGE_SIZE {
+            let phys = self.translate_or_fault(pc, addr, access)?;
+            return self.read_physical(pc, addr, phys, width, access);
         }
+
+        // NOTE(patrik): A misaligned access that crosses a page is split
+        // into bytes since the two pages can be anywhere in memory
+        let mut value = 0;
+        for index in 0..size {
+            let addr = addr.wrapping_add(index);
+            let phys = self.translate_or_fault(pc, addr, access)?;
+            let byte = self.read_physical(pc, addr, phys, TypeWidth::Byte,
+                                          access)?;
+            value |= byte << (index * 8);
+        }
+
+        Some(value)
     }
 
+    fn load(&mut self, pc: u64, addr: u64, width: TypeWidth) -> Option<u64> {
+        self.read_virtual(pc, addr, width, Access::Load)
+    }
+
+    /// Write to the virtual address ´addr´ for the instruction at ´pc´,
+    /// nothing is written if the access raised an exception
+    fn store(&mut self, pc: u64, addr: u64, value: u64, width: TypeWidth) {
+        let size = width.size();
+        if (addr % PAGE_SIZE) + size <= PAGE_SIZE {
+            let phys = self.translate_or_fault(pc, addr, Access::Store);
+            if let Some(phys) = phys {
+                self.write_physical(pc, addr, phys, value, width);
+            }
+
+            return;
+        }
 
-    fn fetch_u32(&mut self) -> u32 {
+        // NOTE(patrik): Both pages are translated before anything is
+        // written so a page fault doesn't leave a partial write behind
+        let mut addrs = [0u64; 8];
+        for index in 0..size {
+            let virt = addr.wrapping_add(index);
+            let phys = self.translate_or_fault(pc, virt, Access::Store);
+            let Some(phys) = phys else {
+                return;
+            };
+
+            addrs[index as usize] = phys;
+        }
+
+        // TODO(patrik): The bytes before an unmapped one are still written
+        for index in 0..size {
+            let byte = (value >> (index * 8)) & 0xff;
+            let phys = addrs[index as usize];
+            if !self.mmu.write(phys, byte, TypeWidth::Byte) {
+                let addr = addr.wrapping_add(index);
+                self.trap(EXCEPTION_STORE_ACCESS_FAULT, pc, addr);
+                return;
+            }
+        }
+    }
+
+    /// Fetch the instruction at pc and move pc past it, returns None if the
+    /// fetch raised an exception
+    fn fetch(&mut self) -> Option<u32> {
         let pc = self.reg(Reg::Pc);
-        // TODO(patrik): Read from memory
-        let res = self.mmu.read_u32(pc);
-        self.set_reg(Reg::Pc, pc + 4);
 
-        res
+        // NOTE(patrik): Both halves are read at once when they are on the
+        // same page, the upper half is dropped for a compressed instruction
+        let (low, high) = if pc % PAGE_SIZE <= PAGE_SIZE - 4 {
+            let inst = self.read_virtual(pc, pc, TypeWidth::Word,
+                                         Access::Fetch)? as u32;
+            (inst & 0xffff, Some(inst >> 16))
+        } else {
+            let low = self.read_virtual(pc, pc, TypeWidth::HalfWord,
+                                        Access::Fetch)? as u32;
+            (low, None)
+        };
+
+        // NOTE(patrik): Instructions from the C extension are 16 bits, all
+        // others have the lowest two bits set
+        if low & 0b11 != 0b11 {
+            self.set_reg(Reg::Pc, pc.wrapping_add(2));
+            return Some(low);
+        }
+
+        let high = match high {
+            Some(high) => high,
+            None => {
+                let addr = pc.wrapping_add(2);
+                self.read_virtual(pc, addr, TypeWidth::HalfWord,
+                                  Access::Fetch)? as u32
+            }
+        };
+        self.set_reg(Reg::Pc, pc.wrapping_add(4));
+
+        Some(low | (high << 16))
+    }
+
+    /// Execute an LR, SC or AMO instruction
+    fn atomic(&mut self, pc: u64, inst: Instruction) {
+        let (rd, rs1, width) = match inst {
+            Instruction::Lr { rd, rs1, width } |
+            Instruction::Sc { rd, rs1, width, .. } |
+            Instruction::Amo { rd, rs1, width, .. } => (rd, rs1, width),
+
+            _ => unreachable!(),
+        };
+
+        let addr = self.reg(rs1);
+        let is_load = matches!(inst, Instruction::Lr { .. });
+
+        if addr % width.size() != 0 {
+            let cause = if is_load {
+                EXCEPTION_LOAD_MISALIGNED
+            } else {
+                EXCEPTION_STORE_MISALIGNED
+            };
+
+            self.trap(cause, pc, addr);
+            return;
+        }
+
+        let access = if is_load { Access::Load } else { Access::Store };
+        let virt = addr;
+        let Some(addr) = self.translate_or_fault(pc, virt, access) else {
+            return;
+        };
+
+        let sign_extend = |value: u64| {
+            if width == TypeWidth::Word {
+                value as i32 as i64 as u64
+            } else {
+                value
+            }
+        };
+
+        match inst {
+            Instruction::Lr { .. } => {
+                let Some(value) = self.read_physical(pc, virt, addr, width,
+                                                     access)
+                else {
+                    return;
+                };
+
+                self.mmu.reserve(self.hartid, addr);
+                self.set_reg(rd, sign_extend(value));
+            }
+
+            Instruction::Sc { rs2, .. } => {
+                if self.mmu.take_reservation(self.hartid, addr) {
+                    let value = self.reg(rs2);
+                    if self.write_physical(pc, virt, addr, value, width) {
+                        self.set_reg(rd, 0);
+                    }
+                } else {
+                    self.set_reg(rd, 1);
+                }
+            }
+
+            Instruction::Amo { op, rs2, .. } => {
+                // NOTE(patrik): An AMO raises store access faults, also for
+                // the read
+                let Some(old) = self.read_physical(pc, virt, addr, width,
+                                                   access)
+                else {
+                    return;
+                };
+
+                let old = sign_extend(old);
+                let src = sign_extend(self.reg(rs2));
+
+                let new = match op {
+                    AmoOp::Swap => src,
+                    AmoOp::Add => old.wrapping_add(src),
+                    AmoOp::Xor => old ^ src,
+                    AmoOp::And => old & src,
+                    AmoOp::Or => old | src,
+                    AmoOp::Min => (old as i64).min(src as i64) as u64,
+                    AmoOp::Max => (old as i64).max(src as i64) as u64,
+                    // NOTE(patrik): Both values are sign extended the same
+                    // way so comparing them unsigned gives the right order
+                    // for words too
+                    AmoOp::Minu => old.min(src),
+                    AmoOp::Maxu => old.max(src),
+                };
+
+                if self.write_physical(pc, virt, addr, new, width) {
+                    self.set_reg(rd, old);
+                }
+            }
+
+            _ => unreachable!(),
+        }
     }
 
     fn execute_instruction(&mut self, current_pc: u64, inst: Instruction) {
@@ -58,7 +1031,7 @@ impl SimpleHart {
 
             Instruction::Jalr { rd, rs1, imm } => {
                 let target = self.reg(rs1)
-                    .wrapping_add(imm as i64 as u64);
+                    .wrapping_add(imm as i64 as u64) & !1;
 
                 let return_addr = self.reg(Reg::Pc);
                 self.set_reg(rd, return_addr);
@@ -110,78 +1083,92 @@ impl SimpleHart {
             Instruction::Lb  { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u8(addr);
-                self.set_reg(rd, result as i8 as i64 as u64);
+                let result = self.load(current_pc, addr, TypeWidth::Byte);
+                if let Some(result) = result {
+                    self.set_reg(rd, result as i8 as i64 as u64);
+                }
             }
 
             Instruction::Lh  { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u16(addr);
-                self.set_reg(rd, result as i16 as i64 as u64);
+                let result = self.load(current_pc, addr, TypeWidth::HalfWord);
+                if let Some(result) = result {
+                    self.set_reg(rd, result as i16 as i64 as u64);
+                }
             }
 
             Instruction::Lw  { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u32(addr);
-                self.set_reg(rd, result as i32 as i64 as u64);
+                let result = self.load(current_pc, addr, TypeWidth::Word);
+                if let Some(result) = result {
+                    self.set_reg(rd, result as i32 as i64 as u64);
+                }
             }
 
             Instruction::Lbu { rd, rs1, imm } => { 
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u8(addr);
-                self.set_reg(rd, result as u64);
+                let result = self.load(current_pc, addr, TypeWidth::Byte);
+                if let Some(result) = result {
+                    self.set_reg(rd, result);
+                }
             }
 
             Instruction::Lhu { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u16(addr);
-                self.set_reg(rd, result as u64);
+                let result = self.load(current_pc, addr, TypeWidth::HalfWord);
+                if let Some(result) = result {
+                    self.set_reg(rd, result);
+                }
             }
 
             Instruction::Lwu { rd, rs1, imm } => { 
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u32(addr);
-                self.set_reg(rd, result as u64);
+                let result = self.load(current_pc, addr, TypeWidth::Word);
+                if let Some(result) = result {
+                    self.set_reg(rd, result);
+                }
             }
 
             Instruction::Ld  { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let result = self.mmu.read_u64(addr);
-                self.set_reg(rd, result as i64 as u64);
+                let result = self.load(current_pc, addr, TypeWidth::DoubleWord);
+                if let Some(result) = result {
+                    self.set_reg(rd, result as i64 as u64);
+                }
             }
 
             Instruction::Sb { rs1, rs2, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let value = self.reg(rs2) as u8;
-                self.mmu.write_u8(addr, value);
+                let value = self.reg(rs2);
+                self.store(current_pc, addr, value, TypeWidth::Byte);
             }
 
             Instruction::Sh { rs1, rs2, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let value = self.reg(rs2) as u16;
-                self.mmu.write_u16(addr, value);
+                let value = self.reg(rs2);
+                self.store(current_pc, addr, value, TypeWidth::HalfWord);
             }
 
             Instruction::Sw { rs1, rs2, imm } => { 
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
-                let value = self.reg(rs2) as u32;
-                self.mmu.write_u32(addr, value);
+                let value = self.reg(rs2);
+                self.store(current_pc, addr, value, TypeWidth::Word);
             }
 
             Instruction::Sd { rs1, rs2, imm } => { 
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
                 let value = self.reg(rs2);
-                self.mmu.write_u64(addr, value);
+                self.store(current_pc, addr, value, TypeWidth::DoubleWord);
             }
 
             Instruction::Addi  { rd, rs1, imm } => { 
@@ -348,59 +1335,245 @@ impl SimpleHart {
                 self.set_reg(rd, result as i64 as u64);
             }
 
+            Instruction::Mul    { rd, rs1, rs2 } => {
+                let result = self.reg(rs1).wrapping_mul(self.reg(rs2));
+                self.set_reg(rd, result);
+            }
+
+            Instruction::Mulh   { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as i64 as i128;
+                let rs2 = self.reg(rs2) as i64 as i128;
+                let result = (rs1 * rs2) >> 64;
+                self.set_reg(rd, result as u64);
+            }
+
+            Instruction::Mulhsu { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as i64 as i128;
+                let rs2 = self.reg(rs2) as i128;
+                let result = rs1.wrapping_mul(rs2) >> 64;
+                self.set_reg(rd, result as u64);
+            }
+
+            Instruction::Mulhu  { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u128;
+                let rs2 = self.reg(rs2) as u128;
+                let result = (rs1 * rs2) >> 64;
+                self.set_reg(rd, result as u64);
+            }
+
+            // NOTE(patrik): Division doesn't trap, dividing by zero gives
+            // all bits set and the remainder is the dividend. The overflow
+            // of the most negative value divided by -1 wraps around
+            Instruction::Div    { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as i64;
+                let rs2 = self.reg(rs2) as i64;
+                let result = if rs2 == 0 { -1 } else { rs1.wrapping_div(rs2) };
+                self.set_reg(rd, result as u64);
+            }
+
+            Instruction::Divu   { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1);
+                let rs2 = self.reg(rs2);
+                let result = rs1.checked_div(rs2).unwrap_or(u64::MAX);
+                self.set_reg(rd, result);
+            }
+
+            Instruction::Rem    { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as i64;
+                let rs2 = self.reg(rs2) as i64;
+                let result = if rs2 == 0 { rs1 } else { rs1.wrapping_rem(rs2) };
+                self.set_reg(rd, result as u64);
+            }
+
+            Instruction::Remu   { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1);
+                let rs2 = self.reg(rs2);
+                let result = rs1.checked_rem(rs2).unwrap_or(rs1);
+                self.set_reg(rd, result);
+            }
+
+            Instruction::Mulw   { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32;
+                let rs2 = self.reg(rs2) as u32;
+                let result = rs1.wrapping_mul(rs2);
+                self.set_reg(rd, result as i32 as i64 as u64);
+            }
+
+            Instruction::Divw   { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as i32;
+                let rs2 = self.reg(rs2) as i32;
+                let result = if rs2 == 0 { -1 } else { rs1.wrapping_div(rs2) };
+                self.set_reg(rd, result as i64 as u64);
+            }
+
+            Instruction::Divuw  { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32;
+                let rs2 = self.reg(rs2) as u32;
+                let result = rs1.checked_div(rs2).unwrap_or(u32::MAX);
+                self.set_reg(rd, result as i32 as i64 as u64);
+            }
+
+            Instruction::Remw   { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as i32;
+                let rs2 = self.reg(rs2) as i32;
+                let result = if rs2 == 0 { rs1 } else { rs1.wrapping_rem(rs2) };
+                self.set_reg(rd, result as i64 as u64);
+            }
+
+            Instruction::Remuw  { rd, rs1, rs2 } => {
+                let rs1 = self.reg(rs1) as u32;
+                let rs2 = self.reg(rs2) as u32;
+                let result = rs1.checked_rem(rs2).unwrap_or(rs1);
+                self.set_reg(rd, result as i32 as i64 as u64);
+            }
+
+            Instruction::Lr { .. } |
+            Instruction::Sc { .. } |
+            Instruction::Amo { .. } => {
+                self.atomic(current_pc, inst);
+            }
+
             Instruction::Fence {} => { }
 
+            // NOTE(patrik): Instructions aren't cached so there is nothing
+            // to flush
+            Instruction::FenceI => { }
+
+            // NOTE(patrik): There is no TLB, every access walks the page
+            // table so there is nothing to flush
+            Instruction::SfenceVma { .. } => {
+                if self.mode < Privilege::Supervisor {
+                    self.trap(EXCEPTION_ILLEGAL_INSTRUCTION, current_pc, 0);
+                }
+            }
+
+            // NOTE(patrik): Waiting is optional, the interrupts are checked
+            // before every instruction anyway
+            Instruction::Wfi => { }
+
             Instruction::Ecall => {
-                const CSR_MTVEC: u16 = 0x305;
+                if self.mode == Privilege::Supervisor {
+                    if let Some(sbi) = self.sbi.clone() {
+                        self.sbi_call(&sbi);
+                        return;
+                    }
+                }
+
+                if self.mode == Privilege::User && self.linux.is_some() {
+                    self.linux_call();
+                    return;
+                }
 
-                let pc = self.csr[CSR_MTVEC as usize];
+                let cause = match self.mode {
+                    Privilege::User => EXCEPTION_ECALL_U,
+                    Privilege::Supervisor => EXCEPTION_ECALL_S,
+                    Privilege::Machine => EXCEPTION_ECALL_M,
+                };
+
+                self.trap(cause, current_pc, 0);
+            }
+
+            Instruction::Ebreak => {
+                if self.is_semihosting_call(current_pc) {
+                    let operation = self.reg(Reg::X10);
+                    let parameter = self.reg(Reg::X11);
+
+                    if let Some(semihosting) = self.semihosting.as_mut() {
+                        let result = semihosting.call(
+                            self.mmu.as_mut(), operation, parameter);
+                        self.set_reg(Reg::X10, result);
+                    }
+                } else {
+                    self.trap(EXCEPTION_BREAKPOINT, current_pc, current_pc);
+                }
+            }
+
+            Instruction::Sret => {
+                if self.mode < Privilege::Supervisor {
+                    self.trap(EXCEPTION_ILLEGAL_INSTRUCTION, current_pc, 0);
+                    return;
+                }
+
+                // NOTE(patrik): Restore the interrupt enable bit and the
+                // privilege saved when the trap was taken
+                let mstatus = self.csr[CSR_MSTATUS as usize];
+                let sie = if mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+                let mode = if mstatus & MSTATUS_SPP != 0 {
+                    Privilege::Supervisor
+                } else {
+                    Privilege::User
+                };
+
+                let mut mstatus = (mstatus & !(MSTATUS_SIE | MSTATUS_SPP)) |
+                    sie | MSTATUS_SPIE;
+                if mode != Privilege::Machine {
+                    mstatus &= !MSTATUS_MPRV;
+                }
+                self.csr[CSR_MSTATUS as usize] = mstatus;
+
+                self.mode = mode;
+                let pc = self.csr[CSR_SEPC as usize];
                 self.set_reg(Reg::Pc, pc);
             }
 
-            Instruction::Ebreak => { todo!(); }
-            Instruction::Sret => { todo!(); }
             Instruction::Mret => {
-                // TODO(patrik): Not correct 
-                const CSR_MEPC: u16 = 0x341;
-                const CSR_MCAUSE: u16 = 0x342;
+                if self.mode < Privilege::Machine {
+                    self.trap(EXCEPTION_ILLEGAL_INSTRUCTION, current_pc, 0);
+                    return;
+                }
 
-                self.csr[CSR_MCAUSE as usize] = 11;
+                let mstatus = self.csr[CSR_MSTATUS as usize];
+                let mie = if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+                let mode = Privilege::from_bits(mstatus >> 11);
 
+                let mut mstatus = (mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) |
+                    mie | MSTATUS_MPIE;
+                if mode != Privilege::Machine {
+                    mstatus &= !MSTATUS_MPRV;
+                }
+                self.csr[CSR_MSTATUS as usize] = mstatus;
+
+                self.mode = mode;
                 let pc = self.csr[CSR_MEPC as usize];
                 self.set_reg(Reg::Pc, pc);
             }
 
             Instruction::Csrrw { rd, rs1, csr } => {
-                if rd != Reg::X0 {
-                    let old = self.csr[csr as usize];
-                    self.set_reg(rd, old);
-                }
-
-                self.csr[csr as usize] = self.reg(rs1);
+                let value = self.reg(rs1);
+                self.csr_instruction(current_pc, rd, csr, |_| Some(value));
             }
 
             Instruction::Csrrs { rd, rs1, csr } => {
-                let old = self.csr[csr as usize];
-                self.set_reg(rd, old);
-
-                if rs1 != Reg::X0 {
-                    self.csr[csr as usize] = old | self.reg(rs1);
-                }
+                let mask = self.reg(rs1);
+                let write = rs1 != Reg::X0;
+                self.csr_instruction(current_pc, rd, csr, |old| {
+                    write.then_some(old | mask)
+                });
             }
 
-            Instruction::Csrrc { rd, rs1, csr } => { todo!(); }
+            Instruction::Csrrc { rd, rs1, csr } => {
+                let mask = self.reg(rs1);
+                let write = rs1 != Reg::X0;
+                self.csr_instruction(current_pc, rd, csr, |old| {
+                    write.then_some(old & !mask)
+                });
+            }
 
             Instruction::Csrrwi { rd, uimm, csr } => {
-                if rd != Reg::X0 {
-                    let old = self.csr[csr as usize];
-                    self.set_reg(rd, old);
-                }
+                self.csr_instruction(current_pc, rd, csr, |_| Some(uimm as u64));
+            }
 
-                self.csr[csr as usize] = uimm as u64;
+            Instruction::Csrrsi { rd, uimm, csr } => {
+                self.csr_instruction(current_pc, rd, csr, |old| {
+                    (uimm != 0).then_some(old | uimm as u64)
+                });
             }
 
-            Instruction::Csrrsi { rd, uimm, csr } => { todo!(); }
-            Instruction::Csrrci { rd, uimm, csr } => { todo!(); }
+            Instruction::Csrrci { rd, uimm, csr } => {
+                self.csr_instruction(current_pc, rd, csr, |old| {
+                    (uimm != 0).then_some(old & !(uimm as u64))
+                });
+            }
 
             /*
             Instruction::Lui { rd, imm } => {
@@ -556,13 +1729,52 @@ impl Hart for SimpleHart {
 
     /// Step the hart one instruction
     fn step(&mut self) {
+        if self.fault.is_some() {
+            return;
+        }
+
+        if self.stopped && !self.resume_stopped() {
+            return;
+        }
+
+        if self.check_interrupts() {
+            return;
+        }
+
         let pc = self.reg(Reg::Pc);
-        let inst = self.fetch_u32();
+        let Some(inst) = self.fetch() else {
+            return;
+        };
         // println!("{:#x}: {:#x}", pc, inst);
 
-        match Instruction::decode(inst) {
+        self.inst = inst;
+        self.instret = self.instret.wrapping_add(1);
+
+        let decoded = if inst & 0b11 == 0b11 {
+            Instruction::decode(inst)
+        } else {
+            Instruction::decode_compressed(inst as u16)
+        };
+
+        match decoded {
             Ok(inst) => self.execute_instruction(pc, inst),
-            Err(e) => panic!("Failed to decode inst: {:#x} {:x?}", pc, e),
+            Err(e) => {
+                // NOTE(patrik): Without a trap handler the illegal
+                // instruction would just jump to 0, fail loudly instead
+                let handled = self.mode < Privilege::Machine ||
+                    self.csr[CSR_MTVEC as usize] != 0;
+                if !handled {
+                    eprintln!("Failed to decode inst: {:#x} {:x?}", pc, e);
+                    self.fault = Some(Fault {
+                        cause: EXCEPTION_ILLEGAL_INSTRUCTION,
+                        epc: pc,
+                        tval: inst as u64,
+                    });
+                    return;
+                }
+
+                self.trap(EXCEPTION_ILLEGAL_INSTRUCTION, pc, inst as u64);
+            }
         }
     }
 }
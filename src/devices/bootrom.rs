@@ -0,0 +1,66 @@
+//! Read-only memory the harts start executing from at reset
+
+use crate::memory::TypeWidth;
+use super::Device;
+
+/// Offset of the device tree in the Spike boot ROM, right after the reset
+/// vector
+pub const BOOT_ROM_DTB_OFFSET: u64 = 0x20;
+
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    /// Boot ROM with the same reset vector as Spike followed by the device
+    /// tree, every hart jumps to ´entry´ with its hartid in a0 and the
+    /// address of the device tree in a1
+    pub fn spike(entry: u64, dtb: &[u8]) -> Self {
+        let reset_vector: [u32; 8] = [
+            // auipc t0, 0
+            0x00000297,
+            // addi a1, t0, BOOT_ROM_DTB_OFFSET
+            0x00028593 | ((BOOT_ROM_DTB_OFFSET as u32) << 20),
+            // csrr a0, mhartid
+            0xf1402573,
+            // ld t0, 24(t0)
+            0x0182b283,
+            // jr t0
+            0x00028067,
+            0,
+            entry as u32,
+            (entry >> 32) as u32,
+        ];
+
+        let mut data: Vec<u8> = reset_vector.iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        data.extend_from_slice(dtb);
+
+        Self {
+            data,
+        }
+    }
+
+    /// Size of the region the ROM is mapped at, rounded up to a page
+    pub fn size(&self) -> u64 {
+        (self.data.len() as u64).next_multiple_of(0x1000)
+    }
+}
+
+impl Device for BootRom {
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        let mut value = 0;
+        for index in 0..width.size() {
+            let byte = self.data.get((offset + index) as usize)
+                .copied()
+                .unwrap_or(0);
+            value |= (byte as u64) << (index * 8);
+        }
+
+        value
+    }
+
+    // NOTE(patrik): Writes to the ROM are ignored
+    fn write(&mut self, _offset: u64, _value: u64, _width: TypeWidth) {}
+}
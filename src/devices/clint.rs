@@ -0,0 +1,152 @@
+//! SiFive CLINT, the machine timer and software interrupts of the harts
+
+use crate::memory::TypeWidth;
+use crate::cpu::{ MIP_MSIP, MIP_MTIP };
+use super::{ Device, Clock };
+
+/// Size of the register region of the CLINT
+pub const CLINT_SIZE: u64 = 0x10000;
+
+const MSIP: u64 = 0x0000;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// How many ticks between every comparison of ´mtime´ against the
+/// ´mtimecmp´ registers, reading the host clock is slow
+const POLL_INTERVAL: u64 = 64;
+
+pub struct Clint {
+    clock: Clock,
+
+    /// ´msip´ register of every hart
+    msip: Vec<bool>,
+
+    /// ´mtimecmp´ register of every hart
+    mtimecmp: Vec<u64>,
+
+    /// Timer interrupt of every hart from the last comparison
+    mtip: Vec<bool>,
+
+    ticks: u64,
+}
+
+impl Clint {
+    pub fn new(harts: usize, clock: Clock) -> Self {
+        Self {
+            clock,
+
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+            mtip: vec![false; harts],
+
+            ticks: 0,
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        self.clock.timebase_ticks()
+    }
+
+    fn update_timers(&mut self) {
+        let mtime = self.mtime();
+        for (mtip, mtimecmp) in self.mtip.iter_mut().zip(&self.mtimecmp) {
+            *mtip = mtime >= *mtimecmp;
+        }
+    }
+}
+
+/// Read ´width´ from the 64-bit register ´value´ at ´offset´ bytes into it
+fn read_part(value: u64, offset: u64, width: TypeWidth) -> u64 {
+    let value = value >> (offset * 8);
+    match width {
+        TypeWidth::Byte => value & 0xff,
+        TypeWidth::HalfWord => value & 0xffff,
+        TypeWidth::Word => value & 0xffffffff,
+        TypeWidth::DoubleWord => value,
+    }
+}
+
+/// Write ´width´ of ´new´ into the 64-bit register ´value´ at ´offset´
+/// bytes into it, RV32 software writes ´mtimecmp´ in two halves
+fn write_part(value: u64, offset: u64, new: u64, width: TypeWidth) -> u64 {
+    let shift = offset * 8;
+    let mask = match width {
+        TypeWidth::Byte => 0xff,
+        TypeWidth::HalfWord => 0xffff,
+        TypeWidth::Word => 0xffffffff,
+        TypeWidth::DoubleWord => u64::MAX,
+    } << shift;
+
+    (value & !mask) | ((new << shift) & mask)
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        let harts = self.msip.len() as u64;
+
+        if (MTIME..MTIME + 8).contains(&offset) {
+            return read_part(self.mtime(), offset - MTIME, width);
+        }
+
+        if (MTIMECMP..MTIMECMP + harts * 8).contains(&offset) {
+            let hart = ((offset - MTIMECMP) / 8) as usize;
+            let value = self.mtimecmp[hart];
+            return read_part(value, (offset - MTIMECMP) % 8, width);
+        }
+
+        if offset < MSIP + harts * 4 {
+            let hart = ((offset - MSIP) / 4) as usize;
+            return self.msip[hart] as u64;
+        }
+
+        0
+    }
+
+    fn write(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        let harts = self.msip.len() as u64;
+
+        // NOTE(patrik): ´mtime´ is read-only, it's the same clock the
+        // ´time´ CSR reads
+        if (MTIMECMP..MTIMECMP + harts * 8).contains(&offset) {
+            let hart = ((offset - MTIMECMP) / 8) as usize;
+            let old = self.mtimecmp[hart];
+            self.mtimecmp[hart] =
+                write_part(old, (offset - MTIMECMP) % 8, value, width);
+
+            // NOTE(patrik): Compare right away, handlers expect the
+            // interrupt to go away as soon as ´mtimecmp´ is moved
+            self.update_timers();
+            return;
+        }
+
+        if offset < MSIP + harts * 4 {
+            let hart = ((offset - MSIP) / 4) as usize;
+            self.msip[hart] = value & 1 != 0;
+        }
+    }
+
+    fn hart_interrupts(&mut self, hartid: u64) -> u64 {
+        let hart = hartid as usize;
+        if hart >= self.msip.len() {
+            return 0;
+        }
+
+        let mut mip = 0;
+        if self.msip[hart] {
+            mip |= MIP_MSIP;
+        }
+
+        if self.mtip[hart] {
+            mip |= MIP_MTIP;
+        }
+
+        mip
+    }
+
+    fn tick(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.ticks.is_multiple_of(POLL_INTERVAL) {
+            self.update_timers();
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Host side console used by the devices that talk to the terminal
+
+use std::io::{ Read, Write };
+use std::sync::mpsc::{ self, Receiver, TryRecvError };
+
+pub struct HostConsole {
+    input: Option<Receiver<u8>>,
+}
+
+impl HostConsole {
+    pub fn new() -> Self {
+        Self {
+            input: None,
+        }
+    }
+
+    /// Write a single byte to the host stdout
+    pub fn putchar(&mut self, value: u8) {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&[value]).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    /// Read a single byte from the host stdin without blocking
+    pub fn getchar(&mut self) -> Option<u8> {
+        // NOTE(patrik): The reader thread is only started when the guest
+        // first asks for input so programs that never read from the
+        // console doesn't hold on to stdin
+        let input = self.input.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+
+            std::thread::spawn(move || {
+                let mut stdin = std::io::stdin();
+                let mut buffer = [0u8; 1];
+
+                while let Ok(1) = stdin.read(&mut buffer) {
+                    if sender.send(buffer[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            receiver
+        });
+
+        match input.try_recv() {
+            Ok(value) => Some(value),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Linear framebuffer that can be dumped to PPM files
+//!
+//! The pixels are stored at the start of the region, a page with control
+//! registers follows right after the pixels:
+//!   0x00 SNAPSHOT (W) Dump the framebuffer right now
+//!   0x04 FRAME    (R) Current frame number
+//!   0x08 WIDTH    (R)
+//!   0x0c HEIGHT   (R)
+//!   0x10 STRIDE   (R) Bytes per line
+//!   0x14 FORMAT   (R) Index of the ´PixelFormat´
+
+use std::fs::File;
+use std::io::{ BufWriter, Write };
+use std::path::PathBuf;
+
+use crate::memory::{ TestingMemory, TypeWidth };
+use super::{ Device, Clock };
+
+const SNAPSHOT: u64 = 0x00;
+const FRAME: u64 = 0x04;
+const WIDTH: u64 = 0x08;
+const HEIGHT: u64 = 0x0c;
+const STRIDE: u64 = 0x10;
+const FORMAT: u64 = 0x14;
+
+const CONTROL_SIZE: u64 = 0x1000;
+
+/// Length of one frame, the framebuffer runs at 60 Hz
+const FRAME_NS: u64 = 1_000_000_000 / 60;
+
+/// Number of ticks between checking if a new frame has started, a frame
+/// is a lot longer than that
+const TICKS_PER_CHECK: u32 = 1024;
+
+/// Pixel formats, named like the Linux simple-framebuffer formats
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    R5g6b5,
+    R8g8b8,
+    X8r8g8b8,
+    A8r8g8b8,
+    X8b8g8r8,
+    A8b8g8r8,
+}
+
+impl PixelFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "r5g6b5" => Some(PixelFormat::R5g6b5),
+            "r8g8b8" => Some(PixelFormat::R8g8b8),
+            "x8r8g8b8" => Some(PixelFormat::X8r8g8b8),
+            "a8r8g8b8" => Some(PixelFormat::A8r8g8b8),
+            "x8b8g8r8" => Some(PixelFormat::X8b8g8r8),
+            "a8b8g8r8" => Some(PixelFormat::A8b8g8r8),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::R5g6b5 => "r5g6b5",
+            PixelFormat::R8g8b8 => "r8g8b8",
+            PixelFormat::X8r8g8b8 => "x8r8g8b8",
+            PixelFormat::A8r8g8b8 => "a8r8g8b8",
+            PixelFormat::X8b8g8r8 => "x8b8g8r8",
+            PixelFormat::A8b8g8r8 => "a8b8g8r8",
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> u64 {
+        match self {
+            PixelFormat::R5g6b5 => 2,
+            PixelFormat::R8g8b8 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Convert a pixel to 8-bit RGB, ´bytes´ is in memory order
+    fn rgb(&self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5g6b5 => {
+                let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let r = ((value >> 11) & 0x1f) as u8;
+                let g = ((value >> 5) & 0x3f) as u8;
+                let b = (value & 0x1f) as u8;
+
+                [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+            }
+
+            // NOTE(patrik): The formats are named from the most significant
+            // bits of the little endian pixel value
+            PixelFormat::R8g8b8 => [bytes[2], bytes[1], bytes[0]],
+            PixelFormat::X8r8g8b8 | PixelFormat::A8r8g8b8 =>
+                [bytes[2], bytes[1], bytes[0]],
+            PixelFormat::X8b8g8r8 | PixelFormat::A8b8g8r8 =>
+                [bytes[0], bytes[1], bytes[2]],
+        }
+    }
+
+    fn index(&self) -> u64 {
+        match self {
+            PixelFormat::R5g6b5 => 0,
+            PixelFormat::R8g8b8 => 1,
+            PixelFormat::X8r8g8b8 => 2,
+            PixelFormat::A8r8g8b8 => 3,
+            PixelFormat::X8b8g8r8 => 4,
+            PixelFormat::A8b8g8r8 => 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FramebufferConfig {
+    pub width: u64,
+    pub height: u64,
+    pub format: PixelFormat,
+
+    /// Snapshots are written to ´<output>-<frame>.ppm´ and the snapshot
+    /// taken at exit to ´<output>.ppm´
+    pub output: PathBuf,
+
+    /// Write a snapshot every N frames, 0 disables periodic snapshots
+    pub every: u64,
+}
+
+impl FramebufferConfig {
+    /// Bytes per line
+    pub fn stride(&self) -> u64 {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Size of the pixel memory in bytes
+    pub fn pixels_size(&self) -> u64 {
+        self.stride() * self.height
+    }
+
+    /// Size of the whole MMIO region, pixels and control registers
+    pub fn region_size(&self) -> u64 {
+        let pixels = (self.pixels_size() + CONTROL_SIZE - 1) & !(CONTROL_SIZE - 1);
+        pixels + CONTROL_SIZE
+    }
+
+    /// Offset of the control registers inside the region
+    fn control_offset(&self) -> u64 {
+        self.region_size() - CONTROL_SIZE
+    }
+}
+
+pub struct Framebuffer {
+    config: FramebufferConfig,
+    memory: TestingMemory,
+
+    clock: Clock,
+    frame: u64,
+    ticks: u32,
+}
+
+impl Framebuffer {
+    pub fn new(config: FramebufferConfig, clock: Clock) -> Self {
+        let memory = TestingMemory::new(config.pixels_size() as usize);
+
+        Self {
+            config,
+            memory,
+
+            clock,
+            frame: 0,
+            ticks: 0,
+        }
+    }
+
+    /// Write the framebuffer as a binary PPM file to ´path´
+    pub fn write_ppm(&self, path: &PathBuf) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "P6\n{} {}\n255\n", self.config.width, self.config.height)?;
+
+        let bpp = self.config.format.bytes_per_pixel() as usize;
+        let stride = self.config.stride() as usize;
+
+        for y in 0..self.config.height as usize {
+            for x in 0..self.config.width as usize {
+                let start = y * stride + x * bpp;
+                let mut pixel = [0u8; 4];
+                for (index, value) in pixel[..bpp].iter_mut().enumerate() {
+                    *value = self.memory.read_u8(start + index);
+                }
+
+                writer.write_all(&self.config.format.rgb(&pixel))?;
+            }
+        }
+
+        writer.flush()
+    }
+
+    fn snapshot(&self, path: PathBuf) {
+        if let Err(e) = self.write_ppm(&path) {
+            eprintln!("Failed to write framebuffer to '{}': {}",
+                      path.display(), e);
+        }
+    }
+
+    fn frame_path(&self) -> PathBuf {
+        let mut path = self.config.output.clone().into_os_string();
+        path.push(format!("-{:06}.ppm", self.frame));
+        PathBuf::from(path)
+    }
+
+    fn read_control(&self, offset: u64) -> u64 {
+        match offset {
+            FRAME => self.frame,
+            WIDTH => self.config.width,
+            HEIGHT => self.config.height,
+            STRIDE => self.config.stride(),
+            FORMAT => self.config.format.index(),
+            _ => 0,
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        let control = self.config.control_offset();
+        if offset >= control {
+            return self.read_control(offset - control);
+        }
+
+        if offset + width.size() > self.config.pixels_size() {
+            return 0;
+        }
+
+        let offset = offset as usize;
+        match width {
+            TypeWidth::Byte => self.memory.read_u8(offset) as u64,
+            TypeWidth::HalfWord => self.memory.read_u16(offset) as u64,
+            TypeWidth::Word => self.memory.read_u32(offset) as u64,
+            TypeWidth::DoubleWord => self.memory.read_u64(offset),
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        let control = self.config.control_offset();
+        if offset >= control {
+            if offset - control == SNAPSHOT {
+                self.snapshot(self.frame_path());
+            }
+
+            return;
+        }
+
+        if offset + width.size() > self.config.pixels_size() {
+            return;
+        }
+
+        let offset = offset as usize;
+        match width {
+            TypeWidth::Byte => self.memory.write_u8(offset, value as u8),
+            TypeWidth::HalfWord => self.memory.write_u16(offset, value as u16),
+            TypeWidth::Word => self.memory.write_u32(offset, value as u32),
+            TypeWidth::DoubleWord => self.memory.write_u64(offset, value),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks < TICKS_PER_CHECK {
+            return;
+        }
+        self.ticks = 0;
+
+        let frame = self.clock.elapsed_ns() / FRAME_NS;
+        if frame == self.frame {
+            return;
+        }
+
+        // NOTE(patrik): Several frames can pass between the checks so look
+        // for crossing a multiple of ´every´
+        let every = self.config.every;
+        let snapshot = every != 0 && frame / every != self.frame / every;
+
+        self.frame = frame;
+        if snapshot {
+            self.snapshot(self.frame_path());
+        }
+    }
+
+    fn shutdown(&mut self) {
+        let mut path = self.config.output.clone().into_os_string();
+        path.push(".ppm");
+        self.snapshot(PathBuf::from(path));
+    }
+}
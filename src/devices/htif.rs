@@ -0,0 +1,227 @@
+//! Host-Target Interface (HTIF) as used by Spike, riscv-tests and riscv-pk
+//!
+//! The guest talks to the host by writing a command to the ´tohost´ symbol
+//! and the host answers by writing to the ´fromhost´ symbol. A command is
+//! encoded as:
+//!   [63:56] device
+//!   [55:48] command
+//!   [47:0]  payload
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::elf::Elf;
+use crate::memory::{ Mmu, TypeWidth };
+use super::{ ExitSignal, HostConsole };
+
+/// Device 0: Syscall proxy and exit
+const DEVICE_SYSCALL: u64 = 0;
+/// Device 1: Blocking character device (console)
+const DEVICE_CONSOLE: u64 = 1;
+
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+
+const EBADF: i64 = 9;
+const ENOSYS: i64 = 38;
+
+/// Largest write done at once, the guest gets a short count for bigger
+/// ones
+const MAX_TRANSFER: u64 = 1 << 20;
+
+const PAYLOAD_MASK: u64 = 0x0000ffffffffffff;
+
+fn encode(device: u64, command: u64, payload: u64) -> u64 {
+    (device << 56) | (command << 48) | (payload & PAYLOAD_MASK)
+}
+
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+
+    exit: ExitSignal,
+    console: HostConsole,
+
+    /// Responses waiting for the guest to clear ´fromhost´
+    responses: VecDeque<u64>,
+
+    /// Number of console reads the guest is waiting on
+    pending_reads: usize,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>, exit: ExitSignal)
+        -> Self
+    {
+        Self {
+            tohost,
+            fromhost,
+
+            exit,
+            console: HostConsole::new(),
+
+            responses: VecDeque::new(),
+            pending_reads: 0,
+        }
+    }
+
+    /// Create the HTIF from the ´tohost´ and ´fromhost´ symbols of ´elf´,
+    /// or from the ´.tohost´ section of stripped programs. Returns None if
+    /// the ELF has neither. ´bias´ is where a position independent
+    /// executable was moved
+    pub fn from_elf(elf: &Elf, bias: u64, exit: ExitSignal) -> Option<Self> {
+        if let Some(tohost) = elf.symbol_address("tohost") {
+            let tohost = tohost.wrapping_add(bias);
+            let fromhost = elf.symbol_address("fromhost")
+                .map(|fromhost| fromhost.wrapping_add(bias));
+            return Some(Self::new(tohost, fromhost, exit));
+        }
+
+        // NOTE(patrik): The riscv-tests place ´fromhost´ 64 bytes after
+        // ´tohost´ in the section
+        let section = elf.section_header_by_name(".tohost")?;
+        let tohost = section.addr().wrapping_add(bias);
+        let fromhost = (section.size() >= 72).then(|| tohost + 64);
+
+        Some(Self::new(tohost, fromhost, exit))
+    }
+
+    pub fn tohost(&self) -> u64 {
+        self.tohost
+    }
+
+    pub fn fromhost(&self) -> Option<u64> {
+        self.fromhost
+    }
+
+    /// Check if an access at ´addr´ touches the ´tohost´ register
+    pub fn is_tohost(&self, addr: u64, width: TypeWidth) -> bool {
+        overlaps(addr, width, self.tohost)
+    }
+
+    /// Check if an access at ´addr´ touches the ´fromhost´ register
+    pub fn is_fromhost(&self, addr: u64, width: TypeWidth) -> bool {
+        self.fromhost.is_some_and(|fromhost| {
+            overlaps(addr, width, fromhost)
+        })
+    }
+
+    /// Handle the command the guest has written to ´tohost´
+    pub fn tohost_written(&mut self, mmu: &mut dyn Mmu) {
+        let value = mmu.read_u64(self.tohost);
+        if value == 0 {
+            return;
+        }
+
+        // NOTE(patrik): The host clears ´tohost´ to tell the guest that the
+        // command has been consumed
+        mmu.write_u64(self.tohost, 0);
+
+        let device = value >> 56;
+        let command = (value >> 48) & 0xff;
+        let payload = value & PAYLOAD_MASK;
+
+        match (device, command) {
+            (DEVICE_SYSCALL, 0) => {
+                if payload & 1 == 1 {
+                    self.exit.exit(payload >> 1);
+                } else {
+                    self.syscall(mmu, payload);
+                    self.responses.push_back(encode(DEVICE_SYSCALL, 0, 1));
+                }
+            }
+
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                self.console.putchar(payload as u8);
+                self.responses.push_back(
+                    encode(DEVICE_CONSOLE, CONSOLE_PUTCHAR, 0x100 | (payload & 0xff)));
+            }
+
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => {
+                self.pending_reads += 1;
+            }
+
+            // NOTE(patrik): Unknown commands are consumed and ignored like
+            // Spike does
+            _ => {}
+        }
+
+        self.fromhost_accessed(mmu);
+    }
+
+    /// Deliver pending responses when the guest has cleared ´fromhost´
+    pub fn fromhost_accessed(&mut self, mmu: &mut dyn Mmu) {
+        let Some(fromhost) = self.fromhost else {
+            self.responses.clear();
+            return;
+        };
+
+        if self.pending_reads > 0 {
+            if let Some(value) = self.console.getchar() {
+                self.pending_reads -= 1;
+                self.responses.push_back(
+                    encode(DEVICE_CONSOLE, CONSOLE_GETCHAR, 0x100 | value as u64));
+            }
+        }
+
+        if mmu.read_u64(fromhost) != 0 {
+            return;
+        }
+
+        if let Some(response) = self.responses.pop_front() {
+            mmu.write_u64(fromhost, response);
+        }
+    }
+
+    /// Proxy a syscall to the host, ´addr´ points to the syscall
+    /// arguments: [number, arg0, arg1, ..., arg6]
+    fn syscall(&mut self, mmu: &mut dyn Mmu, addr: u64) {
+        let mut args = [0u64; 8];
+        for (index, arg) in args.iter_mut().enumerate() {
+            *arg = mmu.read_u64(addr.wrapping_add(index as u64 * 8));
+        }
+
+        let result = match args[0] {
+            SYS_WRITE => {
+                let fd = args[1];
+                let buffer = args[2];
+                let len = args[3];
+
+                let mut data = vec![0; len.min(MAX_TRANSFER) as usize];
+                mmu.read_bytes(buffer, &mut data);
+
+                match fd {
+                    1 => write_host(std::io::stdout(), &data),
+                    2 => write_host(std::io::stderr(), &data),
+                    _ => -EBADF,
+                }
+            }
+
+            SYS_EXIT => {
+                self.exit.exit(args[1]);
+                0
+            }
+
+            _ => -ENOSYS,
+        };
+
+        mmu.write_u64(addr, result as u64);
+    }
+}
+
+fn write_host<W: Write>(mut writer: W, data: &[u8]) -> i64 {
+    match writer.write_all(data).and_then(|_| writer.flush()) {
+        Ok(_) => data.len() as i64,
+        Err(_) => -EBADF,
+    }
+}
+
+fn overlaps(addr: u64, width: TypeWidth, register: u64) -> bool {
+    // NOTE(patrik): Nothing is mapped past the end of the address space,
+    // so the ends saturate instead of wrapping around
+    addr < register.saturating_add(8) &&
+        register < addr.saturating_add(width.size())
+}
@@ -0,0 +1,188 @@
+//! Module for the devices attached to the memory bus
+
+use std::rc::Rc;
+use std::cell::Cell;
+use std::time::Instant;
+
+use crate::memory::TypeWidth;
+
+pub use htif::Htif;
+pub use console::HostConsole;
+pub use semihosting::Semihosting;
+pub use rtc::{ GoldfishRtc, GOLDFISH_RTC_SIZE };
+pub use framebuffer::{ Framebuffer, FramebufferConfig, PixelFormat };
+pub use pflash::{ Pflash, PFLASH_SIZE };
+pub use pci::{ PciBus, PciRegion, PciWindow };
+pub use virtio::{ VirtioBlock, VirtioMmio, VirtioPci, VIRTIO_MMIO_SIZE };
+pub use clint::{ Clint, CLINT_SIZE };
+pub use plic::{ Plic, PLIC_SIZE };
+pub use uart::{ Uart16550, UART_SIZE };
+pub use sifive_test::{ SifiveTest, SIFIVE_TEST_SIZE };
+pub use bootrom::{ BootRom, BOOT_ROM_DTB_OFFSET };
+
+mod htif;
+mod console;
+pub mod semihosting;
+mod rtc;
+mod framebuffer;
+mod pflash;
+pub mod pci;
+pub mod virtio;
+mod clint;
+mod plic;
+mod uart;
+mod sifive_test;
+mod bootrom;
+
+/// Memory mapped device, the offsets are relative to the start of the
+/// region the device is mapped at
+pub trait Device {
+    /// Read a register
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64;
+
+    /// Write a register
+    fn write(&mut self, offset: u64, value: u64, width: TypeWidth);
+
+    /// Level of the interrupt line of the device
+    fn irq(&mut self) -> bool {
+        false
+    }
+
+    /// Number of interrupt lines of the device, they are connected to
+    /// consecutive PLIC sources
+    fn irq_count(&self) -> u32 {
+        1
+    }
+
+    /// Level of the interrupt line ´line´, for devices with more than one
+    fn irq_line(&mut self, _line: u32) -> bool {
+        self.irq()
+    }
+
+    /// Bits of ´mip´ the device raises directly on the hart ´hartid´, used
+    /// by the interrupt controllers
+    fn hart_interrupts(&mut self, _hartid: u64) -> u64 {
+        0
+    }
+
+    /// Called after every instruction for devices that do work over time
+    fn tick(&mut self) {}
+
+    /// Called when the emulator exits
+    fn shutdown(&mut self) {}
+
+    /// Do work that needs access to the guest memory, called after every
+    /// write to the device
+    fn dma(&mut self, _memory: &mut dyn Dma) {}
+}
+
+/// Guest memory as seen by devices doing DMA, the accesses return false if
+/// the address isn't backed by memory
+pub trait Dma {
+    fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> bool;
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> bool;
+
+    fn read_u16(&mut self, addr: u64) -> Option<u16> {
+        let mut bytes = [0u8; 2];
+        self.read_bytes(addr, &mut bytes).then(|| u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&mut self, addr: u64) -> Option<u32> {
+        let mut bytes = [0u8; 4];
+        self.read_bytes(addr, &mut bytes).then(|| u32::from_le_bytes(bytes))
+    }
+
+    fn read_u64(&mut self, addr: u64) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        self.read_bytes(addr, &mut bytes).then(|| u64::from_le_bytes(bytes))
+    }
+
+    fn write_u16(&mut self, addr: u64, value: u16) -> bool {
+        self.write_bytes(addr, &value.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, addr: u64, value: u32) -> bool {
+        self.write_bytes(addr, &value.to_le_bytes())
+    }
+}
+
+/// Shared flag used by devices to tell the emulator to stop running
+#[derive(Clone, Default, Debug)]
+pub struct ExitSignal {
+    code: Rc<Cell<Option<u64>>>,
+}
+
+impl ExitSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the emulator to exit with ´code´
+    pub fn exit(&self, code: u64) {
+        self.code.set(Some(code));
+    }
+
+    /// Get the exit code if an exit has been requested
+    pub fn code(&self) -> Option<u64> {
+        self.code.get()
+    }
+}
+
+/// Frequency of the ´time´ CSR and the machine timer, same as on the QEMU
+/// virt machine
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Time source shared by the devices
+#[derive(Clone, Debug)]
+pub struct Clock {
+    start: Instant,
+
+    /// Time in nanoseconds for deterministic clocks, None when the clock
+    /// follows the host
+    virtual_time: Option<Rc<Cell<u64>>>,
+}
+
+impl Clock {
+    /// Clock that follows the host time
+    pub fn host() -> Self {
+        Self {
+            start: Instant::now(),
+            virtual_time: None,
+        }
+    }
+
+    /// Clock that only moves when ´advance´ is called, used for
+    /// deterministic runs
+    pub fn deterministic() -> Self {
+        Self {
+            start: Instant::now(),
+            virtual_time: Some(Rc::new(Cell::new(0))),
+        }
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.virtual_time.is_some()
+    }
+
+    /// Advance a deterministic clock by ´ns´ nanoseconds, does nothing
+    /// for host clocks
+    pub fn advance(&self, ns: u64) {
+        if let Some(time) = &self.virtual_time {
+            time.set(time.get().wrapping_add(ns));
+        }
+    }
+
+    /// Nanoseconds since the clock was created
+    pub fn elapsed_ns(&self) -> u64 {
+        match &self.virtual_time {
+            Some(time) => time.get(),
+            None => self.start.elapsed().as_nanos() as u64,
+        }
+    }
+
+    /// Time in ticks of ´TIMEBASE_FREQUENCY´
+    pub fn timebase_ticks(&self) -> u64 {
+        self.elapsed_ns() / (1_000_000_000 / TIMEBASE_FREQUENCY)
+    }
+}
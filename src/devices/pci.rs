@@ -0,0 +1,686 @@
+//! Generic PCIe host bridge, like the gpex bridge on the QEMU virt machine
+//!
+//! The bridge is made of three regions on the memory bus:
+//!   ECAM    Configuration space, 1 MiB per bus and 4 KiB per function
+//!   Memory  Window for the memory BARs, PCI addresses are the same as
+//!           the CPU addresses
+//!   I/O     Window for the I/O BARs, the I/O space starts at PCI
+//!           address 0 at the start of the window
+//!
+//! Only bus 0 and function 0 of every device is implemented. The BARs are
+//! allocated from the windows when the device is added, the guest can move
+//! them later. The INTx pins of the devices are swizzled over the four
+//! interrupt lines of the bridge by device number, like QEMU does. Devices
+//! with MSI enabled signal interrupts by writing the MSI data to the MSI
+//! address instead, the MSI doorbell turns that into a PLIC interrupt.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::memory::TypeWidth;
+use super::{ Device, Dma };
+
+/// Size of the configuration space of one function
+pub const PCI_CONFIG_SIZE: usize = 0x1000;
+
+/// Max number of devices on a bus
+const MAX_DEVICES: usize = 32;
+
+const COMMAND: usize = 0x04;
+const STATUS: usize = 0x06;
+const REVISION: usize = 0x08;
+const CLASS: usize = 0x09;
+const CACHE_LINE_SIZE: usize = 0x0c;
+const LATENCY_TIMER: usize = 0x0d;
+const HEADER_TYPE: usize = 0x0e;
+const BAR0: usize = 0x10;
+const SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const SUBSYSTEM_ID: usize = 0x2e;
+const CAPABILITIES: usize = 0x34;
+const INTERRUPT_LINE: usize = 0x3c;
+const INTERRUPT_PIN: usize = 0x3d;
+
+/// Start of the space for the capabilities
+const CAPABILITIES_START: usize = 0x40;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_INTERRUPT: u16 = 1 << 3;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+/// Number of INTx lines of the bridge, INTA to INTD
+pub const INTX_LINES: u32 = 4;
+
+/// Number of BARs in a type 0 header
+pub const BAR_COUNT: usize = 6;
+
+/// First PCI address handed out in the I/O window, the low addresses are
+/// left for legacy devices
+const IO_ALLOCATION_START: u64 = 0x1000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BarKind {
+    /// 32-bit memory BAR
+    Memory,
+    Io,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Bar {
+    pub kind: BarKind,
+
+    /// Size in bytes, has to be a power of two
+    pub size: u64,
+}
+
+/// Configuration space of a function, the BARs are handled by the bus
+pub struct PciConfig {
+    data: [u8; PCI_CONFIG_SIZE],
+
+    /// Bits the guest is allowed to change
+    writable: [u8; PCI_CONFIG_SIZE],
+
+    /// Offset of the last capability in the list
+    last_capability: Option<usize>,
+    next_capability: usize,
+}
+
+impl PciConfig {
+    /// Create a type 0 header, ´class´ is the class code, sub class and
+    /// programming interface
+    pub fn new(vendor_id: u16, device_id: u16, class: u32, revision: u8)
+        -> Self
+    {
+        let mut config = Self {
+            data: [0; PCI_CONFIG_SIZE],
+            writable: [0; PCI_CONFIG_SIZE],
+
+            last_capability: None,
+            next_capability: CAPABILITIES_START,
+        };
+
+        config.set_u16(0x00, vendor_id);
+        config.set_u16(0x02, device_id);
+        config.data[REVISION] = revision;
+        config.data[CLASS..CLASS + 3]
+            .copy_from_slice(&class.to_le_bytes()[..3]);
+        config.data[HEADER_TYPE] = 0;
+
+        let command = COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER |
+            COMMAND_INTX_DISABLE;
+        config.set_writable(COMMAND, &command.to_le_bytes());
+        config.set_writable(CACHE_LINE_SIZE, &[0xff, 0xff]);
+        config.set_writable(INTERRUPT_LINE, &[0xff]);
+
+        config
+    }
+
+    pub fn set_u8(&mut self, offset: usize, value: u8) {
+        self.data[offset] = value;
+    }
+
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn set_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Let the guest write the bits in ´mask´ starting at ´offset´
+    pub fn set_writable(&mut self, offset: usize, mask: &[u8]) {
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set_u16(SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.set_u16(SUBSYSTEM_ID, id);
+    }
+
+    /// Set the interrupt pin, 1 is INTA and 0 is no interrupt
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.data[INTERRUPT_PIN] = pin;
+    }
+
+    pub fn interrupt_pin(&self) -> u8 {
+        self.data[INTERRUPT_PIN]
+    }
+
+    /// Append a capability to the list, ´body´ is the capability without
+    /// the id and next pointer, returns the offset of the capability
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> usize {
+        let offset = self.next_capability;
+        assert!(offset + 2 + body.len() <= 0x100,
+                "PCI capabilities doesn't fit in the config space");
+
+        self.data[offset] = id;
+        self.data[offset + 1] = 0;
+        self.data[offset + 2..offset + 2 + body.len()].copy_from_slice(body);
+
+        match self.last_capability {
+            Some(last) => self.data[last + 1] = offset as u8,
+            None => self.data[CAPABILITIES] = offset as u8,
+        }
+
+        let status = self.u16_at(STATUS) | STATUS_CAPABILITIES;
+        self.set_u16(STATUS, status);
+
+        self.last_capability = Some(offset);
+        // NOTE(patrik): Capabilities are dword aligned
+        self.next_capability = (offset + 2 + body.len() + 3) & !3;
+
+        offset
+    }
+
+    /// Add a MSI capability with one vector and 64-bit addresses
+    pub fn add_msi_capability(&mut self) -> usize {
+        let control = MSI_CONTROL_64BIT;
+        let mut body = [0u8; 12];
+        body[0..2].copy_from_slice(&control.to_le_bytes());
+
+        let offset = self.add_capability(CAP_ID_MSI, &body);
+        self.set_writable(offset + 2, &[0x71, 0x00]);
+        self.set_writable(offset + 4, &[0xfc, 0xff, 0xff, 0xff]);
+        self.set_writable(offset + 8, &[0xff, 0xff, 0xff, 0xff]);
+        self.set_writable(offset + 12, &[0xff, 0xff]);
+
+        offset
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<usize> {
+        let mut offset = self.data[CAPABILITIES] as usize;
+
+        // NOTE(patrik): Limit the walk in case the list loops
+        for _ in 0..48 {
+            if offset < CAPABILITIES_START {
+                return None;
+            }
+
+            if self.data[offset] == id {
+                return Some(offset);
+            }
+
+            offset = self.data[offset + 1] as usize;
+        }
+
+        None
+    }
+
+    fn command(&self) -> u16 {
+        self.u16_at(COMMAND)
+    }
+
+    /// MSI address and data if MSI is enabled
+    fn msi(&self) -> Option<(u64, u32)> {
+        let offset = self.find_capability(CAP_ID_MSI)?;
+        if self.u16_at(offset + 2) & MSI_CONTROL_ENABLE == 0 {
+            return None;
+        }
+
+        let address = self.u32_at(offset + 4) as u64 |
+            ((self.u32_at(offset + 8) as u64) << 32);
+        let data = self.u16_at(offset + 12) as u32;
+
+        Some((address, data))
+    }
+
+    fn write_masked(&mut self, offset: usize, value: u32) {
+        for (index, byte) in value.to_le_bytes().iter().enumerate() {
+            let mask = self.writable[offset + index];
+            let old = self.data[offset + index];
+            self.data[offset + index] = (old & !mask) | (byte & mask);
+        }
+    }
+}
+
+/// Function on the PCI bus
+pub trait PciDevice {
+    /// Build the configuration space, called once when the device is added
+    fn config(&self) -> PciConfig;
+
+    fn bars(&self) -> [Option<Bar>; BAR_COUNT] {
+        [None; BAR_COUNT]
+    }
+
+    fn bar_read(&mut self, _bar: usize, _offset: u64, _width: TypeWidth) -> u64 {
+        0
+    }
+
+    fn bar_write(&mut self, _bar: usize, _offset: u64, _value: u64,
+                 _width: TypeWidth) {}
+
+    /// Level of the interrupt of the device
+    fn irq(&mut self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) {}
+
+    fn shutdown(&mut self) {}
+
+    /// Do work that needs access to the guest memory, called after every
+    /// write to the bridge
+    fn dma(&mut self, _memory: &mut dyn Dma) {}
+}
+
+/// The host bridge itself, function 00.0
+struct HostBridge;
+
+impl PciDevice for HostBridge {
+    fn config(&self) -> PciConfig {
+        // NOTE(patrik): Same ids as the QEMU PCIe host bridge
+        let mut config = PciConfig::new(0x1b36, 0x0008, 0x060000, 0);
+        config.set_subsystem(0x1af4, 0x1100);
+        config
+    }
+}
+
+struct PciSlot {
+    config: PciConfig,
+    device: Box<dyn PciDevice>,
+
+    bars: [Option<Bar>; BAR_COUNT],
+    bases: [u64; BAR_COUNT],
+
+    /// Interrupt level at the last check, MSI is sent on the rising edge
+    irq_level: bool,
+}
+
+impl PciSlot {
+    fn read_dword(&mut self, offset: usize) -> u32 {
+        if (BAR0..BAR0 + BAR_COUNT * 4).contains(&offset) {
+            let index = (offset - BAR0) / 4;
+            return match self.bars[index] {
+                Some(bar) if bar.kind == BarKind::Io =>
+                    self.bases[index] as u32 | 1,
+                Some(_) => self.bases[index] as u32,
+                None => 0,
+            };
+        }
+
+        let mut value = self.config.u32_at(offset);
+        if offset == COMMAND && self.device.irq() {
+            value |= (STATUS_INTERRUPT as u32) << 16;
+        }
+
+        value
+    }
+
+    fn write_dword(&mut self, offset: usize, value: u32) {
+        if (BAR0..BAR0 + BAR_COUNT * 4).contains(&offset) {
+            let index = (offset - BAR0) / 4;
+            if let Some(bar) = self.bars[index] {
+                self.bases[index] = value as u64 & !(bar.size - 1) & 0xffffffff;
+            }
+
+            return;
+        }
+
+        self.config.write_masked(offset, value);
+    }
+
+    /// Find the BAR of ´kind´ that contains the PCI address ´addr´
+    fn bar_at(&self, kind: BarKind, addr: u64) -> Option<(usize, u64)> {
+        let enable = match kind {
+            BarKind::Memory => COMMAND_MEMORY,
+            BarKind::Io => COMMAND_IO,
+        };
+
+        if self.config.command() & enable == 0 {
+            return None;
+        }
+
+        self.bars.iter().enumerate().find_map(|(index, bar)| {
+            let bar = (*bar)?;
+            let base = self.bases[index];
+            if bar.kind == kind && addr >= base && addr - base < bar.size {
+                Some((index, addr - base))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Level of the INTx line of the device
+    fn intx(&mut self) -> bool {
+        if self.config.msi().is_some() ||
+            self.config.command() & COMMAND_INTX_DISABLE != 0
+        {
+            return false;
+        }
+
+        self.device.irq()
+    }
+}
+
+/// Window on the memory bus that BARs are allocated from
+struct Window {
+    /// PCI address of the start of the window
+    base: u64,
+    size: u64,
+
+    /// Next free PCI address
+    next: u64,
+}
+
+impl Window {
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let addr = (self.next + size - 1) & !(size - 1);
+        if addr + size > self.base + self.size {
+            return None;
+        }
+
+        self.next = addr + size;
+        Some(addr)
+    }
+}
+
+pub struct PciBus {
+    slots: Vec<PciSlot>,
+
+    memory: Window,
+    io: Window,
+}
+
+impl PciBus {
+    /// Create a bus with the memory window at the CPU address
+    /// ´memory_base´ and a I/O window of ´io_size´ bytes
+    pub fn new(memory_base: u64, memory_size: u64, io_size: u64) -> Self {
+        let mut bus = Self {
+            slots: Vec::new(),
+
+            memory: Window {
+                base: memory_base,
+                size: memory_size,
+                next: memory_base,
+            },
+
+            io: Window {
+                base: 0,
+                size: io_size,
+                next: IO_ALLOCATION_START,
+            },
+        };
+
+        bus.add_device(Box::new(HostBridge))
+            .expect("Failed to add the PCI host bridge");
+
+        bus
+    }
+
+    /// Plug ´device´ into the next free slot and allocate its BARs,
+    /// returns the device number
+    pub fn add_device(&mut self, device: Box<dyn PciDevice>) -> Option<u8> {
+        if self.slots.len() >= MAX_DEVICES {
+            return None;
+        }
+
+        let bars = device.bars();
+        let mut bases = [0; BAR_COUNT];
+        let mut command = 0;
+
+        for (index, bar) in bars.iter().enumerate() {
+            let Some(bar) = bar else {
+                continue;
+            };
+
+            assert!(bar.size.is_power_of_two(), "PCI BAR size has to be a power of two");
+
+            bases[index] = match bar.kind {
+                BarKind::Memory => {
+                    command |= COMMAND_MEMORY;
+                    self.memory.allocate(bar.size.max(16))?
+                }
+                BarKind::Io => {
+                    command |= COMMAND_IO;
+                    self.io.allocate(bar.size.max(4))?
+                }
+            };
+        }
+
+        // NOTE(patrik): Decoding and DMA is enabled like firmware would have
+        // done, so the guest can use the devices without enumerating the bus
+        let mut config = device.config();
+        config.set_u16(COMMAND,
+                       config.command() | command | COMMAND_BUS_MASTER);
+
+        self.slots.push(PciSlot {
+            config,
+            device,
+
+            bars,
+            bases,
+
+            irq_level: false,
+        });
+
+        Some((self.slots.len() - 1) as u8)
+    }
+
+    fn config_read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        let (slot, reg) = match self.config_slot(offset) {
+            Some(found) => found,
+            None => return all_ones(width),
+        };
+
+        let mut value = 0;
+        for index in 0..width.size() as usize {
+            let reg = reg + index;
+            if reg >= PCI_CONFIG_SIZE {
+                break;
+            }
+
+            let dword = self.slots[slot].read_dword(reg & !3);
+            let byte = (dword >> ((reg & 3) * 8)) & 0xff;
+            value |= (byte as u64) << (index * 8);
+        }
+
+        value
+    }
+
+    fn config_write(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        let Some((slot, reg)) = self.config_slot(offset) else {
+            return;
+        };
+
+        let slot = &mut self.slots[slot];
+        let end = (reg + width.size() as usize).min(PCI_CONFIG_SIZE);
+
+        let mut dword_offset = reg & !3;
+        while dword_offset < end {
+            let mut dword = slot.read_dword(dword_offset);
+
+            for byte_offset in dword_offset..dword_offset + 4 {
+                if byte_offset < reg || byte_offset >= end {
+                    continue;
+                }
+
+                let byte = (value >> ((byte_offset - reg) * 8)) & 0xff;
+                let shift = (byte_offset & 3) * 8;
+                dword = (dword & !(0xff << shift)) | ((byte as u32) << shift);
+            }
+
+            slot.write_dword(dword_offset, dword);
+            dword_offset += 4;
+        }
+    }
+
+    /// Decode a ECAM offset, only function 0 on bus 0 exists
+    fn config_slot(&self, offset: u64) -> Option<(usize, usize)> {
+        let bus = offset >> 20;
+        let device = ((offset >> 15) & 0x1f) as usize;
+        let function = (offset >> 12) & 0x7;
+
+        if bus != 0 || function != 0 || device >= self.slots.len() {
+            return None;
+        }
+
+        Some((device, (offset & 0xfff) as usize))
+    }
+
+    fn bar_read(&mut self, kind: BarKind, addr: u64, width: TypeWidth) -> u64 {
+        for slot in self.slots.iter_mut() {
+            if let Some((bar, offset)) = slot.bar_at(kind, addr) {
+                return slot.device.bar_read(bar, offset, width);
+            }
+        }
+
+        all_ones(width)
+    }
+
+    fn bar_write(&mut self, kind: BarKind, addr: u64, value: u64,
+                 width: TypeWidth)
+    {
+        for slot in self.slots.iter_mut() {
+            if let Some((bar, offset)) = slot.bar_at(kind, addr) {
+                slot.device.bar_write(bar, offset, value, width);
+                return;
+            }
+        }
+    }
+
+    /// Level of any INTx line of the bridge
+    pub fn irq(&mut self) -> bool {
+        self.slots.iter_mut().any(|slot| slot.intx())
+    }
+
+    /// Level of the INTx line ´line´ of the bridge, pin P of device D
+    /// drives line (D + P - 1) % 4
+    pub fn intx_line(&mut self, line: u32) -> bool {
+        self.slots.iter_mut().enumerate().any(|(number, slot)| {
+            let pin = slot.config.interrupt_pin() as u32;
+            pin != 0 && (number as u32 + pin - 1) % INTX_LINES == line &&
+                slot.intx()
+        })
+    }
+
+    fn dma(&mut self, memory: &mut dyn Dma) {
+        for slot in self.slots.iter_mut() {
+            if slot.config.command() & COMMAND_BUS_MASTER == 0 {
+                continue;
+            }
+
+            // NOTE(patrik): The guest may have acknowledged the interrupt
+            // since the last time, then the next one is a new edge
+            let raised = slot.irq_level && slot.device.irq();
+            slot.device.dma(memory);
+
+            let level = slot.device.irq();
+            if level && !raised {
+                if let Some((address, data)) = slot.config.msi() {
+                    memory.write_u32(address, data);
+                }
+            }
+
+            slot.irq_level = level;
+        }
+    }
+}
+
+fn all_ones(width: TypeWidth) -> u64 {
+    match width {
+        TypeWidth::Byte => 0xff,
+        TypeWidth::HalfWord => 0xffff,
+        TypeWidth::Word => 0xffffffff,
+        TypeWidth::DoubleWord => u64::MAX,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PciWindow {
+    Ecam,
+    Memory,
+    Io,
+}
+
+/// One of the regions of the host bridge mapped on the memory bus
+pub struct PciRegion {
+    bus: Rc<RefCell<PciBus>>,
+    window: PciWindow,
+}
+
+impl PciRegion {
+    pub fn new(bus: Rc<RefCell<PciBus>>, window: PciWindow) -> Self {
+        Self {
+            bus,
+            window,
+        }
+    }
+}
+
+impl Device for PciRegion {
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        let mut bus = self.bus.borrow_mut();
+        match self.window {
+            PciWindow::Ecam => bus.config_read(offset, width),
+            PciWindow::Memory => {
+                let addr = bus.memory.base + offset;
+                bus.bar_read(BarKind::Memory, addr, width)
+            }
+            PciWindow::Io => bus.bar_read(BarKind::Io, offset, width),
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        let mut bus = self.bus.borrow_mut();
+        match self.window {
+            PciWindow::Ecam => bus.config_write(offset, value, width),
+            PciWindow::Memory => {
+                let addr = bus.memory.base + offset;
+                bus.bar_write(BarKind::Memory, addr, value, width);
+            }
+            PciWindow::Io => bus.bar_write(BarKind::Io, offset, value, width),
+        }
+    }
+
+    // NOTE(patrik): The bus wide work is only done by the ECAM region so
+    // it isn't repeated for every window
+    fn irq(&mut self) -> bool {
+        self.window == PciWindow::Ecam && self.bus.borrow_mut().irq()
+    }
+
+    fn irq_count(&self) -> u32 {
+        INTX_LINES
+    }
+
+    fn irq_line(&mut self, line: u32) -> bool {
+        self.window == PciWindow::Ecam && self.bus.borrow_mut().intx_line(line)
+    }
+
+    fn tick(&mut self) {
+        if self.window == PciWindow::Ecam {
+            for slot in self.bus.borrow_mut().slots.iter_mut() {
+                slot.device.tick();
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if self.window == PciWindow::Ecam {
+            for slot in self.bus.borrow_mut().slots.iter_mut() {
+                slot.device.shutdown();
+            }
+        }
+    }
+
+    fn dma(&mut self, memory: &mut dyn Dma) {
+        self.bus.borrow_mut().dma(memory);
+    }
+}
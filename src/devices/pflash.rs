@@ -0,0 +1,350 @@
+//! CFI parallel NOR flash using the Intel/Sharp command set, like the
+//! pflash banks on the QEMU virt machine
+//!
+//! The flash is backed by a file on the host, programming and erasing is
+//! written through to the file so the contents survive between runs.
+
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Write, Seek, SeekFrom };
+use std::path::Path;
+
+use crate::memory::{ TestingMemory, TypeWidth };
+use super::Device;
+
+/// Size of a flash bank on the QEMU virt machine
+pub const PFLASH_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Size of an erase block
+pub const PFLASH_BLOCK_SIZE: u64 = 256 * 1024;
+
+/// Width of the data bus in bytes, the CFI addresses are scaled by this
+const BANK_WIDTH: u64 = 4;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_QUERY: u8 = 0x98;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_WRITE_BUFFER: u8 = 0xe8;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_CONFIRM: u8 = 0xd0;
+const CMD_LOCK_SETUP: u8 = 0x60;
+
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+const MANUFACTURER_INTEL: u64 = 0x89;
+const DEVICE_ID: u64 = 0x18;
+
+/// Max size of the write buffer in bytes
+const WRITE_BUFFER_SIZE: u64 = 64;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    Query,
+
+    /// Next write is the data to program
+    Program,
+
+    /// Waiting for the erase confirm command
+    EraseSetup,
+
+    /// Next write is the number of bus words to write minus one
+    BufferCount,
+
+    /// Collecting the data for a buffered write
+    BufferData { remaining: u64 },
+
+    /// Waiting for the confirm command of a buffered write
+    BufferConfirm,
+
+    /// Waiting for the lock/unlock command, locking isn't supported
+    LockSetup,
+}
+
+pub struct Pflash {
+    memory: TestingMemory,
+    size: u64,
+
+    /// Backing file, writes are flushed to it directly
+    file: File,
+
+    state: State,
+    status: u8,
+
+    /// Writes collected by the write buffer command
+    buffer: Vec<(u64, u64, TypeWidth)>,
+
+    cfi: Vec<u8>,
+}
+
+impl Pflash {
+    /// Open the flash backed by ´path´, the file is created if it doesn't
+    /// exist and padded with 0xff up to ´size´
+    pub fn open<P>(path: P, size: u64) -> std::io::Result<Self>
+        where P: AsRef<Path>
+    {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        if contents.len() as u64 > size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("flash image is larger than {:#x} bytes", size)));
+        }
+
+        // NOTE(patrik): Erased flash reads as all ones
+        if (contents.len() as u64) < size {
+            let padding = vec![0xff; (size as usize) - contents.len()];
+            file.write_all(&padding)?;
+            contents.extend_from_slice(&padding);
+        }
+
+        let mut memory = TestingMemory::new(size as usize);
+        memory.write_bytes(0, &contents);
+
+        Ok(Self {
+            memory,
+            size,
+
+            file,
+
+            state: State::ReadArray,
+            status: STATUS_READY,
+
+            buffer: Vec::new(),
+
+            cfi: cfi_table(size),
+        })
+    }
+
+    fn read_memory(&self, offset: u64, width: TypeWidth) -> u64 {
+        let offset = offset as usize;
+        match width {
+            TypeWidth::Byte => self.memory.read_u8(offset) as u64,
+            TypeWidth::HalfWord => self.memory.read_u16(offset) as u64,
+            TypeWidth::Word => self.memory.read_u32(offset) as u64,
+            TypeWidth::DoubleWord => self.memory.read_u64(offset),
+        }
+    }
+
+    /// Program the flash, programming can only clear bits
+    fn program(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        let value = self.read_memory(offset, width) & value;
+
+        let index = offset as usize;
+        match width {
+            TypeWidth::Byte => self.memory.write_u8(index, value as u8),
+            TypeWidth::HalfWord => self.memory.write_u16(index, value as u16),
+            TypeWidth::Word => self.memory.write_u32(index, value as u32),
+            TypeWidth::DoubleWord => self.memory.write_u64(index, value),
+        }
+
+        if self.sync(offset, width.size()).is_err() {
+            self.status |= STATUS_PROGRAM_ERROR;
+        }
+    }
+
+    fn erase_block(&mut self, offset: u64) {
+        let start = offset & !(PFLASH_BLOCK_SIZE - 1);
+        let erased = vec![0xff; PFLASH_BLOCK_SIZE as usize];
+        self.memory.write_bytes(start as usize, &erased);
+
+        if self.sync(start, PFLASH_BLOCK_SIZE).is_err() {
+            self.status |= STATUS_ERASE_ERROR;
+        }
+    }
+
+    /// Write [offset, offset + len) to the backing file
+    fn sync(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        let data = self.memory.read_bytes(offset as usize, len as usize);
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    /// Read from the query or identifier tables, the tables are indexed
+    /// in bus words and only the lowest byte lane holds data
+    fn read_table(&self, offset: u64, value: impl Fn(u64) -> u64) -> u64 {
+        if !offset.is_multiple_of(BANK_WIDTH) {
+            return 0;
+        }
+
+        value(offset / BANK_WIDTH)
+    }
+}
+
+impl Device for Pflash {
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        if offset + width.size() > self.size {
+            return 0;
+        }
+
+        match self.state {
+            State::ReadArray => self.read_memory(offset, width),
+
+            State::ReadId => self.read_table(offset, |index| {
+                match index {
+                    0 => MANUFACTURER_INTEL,
+                    1 => DEVICE_ID,
+                    // NOTE(patrik): Block lock status, never locked
+                    _ => 0,
+                }
+            }),
+
+            State::Query => self.read_table(offset, |index| {
+                self.cfi.get(index as usize).copied().unwrap_or(0) as u64
+            }),
+
+            // NOTE(patrik): Every other state reads the status register
+            _ => self.status as u64,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        if offset + width.size() > self.size {
+            return;
+        }
+
+        let command = value as u8;
+
+        self.state = match self.state {
+            State::Program => {
+                self.program(offset, value, width);
+                State::ReadStatus
+            }
+
+            State::EraseSetup => {
+                if command == CMD_CONFIRM {
+                    self.erase_block(offset);
+                } else {
+                    self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                }
+
+                State::ReadStatus
+            }
+
+            State::BufferCount => {
+                let remaining = (value & 0xffff) + 1;
+                if remaining * width.size() > WRITE_BUFFER_SIZE {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                    State::ReadStatus
+                } else {
+                    self.buffer.clear();
+                    State::BufferData { remaining }
+                }
+            }
+
+            State::BufferData { remaining } => {
+                self.buffer.push((offset, value, width));
+
+                if remaining > 1 {
+                    State::BufferData { remaining: remaining - 1 }
+                } else {
+                    State::BufferConfirm
+                }
+            }
+
+            State::BufferConfirm => {
+                if command == CMD_CONFIRM {
+                    for (offset, value, width) in std::mem::take(&mut self.buffer) {
+                        self.program(offset, value, width);
+                    }
+                } else {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+
+                State::ReadStatus
+            }
+
+            // NOTE(patrik): The lock commands are accepted but the blocks
+            // are never locked
+            State::LockSetup => State::ReadStatus,
+
+            _ => match command {
+                CMD_READ_ARRAY => State::ReadArray,
+                CMD_READ_ID => State::ReadId,
+                CMD_QUERY => State::Query,
+                CMD_READ_STATUS => State::ReadStatus,
+                CMD_CLEAR_STATUS => {
+                    self.status = STATUS_READY;
+                    self.state
+                }
+                CMD_PROGRAM | CMD_PROGRAM_ALT => State::Program,
+                CMD_WRITE_BUFFER => State::BufferCount,
+                CMD_BLOCK_ERASE => State::EraseSetup,
+                CMD_LOCK_SETUP => State::LockSetup,
+
+                _ => State::ReadArray,
+            },
+        };
+    }
+}
+
+/// Build the CFI query table for a flash of ´size´ bytes
+fn cfi_table(size: u64) -> Vec<u8> {
+    let mut table = vec![0u8; 0x40];
+
+    let blocks = size / PFLASH_BLOCK_SIZE - 1;
+    let block_size = PFLASH_BLOCK_SIZE / 256;
+
+    // Query identification string
+    table[0x10] = b'Q';
+    table[0x11] = b'R';
+    table[0x12] = b'Y';
+    // Primary command set, Intel/Sharp extended
+    table[0x13] = 0x01;
+    table[0x14] = 0x00;
+    // Address of the primary extended table
+    table[0x15] = 0x31;
+    table[0x16] = 0x00;
+
+    // Vcc min and max
+    table[0x1b] = 0x45;
+    table[0x1c] = 0x55;
+
+    // Typical timeouts: word program 2^n us, buffer write 2^n us, block
+    // erase 2^n ms, chip erase isn't supported
+    table[0x1f] = 0x07;
+    table[0x20] = 0x07;
+    table[0x21] = 0x0a;
+    // Max timeouts: 2^n times the typical
+    table[0x23] = 0x04;
+    table[0x24] = 0x04;
+    table[0x25] = 0x04;
+
+    // Device size 2^n bytes
+    table[0x27] = size.trailing_zeros() as u8;
+    // Interface: x8/x16
+    table[0x28] = 0x02;
+    // Max bytes in a buffered write 2^n
+    table[0x2a] = WRITE_BUFFER_SIZE.trailing_zeros() as u8;
+    // One erase block region
+    table[0x2c] = 0x01;
+    table[0x2d] = blocks as u8;
+    table[0x2e] = (blocks >> 8) as u8;
+    table[0x2f] = block_size as u8;
+    table[0x30] = (block_size >> 8) as u8;
+
+    // Primary extended table, version 1.0
+    table[0x31] = b'P';
+    table[0x32] = b'R';
+    table[0x33] = b'I';
+    table[0x34] = b'1';
+    table[0x35] = b'0';
+
+    table
+}
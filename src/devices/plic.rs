@@ -0,0 +1,276 @@
+//! SiFive PLIC, routes the interrupt lines of the devices to the harts
+//!
+//! Every hart has two contexts, context 2 * hartid is the M-mode external
+//! interrupt and 2 * hartid + 1 the S-mode one, same as on the QEMU virt
+//! machine
+
+use crate::memory::TypeWidth;
+use crate::cpu::{ MIP_MEIP, MIP_SEIP };
+use super::Device;
+
+/// Size of the register region of the PLIC
+pub const PLIC_SIZE: u64 = 0x600000;
+
+const PRIORITY: u64 = 0x000000;
+const PENDING: u64 = 0x001000;
+const ENABLE: u64 = 0x002000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x200000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const THRESHOLD: u64 = 0x0;
+const CLAIM: u64 = 0x4;
+
+/// Highest priority a source can have
+const PRIORITY_MAX: u32 = 7;
+
+struct Context {
+    /// Bitmap of the enabled sources
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+pub struct Plic {
+    /// Priority of every source, source 0 doesn't exist
+    priority: Vec<u32>,
+
+    /// Level of the interrupt line of every source
+    level: Vec<bool>,
+
+    pending: Vec<bool>,
+
+    /// Edges from ´trigger´ that came while the source was claimed, they
+    /// are forwarded on the completion
+    edge: Vec<bool>,
+
+    /// Claimed sources that haven't been completed yet, the gateway
+    /// doesn't forward new requests for them
+    claimed: Vec<bool>,
+
+    contexts: Vec<Context>,
+}
+
+impl Plic {
+    /// Create a PLIC with sources 1 to ´sources´ for ´harts´ harts
+    pub fn new(sources: u32, harts: usize) -> Self {
+        let count = sources as usize + 1;
+        let words = count.div_ceil(32);
+
+        let contexts = (0..harts * 2)
+            .map(|_| Context {
+                enable: vec![0; words],
+                threshold: 0,
+            })
+            .collect();
+
+        Self {
+            priority: vec![0; count],
+            level: vec![false; count],
+            pending: vec![false; count],
+            edge: vec![false; count],
+            claimed: vec![false; count],
+            contexts,
+        }
+    }
+
+    /// Set the level of the interrupt line of ´source´
+    pub fn set_level(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if source == 0 || source >= self.level.len() {
+            return;
+        }
+
+        self.level[source] = level;
+
+        // NOTE(patrik): The sources are level triggered, the request
+        // follows the line until it has been claimed
+        if !self.claimed[source] {
+            self.pending[source] = level;
+        }
+    }
+
+    /// Raise an edge on ´source´, used for message signaled interrupts.
+    /// The request stays pending until it's claimed
+    pub fn trigger(&mut self, source: u32) {
+        let source = source as usize;
+        if source == 0 || source >= self.level.len() {
+            return;
+        }
+
+        if self.claimed[source] {
+            self.edge[source] = true;
+        } else {
+            self.pending[source] = true;
+        }
+    }
+
+    fn is_enabled(&self, context: usize, source: usize) -> bool {
+        self.contexts[context].enable[source / 32] & (1 << (source % 32)) != 0
+    }
+
+    /// Pending and enabled source with the highest priority above the
+    /// threshold of ´context´, the lowest id wins a tie
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let threshold = self.contexts[context].threshold;
+
+        let mut best: Option<usize> = None;
+        for source in 1..self.pending.len() {
+            if !self.pending[source] || !self.is_enabled(context, source) {
+                continue;
+            }
+
+            let priority = self.priority[source];
+            if priority <= threshold {
+                continue;
+            }
+
+            if best.is_none_or(|best| priority > self.priority[best]) {
+                best = Some(source);
+            }
+        }
+
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best_source(context) else {
+            return 0;
+        };
+
+        self.pending[source] = false;
+        self.claimed[source] = true;
+        source as u32
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if source == 0 || source >= self.claimed.len() ||
+            !self.is_enabled(context, source)
+        {
+            return;
+        }
+
+        // NOTE(patrik): A line that is still raised is forwarded again
+        self.claimed[source] = false;
+        self.pending[source] = self.level[source] ||
+            std::mem::take(&mut self.edge[source]);
+    }
+
+    /// Bits of ´mip´ the PLIC raises for ´hartid´
+    pub fn interrupts(&self, hartid: u64) -> u64 {
+        let context = hartid as usize * 2;
+        if context >= self.contexts.len() || !self.pending.contains(&true) {
+            return 0;
+        }
+
+        let mut mip = 0;
+        if self.best_source(context).is_some() {
+            mip |= MIP_MEIP;
+        }
+
+        if self.best_source(context + 1).is_some() {
+            mip |= MIP_SEIP;
+        }
+
+        mip
+    }
+
+    /// Split ´offset´ into the context and the register in the per context
+    /// region
+    fn context_register(&self, offset: u64) -> Option<(usize, u64)> {
+        let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+        if context >= self.contexts.len() {
+            return None;
+        }
+
+        Some((context, (offset - CONTEXT) % CONTEXT_STRIDE))
+    }
+
+    /// Split ´offset´ into the context and the word in the enable region
+    fn enable_word(&self, offset: u64) -> Option<(usize, usize)> {
+        let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+        let word = (((offset - ENABLE) % ENABLE_STRIDE) / 4) as usize;
+        if context >= self.contexts.len() ||
+            word >= self.contexts[context].enable.len()
+        {
+            return None;
+        }
+
+        Some((context, word))
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, _width: TypeWidth) -> u64 {
+        if offset >= CONTEXT {
+            return match self.context_register(offset) {
+                Some((context, THRESHOLD)) => {
+                    self.contexts[context].threshold as u64
+                }
+                Some((context, CLAIM)) => self.claim(context) as u64,
+
+                _ => 0,
+            };
+        }
+
+        if offset >= ENABLE {
+            return match self.enable_word(offset) {
+                Some((context, word)) => {
+                    self.contexts[context].enable[word] as u64
+                }
+                None => 0,
+            };
+        }
+
+        if offset >= PENDING {
+            let first = ((offset - PENDING) / 4) as usize * 32;
+            let mut value = 0u32;
+            for bit in 0..32 {
+                if self.pending.get(first + bit).copied().unwrap_or(false) {
+                    value |= 1 << bit;
+                }
+            }
+
+            return value as u64;
+        }
+
+        let source = ((offset - PRIORITY) / 4) as usize;
+        self.priority.get(source).copied().unwrap_or(0) as u64
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _width: TypeWidth) {
+        let value = value as u32;
+
+        if offset >= CONTEXT {
+            match self.context_register(offset) {
+                Some((context, THRESHOLD)) => {
+                    self.contexts[context].threshold = value.min(PRIORITY_MAX);
+                }
+                Some((context, CLAIM)) => self.complete(context, value),
+
+                _ => {}
+            }
+
+            return;
+        }
+
+        if offset >= ENABLE {
+            if let Some((context, word)) = self.enable_word(offset) {
+                // NOTE(patrik): Source 0 doesn't exist and can't be enabled
+                let value = if word == 0 { value & !1 } else { value };
+                self.contexts[context].enable[word] = value;
+            }
+
+            return;
+        }
+
+        // NOTE(patrik): The pending bits are read-only
+        if offset >= PENDING {
+            return;
+        }
+
+        let source = ((offset - PRIORITY) / 4) as usize;
+        if source != 0 && source < self.priority.len() {
+            self.priority[source] = value.min(PRIORITY_MAX);
+        }
+    }
+}
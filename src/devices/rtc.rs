@@ -0,0 +1,139 @@
+//! Goldfish RTC, the real time clock used by the QEMU virt machine
+
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use crate::memory::TypeWidth;
+use super::{ Device, Clock };
+
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0c;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1c;
+
+/// Size of the MMIO region
+pub const GOLDFISH_RTC_SIZE: u64 = 0x1000;
+
+pub struct GoldfishRtc {
+    clock: Clock,
+
+    /// Time in nanoseconds since the UNIX epoch when ´clock´ was at 0
+    offset: u64,
+
+    /// High part of the time, latched when TIME_LOW is read
+    time_high: u32,
+
+    alarm: u64,
+    alarm_high: u32,
+    alarm_running: bool,
+
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl GoldfishRtc {
+    /// Create a RTC starting at ´epoch´ seconds since the UNIX epoch, if
+    /// ´epoch´ is None the RTC starts at the current host time
+    pub fn new(clock: Clock, epoch: Option<u64>) -> Self {
+        let start = match epoch {
+            Some(epoch) => epoch.saturating_mul(1_000_000_000),
+            None => SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or(0),
+        };
+
+        let offset = start.wrapping_sub(clock.elapsed_ns());
+
+        Self {
+            clock,
+            offset,
+
+            time_high: 0,
+
+            alarm: 0,
+            alarm_high: 0,
+            alarm_running: false,
+
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    /// Current time in nanoseconds since the UNIX epoch
+    fn now(&self) -> u64 {
+        self.offset.wrapping_add(self.clock.elapsed_ns())
+    }
+
+    fn set_time(&mut self, time: u64) {
+        self.offset = time.wrapping_sub(self.clock.elapsed_ns());
+    }
+
+    /// Fire the alarm if the time has passed
+    fn update_alarm(&mut self) {
+        if self.alarm_running && self.now() >= self.alarm {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn read(&mut self, offset: u64, _width: TypeWidth) -> u64 {
+        self.update_alarm();
+
+        match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now & 0xffffffff
+            }
+
+            TIME_HIGH => self.time_high as u64,
+            ALARM_LOW => self.alarm & 0xffffffff,
+            ALARM_HIGH => self.alarm >> 32,
+            IRQ_ENABLED => self.irq_enabled as u64,
+            ALARM_STATUS => self.alarm_running as u64,
+
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _width: TypeWidth) {
+        let value = value as u32;
+
+        match offset {
+            // NOTE(patrik): Setting the time is done by writing TIME_HIGH
+            // first and then TIME_LOW
+            TIME_LOW => {
+                let time = ((self.time_high as u64) << 32) | value as u64;
+                self.set_time(time);
+            }
+
+            TIME_HIGH => self.time_high = value,
+
+            // NOTE(patrik): Writing ALARM_LOW arms the alarm with the
+            // previously written ALARM_HIGH
+            ALARM_LOW => {
+                self.alarm = ((self.alarm_high as u64) << 32) | value as u64;
+                self.alarm_running = true;
+            }
+
+            ALARM_HIGH => self.alarm_high = value,
+            IRQ_ENABLED => self.irq_enabled = value & 1 == 1,
+            CLEAR_ALARM => self.alarm_running = false,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+
+            _ => {}
+        }
+
+        self.update_alarm();
+    }
+
+    fn irq(&mut self) -> bool {
+        self.update_alarm();
+        self.irq_enabled && self.irq_pending
+    }
+}
@@ -0,0 +1,528 @@
+//! RISC-V semihosting
+//!
+//! The guest requests a semihosting call with the magic sequence:
+//!   slli x0, x0, 0x1f
+//!   ebreak
+//!   srai x0, x0, 7
+//! The operation number is passed in a0 and a pointer to the parameter
+//! block in a1, the result is returned in a0. All file access is sandboxed
+//! to a directory on the host.
+
+use std::collections::HashMap;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Write, Seek, SeekFrom };
+use std::path::{ Path, PathBuf, Component };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+use crate::memory::Mmu;
+use super::{ ExitSignal, HostConsole };
+
+/// slli x0, x0, 0x1f
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f01013;
+/// srai x0, x0, 7
+pub const SEMIHOSTING_EXIT: u32 = 0x40705013;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_TMPNAM: u64 = 0x0d;
+const SYS_REMOVE: u64 = 0x0e;
+const SYS_RENAME: u64 = 0x0f;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_SYSTEM: u64 = 0x12;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+const SYS_ELAPSED: u64 = 0x30;
+const SYS_TICKFREQ: u64 = 0x31;
+
+/// Reason passed to SYS_EXIT when the application exits normally
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EINVAL: i32 = 22;
+const ENAMETOOLONG: i32 = 36;
+
+/// Largest read or write done at once, the guest gets a short count for
+/// bigger ones
+const MAX_TRANSFER: u64 = 1 << 20;
+
+/// Longest file name the guest can pass
+const MAX_PATH: u64 = 4096;
+
+/// Size of one field inside the parameter block (XLEN)
+const FIELD_SIZE: u64 = 8;
+
+/// Value returned to the guest on failure
+const FAILURE: u64 = -1i64 as u64;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl Handle {
+    fn is_tty(&self) -> bool {
+        !matches!(self, Handle::File(_))
+    }
+}
+
+pub struct Semihosting {
+    /// Host directory the guest has access to
+    root: PathBuf,
+    cmdline: String,
+
+    exit: ExitSignal,
+    console: HostConsole,
+
+    handles: HashMap<u64, Handle>,
+    next_handle: u64,
+
+    /// Error code from the last failing call, returned by SYS_ERRNO
+    errno: i32,
+
+    start: Instant,
+}
+
+impl Semihosting {
+    pub fn new<P>(root: P, exit: ExitSignal) -> Self
+        where P: AsRef<Path>
+    {
+        // NOTE(patrik): The resolved paths are compared against the root,
+        // so it has to be canonical as well
+        let root = root.as_ref();
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+
+        Self {
+            root,
+            cmdline: String::new(),
+
+            exit,
+            console: HostConsole::new(),
+
+            handles: HashMap::new(),
+            next_handle: 1,
+
+            errno: 0,
+
+            start: Instant::now(),
+        }
+    }
+
+    /// Set the command line returned by SYS_GET_CMDLINE
+    pub fn set_cmdline(&mut self, cmdline: &str) {
+        self.cmdline = cmdline.to_string();
+    }
+
+    /// Execute a semihosting call and return the value for a0
+    pub fn call(&mut self, mmu: &mut dyn Mmu, operation: u64, parameter: u64)
+        -> u64
+    {
+        let field = |mmu: &mut dyn Mmu, index: u64| {
+            mmu.read_u64(parameter + index * FIELD_SIZE)
+        };
+
+        match operation {
+            SYS_OPEN => {
+                let name = field(mmu, 0);
+                let mode = field(mmu, 1);
+                let len = field(mmu, 2);
+                if len > MAX_PATH {
+                    return self.fail(ENAMETOOLONG);
+                }
+
+                let name = read_bytes(mmu, name, len);
+                let name = String::from_utf8_lossy(&name).into_owned();
+                self.open(&name, mode)
+            }
+
+            SYS_CLOSE => {
+                let handle = field(mmu, 0);
+                match self.handles.remove(&handle) {
+                    Some(_) => 0,
+                    None => self.fail(EBADF),
+                }
+            }
+
+            SYS_WRITEC => {
+                let value = mmu.read_u8(parameter);
+                self.console.putchar(value);
+                0
+            }
+
+            SYS_WRITE0 => {
+                for addr in parameter..parameter.saturating_add(MAX_TRANSFER) {
+                    let value = mmu.read_u8(addr);
+                    if value == 0 {
+                        break;
+                    }
+
+                    self.console.putchar(value);
+                }
+
+                0
+            }
+
+            SYS_WRITE => {
+                let handle = field(mmu, 0);
+                let buffer = field(mmu, 1);
+                let len = field(mmu, 2);
+
+                // NOTE(patrik): What's past the limit is reported as not
+                // written, the guest retries with the rest
+                let count = len.min(MAX_TRANSFER);
+                let data = read_bytes(mmu, buffer, count);
+                match self.write(handle, &data) {
+                    FAILURE => FAILURE,
+                    unwritten => unwritten + (len - count),
+                }
+            }
+
+            SYS_READ => {
+                let handle = field(mmu, 0);
+                let buffer = field(mmu, 1);
+                let len = field(mmu, 2);
+
+                self.read(mmu, handle, buffer, len)
+            }
+
+            SYS_READC => {
+                let mut value = [0u8; 1];
+                match std::io::stdin().read(&mut value) {
+                    Ok(1) => value[0] as u64,
+                    _ => FAILURE,
+                }
+            }
+
+            SYS_ISERROR => {
+                let status = field(mmu, 0);
+                ((status as i64) < 0) as u64
+            }
+
+            SYS_ISTTY => {
+                let handle = field(mmu, 0);
+                match self.handles.get(&handle) {
+                    Some(handle) => handle.is_tty() as u64,
+                    None => self.fail(EBADF),
+                }
+            }
+
+            SYS_SEEK => {
+                let handle = field(mmu, 0);
+                let position = field(mmu, 1);
+
+                match self.handles.get_mut(&handle) {
+                    Some(Handle::File(file)) => {
+                        match file.seek(SeekFrom::Start(position)) {
+                            Ok(_) => 0,
+                            Err(e) => self.host_error(e),
+                        }
+                    }
+                    Some(_) => self.fail(EINVAL),
+                    None => self.fail(EBADF),
+                }
+            }
+
+            SYS_FLEN => {
+                let handle = field(mmu, 0);
+                match self.handles.get(&handle) {
+                    Some(Handle::File(file)) => {
+                        match file.metadata() {
+                            Ok(metadata) => metadata.len(),
+                            Err(e) => self.host_error(e),
+                        }
+                    }
+                    Some(_) => 0,
+                    None => self.fail(EBADF),
+                }
+            }
+
+            SYS_TMPNAM => {
+                let buffer = field(mmu, 0);
+                let id = field(mmu, 1);
+                let len = field(mmu, 2);
+
+                let name = format!("kira-tmp-{:03}\0", id & 0xff);
+                if name.len() as u64 > len {
+                    return self.fail(EINVAL);
+                }
+
+                write_bytes(mmu, buffer, name.as_bytes());
+                0
+            }
+
+            SYS_REMOVE => {
+                let name = field(mmu, 0);
+                let len = field(mmu, 1);
+                if len > MAX_PATH {
+                    return self.fail(ENAMETOOLONG);
+                }
+
+                let name = read_bytes(mmu, name, len);
+                let name = String::from_utf8_lossy(&name).into_owned();
+
+                let Some(path) = self.resolve(&name) else {
+                    return self.fail(EACCES);
+                };
+
+                match std::fs::remove_file(path) {
+                    Ok(_) => 0,
+                    Err(e) => self.host_error(e),
+                }
+            }
+
+            SYS_RENAME => {
+                let old = field(mmu, 0);
+                let old_len = field(mmu, 1);
+                let new = field(mmu, 2);
+                let new_len = field(mmu, 3);
+                if old_len > MAX_PATH || new_len > MAX_PATH {
+                    return self.fail(ENAMETOOLONG);
+                }
+
+                let old = read_bytes(mmu, old, old_len);
+                let old = String::from_utf8_lossy(&old).into_owned();
+                let new = read_bytes(mmu, new, new_len);
+                let new = String::from_utf8_lossy(&new).into_owned();
+
+                let (Some(old), Some(new)) =
+                    (self.resolve(&old), self.resolve(&new)) else
+                {
+                    return self.fail(EACCES);
+                };
+
+                match std::fs::rename(old, new) {
+                    Ok(_) => 0,
+                    Err(e) => self.host_error(e),
+                }
+            }
+
+            SYS_CLOCK => {
+                (self.start.elapsed().as_millis() / 10) as u64
+            }
+
+            SYS_TIME => {
+                SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_secs())
+                    .unwrap_or(0)
+            }
+
+            // NOTE(patrik): Running host commands would escape the sandbox
+            SYS_SYSTEM => self.fail(EACCES),
+
+            SYS_ERRNO => self.errno as u64,
+
+            SYS_GET_CMDLINE => {
+                let buffer = field(mmu, 0);
+                let len = field(mmu, 1);
+
+                let mut cmdline = self.cmdline.as_bytes().to_vec();
+                cmdline.push(0);
+
+                if cmdline.len() as u64 > len {
+                    return self.fail(EINVAL);
+                }
+
+                write_bytes(mmu, buffer, &cmdline);
+                mmu.write_u64(parameter + FIELD_SIZE, self.cmdline.len() as u64);
+                0
+            }
+
+            SYS_HEAPINFO => {
+                // NOTE(patrik): Report the heap and stack as unknown, the
+                // C library then falls back to the values from the linker
+                let block = field(mmu, 0);
+                for index in 0..4 {
+                    mmu.write_u64(block + index * FIELD_SIZE, 0);
+                }
+
+                0
+            }
+
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                let reason = field(mmu, 0);
+                let subcode = field(mmu, 1);
+
+                if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    self.exit.exit(subcode);
+                } else {
+                    self.exit.exit(1);
+                }
+
+                0
+            }
+
+            SYS_ELAPSED => {
+                let ticks = self.start.elapsed().as_micros() as u64;
+                mmu.write_u64(parameter, ticks);
+                0
+            }
+
+            SYS_TICKFREQ => 1_000_000,
+
+            _ => self.fail(EINVAL),
+        }
+    }
+
+    fn open(&mut self, name: &str, mode: u64) -> u64 {
+        // NOTE(patrik): ":tt" is the console, the mode selects which stream
+        let handle = if name == ":tt" {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let Some(path) = self.resolve(name) else {
+                return self.fail(EACCES);
+            };
+
+            let mut options = OpenOptions::new();
+            match mode {
+                // r, rb
+                0 | 1 => options.read(true),
+                // r+, r+b
+                2 | 3 => options.read(true).write(true),
+                // w, wb
+                4 | 5 => options.write(true).create(true).truncate(true),
+                // w+, w+b
+                6 | 7 => options.read(true).write(true)
+                    .create(true).truncate(true),
+                // a, ab
+                8 | 9 => options.append(true).create(true),
+                // a+, a+b
+                10 | 11 => options.read(true).append(true).create(true),
+
+                _ => return self.fail(EINVAL),
+            };
+
+            match options.open(path) {
+                Ok(file) => Handle::File(file),
+                Err(e) => return self.host_error(e),
+            }
+        };
+
+        let id = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(id, handle);
+
+        id
+    }
+
+    /// Returns the number of bytes that was NOT written
+    fn write(&mut self, handle: u64, data: &[u8]) -> u64 {
+        let result = match self.handles.get_mut(&handle) {
+            Some(Handle::Stdout) => write_all(std::io::stdout(), data),
+            Some(Handle::Stderr) => write_all(std::io::stderr(), data),
+            Some(Handle::File(file)) => write_all(file, data),
+            Some(Handle::Stdin) => return self.fail(EBADF),
+            None => return self.fail(EBADF),
+        };
+
+        match result {
+            Ok(_) => 0,
+            Err(e) => {
+                self.host_error(e);
+                data.len() as u64
+            }
+        }
+    }
+
+    /// Returns the number of bytes that was NOT read
+    fn read(&mut self, mmu: &mut dyn Mmu, handle: u64, buffer: u64, len: u64)
+        -> u64
+    {
+        let mut data = vec![0u8; len.min(MAX_TRANSFER) as usize];
+
+        let result = match self.handles.get_mut(&handle) {
+            Some(Handle::Stdin) => std::io::stdin().read(&mut data),
+            Some(Handle::File(file)) => file.read(&mut data),
+            Some(_) => return self.fail(EBADF),
+            None => return self.fail(EBADF),
+        };
+
+        match result {
+            Ok(count) => {
+                write_bytes(mmu, buffer, &data[..count]);
+                len - count as u64
+            }
+
+            Err(e) => {
+                self.host_error(e);
+                len
+            }
+        }
+    }
+
+    /// Resolve a guest path inside the sandbox, returns None if the path
+    /// tries to escape the sandbox, also through a symlink
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir | Component::RootDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+
+            // NOTE(patrik): A file that is about to be created doesn't
+            // exist yet, its directory has to. A dangling symlink is
+            // refused, creating the file would follow it
+            Err(_) if path.symlink_metadata().is_err() => {
+                let file_name = path.file_name()?;
+                path.parent()?.canonicalize().ok()?.join(file_name)
+            }
+            Err(_) => return None,
+        };
+
+        // NOTE(patrik): The guest can still swap a directory for a symlink
+        // between this check and the use of the path, the sandbox is meant
+        // to keep honest programs in, not to contain hostile ones
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+
+    fn fail(&mut self, errno: i32) -> u64 {
+        self.errno = errno;
+        FAILURE
+    }
+
+    fn host_error(&mut self, error: std::io::Error) -> u64 {
+        self.fail(error.raw_os_error().unwrap_or(EINVAL))
+    }
+}
+
+/// Copy ´len´ bytes of the guest memory at ´addr´, unmapped memory reads as
+/// zero. The callers limit ´len´
+fn read_bytes(mmu: &mut dyn Mmu, addr: u64, len: u64) -> Vec<u8> {
+    let mut data = vec![0u8; len as usize];
+    mmu.read_bytes(addr, &mut data);
+    data
+}
+
+fn write_bytes(mmu: &mut dyn Mmu, addr: u64, data: &[u8]) {
+    mmu.write_bytes(addr, data);
+}
+
+fn write_all<W: Write>(mut writer: W, data: &[u8]) -> std::io::Result<()> {
+    writer.write_all(data)?;
+    writer.flush()
+}
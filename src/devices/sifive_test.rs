@@ -0,0 +1,47 @@
+//! SiFive test device from the QEMU virt board, the guest writes to it to
+//! power off or reboot the machine
+
+use crate::memory::TypeWidth;
+use super::{ Device, ExitSignal };
+
+/// Size of the register region of the test device
+pub const SIFIVE_TEST_SIZE: u64 = 0x1000;
+
+/// Power off with the exit code in the upper 16 bits
+const FINISHER_FAIL: u64 = 0x3333;
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+pub struct SifiveTest {
+    exit: ExitSignal,
+}
+
+impl SifiveTest {
+    pub fn new(exit: ExitSignal) -> Self {
+        Self {
+            exit,
+        }
+    }
+}
+
+impl Device for SifiveTest {
+    fn read(&mut self, _offset: u64, _width: TypeWidth) -> u64 {
+        0
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _width: TypeWidth) {
+        if offset != 0 {
+            return;
+        }
+
+        match value & 0xffff {
+            FINISHER_FAIL => self.exit.exit((value >> 16) & 0xffff),
+            FINISHER_PASS => self.exit.exit(0),
+
+            // TODO(patrik): Reset the machine instead of exiting
+            FINISHER_RESET => self.exit.exit(0),
+
+            _ => {}
+        }
+    }
+}
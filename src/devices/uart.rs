@@ -0,0 +1,207 @@
+//! 16550 compatible UART connected to the host terminal
+//!
+//! Transmitting is instant so the transmitter is always idle, the received
+//! bytes come from the host stdin
+
+use std::collections::VecDeque;
+
+use crate::memory::TypeWidth;
+use super::{ Device, HostConsole };
+
+/// Size of the register region of the UART
+pub const UART_SIZE: u64 = 0x100;
+
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+/// Carrier detect, data set ready and clear to send
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// How many ticks between every check for input from the host
+const POLL_INTERVAL: u64 = 1024;
+
+pub struct Uart16550 {
+    console: HostConsole,
+    rx: VecDeque<u8>,
+
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+
+    /// Set when the transmitter became empty and cleared when the driver
+    /// acknowledges it
+    tx_interrupt: bool,
+
+    ticks: u64,
+}
+
+impl Uart16550 {
+    pub fn new() -> Self {
+        Self {
+            console: HostConsole::new(),
+            rx: VecDeque::new(),
+
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+
+            tx_interrupt: false,
+
+            ticks: 0,
+        }
+    }
+
+    fn poll_input(&mut self) {
+        while let Some(value) = self.console.getchar() {
+            self.rx.push_back(value);
+        }
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        let id = if self.ier & IER_RX_AVAILABLE != 0 && !self.rx.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_TX_EMPTY != 0 && self.tx_interrupt {
+            IIR_TX_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        };
+
+        if self.fifo_enabled {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+}
+
+impl Device for Uart16550 {
+    fn read(&mut self, offset: u64, _width: TypeWidth) -> u64 {
+        let value = match offset {
+            RBR_THR_DLL if self.dlab() => self.divisor as u8,
+            RBR_THR_DLL => {
+                if self.rx.is_empty() {
+                    self.poll_input();
+                }
+
+                self.rx.pop_front().unwrap_or(0)
+            }
+            IER_DLM if self.dlab() => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id & 0x0f == IIR_TX_EMPTY {
+                    self.tx_interrupt = false;
+                }
+
+                id
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                if self.rx.is_empty() {
+                    self.poll_input();
+                }
+
+                // NOTE(patrik): Reading the status also acknowledges the
+                // transmitter interrupt. Some drivers, like the xv6 one
+                // before 2024, never read the IIR and the line would
+                // otherwise stay raised forever
+                self.tx_interrupt = false;
+
+                let data_ready = if self.rx.is_empty() { 0 } else { LSR_DATA_READY };
+                data_ready | LSR_THR_EMPTY | LSR_TX_IDLE
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+
+            _ => 0,
+        };
+
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _width: TypeWidth) {
+        let value = value as u8;
+
+        match offset {
+            RBR_THR_DLL if self.dlab() => {
+                self.divisor = (self.divisor & 0xff00) | value as u16;
+            }
+            RBR_THR_DLL => {
+                self.console.putchar(value);
+                self.tx_interrupt = true;
+            }
+            IER_DLM if self.dlab() => {
+                self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8);
+            }
+            IER_DLM => {
+                // NOTE(patrik): Enabling the transmitter interrupt while
+                // the transmitter is empty raises it right away
+                if value & IER_TX_EMPTY != 0 && self.ier & IER_TX_EMPTY == 0 {
+                    self.tx_interrupt = true;
+                }
+
+                self.ier = value & 0x0f;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+
+            _ => {}
+        }
+    }
+
+    fn irq(&mut self) -> bool {
+        self.interrupt_id() & IIR_NO_INTERRUPT == 0
+    }
+
+    fn tick(&mut self) {
+        // NOTE(patrik): Only look for input once the driver wants it, the
+        // host stdin is left alone for guests that never read it
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.ier & IER_RX_AVAILABLE != 0 &&
+            self.ticks.is_multiple_of(POLL_INTERVAL)
+        {
+            self.poll_input();
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Virtio block device backed by a disk image on the host
+
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Write, Seek, SeekFrom };
+use std::path::Path;
+
+use crate::memory::TypeWidth;
+use crate::devices::Dma;
+use super::{ VirtioDevice, Queue, Chain };
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Size of the request header, type, reserved and sector
+const HEADER_SIZE: usize = 16;
+
+/// Length of the id string returned by VIRTIO_BLK_T_GET_ID
+const ID_SIZE: usize = 20;
+
+pub struct VirtioBlock {
+    file: File,
+
+    /// Size of the disk in sectors
+    capacity: u64,
+}
+
+impl VirtioBlock {
+    /// Open the disk image at ´path´, the image is written to by the guest
+    pub fn open<P>(path: P) -> std::io::Result<Self>
+        where P: AsRef<Path>
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        let capacity = file.metadata()?.len() / SECTOR_SIZE;
+
+        Ok(Self {
+            file,
+            capacity,
+        })
+    }
+
+    fn config(&self) -> [u8; 8] {
+        self.capacity.to_le_bytes()
+    }
+
+    /// Check that [sector, sector + len) is inside the disk
+    fn in_range(&self, sector: u64, len: u64) -> bool {
+        sector.checked_mul(SECTOR_SIZE)
+            .and_then(|start| start.checked_add(len))
+            .is_some_and(|end| end <= self.capacity * SECTOR_SIZE)
+    }
+
+    /// Handle one request and return the status and the number of bytes
+    /// written to the chain, not counting the status byte
+    fn handle(&mut self, chain: &Chain, memory: &mut dyn Dma) -> (u8, u64) {
+        let Some(readable) = chain.read_all(memory) else {
+            return (VIRTIO_BLK_S_IOERR, 0);
+        };
+
+        if readable.len() < HEADER_SIZE {
+            return (VIRTIO_BLK_S_IOERR, 0);
+        }
+
+        let kind = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+
+        // NOTE(patrik): The last writable byte is the status
+        let data_len = chain.writable_len().saturating_sub(1);
+
+        match kind {
+            VIRTIO_BLK_T_IN => {
+                if !self.in_range(sector, data_len) {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                let mut data = vec![0u8; data_len as usize];
+                let result = self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))
+                    .and_then(|_| self.file.read_exact(&mut data));
+                if result.is_err() || !chain.write_at(memory, 0, &data) {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                (VIRTIO_BLK_S_OK, data_len)
+            }
+
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[HEADER_SIZE..];
+                if !self.in_range(sector, data.len() as u64) {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                let result = self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))
+                    .and_then(|_| self.file.write_all(data));
+                if result.is_err() {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                (VIRTIO_BLK_S_OK, 0)
+            }
+
+            VIRTIO_BLK_T_FLUSH => {
+                if self.file.sync_data().is_err() {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                (VIRTIO_BLK_S_OK, 0)
+            }
+
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0u8; ID_SIZE];
+                let name = b"kira-virtio-blk";
+                id[..name.len()].copy_from_slice(name);
+
+                let len = (data_len as usize).min(ID_SIZE);
+                if !chain.write_at(memory, 0, &id[..len]) {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                (VIRTIO_BLK_S_OK, len as u64)
+            }
+
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BLK_F_FLUSH
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        let config = self.config();
+
+        let mut value = 0;
+        for index in 0..width.size() {
+            let byte = config.get((offset + index) as usize).copied()
+                .unwrap_or(0);
+            value |= (byte as u64) << (index * 8);
+        }
+
+        value
+    }
+
+    fn process_queue(&mut self, _index: usize, queue: &mut Queue,
+                     memory: &mut dyn Dma)
+    {
+        while let Some(chain) = queue.pop(memory) {
+            let (status, written) = self.handle(&chain, memory);
+
+            let status_offset = chain.writable_len().saturating_sub(1);
+            chain.write_at(memory, status_offset, &[status]);
+
+            queue.push(memory, chain.head, (written + 1) as u32);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.file.flush();
+    }
+}
@@ -0,0 +1,146 @@
+//! Virtio over MMIO, version 2 of the register layout
+
+use crate::memory::TypeWidth;
+use crate::devices::{ Device, Dma };
+use super::{ Virtio, VirtioDevice, QUEUE_SIZE_MAX };
+
+/// Size of the register region of one virtio-mmio device
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+/// "virt" in little endian
+const MAGIC: u64 = 0x74726976;
+
+/// "QEMU", the vendor id QEMU uses
+const VENDOR: u64 = 0x554d4551;
+
+/// Replace the low or the high half of ´value´
+fn set_half(value: u64, half: u64, high: bool) -> u64 {
+    let half = half & 0xffffffff;
+    if high {
+        (value & 0xffffffff) | (half << 32)
+    } else {
+        (value & !0xffffffff) | half
+    }
+}
+
+pub struct VirtioMmio<D> {
+    virtio: Virtio<D>,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            virtio: Virtio::new(device),
+        }
+    }
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn read(&mut self, offset: u64, width: TypeWidth) -> u64 {
+        if offset >= CONFIG {
+            return self.virtio.device.read_config(offset - CONFIG, width);
+        }
+
+        let virtio = &mut self.virtio;
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => virtio.device.device_id() as u64,
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => virtio.device_features_page(),
+            QUEUE_NUM_MAX => {
+                virtio.selected_queue().map_or(0, |_| QUEUE_SIZE_MAX as u64)
+            }
+            QUEUE_READY => {
+                virtio.selected_queue().map_or(0, |queue| queue.ready as u64)
+            }
+            INTERRUPT_STATUS => virtio.interrupt_status as u64,
+            STATUS => virtio.status as u64,
+            CONFIG_GENERATION => virtio.config_generation as u64,
+
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        if offset >= CONFIG {
+            self.virtio.device.write_config(offset - CONFIG, value, width);
+            return;
+        }
+
+        let virtio = &mut self.virtio;
+        match offset {
+            DEVICE_FEATURES_SEL => virtio.device_features_select = value as u32,
+            DRIVER_FEATURES => virtio.set_driver_features_page(value),
+            DRIVER_FEATURES_SEL => virtio.driver_features_select = value as u32,
+            QUEUE_SEL => virtio.queue_select = value as u32,
+            QUEUE_NOTIFY => virtio.notify(value as usize),
+            INTERRUPT_ACK => virtio.interrupt_status &= !(value as u32),
+            STATUS => virtio.set_status(value as u32),
+
+            _ => {
+                let Some(queue) = virtio.selected_queue() else {
+                    return;
+                };
+
+                match offset {
+                    QUEUE_NUM => {
+                        queue.size = (value as u16).min(QUEUE_SIZE_MAX);
+                    }
+                    QUEUE_READY => queue.ready = value & 1 != 0,
+                    QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                        let high = offset == QUEUE_DESC_HIGH;
+                        queue.desc = set_half(queue.desc, value, high);
+                    }
+                    QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                        let high = offset == QUEUE_DRIVER_HIGH;
+                        queue.driver = set_half(queue.driver, value, high);
+                    }
+                    QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                        let high = offset == QUEUE_DEVICE_HIGH;
+                        queue.device = set_half(queue.device, value, high);
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn irq(&mut self) -> bool {
+        self.virtio.interrupt_status != 0
+    }
+
+    fn dma(&mut self, memory: &mut dyn Dma) {
+        self.virtio.process(memory);
+    }
+
+    fn shutdown(&mut self) {
+        self.virtio.device.shutdown();
+    }
+}
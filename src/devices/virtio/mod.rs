@@ -0,0 +1,376 @@
+//! Virtio devices, the device models are shared between the virtio-mmio
+//! and the virtio-pci transports
+//!
+//! Only the split virtqueue layout from virtio 1.x is implemented, the
+//! legacy interface isn't supported.
+
+use crate::memory::TypeWidth;
+use super::Dma;
+
+pub use mmio::{ VirtioMmio, VIRTIO_MMIO_SIZE };
+pub use pci::VirtioPci;
+pub use block::VirtioBlock;
+
+mod mmio;
+mod pci;
+mod block;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+pub const STATUS_FAILED: u32 = 128;
+
+/// Interrupt status bits
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Max number of descriptors in a queue
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Device specific part of a virtio device
+pub trait VirtioDevice {
+    /// Virtio device id, 2 for block devices
+    fn device_id(&self) -> u32;
+
+    /// Device specific feature bits, VIRTIO_F_VERSION_1 is added by the
+    /// transport
+    fn features(&self) -> u64;
+
+    fn num_queues(&self) -> usize;
+
+    /// Read from the device configuration space
+    fn read_config(&mut self, offset: u64, width: TypeWidth) -> u64;
+
+    /// Write to the device configuration space
+    fn write_config(&mut self, _offset: u64, _value: u64, _width: TypeWidth) {}
+
+    /// Handle the buffers the driver has made available on ´queue´
+    fn process_queue(&mut self, index: usize, queue: &mut Queue,
+                     memory: &mut dyn Dma);
+
+    /// Called when the driver resets the device
+    fn reset(&mut self) {}
+
+    fn shutdown(&mut self) {}
+}
+
+/// Buffer described by a descriptor
+#[derive(Copy, Clone, Debug)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+
+    /// Written by the device, otherwise only read by the device
+    pub writable: bool,
+}
+
+/// Chain of descriptors popped from the available ring
+#[derive(Debug)]
+pub struct Chain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+    /// Read the device readable part of the chain as one buffer
+    pub fn read_all(&self, memory: &mut dyn Dma) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !desc.writable) {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            if !memory.read_bytes(desc.addr, &mut data[start..]) {
+                return None;
+            }
+        }
+
+        Some(data)
+    }
+
+    /// Total size of the device writable part of the chain
+    pub fn writable_len(&self) -> u64 {
+        self.descriptors.iter()
+            .filter(|desc| desc.writable)
+            .map(|desc| desc.len as u64)
+            .sum()
+    }
+
+    /// Scatter ´data´ over the device writable part of the chain, starting
+    /// at byte ´offset´ of that part
+    pub fn write_at(&self, memory: &mut dyn Dma, mut offset: u64,
+                    mut data: &[u8]) -> bool
+    {
+        for desc in self.descriptors.iter().filter(|desc| desc.writable) {
+            if data.is_empty() {
+                break;
+            }
+
+            let len = desc.len as u64;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+
+            let count = ((len - offset) as usize).min(data.len());
+            if !memory.write_bytes(desc.addr + offset, &data[..count]) {
+                return false;
+            }
+
+            data = &data[count..];
+            offset = 0;
+        }
+
+        data.is_empty()
+    }
+}
+
+/// Split virtqueue, the addresses are guest physical addresses
+#[derive(Clone, Debug)]
+pub struct Queue {
+    pub size: u16,
+    pub ready: bool,
+
+    /// Descriptor table
+    pub desc: u64,
+    /// Available ring
+    pub driver: u64,
+    /// Used ring
+    pub device: u64,
+
+    /// MSI-X vector, only used by virtio-pci
+    pub msix_vector: u16,
+
+    last_avail: u16,
+
+    /// Set when buffers were added to the used ring and the driver wants
+    /// an interrupt for it
+    pub needs_interrupt: bool,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Self {
+            size: QUEUE_SIZE_MAX,
+            ready: false,
+
+            desc: 0,
+            driver: 0,
+            device: 0,
+
+            msix_vector: 0xffff,
+
+            last_avail: 0,
+            needs_interrupt: false,
+        }
+    }
+
+    /// Pop the next chain from the available ring, returns None when the
+    /// ring is empty or the chain is broken
+    pub fn pop(&mut self, memory: &mut dyn Dma) -> Option<Chain> {
+        if !self.ready || self.size == 0 {
+            return None;
+        }
+
+        let avail_idx = memory.read_u16(self.driver + 2)?;
+        if avail_idx == self.last_avail {
+            return None;
+        }
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = memory.read_u16(self.driver + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            // NOTE(patrik): Guard against loops in the chain
+            if index >= self.size || descriptors.len() >= self.size as usize {
+                return None;
+            }
+
+            let entry = self.desc + index as u64 * 16;
+            let addr = memory.read_u64(entry)?;
+            let len = memory.read_u32(entry + 8)?;
+            let flags = memory.read_u16(entry + 12)?;
+            let next = memory.read_u16(entry + 14)?;
+
+            descriptors.push(Descriptor {
+                addr,
+                len,
+                writable: flags & DESC_F_WRITE != 0,
+            });
+
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+
+            index = next;
+        }
+
+        Some(Chain { head, descriptors })
+    }
+
+    /// Return a chain to the driver, ´len´ is the number of bytes written
+    /// to the chain
+    pub fn push(&mut self, memory: &mut dyn Dma, head: u16, len: u32) {
+        let Some(used_idx) = memory.read_u16(self.device + 2) else {
+            return;
+        };
+
+        let slot = (used_idx % self.size) as u64;
+        let entry = self.device + 4 + slot * 8;
+        memory.write_u32(entry, head as u32);
+        memory.write_u32(entry + 4, len);
+        memory.write_u16(self.device + 2, used_idx.wrapping_add(1));
+
+        let flags = memory.read_u16(self.driver).unwrap_or(0);
+        if flags & AVAIL_F_NO_INTERRUPT == 0 {
+            self.needs_interrupt = true;
+        }
+    }
+}
+
+/// Transport independent state of a virtio device
+pub struct Virtio<D> {
+    pub device: D,
+
+    pub device_features_select: u32,
+    pub driver_features_select: u32,
+    pub driver_features: u64,
+
+    pub status: u32,
+    pub config_generation: u32,
+
+    pub queue_select: u32,
+    pub queues: Vec<Queue>,
+
+    pub interrupt_status: u32,
+
+    /// Queues notified by the driver that hasn't been processed yet
+    notified: Vec<usize>,
+}
+
+impl<D: VirtioDevice> Virtio<D> {
+    pub fn new(device: D) -> Self {
+        let queues = vec![Queue::new(); device.num_queues()];
+
+        Self {
+            device,
+
+            device_features_select: 0,
+            driver_features_select: 0,
+            driver_features: 0,
+
+            status: 0,
+            config_generation: 0,
+
+            queue_select: 0,
+            queues,
+
+            interrupt_status: 0,
+
+            notified: Vec::new(),
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    /// Currently selected page of the device features
+    pub fn device_features_page(&self) -> u64 {
+        match self.device_features_select {
+            0 => self.device_features() & 0xffffffff,
+            1 => self.device_features() >> 32,
+            _ => 0,
+        }
+    }
+
+    /// Write to the currently selected page of the driver features
+    pub fn set_driver_features_page(&mut self, value: u64) {
+        let value = value & 0xffffffff;
+        match self.driver_features_select {
+            0 => self.driver_features =
+                (self.driver_features & !0xffffffff) | value,
+            1 => self.driver_features =
+                (self.driver_features & 0xffffffff) | (value << 32),
+            _ => {}
+        }
+    }
+
+    pub fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+
+        let mut status = status & 0xff;
+
+        // NOTE(patrik): Refuse features the device didn't offer
+        if status & STATUS_FEATURES_OK != 0 &&
+            self.driver_features & !self.device_features() != 0
+        {
+            status &= !STATUS_FEATURES_OK;
+        }
+
+        self.status = status;
+    }
+
+    pub fn reset(&mut self) {
+        self.device_features_select = 0;
+        self.driver_features_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_select = 0;
+        self.interrupt_status = 0;
+        self.notified.clear();
+
+        for queue in self.queues.iter_mut() {
+            *queue = Queue::new();
+        }
+
+        self.device.reset();
+    }
+
+    pub fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    /// The driver notified the device about new buffers on ´queue´, the
+    /// queue is processed by the next call to ´process´
+    pub fn notify(&mut self, queue: usize) {
+        if queue < self.queues.len() && !self.notified.contains(&queue) {
+            self.notified.push(queue);
+        }
+    }
+
+    /// Process the notified queues, returns the queues that needs an
+    /// interrupt
+    pub fn process(&mut self, memory: &mut dyn Dma) -> Vec<usize> {
+        let mut interrupts = Vec::new();
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return interrupts;
+        }
+
+        for index in std::mem::take(&mut self.notified) {
+            let queue = &mut self.queues[index];
+            self.device.process_queue(index, queue, memory);
+
+            if queue.needs_interrupt {
+                queue.needs_interrupt = false;
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+                interrupts.push(index);
+            }
+        }
+
+        interrupts
+    }
+}
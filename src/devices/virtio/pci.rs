@@ -0,0 +1,261 @@
+//! Virtio over PCI, the modern (virtio 1.x) interface
+//!
+//! All the structures live in BAR 0:
+//!   0x0000 Common configuration
+//!   0x1000 ISR status
+//!   0x2000 Device configuration
+//!   0x3000 Notifications, one register per queue
+
+use crate::memory::TypeWidth;
+use crate::devices::Dma;
+use crate::devices::pci::{
+    PciDevice, PciConfig, Bar, BarKind, BAR_COUNT, CAP_ID_VENDOR
+};
+use super::{ Virtio, VirtioDevice, QUEUE_SIZE_MAX };
+
+const VENDOR_ID: u16 = 0x1af4;
+
+/// Modern devices use 0x1040 + the virtio device id
+const DEVICE_ID_BASE: u16 = 0x1040;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+const BAR_SIZE: u64 = 0x4000;
+
+const COMMON_OFFSET: u64 = 0x0000;
+const ISR_OFFSET: u64 = 0x1000;
+const DEVICE_OFFSET: u64 = 0x2000;
+const NOTIFY_OFFSET: u64 = 0x3000;
+const REGION_SIZE: u64 = 0x1000;
+
+const NOTIFY_OFF_MULTIPLIER: u64 = 4;
+
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const CONFIG_MSIX_VECTOR: u64 = 0x10;
+const NUM_QUEUES: u64 = 0x12;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+/// MSI-X isn't supported so the vectors always read as no vector
+const NO_VECTOR: u64 = 0xffff;
+
+/// Mask of the bits an access of ´width´ covers
+fn width_mask(width: TypeWidth) -> u64 {
+    match width {
+        TypeWidth::DoubleWord => u64::MAX,
+        _ => (1u64 << (width.size() * 8)) - 1,
+    }
+}
+
+/// Replace ´width´ bytes of ´value´ starting at byte ´shift´
+fn set_bytes(value: u64, shift: u64, new: u64, width: TypeWidth) -> u64 {
+    let mask = width_mask(width) << (shift * 8);
+
+    (value & !mask) | ((new << (shift * 8)) & mask)
+}
+
+pub struct VirtioPci<D> {
+    virtio: Virtio<D>,
+}
+
+impl<D: VirtioDevice> VirtioPci<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            virtio: Virtio::new(device),
+        }
+    }
+
+    fn read_common(&mut self, offset: u64) -> u64 {
+        let virtio = &mut self.virtio;
+        match offset {
+            DEVICE_FEATURE_SELECT => virtio.device_features_select as u64,
+            DEVICE_FEATURE => virtio.device_features_page(),
+            DRIVER_FEATURE_SELECT => virtio.driver_features_select as u64,
+            DRIVER_FEATURE => {
+                if virtio.driver_features_select == 0 {
+                    virtio.driver_features & 0xffffffff
+                } else {
+                    virtio.driver_features >> 32
+                }
+            }
+            CONFIG_MSIX_VECTOR => NO_VECTOR,
+            NUM_QUEUES => virtio.queues.len() as u64,
+            DEVICE_STATUS => virtio.status as u64,
+            CONFIG_GENERATION => virtio.config_generation as u64,
+            QUEUE_SELECT => virtio.queue_select as u64,
+            QUEUE_NOTIFY_OFF => virtio.queue_select as u64,
+
+            _ => {
+                let Some(queue) = virtio.selected_queue() else {
+                    return 0;
+                };
+
+                match offset {
+                    QUEUE_SIZE => queue.size as u64,
+                    QUEUE_MSIX_VECTOR => NO_VECTOR,
+                    QUEUE_ENABLE => queue.ready as u64,
+                    QUEUE_DESC..=0x27 =>
+                        queue.desc >> ((offset - QUEUE_DESC) * 8),
+                    QUEUE_DRIVER..=0x2f =>
+                        queue.driver >> ((offset - QUEUE_DRIVER) * 8),
+                    QUEUE_DEVICE..=0x37 =>
+                        queue.device >> ((offset - QUEUE_DEVICE) * 8),
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn write_common(&mut self, offset: u64, value: u64, width: TypeWidth) {
+        let virtio = &mut self.virtio;
+        match offset {
+            DEVICE_FEATURE_SELECT => virtio.device_features_select = value as u32,
+            DRIVER_FEATURE_SELECT => virtio.driver_features_select = value as u32,
+            DRIVER_FEATURE => virtio.set_driver_features_page(value),
+            DEVICE_STATUS => virtio.set_status(value as u32 & 0xff),
+            QUEUE_SELECT => virtio.queue_select = value as u32 & 0xffff,
+
+            _ => {
+                let Some(queue) = virtio.selected_queue() else {
+                    return;
+                };
+
+                match offset {
+                    QUEUE_SIZE => {
+                        queue.size = (value as u16).min(QUEUE_SIZE_MAX);
+                    }
+                    QUEUE_ENABLE => queue.ready = value & 1 != 0,
+                    QUEUE_DESC..=0x27 => {
+                        let shift = offset - QUEUE_DESC;
+                        queue.desc = set_bytes(queue.desc, shift, value, width);
+                    }
+                    QUEUE_DRIVER..=0x2f => {
+                        let shift = offset - QUEUE_DRIVER;
+                        queue.driver = set_bytes(queue.driver, shift, value, width);
+                    }
+                    QUEUE_DEVICE..=0x37 => {
+                        let shift = offset - QUEUE_DEVICE;
+                        queue.device = set_bytes(queue.device, shift, value, width);
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Body of a virtio PCI capability pointing at ´length´ bytes at ´offset´
+/// in BAR 0
+fn virtio_capability(cfg_type: u8, offset: u64, length: u64,
+                     extra: Option<u32>) -> Vec<u8>
+{
+    // NOTE(patrik): The length includes the id and next pointer
+    let cap_len = if extra.is_some() { 20 } else { 16 };
+
+    let mut body = vec![cap_len, cfg_type, 0, 0, 0, 0];
+    body.extend_from_slice(&(offset as u32).to_le_bytes());
+    body.extend_from_slice(&(length as u32).to_le_bytes());
+    if let Some(extra) = extra {
+        body.extend_from_slice(&extra.to_le_bytes());
+    }
+
+    body
+}
+
+impl<D: VirtioDevice> PciDevice for VirtioPci<D> {
+    fn config(&self) -> PciConfig {
+        let id = self.virtio.device.device_id();
+
+        let class = match id {
+            // Mass storage, other
+            2 => 0x018000,
+            _ => 0xff0000,
+        };
+
+        let mut config = PciConfig::new(VENDOR_ID, DEVICE_ID_BASE + id as u16,
+                                        class, 1);
+        config.set_subsystem(VENDOR_ID, 0x1100);
+        config.set_interrupt_pin(1);
+
+        config.add_capability(CAP_ID_VENDOR, &virtio_capability(
+            CFG_TYPE_COMMON, COMMON_OFFSET, 0x38, None));
+        config.add_capability(CAP_ID_VENDOR, &virtio_capability(
+            CFG_TYPE_ISR, ISR_OFFSET, 1, None));
+        config.add_capability(CAP_ID_VENDOR, &virtio_capability(
+            CFG_TYPE_DEVICE, DEVICE_OFFSET, REGION_SIZE, None));
+        config.add_capability(CAP_ID_VENDOR, &virtio_capability(
+            CFG_TYPE_NOTIFY, NOTIFY_OFFSET, REGION_SIZE,
+            Some(NOTIFY_OFF_MULTIPLIER as u32)));
+        config.add_msi_capability();
+
+        config
+    }
+
+    fn bars(&self) -> [Option<Bar>; BAR_COUNT] {
+        let mut bars = [None; BAR_COUNT];
+        bars[0] = Some(Bar { kind: BarKind::Memory, size: BAR_SIZE });
+        bars
+    }
+
+    fn bar_read(&mut self, _bar: usize, offset: u64, width: TypeWidth) -> u64 {
+        let region = offset & !(REGION_SIZE - 1);
+        let offset = offset & (REGION_SIZE - 1);
+
+        match region {
+            // NOTE(patrik): The queue addresses are read a part at a time,
+            // the bytes above the access have to be dropped
+            COMMON_OFFSET => self.read_common(offset) & width_mask(width),
+            ISR_OFFSET => {
+                // NOTE(patrik): Reading the ISR acknowledges the interrupt
+                let status = self.virtio.interrupt_status;
+                self.virtio.interrupt_status = 0;
+                status as u64
+            }
+            DEVICE_OFFSET => self.virtio.device.read_config(offset, width),
+            _ => 0,
+        }
+    }
+
+    fn bar_write(&mut self, _bar: usize, offset: u64, value: u64,
+                 width: TypeWidth)
+    {
+        let region = offset & !(REGION_SIZE - 1);
+        let offset = offset & (REGION_SIZE - 1);
+
+        match region {
+            COMMON_OFFSET => self.write_common(offset, value, width),
+            DEVICE_OFFSET =>
+                self.virtio.device.write_config(offset, value, width),
+            NOTIFY_OFFSET =>
+                self.virtio.notify((offset / NOTIFY_OFF_MULTIPLIER) as usize),
+            _ => {}
+        }
+    }
+
+    fn irq(&mut self) -> bool {
+        self.virtio.interrupt_status != 0
+    }
+
+    fn dma(&mut self, memory: &mut dyn Dma) {
+        self.virtio.process(memory);
+    }
+
+    fn shutdown(&mut self) {
+        self.virtio.device.shutdown();
+    }
+}
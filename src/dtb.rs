@@ -0,0 +1,559 @@
+//! Flattened device tree (DTB) generator
+//!
+//! The tree is built from a ´MachineDescription´ filled in from what is
+//! actually attached to the bus, so there are no .dts files to keep in
+//! sync with the emulator. The layout of the nodes follows the QEMU virt
+//! machine so the same kernels and drivers work.
+
+use std::collections::HashMap;
+
+use crate::devices::{ FramebufferConfig, PFLASH_SIZE };
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// Size of the header, all the fields are 32-bit
+const HEADER_SIZE: usize = 40;
+
+/// Builder for the structure and strings blocks of a DTB
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+
+    /// Offsets of the property names already in the strings block
+    string_offsets: HashMap<String, u32>,
+
+    reserved: Vec<(u64, u64)>,
+
+    depth: usize,
+    next_phandle: u32,
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+
+            string_offsets: HashMap::new(),
+
+            reserved: Vec::new(),
+
+            depth: 0,
+            next_phandle: 1,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Pad the structure block to the next 4 byte boundary
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+
+        offset
+    }
+
+    /// Allocate a new phandle, the caller adds the ´phandle´ property
+    pub fn alloc_phandle(&mut self) -> u32 {
+        let phandle = self.next_phandle;
+        self.next_phandle += 1;
+        phandle
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "Unbalanced device tree nodes");
+
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, data: &[u8]) {
+        let name_offset = self.string_offset(name);
+
+        self.push_u32(FDT_PROP);
+        self.push_u32(data.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(data);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let data: Vec<u8> = cells.iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect();
+        self.property(name, &data);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut data = Vec::new();
+        for value in values {
+            data.extend_from_slice(value.as_bytes());
+            data.push(0);
+        }
+
+        self.property(name, &data);
+    }
+
+    /// ´reg´ property for a region with 2 address and 2 size cells
+    pub fn property_reg(&mut self, regions: &[(u64, u64)]) {
+        let cells: Vec<u32> = regions.iter()
+            .flat_map(|(base, size)| split_u64(*base)
+                      .into_iter()
+                      .chain(split_u64(*size)))
+            .collect();
+        self.property_cells("reg", &cells);
+    }
+
+    /// Add a entry to the memory reservation block
+    pub fn add_reserved(&mut self, addr: u64, size: u64) {
+        self.reserved.push((addr, size));
+    }
+
+    /// Build the final blob
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert!(self.depth == 0, "Unbalanced device tree nodes");
+        self.push_u32(FDT_END);
+
+        let reserved_offset = HEADER_SIZE;
+        let reserved_size = (self.reserved.len() + 1) * 16;
+        let structure_offset = reserved_offset + reserved_size;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            reserved_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+
+        // NOTE(patrik): The reservation block ends with a zero entry
+        for (addr, size) in self.reserved.iter().chain(&[(0, 0)]) {
+            blob.extend_from_slice(&addr.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+}
+
+/// Split ´value´ into two cells, high cell first
+fn split_u64(value: u64) -> [u32; 2] {
+    [(value >> 32) as u32, value as u32]
+}
+
+/// Device on the bus with an interrupt line
+#[derive(Copy, Clone, Debug)]
+pub struct IrqDevice {
+    pub base: u64,
+    pub irq: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct PcieDescription {
+    pub ecam_base: u64,
+    pub ecam_size: u64,
+    pub mmio_base: u64,
+    pub mmio_size: u64,
+    pub pio_base: u64,
+    pub pio_size: u64,
+
+    /// PLIC source of INTx line 0 of the bridge, lines 1 to 3 follow
+    pub irq: u32,
+
+    /// MSI doorbell, a write of N to it raises PLIC source N
+    pub msi_base: u64,
+    /// First PLIC source for MSIs and the number of them
+    pub msi_irq: u32,
+    pub msi_count: u32,
+}
+
+/// Everything the device tree describes about the machine
+#[derive(Clone, Debug, Default)]
+pub struct MachineDescription {
+    /// Value of ´compatible´ of the root node
+    pub compatible: String,
+
+    pub memory_base: u64,
+    pub memory_size: u64,
+
+    pub harts: usize,
+    pub isa: String,
+    /// Value for ´mmu-type´, no MMU when None
+    pub mmu_type: Option<String>,
+    pub timebase_frequency: u64,
+
+    /// SiFive test device used to power off and reboot
+    pub test_finisher: Option<u64>,
+    pub clint: Option<u64>,
+    /// Base and number of interrupt sources
+    pub plic: Option<(u64, u32)>,
+    /// 16550 compatible UART
+    pub uart: Option<IrqDevice>,
+    pub virtio_mmio: Vec<IrqDevice>,
+    pub rtc: Option<IrqDevice>,
+    pub pflash: Vec<u64>,
+    pub framebuffer: Option<(u64, FramebufferConfig)>,
+    pub pcie: Option<PcieDescription>,
+    pub htif: bool,
+
+    pub bootargs: String,
+    /// Start and end of the initramfs in memory
+    pub initrd: Option<(u64, u64)>,
+}
+
+const TEST_FINISHER_SIZE: u64 = 0x1000;
+const CLINT_SIZE: u64 = 0x10000;
+const PLIC_SIZE: u64 = 0x600000;
+const UART_SIZE: u64 = 0x100;
+const UART_CLOCK: u32 = 3686400;
+const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+const RTC_SIZE: u64 = 0x1000;
+const MSI_DOORBELL_SIZE: u64 = 0x1000;
+
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_S_SOFT: u32 = 1;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_TIMER: u32 = 5;
+const IRQ_M_EXT: u32 = 11;
+const IRQ_S_EXT: u32 = 9;
+
+/// Generate the DTB for ´machine´
+pub fn generate(machine: &MachineDescription) -> Vec<u8> {
+    let mut fdt = FdtBuilder::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", &machine.compatible);
+    fdt.property_string("model", "kira");
+
+    // NOTE(patrik): The interrupt controllers of the harts are referenced
+    // by the CLINT and PLIC so the cpus are added first
+    let intcs = add_cpus(&mut fdt, machine);
+
+    fdt.begin_node(&format!("memory@{:x}", machine.memory_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_reg(&[(machine.memory_base, machine.memory_size)]);
+    fdt.end_node();
+
+    if machine.htif {
+        fdt.begin_node("htif");
+        fdt.property_string("compatible", "ucb,htif0");
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    if let Some(base) = machine.test_finisher {
+        add_test_finisher(&mut fdt, base);
+    }
+
+    if let Some(base) = machine.clint {
+        let cells: Vec<u32> = intcs.iter()
+            .flat_map(|intc| [*intc, IRQ_M_SOFT, *intc, IRQ_M_TIMER])
+            .collect();
+
+        fdt.begin_node(&format!("clint@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg(&[(base, CLINT_SIZE)]);
+        fdt.property_cells("interrupts-extended", &cells);
+        fdt.end_node();
+    }
+
+    let plic = machine.plic.map(|(base, sources)| {
+        let phandle = fdt.alloc_phandle();
+        let cells: Vec<u32> = intcs.iter()
+            .flat_map(|intc| [*intc, IRQ_M_EXT, *intc, IRQ_S_EXT])
+            .collect();
+
+        fdt.begin_node(&format!("plic@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(&[(base, PLIC_SIZE)]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("riscv,ndev", sources);
+        fdt.property_cells("interrupts-extended", &cells);
+        fdt.property_u32("phandle", phandle);
+        fdt.end_node();
+
+        phandle
+    });
+
+    // Interrupt properties are only added when there is a PLIC to route
+    // the interrupts through
+    let interrupts = |fdt: &mut FdtBuilder, irq: u32| {
+        if let Some(plic) = plic {
+            fdt.property_u32("interrupt-parent", plic);
+            fdt.property_u32("interrupts", irq);
+        }
+    };
+
+    if let Some(uart) = machine.uart {
+        fdt.begin_node(&format!("serial@{:x}", uart.base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(&[(uart.base, UART_SIZE)]);
+        fdt.property_u32("clock-frequency", UART_CLOCK);
+        interrupts(&mut fdt, uart.irq);
+        fdt.end_node();
+    }
+
+    for virtio in machine.virtio_mmio.iter() {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", virtio.base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(&[(virtio.base, VIRTIO_MMIO_SIZE)]);
+        interrupts(&mut fdt, virtio.irq);
+        fdt.end_node();
+    }
+
+    if let Some(rtc) = machine.rtc {
+        fdt.begin_node(&format!("rtc@{:x}", rtc.base));
+        fdt.property_string("compatible", "google,goldfish-rtc");
+        fdt.property_reg(&[(rtc.base, RTC_SIZE)]);
+        interrupts(&mut fdt, rtc.irq);
+        fdt.end_node();
+    }
+
+    if let Some(first) = machine.pflash.first() {
+        let banks: Vec<(u64, u64)> = machine.pflash.iter()
+            .map(|base| (*base, PFLASH_SIZE))
+            .collect();
+
+        fdt.begin_node(&format!("flash@{:x}", first));
+        fdt.property_string("compatible", "cfi-flash");
+        fdt.property_reg(&banks);
+        fdt.property_u32("bank-width", 4);
+        fdt.end_node();
+    }
+
+    if let Some((base, config)) = &machine.framebuffer {
+        fdt.begin_node(&format!("framebuffer@{:x}", base));
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_reg(&[(*base, config.pixels_size())]);
+        fdt.property_u32("width", config.width as u32);
+        fdt.property_u32("height", config.height as u32);
+        fdt.property_u32("stride", config.stride() as u32);
+        fdt.property_string("format", config.format.name());
+        fdt.end_node();
+    }
+
+    if let Some(pcie) = &machine.pcie {
+        add_pcie(&mut fdt, pcie, plic);
+    }
+
+    fdt.end_node();
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", &machine.bootargs);
+    if let Some(uart) = machine.uart {
+        fdt.property_string("stdout-path",
+                            &format!("/soc/serial@{:x}", uart.base));
+    }
+    if let Some((start, end)) = machine.initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+
+    fdt.finish(0)
+}
+
+/// Add the cpus node and return the phandles of the interrupt controllers
+/// of the harts
+fn add_cpus(fdt: &mut FdtBuilder, machine: &MachineDescription) -> Vec<u32> {
+    let mut intcs = Vec::new();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", machine.timebase_frequency as u32);
+
+    for hart in 0..machine.harts {
+        let cpu = fdt.alloc_phandle();
+        let intc = fdt.alloc_phandle();
+
+        fdt.begin_node(&format!("cpu@{}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &machine.isa);
+        if let Some(mmu_type) = &machine.mmu_type {
+            fdt.property_string("mmu-type", mmu_type);
+        }
+        fdt.property_u32("phandle", cpu);
+
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc);
+        fdt.end_node();
+
+        fdt.end_node();
+
+        intcs.push(intc);
+    }
+
+    fdt.end_node();
+
+    intcs
+}
+
+/// Add the test device and the power off and reboot nodes that use it, the
+/// same nodes QEMU adds
+fn add_test_finisher(fdt: &mut FdtBuilder, base: u64) {
+    const FINISHER_PASS: u32 = 0x5555;
+    const FINISHER_RESET: u32 = 0x7777;
+
+    let phandle = fdt.alloc_phandle();
+
+    fdt.begin_node(&format!("test@{:x}", base));
+    fdt.property_strings("compatible",
+                         &["sifive,test1", "sifive,test0", "syscon"]);
+    fdt.property_reg(&[(base, TEST_FINISHER_SIZE)]);
+    fdt.property_u32("phandle", phandle);
+    fdt.end_node();
+
+    for (name, compatible, value) in [
+        ("poweroff", "syscon-poweroff", FINISHER_PASS),
+        ("reboot", "syscon-reboot", FINISHER_RESET),
+    ] {
+        fdt.begin_node(name);
+        fdt.property_string("compatible", compatible);
+        fdt.property_u32("regmap", phandle);
+        fdt.property_u32("offset", 0);
+        fdt.property_u32("value", value);
+        fdt.end_node();
+    }
+}
+
+fn add_pcie(fdt: &mut FdtBuilder, pcie: &PcieDescription, plic: Option<u32>) {
+    const PCI_SPACE_IO: u32 = 0x01000000;
+    const PCI_SPACE_MEMORY: u32 = 0x02000000;
+
+    // NOTE(patrik): The doorbell needs the PLIC to deliver the interrupts
+    let msi = plic.map(|plic| {
+        let phandle = fdt.alloc_phandle();
+
+        fdt.begin_node(&format!("msi@{:x}", pcie.msi_base));
+        fdt.property_string("compatible", "kira,msi-doorbell");
+        fdt.property_reg(&[(pcie.msi_base, MSI_DOORBELL_SIZE)]);
+        fdt.property_empty("msi-controller");
+        fdt.property_u32("interrupt-parent", plic);
+        fdt.property_cells("kira,msi-sources",
+                           &[pcie.msi_irq, pcie.msi_count]);
+        fdt.property_u32("phandle", phandle);
+        fdt.end_node();
+
+        phandle
+    });
+
+    fdt.begin_node(&format!("pci@{:x}", pcie.ecam_base));
+    fdt.property_string("compatible", "pci-host-ecam-generic");
+    fdt.property_string("device_type", "pci");
+    fdt.property_u32("#address-cells", 3);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_cells("bus-range", &[0, 0]);
+    fdt.property_empty("dma-coherent");
+    fdt.property_reg(&[(pcie.ecam_base, pcie.ecam_size)]);
+    if let Some(msi) = msi {
+        fdt.property_u32("msi-parent", msi);
+    }
+
+    // NOTE(patrik): The I/O space starts at PCI address 0 and the memory
+    // window is identity mapped
+    let [pio_hi, pio_lo] = split_u64(pcie.pio_base);
+    let [pio_size_hi, pio_size_lo] = split_u64(pcie.pio_size);
+    let [mmio_hi, mmio_lo] = split_u64(pcie.mmio_base);
+    let [mmio_size_hi, mmio_size_lo] = split_u64(pcie.mmio_size);
+    fdt.property_cells("ranges", &[
+        PCI_SPACE_IO, 0, 0, pio_hi, pio_lo, pio_size_hi, pio_size_lo,
+        PCI_SPACE_MEMORY, mmio_hi, mmio_lo, mmio_hi, mmio_lo,
+        mmio_size_hi, mmio_size_lo,
+    ]);
+
+    // The INTx lines are swizzled over four PLIC sources by device number
+    if let Some(plic) = plic {
+        let mut map = Vec::new();
+        for slot in 0..4u32 {
+            for pin in 1..=4u32 {
+                let irq = pcie.irq + (slot + pin - 1) % 4;
+                map.extend_from_slice(&[slot << 11, 0, 0, pin, plic, irq]);
+            }
+        }
+
+        fdt.property_cells("interrupt-map-mask", &[0x1800, 0, 0, 7]);
+        fdt.property_cells("interrupt-map", &map);
+    }
+
+    fdt.end_node();
+}
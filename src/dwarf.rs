@@ -0,0 +1,666 @@
+//! Module to parse the DWARF line tables in ´.debug_line´
+//!
+//! The line number program of every unit is run and the rows it produces
+//! are kept sorted by address, so a pc can be mapped to the file and line
+//! it was compiled from. DWARF 2 to 5 are supported, in the 32-bit and
+//! 64-bit formats.
+
+use crate::elf::{ Elf, Data, read_uleb128, read_string };
+
+#[derive(Debug)]
+pub enum DwarfError {
+    /// A unit or a value inside it goes past the end of the section
+    Truncated,
+
+    /// The version of the line table is not 2 to 5
+    UnsupportedVersion(u16),
+
+    /// The line range of the header is zero, special opcodes would divide
+    /// by it
+    InvalidLineRange,
+
+    /// The size of an address is not 1, 2, 4 or 8 bytes
+    InvalidAddressSize(u8),
+
+    /// A form in the DWARF 5 directory or file name formats that is not
+    /// allowed there
+    UnsupportedForm(u64),
+
+    /// A string offset is outside ´.debug_str´ or ´.debug_line_str´
+    InvalidStringOffset(u64),
+
+    /// A DWARF 5 directory or file name table has entries but no format
+    /// describing them
+    MissingEntryFormat,
+}
+
+pub type Result<T> = std::result::Result<T, DwarfError>;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_SET_BASIC_BLOCK: u8 = 7;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK2: u64 = 0x03;
+const DW_FORM_BLOCK4: u64 = 0x04;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// Reads the values of a section in the byte order of the file
+#[derive(Copy, Clone)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], big_endian: bool) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+            big_endian,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.offset.checked_add(len).ok_or(DwarfError::Truncated)?;
+        let bytes = self.bytes.get(self.offset..end)
+            .ok_or(DwarfError::Truncated)?;
+        self.offset = end;
+
+        Ok(bytes)
+    }
+
+    /// Read an unsigned value of ´size´ bytes
+    fn uint(&mut self, size: usize) -> Result<u64> {
+        let bytes = self.bytes(size)?;
+        let fold = |value: u64, byte: &u8| (value << 8) | *byte as u64;
+
+        Ok(if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn uleb128(&mut self) -> Result<u64> {
+        read_uleb128(self.bytes, &mut self.offset).ok_or(DwarfError::Truncated)
+    }
+
+    fn sleb128(&mut self) -> Result<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                // NOTE(patrik): Extend the sign bit of the last byte
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str> {
+        read_string(self.bytes, &mut self.offset).ok_or(DwarfError::Truncated)
+    }
+}
+
+/// Get the string at ´offset´ of a string section
+fn section_string(section: &[u8], offset: u64) -> Result<&str> {
+    let mut offset: usize = offset.try_into()
+        .map_err(|_| DwarfError::InvalidStringOffset(offset))?;
+    let start = offset;
+
+    read_string(section, &mut offset)
+        .ok_or(DwarfError::InvalidStringOffset(start as u64))
+}
+
+/// One row of the line table
+#[derive(Copy, Clone, Debug)]
+struct Row {
+    address: u64,
+
+    /// Index into ´LineTable::files´
+    file: usize,
+
+    line: u64,
+    column: u64,
+
+    /// The first address after a sequence, not part of the program
+    end_sequence: bool,
+}
+
+/// Where an instruction came from in the source
+#[derive(Copy, Clone, Debug)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: u64,
+
+    /// Zero if the compiler didn't record the column
+    pub column: u64,
+}
+
+impl std::fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.column == 0 {
+            return write!(f, "{}:{}", self.file, self.line);
+        }
+
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Line number information of all units in the file
+#[derive(Debug)]
+pub struct LineTable {
+    files: Vec<String>,
+
+    /// Sorted by address, the end of a sequence before a row starting at
+    /// the same address
+    rows: Vec<Row>,
+}
+
+/// Strings of the sections a line table refers to
+struct Strings<'a> {
+    /// ´.debug_str´, used by ´DW_FORM_strp´
+    debug_str: &'a [u8],
+
+    /// ´.debug_line_str´, used by ´DW_FORM_line_strp´
+    debug_line_str: &'a [u8],
+}
+
+/// Header of a line number program
+struct UnitHeader {
+    version: u16,
+    offset_size: usize,
+    address_size: Option<u8>,
+    minimum_instruction_length: u8,
+    default_is_stmt: bool,
+    line_base: i8,
+    line_range: u8,
+    opcode_base: u8,
+    standard_opcode_lengths: Vec<u8>,
+}
+
+/// State of the line number state machine
+struct State {
+    address: u64,
+    file: u64,
+    line: u64,
+    column: u64,
+    is_stmt: bool,
+}
+
+impl State {
+    fn new(header: &UnitHeader) -> Self {
+        Self {
+            address: 0,
+            file: 1,
+            line: 1,
+            column: 0,
+            is_stmt: header.default_is_stmt,
+        }
+    }
+}
+
+impl LineTable {
+    /// Parse ´.debug_line´ of ´elf´, a file without it gets an empty table
+    pub fn parse(elf: &Elf) -> Result<Self> {
+        let mut table = Self {
+            files: Vec::new(),
+            rows: Vec::new(),
+        };
+
+        let Some(debug_line) = elf.section_by_name(".debug_line") else {
+            return Ok(table);
+        };
+
+        let strings = Strings {
+            debug_str: elf.section_by_name(".debug_str").unwrap_or(&[]),
+            debug_line_str: elf.section_by_name(".debug_line_str")
+                .unwrap_or(&[]),
+        };
+
+        let big_endian = elf.data() == Data::BigEndian;
+        let mut reader = Reader::new(debug_line, big_endian);
+        while !reader.is_empty() {
+            table.parse_unit(&mut reader, &strings)?;
+        }
+
+        table.rows.sort_by_key(|row| (row.address, !row.end_sequence));
+
+        Ok(table)
+    }
+
+    /// Parse the unit at the reader and run its line number program
+    fn parse_unit<'a>(&mut self, reader: &mut Reader<'a>,
+                      strings: &Strings<'a>)
+        -> Result<()>
+    {
+        // NOTE(patrik): A length of 0xffffffff marks the 64-bit format
+        let mut offset_size = 4;
+        let mut unit_length = reader.u32()? as u64;
+        if unit_length == 0xffffffff {
+            offset_size = 8;
+            unit_length = reader.uint(8)?;
+        }
+
+        let unit_length: usize = unit_length.try_into()
+            .map_err(|_| DwarfError::Truncated)?;
+        let mut unit = Reader::new(reader.bytes(unit_length)?,
+                                   reader.big_endian);
+
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(DwarfError::UnsupportedVersion(version));
+        }
+
+        let mut address_size = None;
+        if version >= 5 {
+            address_size = Some(unit.u8()?);
+            let _segment_selector_size = unit.u8()?;
+        }
+
+        let header_length: usize = unit.uint(offset_size)?.try_into()
+            .map_err(|_| DwarfError::Truncated)?;
+        let program_start = unit.offset.checked_add(header_length)
+            .ok_or(DwarfError::Truncated)?;
+
+        let minimum_instruction_length = unit.u8()?;
+        if version >= 4 {
+            // TODO(patrik): VLIW is not supported, the operation index is
+            // ignored
+            let _maximum_operations_per_instruction = unit.u8()?;
+        }
+
+        let default_is_stmt = unit.u8()? != 0;
+        let line_base = unit.u8()? as i8;
+        let line_range = unit.u8()?;
+        if line_range == 0 {
+            return Err(DwarfError::InvalidLineRange);
+        }
+
+        let opcode_base = unit.u8()?;
+        let standard_opcode_lengths = unit
+            .bytes(opcode_base.saturating_sub(1) as usize)?
+            .to_vec();
+
+        let header = UnitHeader {
+            version,
+            offset_size,
+            address_size,
+            minimum_instruction_length,
+            default_is_stmt,
+            line_base,
+            line_range,
+            opcode_base,
+            standard_opcode_lengths,
+        };
+
+        // NOTE(patrik): The files of the unit are added to the end of
+        // ´files´, the file register of the program is relative to it
+        let first_file = self.files.len();
+        if version >= 5 {
+            self.parse_entries_v5(&mut unit, &header, strings)?;
+        } else {
+            self.parse_entries(&mut unit)?;
+        }
+
+        if program_start > unit.bytes.len() {
+            return Err(DwarfError::Truncated);
+        }
+        unit.offset = program_start;
+
+        self.run_program(&mut unit, &header, first_file)
+    }
+
+    /// Parse the include directories and file names of DWARF 2 to 4
+    fn parse_entries(&mut self, unit: &mut Reader) -> Result<()> {
+        // NOTE(patrik): Directory 0 is the directory of the compilation,
+        // which is only in ´.debug_info´, the names are left relative to it
+        let mut directories = vec![""];
+        loop {
+            let directory = unit.string()?;
+            if directory.is_empty() {
+                break;
+            }
+
+            directories.push(directory);
+        }
+
+        // NOTE(patrik): File 0 is not used before DWARF 5
+        self.files.push(String::new());
+        loop {
+            let name = unit.string()?;
+            if name.is_empty() {
+                break;
+            }
+
+            let directory = unit.uleb128()?;
+            let _modification_time = unit.uleb128()?;
+            let _length = unit.uleb128()?;
+
+            let directory = directories.get(directory as usize)
+                .copied()
+                .unwrap_or("");
+            self.files.push(join_path(directory, name));
+        }
+
+        Ok(())
+    }
+
+    /// Parse the directory and file name tables of DWARF 5, they are
+    /// described by a list of content types and forms
+    fn parse_entries_v5<'a>(&mut self, unit: &mut Reader<'a>,
+                            header: &UnitHeader, strings: &Strings<'a>)
+        -> Result<()>
+    {
+        let directories = Self::parse_entry_table(unit, header, strings)?;
+        let directories: Vec<&str> = directories.iter()
+            .map(|(path, _)| *path)
+            .collect();
+
+        let files = Self::parse_entry_table(unit, header, strings)?;
+        for (name, directory) in files {
+            let directory = directories.get(directory as usize)
+                .copied()
+                .unwrap_or("");
+            self.files.push(join_path(directory, name));
+        }
+
+        Ok(())
+    }
+
+    /// Parse one DWARF 5 entry table, returns the path and the directory
+    /// index of every entry
+    fn parse_entry_table<'a>(unit: &mut Reader<'a>, header: &UnitHeader,
+                             strings: &Strings<'a>)
+        -> Result<Vec<(&'a str, u64)>>
+    {
+        let format_count = unit.u8()?;
+        let mut formats = Vec::with_capacity(format_count as usize);
+        for _ in 0..format_count {
+            formats.push((unit.uleb128()?, unit.uleb128()?));
+        }
+
+        // NOTE(patrik): Without a format the entries take no space, a
+        // large count would never end
+        let count = unit.uleb128()?;
+        if formats.is_empty() && count > 0 {
+            return Err(DwarfError::MissingEntryFormat);
+        }
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let mut path = "";
+            let mut directory = 0;
+            for &(content, form) in &formats {
+                let value = read_form(unit, header, strings, form)?;
+                match (content, value) {
+                    (DW_LNCT_PATH, FormValue::String(s)) => path = s,
+                    (DW_LNCT_DIRECTORY_INDEX, FormValue::Unsigned(n)) => {
+                        directory = n;
+                    }
+
+                    _ => {}
+                }
+            }
+
+            entries.push((path, directory));
+        }
+
+        Ok(entries)
+    }
+
+    /// Run the line number program of a unit and add its rows
+    fn run_program(&mut self, unit: &mut Reader, header: &UnitHeader,
+                   first_file: usize)
+        -> Result<()>
+    {
+        let min_length = header.minimum_instruction_length as u64;
+        let mut state = State::new(header);
+
+        while !unit.is_empty() {
+            let opcode = unit.u8()?;
+            if opcode >= header.opcode_base && opcode != 0 {
+                // NOTE(patrik): Special opcodes advance both the address
+                // and the line and add a row
+                let adjusted = opcode - header.opcode_base;
+                let advance = (adjusted / header.line_range) as u64;
+                let line = header.line_base as i64 +
+                    (adjusted % header.line_range) as i64;
+
+                state.address = state.address
+                    .wrapping_add(advance * min_length);
+                state.line = state.line.wrapping_add(line as u64);
+                self.push_row(&state, first_file, false);
+                continue;
+            }
+
+            match opcode {
+                0 => {
+                    let length: usize = unit.uleb128()?.try_into()
+                        .map_err(|_| DwarfError::Truncated)?;
+                    let mut extended = Reader::new(unit.bytes(length)?,
+                                                   unit.big_endian);
+                    if length == 0 {
+                        continue;
+                    }
+
+                    match extended.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.push_row(&state, first_file, true);
+                            state = State::new(header);
+                        }
+
+                        DW_LNE_SET_ADDRESS => {
+                            let size = length - 1;
+                            if header.address_size
+                                .is_some_and(|n| n as usize != size) ||
+                                !matches!(size, 1 | 2 | 4 | 8)
+                            {
+                                return Err(DwarfError::InvalidAddressSize(
+                                        size as u8));
+                            }
+
+                            state.address = extended.uint(size)?;
+                        }
+
+                        DW_LNE_DEFINE_FILE if header.version < 5 => {
+                            let name = extended.string()?;
+                            self.files.push(name.to_string());
+                        }
+
+                        // NOTE(patrik): The rest, like the discriminator,
+                        // doesn't change the location
+                        _ => {}
+                    }
+                }
+
+                DW_LNS_COPY => self.push_row(&state, first_file, false),
+                DW_LNS_ADVANCE_PC => {
+                    let advance = unit.uleb128()?;
+                    state.address = state.address
+                        .wrapping_add(advance.wrapping_mul(min_length));
+                }
+
+                DW_LNS_ADVANCE_LINE => {
+                    let advance = unit.sleb128()?;
+                    state.line = state.line.wrapping_add(advance as u64);
+                }
+
+                DW_LNS_SET_FILE => state.file = unit.uleb128()?,
+                DW_LNS_SET_COLUMN => state.column = unit.uleb128()?,
+                DW_LNS_NEGATE_STMT => state.is_stmt = !state.is_stmt,
+                DW_LNS_SET_BASIC_BLOCK => {}
+                DW_LNS_CONST_ADD_PC => {
+                    let advance = ((255 - header.opcode_base) /
+                                   header.line_range) as u64;
+                    state.address = state.address
+                        .wrapping_add(advance * min_length);
+                }
+
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    state.address = state.address
+                        .wrapping_add(unit.u16()? as u64);
+                }
+
+                // NOTE(patrik): Skip the operands of the opcodes that
+                // don't change the location, the header has how many
+                // there are
+                _ => {
+                    let operands = header.standard_opcode_lengths
+                        [opcode as usize - 1];
+                    for _ in 0..operands {
+                        unit.uleb128()?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_row(&mut self, state: &State, first_file: usize,
+                end_sequence: bool)
+    {
+        // NOTE(patrik): A file index outside the unit points at no file
+        let file = (state.file as usize).checked_add(first_file)
+            .filter(|&file| file < self.files.len())
+            .unwrap_or(usize::MAX);
+
+        self.rows.push(Row {
+            address: state.address,
+            file,
+            line: state.line,
+            column: state.column,
+            end_sequence,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Find the source location of the instruction at ´addr´
+    pub fn lookup(&self, addr: u64) -> Option<Location<'_>> {
+        let count = self.rows.partition_point(|row| row.address <= addr);
+        let row = self.rows[..count].last()?;
+        if row.end_sequence {
+            return None;
+        }
+
+        Some(Location {
+            file: self.files.get(row.file).map_or("??", |file| file.as_str()),
+            line: row.line,
+            column: row.column,
+        })
+    }
+}
+
+/// Value of an attribute in a DWARF 5 entry table
+enum FormValue<'a> {
+    String(&'a str),
+    Unsigned(u64),
+    Other,
+}
+
+/// Read a value of ´form´, only the forms the line table may use are
+/// allowed
+fn read_form<'a>(unit: &mut Reader<'a>, header: &UnitHeader,
+                 strings: &Strings<'a>, form: u64)
+    -> Result<FormValue<'a>>
+{
+    Ok(match form {
+        DW_FORM_STRING => FormValue::String(unit.string()?),
+        DW_FORM_STRP => {
+            let offset = unit.uint(header.offset_size)?;
+            FormValue::String(section_string(strings.debug_str, offset)?)
+        }
+
+        DW_FORM_LINE_STRP => {
+            let offset = unit.uint(header.offset_size)?;
+            FormValue::String(section_string(strings.debug_line_str, offset)?)
+        }
+
+        DW_FORM_DATA1 => FormValue::Unsigned(unit.uint(1)?),
+        DW_FORM_DATA2 => FormValue::Unsigned(unit.uint(2)?),
+        DW_FORM_DATA4 => FormValue::Unsigned(unit.uint(4)?),
+        DW_FORM_DATA8 => FormValue::Unsigned(unit.uint(8)?),
+        DW_FORM_UDATA => FormValue::Unsigned(unit.uleb128()?),
+        DW_FORM_DATA16 => {
+            unit.bytes(16)?;
+            FormValue::Other
+        }
+
+        DW_FORM_BLOCK1 | DW_FORM_BLOCK2 | DW_FORM_BLOCK4 | DW_FORM_BLOCK => {
+            let length = match form {
+                DW_FORM_BLOCK1 => unit.uint(1)?,
+                DW_FORM_BLOCK2 => unit.uint(2)?,
+                DW_FORM_BLOCK4 => unit.uint(4)?,
+                _ => unit.uleb128()?,
+            };
+            let length = length.try_into()
+                .map_err(|_| DwarfError::Truncated)?;
+            unit.bytes(length)?;
+            FormValue::Other
+        }
+
+        _ => return Err(DwarfError::UnsupportedForm(form)),
+    })
+}
+
+/// Join a directory and a file name, absolute names are used as they are
+fn join_path(directory: &str, name: &str) -> String {
+    if directory.is_empty() || name.starts_with('/') {
+        return name.to_string();
+    }
+
+    format!("{}/{}", directory.trim_end_matches('/'), name)
+}
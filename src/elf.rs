@@ -3,6 +3,9 @@
 
 #[derive(Debug)]
 pub enum ElfError {
+    /// The file is smaller than the ELF Header
+    TruncatedHeader,
+
     /// Invalid ELF Header magic (0x00-0x03)
     InvalidMagic,
 
@@ -38,17 +41,95 @@ pub enum ElfError {
 
     /// Invalid byte buffer size for ´ProgramHeader::parse´
     InvalidProgramHeaderBufferSize,
+
+    /// Failed to cast the offset of a program header
+    ProgramHeaderOffsetOverflow,
+
+    /// Failed to cast the file size of a program header
+    ProgramHeaderFileSizeOverflow,
+
+    /// Program header entries are smaller than a program header
+    InvalidProgramHeaderEntrySize(usize),
+
+    /// The program header table is outside the file
+    ProgramHeaderTableOutOfBounds,
+
+    /// There is no program header at the index
+    ProgramHeaderOutOfBounds(usize),
+
+    /// The data of a program header is outside the file
+    ProgramHeaderDataOutOfBounds,
+
+    /// Invalid byte buffer size for ´SectionHeader::parse´
+    InvalidSectionHeaderBufferSize,
+
+    /// Failed to cast the offset of a section header
+    SectionHeaderOffsetOverflow,
+
+    /// Section header entries are smaller than a section header
+    InvalidSectionHeaderEntrySize(usize),
+
+    /// The section header table is outside the file
+    SectionHeaderTableOutOfBounds,
+
+    /// The section header string table index is not a section
+    InvalidStringTableIndex(usize),
+
+    /// There is no section header at the index
+    SectionHeaderOutOfBounds(usize),
+
+    /// The data of a section header is outside the file
+    SectionHeaderDataOutOfBounds,
+
+    /// The name of a section is not a valid string in the section header
+    /// string table
+    InvalidSectionName(u32),
+
+    /// The name of a symbol is not a valid string in its string table
+    InvalidSymbolName(u32),
+
+    /// The file is not for RISC-V
+    NotRiscV(Machine),
+
+    /// The file is not an executable
+    NotExecutable(Typ),
+
+    /// The file is for another XLEN than the hart
+    ClassMismatch(Class),
+
+    /// The file is big endian, the hart is little endian
+    UnsupportedByteOrder,
+
+    /// The program needs an extension the hart doesn't implement
+    MissingExtension(char),
+
+    /// ´.riscv.attributes´ doesn't start with the version of the format
+    InvalidAttributesVersion(u8),
+
+    /// ´.riscv.attributes´ ends in the middle of a subsection or an
+    /// attribute
+    TruncatedAttributes,
+
+    /// The address in the dynamic section is not inside any segment
+    InvalidDynamicAddress(u64),
+
+    /// The dynamic section uses ´DT_REL´, RISC-V only has ´DT_RELA´
+    UnsupportedRel,
+
+    /// The path in ´PT_INTERP´ is not a NUL terminated UTF-8 string
+    InvalidInterpreter,
 }
 
 type Result<T> = std::result::Result<T, ElfError>;
 
-#[derive(Debug)]
-enum Class {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Class {
     Elf32,
     Elf64,
 }
 
 impl Class {
+    #[allow(clippy::needless_return)]
     fn parse(value: u8) -> Result<Self> {
         return match value {
             1 => Ok(Class::Elf32),
@@ -58,13 +139,14 @@ impl Class {
     }
 }
 
-#[derive(Debug)]
-enum Data {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Data {
     LittleEndian,
     BigEndian
 }
 
 impl Data {
+    #[allow(clippy::needless_return)]
     fn parse(value: u8) -> Result<Self> {
         return match value {
             1 => Ok(Data::LittleEndian),
@@ -74,6 +156,82 @@ impl Data {
     }
 }
 
+/// How the fields of a file are encoded, the class decides the size of
+/// addresses, offsets and sizes and the data decides the byte order
+#[derive(Copy, Clone, Debug)]
+struct Encoding {
+    class: Class,
+    data: Data,
+}
+
+impl Encoding {
+    /// Pick the offset of a field from the ELF32 and the ELF64 layouts
+    fn offset(&self, elf32: usize, elf64: usize) -> usize {
+        match self.class {
+            Class::Elf32 => elf32,
+            Class::Elf64 => elf64,
+        }
+    }
+
+    fn array<const N: usize>(&self, bytes: &[u8], offset: usize)
+        -> Result<[u8; N]>
+    {
+        bytes.get(offset..offset + N)
+            .unwrap_or(&[])
+            .try_into()
+            .map_err(ElfError::TryFromSliceFailed)
+    }
+
+    fn u16(&self, bytes: &[u8], offset: usize) -> Result<u16> {
+        let array = self.array(bytes, offset)?;
+        match self.data {
+            Data::LittleEndian => Ok(u16::from_le_bytes(array)),
+            Data::BigEndian => Ok(u16::from_be_bytes(array)),
+        }
+    }
+
+    fn u32(&self, bytes: &[u8], offset: usize) -> Result<u32> {
+        let array = self.array(bytes, offset)?;
+        match self.data {
+            Data::LittleEndian => Ok(u32::from_le_bytes(array)),
+            Data::BigEndian => Ok(u32::from_be_bytes(array)),
+        }
+    }
+
+    fn u64(&self, bytes: &[u8], offset: usize) -> Result<u64> {
+        let array = self.array(bytes, offset)?;
+        match self.data {
+            Data::LittleEndian => Ok(u64::from_le_bytes(array)),
+            Data::BigEndian => Ok(u64::from_be_bytes(array)),
+        }
+    }
+
+    /// Read an address, offset or size, they are 32-bit in ELF32 and
+    /// 64-bit in ELF64
+    fn word(&self, bytes: &[u8], offset: usize) -> Result<u64> {
+        match self.class {
+            Class::Elf32 => self.u32(bytes, offset).map(|value| value as u64),
+            Class::Elf64 => self.u64(bytes, offset),
+        }
+    }
+
+    fn header_size(&self) -> usize {
+        self.offset(52, 64)
+    }
+
+    fn program_header_size(&self) -> usize {
+        self.offset(32, 56)
+    }
+
+    fn section_header_size(&self) -> usize {
+        self.offset(40, 64)
+    }
+
+    fn symbol_size(&self) -> usize {
+        self.offset(16, 24)
+    }
+}
+
 
 #[derive(Debug)]
 enum OsAbi {
@@ -83,6 +241,7 @@ enum OsAbi {
 }
 
 impl OsAbi {
+    #[allow(clippy::needless_return)]
     fn parse(value: u8) -> Self {
         return match value {
             0x00 => OsAbi::SystemV,
@@ -91,8 +250,8 @@ impl OsAbi {
     }
 }
 
-#[derive(Debug)]
-enum Typ {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Typ {
     /// Unknown
     None,
 
@@ -118,6 +277,7 @@ enum Typ {
 }
 
 impl Typ {
+    #[allow(clippy::needless_return)]
     fn parse(value: u16) -> Self {
         return match value {
             0x00 => Typ::None,
@@ -132,8 +292,8 @@ impl Typ {
     }
 }
 
-#[derive(Debug)]
-enum Machine {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Machine {
     X86,
     Amd64,
     RiscV,
@@ -142,6 +302,7 @@ enum Machine {
 }
 
 impl Machine {
+    #[allow(clippy::needless_return)]
     fn parse(value: u16) -> Self {
         return match value {
             0x03 => Machine::X86,
@@ -152,6 +313,75 @@ impl Machine {
     }
 }
 
+/// Code uses the compressed instructions
+const EF_RISCV_RVC: u32 = 0x0001;
+
+/// Which floating point registers the calling convention passes values in
+const EF_RISCV_FLOAT_ABI: u32 = 0x0006;
+const EF_RISCV_FLOAT_ABI_SINGLE: u32 = 0x0002;
+const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x0004;
+const EF_RISCV_FLOAT_ABI_QUAD: u32 = 0x0006;
+
+/// Code only uses the registers of the RV32E/RV64E base
+const EF_RISCV_RVE: u32 = 0x0008;
+
+/// Code needs the RVTSO memory model
+const EF_RISCV_TSO: u32 = 0x0010;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FloatAbi {
+    /// Floating point values are passed in integer registers
+    Soft,
+
+    Single,
+    Double,
+    Quad,
+}
+
+/// RISC-V specific ´e_flags´ of the ELF Header
+#[derive(Copy, Clone, Debug)]
+pub struct RiscVFlags {
+    pub rvc: bool,
+    pub float_abi: FloatAbi,
+    pub rve: bool,
+    pub tso: bool,
+}
+
+impl RiscVFlags {
+    fn parse(value: u32) -> Self {
+        let float_abi = match value & EF_RISCV_FLOAT_ABI {
+            EF_RISCV_FLOAT_ABI_SINGLE => FloatAbi::Single,
+            EF_RISCV_FLOAT_ABI_DOUBLE => FloatAbi::Double,
+            EF_RISCV_FLOAT_ABI_QUAD => FloatAbi::Quad,
+            _ => FloatAbi::Soft,
+        };
+
+        Self {
+            rvc: value & EF_RISCV_RVC != 0,
+            float_abi,
+            rve: value & EF_RISCV_RVE != 0,
+            tso: value & EF_RISCV_TSO != 0,
+        }
+    }
+
+    /// Extension the float ABI needs the registers of, None for the soft
+    /// float ABI
+    pub fn float_extension(&self) -> Option<char> {
+        match self.float_abi {
+            FloatAbi::Soft => None,
+            FloatAbi::Single => Some('F'),
+            FloatAbi::Double => Some('D'),
+            FloatAbi::Quad => Some('Q'),
+        }
+    }
+}
+
+/// Check if the extension ´extension´ is set in ´misa´
+pub fn misa_has_extension(misa: u64, extension: char) -> bool {
+    let bit = (extension as u8).wrapping_sub(b'A') as u64;
+    bit < 26 && misa & (1 << bit) != 0
+}
+
 #[derive(Debug)]
 struct Header {
     offset: usize,
@@ -159,6 +389,32 @@ struct Header {
     num_entries: usize,
 }
 
+impl Header {
+    /// Check that the table fits in a file of ´len´ bytes
+    fn is_inside(&self, len: usize) -> bool {
+        let end = self.entry_size.checked_mul(self.num_entries)
+            .and_then(|size| size.checked_add(self.offset));
+
+        end.is_some_and(|end| end <= len)
+    }
+
+    /// Range of the bytes of entry ´index´, None if there is no such entry
+    fn entry(&self, index: usize) -> Option<std::ops::Range<usize>> {
+        if index >= self.num_entries {
+            return None;
+        }
+
+        // NOTE(patrik): The table is checked to be inside the file when
+        // parsing, this can't overflow
+        let start = self.offset + index * self.entry_size;
+        Some(start..start + self.entry_size)
+    }
+}
+
+/// Size of the identification at the start of the ELF Header, it's the
+/// same for every class
+const IDENT_SIZE: usize = 16;
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ProgramHeaderTyp {
     /// Program header table entry unused
@@ -196,6 +452,7 @@ pub enum ProgramHeaderTyp {
 }
 
 impl ProgramHeaderTyp {
+    #[allow(clippy::needless_return)]
     fn parse(value: u32) -> Self {
         return match value {
             0x00000000 => Self::Null,
@@ -232,47 +489,31 @@ pub struct ProgramHeader {
 }
 
 impl ProgramHeader {
-    fn parse(bytes: &[u8]) -> Result<ProgramHeader> {
-        if bytes.len() < 56 {
+    fn parse(bytes: &[u8], encoding: Encoding) -> Result<ProgramHeader> {
+        if bytes.len() < encoding.program_header_size() {
             return Err(ElfError::InvalidProgramHeaderBufferSize);
         }
 
-        let typ = u32::from_le_bytes(
-            bytes[0..4].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        // NOTE(patrik): ELF32 moves the flags after the sizes to keep the
+        // fields aligned
+        let typ = encoding.u32(bytes, 0)?;
         let typ = ProgramHeaderTyp::parse(typ);
 
-        let flags = u32::from_le_bytes(
-            bytes[4..8].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-
-        let offset = u64::from_le_bytes(
-            bytes[8..16].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-        // TODO(patrik): Add a try_into here
-        let offset = offset as usize;
+        let flags = encoding.u32(bytes, encoding.offset(24, 4))?;
 
-        let vaddr = u64::from_le_bytes(
-            bytes[16..24].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let offset = encoding.word(bytes, encoding.offset(4, 8))?;
+        let offset = offset.try_into()
+            .map_err(|_| ElfError::ProgramHeaderOffsetOverflow)?;
 
-        let paddr = u64::from_le_bytes(
-            bytes[24..32].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let vaddr = encoding.word(bytes, encoding.offset(8, 16))?;
+        let paddr = encoding.word(bytes, encoding.offset(12, 24))?;
 
-        let file_size = u64::from_le_bytes(
-            bytes[32..40].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-        // TODO(patrik): Add a try_into here
-        let file_size = file_size as usize;
+        let file_size = encoding.word(bytes, encoding.offset(16, 32))?;
+        let file_size = file_size.try_into()
+            .map_err(|_| ElfError::ProgramHeaderFileSizeOverflow)?;
 
-        let memory_size = u64::from_le_bytes(
-            bytes[40..48].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-
-        let alignment = u64::from_le_bytes(
-            bytes[48..56].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let memory_size = encoding.word(bytes, encoding.offset(20, 40))?;
+        let alignment = encoding.word(bytes, encoding.offset(28, 48))?;
 
         Ok(ProgramHeader {
             typ,
@@ -342,183 +583,1952 @@ impl<'a> Iterator for ProgramHeaderIter<'a> {
     }
 }
 
-pub struct Elf<'a> {
-    bytes: &'a [u8],
-    class: Class,
-    data: Data,
-    os_abi: OsAbi,
-    os_abi_version: u8,
-    typ: Typ,
-    machine: Machine,
-    entry: u64,
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SectionHeaderTyp {
+    /// Section header table entry unused
+    Null,
 
-    program_header: Header,
-    section_header: Header,
+    /// Program data
+    ProgBits,
 
-    string_table_index: usize,
+    /// Symbol table
+    SymTab,
+
+    /// String table
+    StrTab,
+
+    /// Relocation entries with addends
+    Rela,
+
+    /// Symbol hash table
+    Hash,
+
+    /// Dynamic linking information
+    Dynamic,
+
+    /// Notes
+    Note,
+
+    /// Program space with no data (bss)
+    NoBits,
+
+    /// Relocation entries, no addends
+    Rel,
+
+    /// Reserved
+    Shlib,
+
+    /// Dynamic linker symbol table
+    DynSym,
+
+    /// Array of constructors
+    InitArray,
+
+    /// Array of destructors
+    FiniArray,
+
+    /// Array of pre-constructors
+    PreInitArray,
+
+    /// Section group
+    Group,
+
+    /// Extended section indices
+    SymTabShndx,
+
+    /// RISC-V attributes, ´.riscv.attributes´
+    RiscVAttributes,
+
+    /// Reserved inclusive range. Operating system specific
+    OperatingSystem(u32),
+
+    /// Reserved inclusive range. Processor specific
+    Processor(u32),
+
+    /// Unknown
+    Unknown(u32),
 }
 
-impl<'a> std::fmt::Debug for Elf<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) ->
-        std::result::Result<(), std::fmt::Error>
-    {
-        f.debug_struct("Elf")
-            .field("class", &self.class)
-            .field("data", &self.data)
-            .field("os_abi", &self.os_abi)
-            .field("os_abi_version", &self.os_abi_version)
-            .field("typ", &self.typ)
-            .field("machine", &self.machine)
-            .field("entry", &format_args!("{:#x}", self.entry))
-            .field("program_header", &self.program_header)
-            .field("section_header", &self.section_header)
-            .field("string_table_index", &self.string_table_index)
-            .finish()
+impl SectionHeaderTyp {
+    fn parse(value: u32) -> Self {
+        match value {
+            0x00000000 => Self::Null,
+            0x00000001 => Self::ProgBits,
+            0x00000002 => Self::SymTab,
+            0x00000003 => Self::StrTab,
+            0x00000004 => Self::Rela,
+            0x00000005 => Self::Hash,
+            0x00000006 => Self::Dynamic,
+            0x00000007 => Self::Note,
+            0x00000008 => Self::NoBits,
+            0x00000009 => Self::Rel,
+            0x0000000A => Self::Shlib,
+            0x0000000B => Self::DynSym,
+            0x0000000E => Self::InitArray,
+            0x0000000F => Self::FiniArray,
+            0x00000010 => Self::PreInitArray,
+            0x00000011 => Self::Group,
+            0x00000012 => Self::SymTabShndx,
+
+            0x70000003 => Self::RiscVAttributes,
+
+            0x60000000..=0x6FFFFFFF => Self::OperatingSystem(value),
+            0x70000000..=0x7FFFFFFF => Self::Processor(value),
+
+            _ => Self::Unknown(value),
+        }
     }
 }
 
-impl<'a> Elf<'a> {
-    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
-        // TODO(patrik): Add length checks
+#[derive(Copy, Clone, Debug)]
+pub struct SectionHeader {
+    /// Offset of the name in the section header string table
+    name: u32,
 
-        if &bytes[0..4] != b"\x7fELF" {
-            return Err(ElfError::InvalidMagic);
-        }
+    typ: SectionHeaderTyp,
+    flags: u64,
+    addr: u64,
 
-        let class = Class::parse(bytes[4])?;
-        let data = Data::parse(bytes[5])?;
+    offset: usize,
+    size: u64,
 
-        // TODO(patrik): Should this be included inside the ´Elf´ struct
-        let _version = bytes[6];
+    link: u32,
+    info: u32,
 
-        let os_abi = OsAbi::parse(bytes[7]);
-        let os_abi_version = bytes[8];
+    alignment: u64,
+    entry_size: u64,
+}
 
-        // NOTE(patrik): Some padding inside the header
-        let _pad = &bytes[9..16];
+impl SectionHeader {
+    fn parse(bytes: &[u8], encoding: Encoding) -> Result<SectionHeader> {
+        if bytes.len() < encoding.section_header_size() {
+            return Err(ElfError::InvalidSectionHeaderBufferSize);
+        }
 
-        let typ = u16::from_le_bytes(
-            bytes[16..18].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-        let typ = Typ::parse(typ);
+        let name = encoding.u32(bytes, 0)?;
 
-        let machine = u16::from_le_bytes(
-            bytes[18..20].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-        let machine = Machine::parse(machine);
+        let typ = encoding.u32(bytes, 4)?;
+        let typ = SectionHeaderTyp::parse(typ);
 
-        // TODO(patrik): Should this be included inside the ´Elf´ struct
-        let _version2 = u32::from_le_bytes(
-            bytes[20..24].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let flags = encoding.word(bytes, 8)?;
+        let addr = encoding.word(bytes, encoding.offset(12, 16))?;
 
-        let entry = u64::from_le_bytes(
-            bytes[24..32].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let offset = encoding.word(bytes, encoding.offset(16, 24))?;
+        let offset = offset.try_into()
+            .map_err(|_| ElfError::SectionHeaderOffsetOverflow)?;
 
-        let program_header_offset = u64::from_le_bytes(
-            bytes[32..40].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let size = encoding.word(bytes, encoding.offset(20, 32))?;
 
-        let section_header_offset = u64::from_le_bytes(
-            bytes[40..48].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let link = encoding.u32(bytes, encoding.offset(24, 40))?;
+        let info = encoding.u32(bytes, encoding.offset(28, 44))?;
 
-        // TODO(patrik): Should this be included inside the ´Elf´ struct
-        let _flags = u32::from_le_bytes(
-            bytes[48..52].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        let alignment = encoding.word(bytes, encoding.offset(32, 48))?;
+        let entry_size = encoding.word(bytes, encoding.offset(36, 56))?;
 
-        // TODO(patrik): Should this be included inside the ´Elf´ struct
-        let _header_size = u16::from_le_bytes(
-            bytes[52..54].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+        Ok(SectionHeader {
+            name,
+
+            typ,
+            flags,
+            addr,
 
-        let program_header_entry_size = u16::from_le_bytes(
-            bytes[54..56].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+            offset,
+            size,
 
-        let num_program_header_entries = u16::from_le_bytes(
-            bytes[56..58].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+            link,
+            info,
 
-        let section_header_entry_size = u16::from_le_bytes(
-            bytes[58..60].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+            alignment,
+            entry_size,
+        })
+    }
 
-        let num_section_header_entries = u16::from_le_bytes(
-            bytes[60..62].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
+    pub fn name(&self) -> u32 {
+        self.name
+    }
 
-        let string_table_index = u16::from_le_bytes(
-            bytes[62..64].try_into()
-                .map_err(|e| ElfError::TryFromSliceFailed(e))?);
-        let string_table_index: usize = string_table_index.try_into()
-            .map_err(|_| ElfError::StringTableIndexConvertionError)?;
+    pub fn typ(&self) -> SectionHeaderTyp {
+        self.typ
+    }
 
-        let program_header = Header {
-            offset: program_header_offset.try_into()
-                .map_err(|_| ElfError::ProgramHeaderOffsetConvertionError)?,
-            entry_size: program_header_entry_size.try_into()
-                .map_err(|_| ElfError::ProgramHeaderEntrySizeConvertionError)?,
-            num_entries: num_program_header_entries.try_into()
-                .map_err(|_| ElfError::NumProgramHeaderEntriesConvertionError)?,
-        };
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
 
-        let section_header = Header {
-            offset: section_header_offset.try_into()
-                .map_err(|_| ElfError::SectionHeaderOffsetConvertionError)?,
-            entry_size: section_header_entry_size.try_into()
-                .map_err(|_| ElfError::SectionHeaderEntrySizeConvertionError)?,
-            num_entries: num_section_header_entries.try_into()
-                .map_err(|_| ElfError::NumSectionHeaderEntriesConvertionError)?,
-        };
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
 
-        Ok(Self {
-            bytes,
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
 
-            class,
-            data,
-            os_abi,
-            os_abi_version,
-            typ,
-            machine,
-            entry,
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 
-            program_header,
-            section_header,
+    /// Index of the section this section refers to, e.g. the string table
+    /// of a symbol table
+    pub fn link(&self) -> u32 {
+        self.link
+    }
 
-            string_table_index,
-        })
+    pub fn info(&self) -> u32 {
+        self.info
     }
 
-    pub fn program_header(&self, index: usize) -> Result<ProgramHeader> {
-        let start = self.program_header.offset +
-            index * self.program_header.entry_size;
-        let end = start + self.program_header.entry_size;
-        let bytes = &self.bytes[start..end];
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
 
-        ProgramHeader::parse(bytes)
+    /// Size of an entry for sections that hold a table
+    pub fn entry_size(&self) -> u64 {
+        self.entry_size
     }
+}
 
-    pub fn program_header_iter(&self) -> ProgramHeaderIter {
-        ProgramHeaderIter {
-            elf: self,
-            current_index: 0
+pub struct SectionHeaderIter<'a> {
+    elf: &'a Elf<'a>,
+    current_index: usize,
+}
+
+impl<'a> Iterator for SectionHeaderIter<'a> {
+    type Item = SectionHeader;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_index >= self.elf.section_header.num_entries {
+            return None;
         }
-    }
 
-    pub fn program_header_data(&self, program_header: ProgramHeader)
-        -> Result<&[u8]>
-    {
-        let start = program_header.offset;
-        let end = start + program_header.file_size;
+        let section_header = self.elf.section_header(self.current_index).ok()?;
+        self.current_index += 1;
 
-        // TODO(patrik): Add Bound checks
-        Ok(&self.bytes[start..end])
+        Some(section_header)
     }
+}
 
-    pub fn entry(&self) -> u64 {
-        self.entry
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SymbolTyp {
+    /// Type is not specified
+    NoType,
+
+    /// Data object
+    Object,
+
+    /// Code object
+    Func,
+
+    /// Associated with a section
+    Section,
+
+    /// Source file name
+    File,
+
+    /// Uninitialized common block
+    Common,
+
+    /// Thread-local storage
+    ThreadLocalStorage,
+
+    /// Reserved inclusive range. Operating system specific
+    OperatingSystem(u8),
+
+    /// Reserved inclusive range. Processor specific
+    Processor(u8),
+
+    /// Unknown
+    Unknown(u8),
+}
+
+impl SymbolTyp {
+    fn parse(value: u8) -> Self {
+        match value {
+            0 => Self::NoType,
+            1 => Self::Object,
+            2 => Self::Func,
+            3 => Self::Section,
+            4 => Self::File,
+            5 => Self::Common,
+            6 => Self::ThreadLocalStorage,
+
+            10..=12 => Self::OperatingSystem(value),
+            13..=15 => Self::Processor(value),
+
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SymbolBinding {
+    /// Not visible outside the object file
+    Local,
+
+    /// Visible to all object files
+    Global,
+
+    /// Global with a lower precedence
+    Weak,
+
+    /// Reserved inclusive range. Operating system specific
+    OperatingSystem(u8),
+
+    /// Reserved inclusive range. Processor specific
+    Processor(u8),
+
+    /// Unknown
+    Unknown(u8),
+}
+
+impl SymbolBinding {
+    fn parse(value: u8) -> Self {
+        match value {
+            0 => Self::Local,
+            1 => Self::Global,
+            2 => Self::Weak,
+
+            10..=12 => Self::OperatingSystem(value),
+            13..=15 => Self::Processor(value),
+
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+/// Section index of symbols that are not defined in the file
+const SHN_UNDEF: u16 = 0;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol<'a> {
+    name: &'a str,
+    typ: SymbolTyp,
+    binding: SymbolBinding,
+
+    /// Index of the section the symbol is defined in
+    section_index: u16,
+
+    value: u64,
+    size: u64,
+}
+
+impl<'a> Symbol<'a> {
+    fn parse(bytes: &[u8], strings: &'a [u8], encoding: Encoding)
+        -> Result<Symbol<'a>>
+    {
+        let name = encoding.u32(bytes, 0)?;
+        let name_bytes = strings.get(name as usize..)
+            .and_then(|name| name.split(|&c| c == 0).next())
+            .ok_or(ElfError::InvalidSymbolName(name))?;
+        let name = std::str::from_utf8(name_bytes)
+            .map_err(|_| ElfError::InvalidSymbolName(name))?;
+
+        // NOTE(patrik): ELF32 puts the value and the size before the info
+        let info = encoding.array::<1>(bytes, encoding.offset(12, 4))?[0];
+        let section_index = encoding.u16(bytes, encoding.offset(14, 6))?;
+
+        let value = encoding.word(bytes, encoding.offset(4, 8))?;
+        let size = encoding.word(bytes, encoding.offset(8, 16))?;
+
+        Ok(Symbol {
+            name,
+            typ: SymbolTyp::parse(info & 0xf),
+            binding: SymbolBinding::parse(info >> 4),
+
+            section_index,
+
+            value,
+            size,
+        })
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn typ(&self) -> SymbolTyp {
+        self.typ
+    }
+
+    pub fn binding(&self) -> SymbolBinding {
+        self.binding
+    }
+
+    pub fn section_index(&self) -> u16 {
+        self.section_index
+    }
+
+    /// Address of the symbol in executables and shared objects
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_defined(&self) -> bool {
+        self.section_index != SHN_UNDEF
+    }
+
+    /// Check if the symbol names a place in the program, code or data,
+    /// rather than a file or a section
+    fn is_location(&self) -> bool {
+        let typ = matches!(self.typ,
+                           SymbolTyp::NoType | SymbolTyp::Object |
+                           SymbolTyp::Func | SymbolTyp::Common);
+        typ && self.is_defined() && !self.name.is_empty()
+    }
+}
+
+/// Symbols of ´.symtab´ and ´.dynsym´, indexed by name and by address
+pub struct SymbolTable<'a> {
+    symbols: Vec<Symbol<'a>>,
+
+    /// Indices into ´symbols´ of the locations sorted by address, global
+    /// symbols come before local symbols at the same address
+    by_address: Vec<usize>,
+}
+
+impl<'a> SymbolTable<'a> {
+    fn new(symbols: Vec<Symbol<'a>>) -> Self {
+        let mut by_address = (0..symbols.len())
+            .filter(|&index| symbols[index].is_location())
+            .collect::<Vec<_>>();
+        by_address.sort_by_key(|&index| {
+            let symbol = &symbols[index];
+            (symbol.value, symbol.binding != SymbolBinding::Global)
+        });
+
+        Self {
+            symbols,
+            by_address,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol<'a>> {
+        self.symbols.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Find the defined symbol named ´name´, global symbols are preferred
+    /// over local ones with the same name
+    pub fn by_name(&self, name: &str) -> Option<&Symbol<'a>> {
+        let mut symbols = self.symbols.iter()
+            .filter(|symbol| symbol.name == name && symbol.is_defined());
+        let first = symbols.next()?;
+
+        if first.binding == SymbolBinding::Global {
+            return Some(first);
+        }
+
+        symbols.find(|symbol| symbol.binding == SymbolBinding::Global)
+            .or(Some(first))
+    }
+
+    /// Find the symbol closest below ´addr´ and the offset of ´addr´ from
+    /// it
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol<'a>, u64)> {
+        let count = self.by_address
+            .partition_point(|&index| self.symbols[index].value <= addr);
+        let last = self.symbols[*self.by_address[..count].last()?].value;
+
+        // NOTE(patrik): Go back to the first symbol at the address, it's
+        // the global one if there is one
+        let first = self.by_address[..count]
+            .partition_point(|&index| self.symbols[index].value < last);
+        let symbol = &self.symbols[self.by_address[first]];
+
+        Some((symbol, addr - symbol.value))
+    }
+
+    /// Describe ´addr´ as ´symbol+offset´, or as the plain address when no
+    /// symbol is below it
+    pub fn format_address(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((symbol, 0)) => symbol.name.to_string(),
+            Some((symbol, offset)) => {
+                format!("{}+{:#x}", symbol.name, offset)
+            }
+
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+/// Read an unsigned LEB128 value at ´offset´ and move ´offset´ past it
+pub fn read_uleb128(bytes: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*offset)?;
+        *offset += 1;
+
+        if shift < 64 {
+            value |= ((byte & 0x7f) as u64) << shift;
+        }
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+/// Read a NUL terminated string at ´offset´ and move ´offset´ past it
+pub fn read_string<'a>(bytes: &'a [u8], offset: &mut usize) -> Option<&'a str> {
+    let rest = bytes.get(*offset..)?;
+    let length = rest.iter().position(|&c| c == 0)?;
+    *offset += length + 1;
+
+    std::str::from_utf8(&rest[..length]).ok()
+}
+
+/// Version of the build attributes format, the ´A´ at the start
+const ATTRIBUTES_VERSION: u8 = b'A';
+
+/// Tag of the subsubsection with the attributes of the whole file
+const TAG_FILE: u64 = 1;
+
+const TAG_RISCV_STACK_ALIGN: u64 = 4;
+const TAG_RISCV_ARCH: u64 = 5;
+const TAG_RISCV_UNALIGNED_ACCESS: u64 = 6;
+const TAG_RISCV_PRIV_SPEC: u64 = 8;
+const TAG_RISCV_PRIV_SPEC_MINOR: u64 = 10;
+const TAG_RISCV_PRIV_SPEC_REVISION: u64 = 12;
+
+/// File attributes from ´.riscv.attributes´, the attributes the toolchain
+/// didn't record are None
+#[derive(Clone, Default, Debug)]
+pub struct RiscVAttributes {
+    /// ISA string the file was built for, e.g. ´rv64i2p1_m2p0_c2p0´
+    pub arch: Option<String>,
+
+    /// Alignment of the stack pointer in bytes
+    pub stack_align: Option<u64>,
+
+    /// Whether the code may do unaligned memory accesses
+    pub unaligned_access: Option<bool>,
+
+    /// Version of the privileged spec as (major, minor, revision)
+    pub priv_spec: Option<(u64, u64, u64)>,
+}
+
+impl RiscVAttributes {
+    /// Parse the contents of ´.riscv.attributes´, only the attributes of
+    /// the ´riscv´ vendor that apply to the whole file are kept
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut attributes = Self::default();
+
+        let version = *bytes.first().ok_or(ElfError::TruncatedAttributes)?;
+        if version != ATTRIBUTES_VERSION {
+            return Err(ElfError::InvalidAttributesVersion(version));
+        }
+
+        // NOTE(patrik): The lengths of the subsections include the length
+        // field itself, and are always little endian for RISC-V
+        let mut offset = 1;
+        while offset < bytes.len() {
+            let length = bytes.get(offset..offset + 4)
+                .map(|length| u32::from_le_bytes(length.try_into().unwrap()))
+                .ok_or(ElfError::TruncatedAttributes)? as usize;
+            let subsection = offset.checked_add(length)
+                .and_then(|end| bytes.get(offset..end))
+                .filter(|_| length >= 4)
+                .ok_or(ElfError::TruncatedAttributes)?;
+            offset += length;
+
+            let mut position = 4;
+            let vendor = read_string(subsection, &mut position)
+                .ok_or(ElfError::TruncatedAttributes)?;
+            if vendor == "riscv" {
+                attributes.parse_subsection(&subsection[position..])?;
+            }
+        }
+
+        Ok(attributes)
+    }
+
+    /// Parse the subsubsections of the ´riscv´ vendor subsection
+    fn parse_subsection(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let start = offset;
+            let tag = read_uleb128(bytes, &mut offset)
+                .ok_or(ElfError::TruncatedAttributes)?;
+            let size = bytes.get(offset..offset + 4)
+                .map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+                .ok_or(ElfError::TruncatedAttributes)? as usize;
+            offset += 4;
+
+            // NOTE(patrik): The size covers the tag and the size field too
+            let end = start.checked_add(size)
+                .filter(|&end| end >= offset && end <= bytes.len())
+                .ok_or(ElfError::TruncatedAttributes)?;
+
+            if tag == TAG_FILE {
+                self.parse_attributes(&bytes[offset..end])?;
+            }
+
+            offset = end;
+        }
+
+        Ok(())
+    }
+
+    fn parse_attributes(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut priv_spec = (None, None, None);
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let tag = read_uleb128(bytes, &mut offset)
+                .ok_or(ElfError::TruncatedAttributes)?;
+
+            // NOTE(patrik): Odd tags have string values and even tags
+            // have integer values
+            if tag % 2 == 1 {
+                let value = read_string(bytes, &mut offset)
+                    .ok_or(ElfError::TruncatedAttributes)?;
+                if tag == TAG_RISCV_ARCH {
+                    self.arch = Some(value.to_string());
+                }
+
+                continue;
+            }
+
+            let value = read_uleb128(bytes, &mut offset)
+                .ok_or(ElfError::TruncatedAttributes)?;
+            match tag {
+                TAG_RISCV_STACK_ALIGN => self.stack_align = Some(value),
+                TAG_RISCV_UNALIGNED_ACCESS => {
+                    self.unaligned_access = Some(value != 0);
+                }
+
+                TAG_RISCV_PRIV_SPEC => priv_spec.0 = Some(value),
+                TAG_RISCV_PRIV_SPEC_MINOR => priv_spec.1 = Some(value),
+                TAG_RISCV_PRIV_SPEC_REVISION => priv_spec.2 = Some(value),
+
+                _ => {}
+            }
+        }
+
+        if let (Some(major), minor, revision) = priv_spec {
+            self.priv_spec = Some((major, minor.unwrap_or(0),
+                                   revision.unwrap_or(0)));
+        }
+
+        Ok(())
+    }
+
+    /// Extensions in the ISA string, see ´isa_extensions´
+    pub fn extensions(&self) -> Vec<String> {
+        self.arch.as_deref().map(isa_extensions).unwrap_or_default()
+    }
+}
+
+/// Extensions that come with an extension, the toolchains list the subsets
+/// on their own in the ISA string
+const IMPLIED_EXTENSIONS: &[(&str, &[&str])] = &[
+    ("g", &["i", "m", "a", "f", "d", "zicsr", "zifencei"]),
+    ("i", &["e"]),
+    ("m", &["zmmul"]),
+    ("a", &["zaamo", "zalrsc"]),
+    ("c", &["zca"]),
+];
+
+/// Remove the version from the end of an extension, e.g. ´2p1´ or ´2´
+fn strip_version(extension: &str) -> &str {
+    let is_digit = |c: char| c.is_ascii_digit();
+
+    let rest = extension.trim_end_matches(is_digit);
+    if rest.len() == extension.len() {
+        return extension;
+    }
+
+    match rest.strip_suffix('p') {
+        Some(major) if major.ends_with(is_digit) => {
+            major.trim_end_matches(is_digit)
+        }
+
+        _ => rest,
+    }
+}
+
+/// Split an ISA string like ´rv64imac_zicsr´ or ´rv64i2p1_m2p0´ into the
+/// lower case names of its extensions, without the versions. The
+/// extensions implied by another, like ´zmmul´ by ´m´, are included
+pub fn isa_extensions(isa: &str) -> Vec<String> {
+    let isa = isa.to_ascii_lowercase();
+    let Some(rest) = isa.strip_prefix("rv32").or(isa.strip_prefix("rv64"))
+    else {
+        return Vec::new();
+    };
+
+    let mut extensions = Vec::new();
+    for part in rest.split('_').filter(|part| !part.is_empty()) {
+        // NOTE(patrik): Multi-letter extensions are always on their own
+        // between underscores
+        if part.starts_with(['z', 's', 'x']) {
+            extensions.push(strip_version(part).to_string());
+            continue;
+        }
+
+        let mut chars = part.chars().peekable();
+        while let Some(extension) = chars.next() {
+            // Skip the version, e.g. ´2p1´
+            while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+            if chars.next_if_eq(&'p').is_some() {
+                while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+            }
+
+            extensions.push(extension.to_string());
+        }
+    }
+
+    let mut index = 0;
+    while index < extensions.len() {
+        let implied = IMPLIED_EXTENSIONS.iter()
+            .find(|(extension, _)| *extension == extensions[index]);
+        if let Some((_, implied)) = implied {
+            for name in implied.iter() {
+                if !extensions.iter().any(|extension| extension == name) {
+                    extensions.push(name.to_string());
+                }
+            }
+        }
+
+        index += 1;
+    }
+
+    // NOTE(patrik): ´g´ is only a shorthand for the extensions it implies
+    extensions.retain(|extension| extension != "g");
+
+    extensions
+}
+
+/// Marks the end of the dynamic section
+pub const DT_NULL: i64 = 0;
+pub const DT_NEEDED: i64 = 1;
+
+/// Size of the relocations of the PLT
+pub const DT_PLTRELSZ: i64 = 2;
+
+pub const DT_STRTAB: i64 = 5;
+pub const DT_SYMTAB: i64 = 6;
+
+/// Address of the relocations with addends
+pub const DT_RELA: i64 = 7;
+pub const DT_RELASZ: i64 = 8;
+pub const DT_RELAENT: i64 = 9;
+
+pub const DT_REL: i64 = 17;
+
+/// Type of the relocations of the PLT, ´DT_RELA´ or ´DT_REL´
+pub const DT_PLTREL: i64 = 20;
+
+/// Address of the relocations of the PLT
+pub const DT_JMPREL: i64 = 23;
+
+/// Entry of the dynamic section
+#[derive(Copy, Clone, Debug)]
+pub struct DynamicEntry {
+    pub tag: i64,
+
+    /// Value or address, depending on the tag
+    pub value: u64,
+}
+
+/// Relocation with an addend
+#[derive(Copy, Clone, Debug)]
+pub struct Rela {
+    /// Address of the place to relocate
+    pub offset: u64,
+
+    /// Index of the symbol in the dynamic symbol table
+    pub symbol: u32,
+
+    pub typ: u32,
+    pub addend: i64,
+}
+
+impl Rela {
+    fn parse(bytes: &[u8], encoding: Encoding) -> Result<Rela> {
+        let offset = encoding.word(bytes, 0)?;
+        let info = encoding.word(bytes, encoding.offset(4, 8))?;
+        let addend = encoding.word(bytes, encoding.offset(8, 16))?;
+
+        // NOTE(patrik): ELF32 packs the symbol and the type in 32 bits and
+        // the addend is sign extended
+        match encoding.class {
+            Class::Elf32 => Ok(Rela {
+                offset,
+                symbol: (info >> 8) as u32,
+                typ: (info & 0xff) as u32,
+                addend: addend as u32 as i32 as i64,
+            }),
+
+            Class::Elf64 => Ok(Rela {
+                offset,
+                symbol: (info >> 32) as u32,
+                typ: info as u32,
+                addend: addend as i64,
+            }),
+        }
+    }
+}
+
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    class: Class,
+    data: Data,
+    os_abi: OsAbi,
+    os_abi_version: u8,
+    typ: Typ,
+    machine: Machine,
+    entry: u64,
+    flags: u32,
+
+    program_header: Header,
+    section_header: Header,
+
+    string_table_index: usize,
+}
+
+impl<'a> std::fmt::Debug for Elf<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) ->
+        std::result::Result<(), std::fmt::Error>
+    {
+        f.debug_struct("Elf")
+            .field("class", &self.class)
+            .field("data", &self.data)
+            .field("os_abi", &self.os_abi)
+            .field("os_abi_version", &self.os_abi_version)
+            .field("typ", &self.typ)
+            .field("machine", &self.machine)
+            .field("entry", &format_args!("{:#x}", self.entry))
+            .field("flags", &format_args!("{:#x}", self.flags))
+            .field("program_header", &self.program_header)
+            .field("section_header", &self.section_header)
+            .field("string_table_index", &self.string_table_index)
+            .finish()
+    }
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        if bytes.len() < IDENT_SIZE {
+            return Err(ElfError::TruncatedHeader);
+        }
+
+        if &bytes[0..4] != b"\x7fELF" {
+            return Err(ElfError::InvalidMagic);
+        }
+
+        let class = Class::parse(bytes[4])?;
+        let data = Data::parse(bytes[5])?;
+        let encoding = Encoding {
+            class,
+            data,
+        };
+
+        if bytes.len() < encoding.header_size() {
+            return Err(ElfError::TruncatedHeader);
+        }
+
+        // TODO(patrik): Should this be included inside the ´Elf´ struct
+        let _version = bytes[6];
+
+        let os_abi = OsAbi::parse(bytes[7]);
+        let os_abi_version = bytes[8];
+
+        // NOTE(patrik): Some padding inside the header
+        let _pad = &bytes[9..16];
+
+        let typ = encoding.u16(bytes, 16)?;
+        let typ = Typ::parse(typ);
+
+        let machine = encoding.u16(bytes, 18)?;
+        let machine = Machine::parse(machine);
+
+        // TODO(patrik): Should this be included inside the ´Elf´ struct
+        let _version2 = encoding.u32(bytes, 20)?;
+
+        // NOTE(patrik): The fields after the entry are at different offsets
+        // in ELF32 as the addresses and offsets are 32-bit
+        let entry = encoding.word(bytes, 24)?;
+        let program_header_offset =
+            encoding.word(bytes, encoding.offset(28, 32))?;
+        let section_header_offset =
+            encoding.word(bytes, encoding.offset(32, 40))?;
+
+        let flags = encoding.u32(bytes, encoding.offset(36, 48))?;
+
+        // TODO(patrik): Should this be included inside the ´Elf´ struct
+        let _header_size = encoding.u16(bytes, encoding.offset(40, 52))?;
+
+        let program_header_entry_size =
+            encoding.u16(bytes, encoding.offset(42, 54))?;
+        let num_program_header_entries =
+            encoding.u16(bytes, encoding.offset(44, 56))?;
+        let section_header_entry_size =
+            encoding.u16(bytes, encoding.offset(46, 58))?;
+        let num_section_header_entries =
+            encoding.u16(bytes, encoding.offset(48, 60))?;
+
+        let string_table_index = encoding.u16(bytes, encoding.offset(50, 62))?;
+        let string_table_index = usize::from(string_table_index);
+
+        let program_header = Header {
+            offset: program_header_offset.try_into()
+                .map_err(|_| ElfError::ProgramHeaderOffsetConvertionError)?,
+            entry_size: usize::from(program_header_entry_size),
+            num_entries: usize::from(num_program_header_entries),
+        };
+
+        let section_header = Header {
+            offset: section_header_offset.try_into()
+                .map_err(|_| ElfError::SectionHeaderOffsetConvertionError)?,
+            entry_size: usize::from(section_header_entry_size),
+            num_entries: usize::from(num_section_header_entries),
+        };
+
+        // NOTE(patrik): Validate the tables up front, so looking up their
+        // entries later only has to check the index
+        if program_header.num_entries > 0 {
+            if program_header.entry_size < encoding.program_header_size() {
+                return Err(ElfError::InvalidProgramHeaderEntrySize(
+                        program_header.entry_size));
+            }
+
+            if !program_header.is_inside(bytes.len()) {
+                return Err(ElfError::ProgramHeaderTableOutOfBounds);
+            }
+        }
+
+        if section_header.num_entries > 0 {
+            if section_header.entry_size < encoding.section_header_size() {
+                return Err(ElfError::InvalidSectionHeaderEntrySize(
+                        section_header.entry_size));
+            }
+
+            if !section_header.is_inside(bytes.len()) {
+                return Err(ElfError::SectionHeaderTableOutOfBounds);
+            }
+
+            if string_table_index >= section_header.num_entries {
+                return Err(ElfError::InvalidStringTableIndex(
+                        string_table_index));
+            }
+        }
+
+        Ok(Self {
+            bytes,
+
+            class,
+            data,
+            os_abi,
+            os_abi_version,
+            typ,
+            machine,
+            entry,
+            flags,
+
+            program_header,
+            section_header,
+
+            string_table_index,
+        })
+    }
+
+    pub fn program_header(&self, index: usize) -> Result<ProgramHeader> {
+        let range = self.program_header.entry(index)
+            .ok_or(ElfError::ProgramHeaderOutOfBounds(index))?;
+
+        ProgramHeader::parse(&self.bytes[range], self.encoding())
+    }
+
+    pub fn program_header_iter(&self) -> ProgramHeaderIter<'_> {
+        ProgramHeaderIter {
+            elf: self,
+            current_index: 0
+        }
+    }
+
+    pub fn program_header_data(&self, program_header: ProgramHeader)
+        -> Result<&[u8]>
+    {
+        let start = program_header.offset;
+        let end = start.checked_add(program_header.file_size)
+            .ok_or(ElfError::ProgramHeaderDataOutOfBounds)?;
+
+        self.bytes.get(start..end)
+            .ok_or(ElfError::ProgramHeaderDataOutOfBounds)
+    }
+
+    /// Find the file data at ´vaddr´, up to the end of its segment
+    pub fn vaddr_data(&self, vaddr: u64) -> Option<&'a [u8]> {
+        let program_header = self.program_header_iter()
+            .filter(|header| header.typ() == ProgramHeaderTyp::Load)
+            .find(|header| {
+                vaddr >= header.vaddr() &&
+                    vaddr - header.vaddr() < header.file_size() as u64
+            })?;
+
+        let start = program_header.offset
+            .checked_add((vaddr - program_header.vaddr()) as usize)?;
+        let end = program_header.offset
+            .checked_add(program_header.file_size)?;
+        self.bytes.get(start..end)
+    }
+
+    /// Entries of the dynamic section, empty for files without
+    /// ´PT_DYNAMIC´
+    pub fn dynamic_entries(&self) -> Result<Vec<DynamicEntry>> {
+        let Some(program_header) = self.program_header_iter()
+            .find(|header| header.typ() == ProgramHeaderTyp::Dynamic)
+        else {
+            return Ok(Vec::new());
+        };
+
+        let data = self.program_header_data(program_header)?;
+        let encoding = self.encoding();
+        let entry_size = encoding.offset(8, 16);
+
+        let mut entries = Vec::new();
+        for bytes in data.chunks_exact(entry_size) {
+            let tag = encoding.word(bytes, 0)?;
+            let tag = match encoding.class {
+                Class::Elf32 => tag as u32 as i32 as i64,
+                Class::Elf64 => tag as i64,
+            };
+
+            if tag == DT_NULL {
+                break;
+            }
+
+            let value = encoding.word(bytes, encoding.offset(4, 8))?;
+            entries.push(DynamicEntry {
+                tag,
+                value,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Relocations of the dynamic section, ´DT_RELA´ followed by the PLT
+    /// relocations in ´DT_JMPREL´
+    pub fn dynamic_relocations(&self) -> Result<Vec<Rela>> {
+        let entries = self.dynamic_entries()?;
+        let value = |tag: i64| {
+            entries.iter()
+                .find(|entry| entry.tag == tag)
+                .map(|entry| entry.value)
+        };
+
+        if value(DT_REL).is_some() ||
+            value(DT_PLTREL).is_some_and(|typ| typ as i64 == DT_REL)
+        {
+            return Err(ElfError::UnsupportedRel);
+        }
+
+        let encoding = self.encoding();
+        let default_entry_size = encoding.offset(12, 24) as u64;
+        let entry_size = value(DT_RELAENT).unwrap_or(default_entry_size);
+        if entry_size < default_entry_size {
+            return Err(ElfError::UnsupportedRel);
+        }
+
+        let tables = [
+            (value(DT_RELA), value(DT_RELASZ)),
+            (value(DT_JMPREL), value(DT_PLTRELSZ)),
+        ];
+
+        let mut relocations = Vec::new();
+        for (addr, size) in tables {
+            let (Some(addr), Some(size)) = (addr, size) else {
+                continue;
+            };
+
+            let data = self.vaddr_data(addr)
+                .and_then(|data| data.get(..usize::try_from(size).ok()?))
+                .ok_or(ElfError::InvalidDynamicAddress(addr))?;
+
+            for bytes in data.chunks_exact(entry_size as usize) {
+                relocations.push(Rela::parse(bytes, encoding)?);
+            }
+        }
+
+        Ok(relocations)
+    }
+
+    /// Symbol ´index´ of the dynamic symbol table, ´DT_SYMTAB´
+    pub fn dynamic_symbol(&self, index: u32) -> Result<Symbol<'a>> {
+        let entries = self.dynamic_entries()?;
+        let value = |tag: i64| {
+            entries.iter()
+                .find(|entry| entry.tag == tag)
+                .map(|entry| entry.value)
+                .ok_or(ElfError::InvalidDynamicAddress(0))
+        };
+
+        let encoding = self.encoding();
+        let symbol_size = encoding.symbol_size() as u64;
+
+        let symbols = value(DT_SYMTAB)?;
+        let addr = (index as u64).checked_mul(symbol_size)
+            .and_then(|offset| offset.checked_add(symbols))
+            .ok_or(ElfError::InvalidDynamicAddress(symbols))?;
+        let bytes = self.vaddr_data(addr)
+            .ok_or(ElfError::InvalidDynamicAddress(addr))?;
+
+        let strings = value(DT_STRTAB)?;
+        let strings = self.vaddr_data(strings)
+            .ok_or(ElfError::InvalidDynamicAddress(strings))?;
+
+        Symbol::parse(bytes, strings, encoding)
+    }
+
+    /// Position independent executables are shared objects, they can be
+    /// loaded at any address
+    pub fn is_position_independent(&self) -> bool {
+        self.typ == Typ::Shared
+    }
+
+    /// Path of the dynamic linker from ´PT_INTERP´, None for static
+    /// executables
+    pub fn interpreter(&self) -> Result<Option<&'a str>> {
+        let Some(program_header) = self.program_header_iter()
+            .find(|header| header.typ() == ProgramHeaderTyp::Interp)
+        else {
+            return Ok(None);
+        };
+
+        let start = program_header.offset;
+        let data = start.checked_add(program_header.file_size)
+            .and_then(|end| self.bytes.get(start..end))
+            .ok_or(ElfError::ProgramHeaderDataOutOfBounds)?;
+
+        let path = data.strip_suffix(&[0])
+            .ok_or(ElfError::InvalidInterpreter)?;
+
+        std::str::from_utf8(path)
+            .map(Some)
+            .map_err(|_| ElfError::InvalidInterpreter)
+    }
+
+    /// Virtual address the program header table is loaded at, from
+    /// ´PT_PHDR´ or the ´PT_LOAD´ segment that covers the table
+    pub fn program_header_vaddr(&self) -> Option<u64> {
+        if let Some(program_header) = self.program_header_iter()
+            .find(|header| header.typ() == ProgramHeaderTyp::ProgramHeader)
+        {
+            return Some(program_header.vaddr());
+        }
+
+        let offset = self.program_header.offset;
+        self.program_header_iter()
+            .filter(|header| header.typ() == ProgramHeaderTyp::Load)
+            .find(|header| {
+                offset >= header.offset &&
+                    offset - header.offset < header.file_size
+            })
+            .map(|header| header.vaddr() + (offset - header.offset) as u64)
+    }
+
+    pub fn program_header_entry_size(&self) -> usize {
+        self.program_header.entry_size
+    }
+
+    pub fn num_program_headers(&self) -> usize {
+        self.program_header.num_entries
+    }
+
+    pub fn section_header(&self, index: usize) -> Result<SectionHeader> {
+        let range = self.section_header.entry(index)
+            .ok_or(ElfError::SectionHeaderOutOfBounds(index))?;
+
+        SectionHeader::parse(&self.bytes[range], self.encoding())
+    }
+
+    pub fn section_header_iter(&self) -> SectionHeaderIter<'_> {
+        SectionHeaderIter {
+            elf: self,
+            current_index: 0
+        }
+    }
+
+    /// Contents of a section in the file, empty for ´NoBits´ sections as
+    /// they only take up memory
+    pub fn section_header_data(&self, section_header: SectionHeader)
+        -> Result<&'a [u8]>
+    {
+        if section_header.typ == SectionHeaderTyp::NoBits {
+            return Ok(&[]);
+        }
+
+        let start = section_header.offset;
+        let end = usize::try_from(section_header.size).ok()
+            .and_then(|size| start.checked_add(size))
+            .ok_or(ElfError::SectionHeaderDataOutOfBounds)?;
+
+        self.bytes.get(start..end)
+            .ok_or(ElfError::SectionHeaderDataOutOfBounds)
+    }
+
+    /// Name of ´section_header´ from the section header string table
+    pub fn section_name(&self, section_header: SectionHeader)
+        -> Result<&'a str>
+    {
+        let string_table = self.section_header(self.string_table_index)?;
+        let strings = self.section_header_data(string_table)?;
+
+        let name = strings.get(section_header.name as usize..)
+            .and_then(|name| name.split(|&c| c == 0).next())
+            .ok_or(ElfError::InvalidSectionName(section_header.name))?;
+
+        std::str::from_utf8(name)
+            .map_err(|_| ElfError::InvalidSectionName(section_header.name))
+    }
+
+    /// Find the section named ´name´, e.g. ´.text´
+    pub fn section_header_by_name(&self, name: &str) -> Option<SectionHeader> {
+        self.section_header_iter()
+            .find(|&section_header| {
+                self.section_name(section_header).ok() == Some(name)
+            })
+    }
+
+    /// Contents of the section named ´name´
+    pub fn section_by_name(&self, name: &str) -> Option<&'a [u8]> {
+        let section_header = self.section_header_by_name(name)?;
+        self.section_header_data(section_header).ok()
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    fn encoding(&self) -> Encoding {
+        Encoding {
+            class: self.class,
+            data: self.data,
+        }
+    }
+
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    pub fn typ(&self) -> Typ {
+        self.typ
+    }
+
+    pub fn machine(&self) -> Machine {
+        self.machine
+    }
+
+    /// Raw ´e_flags´ of the ELF Header, see ´riscv_flags´
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn riscv_flags(&self) -> RiscVFlags {
+        RiscVFlags::parse(self.flags)
+    }
+
+    /// Attributes from ´.riscv.attributes´, None if the file doesn't have
+    /// the section
+    pub fn riscv_attributes(&self) -> Result<Option<RiscVAttributes>> {
+        let Some(section_header) = self.section_header_iter()
+            .find(|header| header.typ() == SectionHeaderTyp::RiscVAttributes)
+        else {
+            return Ok(None);
+        };
+
+        let data = self.section_header_data(section_header)?;
+        RiscVAttributes::parse(data).map(Some)
+    }
+
+    /// Check that the file is a RISC-V executable, or a position
+    /// independent executable
+    pub fn validate(&self) -> Result<()> {
+        if self.machine != Machine::RiscV {
+            return Err(ElfError::NotRiscV(self.machine));
+        }
+
+        if self.typ != Typ::Executable && self.typ != Typ::Shared {
+            return Err(ElfError::NotExecutable(self.typ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that a hart with the XLEN and the extensions in ´misa´ can run
+    /// the program
+    ///
+    /// The float ABI is not checked, programs that pass floating point
+    /// values in the F or D registers don't need to use them, see
+    /// ´RiscVFlags::float_extension´
+    pub fn check_hart(&self, misa: u64) -> Result<()> {
+        let xlen_class = match misa >> 62 {
+            1 => Class::Elf32,
+            _ => Class::Elf64,
+        };
+
+        if self.class != xlen_class {
+            return Err(ElfError::ClassMismatch(self.class));
+        }
+
+        if self.data != Data::LittleEndian {
+            return Err(ElfError::UnsupportedByteOrder);
+        }
+
+        // NOTE(patrik): An RVE program runs on an I hart, it only leaves
+        // the upper registers alone. RVTSO is stronger than RVWMO, but the
+        // harts run one instruction at a time, so every access is already
+        // sequentially consistent
+        let flags = self.riscv_flags();
+        if flags.rvc && !misa_has_extension(misa, 'C') {
+            return Err(ElfError::MissingExtension('C'));
+        }
+
+        Ok(())
+    }
+
+    pub fn data(&self) -> Data {
+        self.data
+    }
+
+    /// Parse the symbols of every symbol table in the file, ´.symtab´ and
+    /// ´.dynsym´
+    pub fn symbol_table(&self) -> Result<SymbolTable<'a>> {
+        let mut symbols = Vec::new();
+        for section_header in self.section_header_iter() {
+            let typ = section_header.typ();
+            if typ != SectionHeaderTyp::SymTab &&
+                typ != SectionHeaderTyp::DynSym
+            {
+                continue;
+            }
+
+            let data = self.section_header_data(section_header)?;
+            let string_table =
+                self.section_header(section_header.link() as usize)?;
+            let strings = self.section_header_data(string_table)?;
+
+            // NOTE(patrik): The first entry is always the null symbol
+            let encoding = self.encoding();
+            for bytes in data.chunks_exact(encoding.symbol_size()).skip(1) {
+                symbols.push(Symbol::parse(bytes, strings, encoding)?);
+            }
+        }
+
+        Ok(SymbolTable::new(symbols))
+    }
+
+    /// Find the address of the symbol named ´name´ inside the symbol table
+    pub fn symbol_address(&self, name: &str) -> Option<u64> {
+        let symbol_table = self.symbol_table().ok()?;
+        symbol_table.by_name(name).map(|symbol| symbol.value())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const PT_LOAD: u32 = 1;
+    pub(crate) const PT_DYNAMIC: u32 = 2;
+
+    const SHT_PROGBITS: u32 = 1;
+    const SHT_SYMTAB: u32 = 2;
+    const SHT_STRTAB: u32 = 3;
+    const SHT_RISCV_ATTRIBUTES: u32 = 0x70000003;
+
+    const ET_EXEC: u16 = 2;
+    pub(crate) const ET_DYN: u16 = 3;
+
+    /// Writes the fields of a test file in the class and the byte order of
+    /// the file
+    pub(crate) struct Writer {
+        encoding: Encoding,
+        pub(crate) bytes: Vec<u8>,
+    }
+
+    impl Writer {
+        pub(crate) fn new(class: Class, data: Data) -> Self {
+            Self {
+                encoding: Encoding { class, data },
+                bytes: Vec::new(),
+            }
+        }
+
+        fn push<const N: usize>(&mut self, le: [u8; N], be: [u8; N]) {
+            match self.encoding.data {
+                Data::LittleEndian => self.bytes.extend_from_slice(&le),
+                Data::BigEndian => self.bytes.extend_from_slice(&be),
+            }
+        }
+
+        pub(crate) fn u8(&mut self, value: u8) {
+            self.bytes.push(value);
+        }
+
+        pub(crate) fn u16(&mut self, value: u16) {
+            self.push(value.to_le_bytes(), value.to_be_bytes());
+        }
+
+        pub(crate) fn u32(&mut self, value: u32) {
+            self.push(value.to_le_bytes(), value.to_be_bytes());
+        }
+
+        pub(crate) fn u64(&mut self, value: u64) {
+            self.push(value.to_le_bytes(), value.to_be_bytes());
+        }
+
+        /// Address, offset or size, the width depends on the class
+        pub(crate) fn word(&mut self, value: u64) {
+            match self.encoding.class {
+                Class::Elf32 => self.u32(value as u32),
+                Class::Elf64 => self.u64(value),
+            }
+        }
+
+        pub(crate) fn symbol(&mut self, name: u32, info: u8, section: u16,
+                             value: u64, size: u64)
+        {
+            self.u32(name);
+            if self.encoding.class == Class::Elf32 {
+                self.word(value);
+                self.word(size);
+            }
+
+            self.u8(info);
+            self.u8(0);
+            self.u16(section);
+            if self.encoding.class == Class::Elf64 {
+                self.word(value);
+                self.word(size);
+            }
+        }
+
+        pub(crate) fn rela(&mut self, offset: u64, symbol: u32, typ: u32,
+                           addend: i64)
+        {
+            self.word(offset);
+            match self.encoding.class {
+                Class::Elf32 => self.u32((symbol << 8) | (typ & 0xff)),
+                Class::Elf64 => self.u64(((symbol as u64) << 32) | typ as u64),
+            }
+            self.word(addend as u64);
+        }
+
+        pub(crate) fn dynamic(&mut self, tag: i64, value: u64) {
+            self.word(tag as u64);
+            self.word(value);
+        }
+
+        /// Pad with zeros up to ´len´ bytes
+        pub(crate) fn pad_to(&mut self, len: usize) {
+            self.bytes.resize(len, 0);
+        }
+    }
+
+    pub(crate) struct Segment {
+        pub(crate) typ: u32,
+        pub(crate) vaddr: u64,
+        pub(crate) data: Vec<u8>,
+        pub(crate) memory_size: u64,
+    }
+
+    #[derive(Default)]
+    pub(crate) struct Section {
+        pub(crate) name: &'static str,
+        pub(crate) typ: u32,
+        pub(crate) addr: u64,
+        pub(crate) data: Vec<u8>,
+        pub(crate) link: u32,
+        pub(crate) entry_size: u64,
+    }
+
+    /// ELF file built for the tests, the section header string table is
+    /// added as the last section
+    pub(crate) struct TestElf {
+        pub(crate) class: Class,
+        pub(crate) data: Data,
+        pub(crate) typ: u16,
+        pub(crate) entry: u64,
+        pub(crate) flags: u32,
+        pub(crate) segments: Vec<Segment>,
+        pub(crate) sections: Vec<Section>,
+    }
+
+    impl TestElf {
+        pub(crate) fn new(class: Class, data: Data, typ: u16) -> Self {
+            Self {
+                class,
+                data,
+                typ,
+                entry: 0,
+                flags: 0,
+                segments: Vec::new(),
+                sections: Vec::new(),
+            }
+        }
+
+        pub(crate) fn writer(&self) -> Writer {
+            Writer::new(self.class, self.data)
+        }
+
+        pub(crate) fn build(&self) -> Vec<u8> {
+            let encoding = Encoding { class: self.class, data: self.data };
+            let header_size = encoding.header_size();
+            let phdr_size = encoding.program_header_size();
+            let shdr_size = encoding.section_header_size();
+
+            let mut shstrtab = vec![0];
+            let mut names = Vec::new();
+            for section in &self.sections {
+                names.push(shstrtab.len() as u32);
+                shstrtab.extend_from_slice(section.name.as_bytes());
+                shstrtab.push(0);
+            }
+            let shstrtab_name = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(b".shstrtab\0");
+
+            // NOTE(patrik): The contents follow the program headers, each
+            // aligned to 8 bytes
+            let mut file = vec![0; header_size +
+                                   phdr_size * self.segments.len()];
+            let place = |file: &mut Vec<u8>, data: &[u8]| {
+                file.resize(file.len().next_multiple_of(8), 0);
+                let offset = file.len() as u64;
+                file.extend_from_slice(data);
+                offset
+            };
+
+            let segment_offsets = self.segments.iter()
+                .map(|segment| place(&mut file, &segment.data))
+                .collect::<Vec<_>>();
+            let section_offsets = self.sections.iter()
+                .map(|section| place(&mut file, &section.data))
+                .collect::<Vec<_>>();
+            let shstrtab_offset = place(&mut file, &shstrtab);
+
+            let mut shdrs = self.writer();
+            shdrs.pad_to(shdr_size);
+            let sections = self.sections.iter()
+                .zip(names.iter().zip(&section_offsets))
+                .map(|(section, (&name, &offset))| (section, name, offset));
+            for (section, name, offset) in sections {
+                shdrs.u32(name);
+                shdrs.u32(section.typ);
+                shdrs.word(0);
+                shdrs.word(section.addr);
+                shdrs.word(offset);
+                shdrs.word(section.data.len() as u64);
+                shdrs.u32(section.link);
+                shdrs.u32(0);
+                shdrs.word(1);
+                shdrs.word(section.entry_size);
+            }
+            shdrs.u32(shstrtab_name);
+            shdrs.u32(SHT_STRTAB);
+            shdrs.word(0);
+            shdrs.word(0);
+            shdrs.word(shstrtab_offset);
+            shdrs.word(shstrtab.len() as u64);
+            shdrs.u32(0);
+            shdrs.u32(0);
+            shdrs.word(1);
+            shdrs.word(0);
+
+            let shdr_offset = place(&mut file, &shdrs.bytes);
+            let shdr_count = self.sections.len() as u16 + 2;
+
+            let mut header = self.writer();
+            header.bytes.extend_from_slice(b"\x7fELF");
+            header.u8(match self.class {
+                Class::Elf32 => 1,
+                Class::Elf64 => 2,
+            });
+            header.u8(match self.data {
+                Data::LittleEndian => 1,
+                Data::BigEndian => 2,
+            });
+            header.u8(1);
+            header.pad_to(IDENT_SIZE);
+            header.u16(self.typ);
+            header.u16(0xf3);
+            header.u32(1);
+            header.word(self.entry);
+            header.word(header_size as u64);
+            header.word(shdr_offset);
+            header.u32(self.flags);
+            header.u16(header_size as u16);
+            header.u16(phdr_size as u16);
+            header.u16(self.segments.len() as u16);
+            header.u16(shdr_size as u16);
+            header.u16(shdr_count);
+            header.u16(shdr_count - 1);
+
+            for (segment, &offset) in self.segments.iter()
+                .zip(&segment_offsets)
+            {
+                let size = segment.data.len() as u64;
+                header.u32(segment.typ);
+                if self.class == Class::Elf64 {
+                    header.u32(5);
+                }
+                header.word(offset);
+                header.word(segment.vaddr);
+                header.word(segment.vaddr);
+                header.word(size);
+                header.word(segment.memory_size);
+                if self.class == Class::Elf32 {
+                    header.u32(5);
+                }
+                header.word(0x1000);
+            }
+
+            file[..header.bytes.len()].copy_from_slice(&header.bytes);
+            file
+        }
+    }
+
+    const BASE: u64 = 0x80000000;
+
+    /// ´.strtab´ of ´sample´, the names are at 1, 6, 13, 19 and 23
+    const STRINGS: &[u8] = b"\0loop\0_start\0alias\0dup\0ext\0";
+
+    /// ´.riscv.attributes´ for an RV32IMC file, with a subsection of
+    /// another vendor that is skipped
+    fn attributes() -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(&[4, 16]); // Tag_RISCV_stack_align
+        file.push(5); // Tag_RISCV_arch
+        file.extend_from_slice(b"rv32i2p1_m2p0_c2p0_zicsr2p0\0");
+        file.extend_from_slice(&[6, 1]); // Tag_RISCV_unaligned_access
+        file.extend_from_slice(&[8, 1, 10, 11]); // Tag_RISCV_priv_spec
+
+        let mut riscv = b"riscv\0".to_vec();
+        riscv.push(1); // Tag_File
+        riscv.extend_from_slice(&(file.len() as u32 + 5).to_le_bytes());
+        riscv.extend_from_slice(&file);
+
+        let mut bytes = vec![b'A'];
+        for (vendor, contents) in [(&b"gnu\0"[..], &[1, 5, 0, 0, 0, 0][..]),
+                                   (&riscv[..], &[][..])]
+        {
+            let length = 4 + vendor.len() + contents.len();
+            bytes.extend_from_slice(&(length as u32).to_le_bytes());
+            bytes.extend_from_slice(vendor);
+            bytes.extend_from_slice(contents);
+        }
+
+        bytes
+    }
+
+    /// Executable with 16 bytes of text at ´BASE´, symbols and attributes
+    fn sample(class: Class, data: Data) -> Vec<u8> {
+        let mut elf = TestElf::new(class, data, ET_EXEC);
+        elf.entry = BASE;
+        elf.flags = EF_RISCV_RVC | EF_RISCV_FLOAT_ABI_DOUBLE;
+
+        let text = [0x13, 0, 0, 0].repeat(4);
+        elf.segments.push(Segment {
+            typ: PT_LOAD,
+            vaddr: BASE,
+            data: text.clone(),
+            memory_size: 0x20,
+        });
+
+        let mut symbols = elf.writer();
+        symbols.symbol(0, 0, 0, 0, 0);
+        symbols.symbol(1, 0x02, 1, BASE + 4, 8); // local func loop
+        symbols.symbol(13, 0x00, 1, BASE, 0); // local alias
+        symbols.symbol(6, 0x12, 1, BASE, 16); // global func _start
+        symbols.symbol(19, 0x01, 1, BASE + 8, 4); // local object dup
+        symbols.symbol(19, 0x11, 1, BASE + 12, 4); // global object dup
+        symbols.symbol(23, 0x10, 0, 0, 0); // undefined ext
+
+        let symbol_size = Encoding { class, data }.symbol_size() as u64;
+        elf.sections = vec![
+            Section {
+                name: ".text",
+                typ: SHT_PROGBITS,
+                addr: BASE,
+                data: text,
+                ..Default::default()
+            },
+            Section {
+                name: ".symtab",
+                typ: SHT_SYMTAB,
+                data: symbols.bytes,
+                link: 3,
+                entry_size: symbol_size,
+                ..Default::default()
+            },
+            Section {
+                name: ".strtab",
+                typ: SHT_STRTAB,
+                data: STRINGS.to_vec(),
+                ..Default::default()
+            },
+            Section {
+                name: ".riscv.attributes",
+                typ: SHT_RISCV_ATTRIBUTES,
+                data: attributes(),
+                ..Default::default()
+            },
+        ];
+
+        elf.build()
+    }
+
+    fn check_sample(elf: &Elf, class: Class, data: Data) {
+        assert_eq!(elf.class(), class);
+        assert_eq!(elf.data(), data);
+        assert_eq!(elf.typ(), Typ::Executable);
+        assert_eq!(elf.machine(), Machine::RiscV);
+        assert_eq!(elf.entry(), BASE);
+        assert!(elf.riscv_flags().rvc);
+        assert_eq!(elf.riscv_flags().float_abi, FloatAbi::Double);
+
+        assert_eq!(elf.num_program_headers(), 1);
+        let program_header = elf.program_header(0).unwrap();
+        assert_eq!(program_header.typ(), ProgramHeaderTyp::Load);
+        assert_eq!(program_header.flags(), 5);
+        assert_eq!(program_header.vaddr(), BASE);
+        assert_eq!(program_header.paddr(), BASE);
+        assert_eq!(program_header.file_size(), 16);
+        assert_eq!(program_header.memory_size(), 0x20);
+        assert_eq!(program_header.alignment(), 0x1000);
+        assert_eq!(elf.program_header_data(program_header).unwrap()[..4],
+                   [0x13, 0, 0, 0]);
+
+        let text = elf.section_header_by_name(".text").unwrap();
+        assert_eq!(text.typ(), SectionHeaderTyp::ProgBits);
+        assert_eq!(text.addr(), BASE);
+        assert_eq!(text.size(), 16);
+        assert_eq!(elf.section_name(text).unwrap(), ".text");
+
+        let symbols = elf.symbol_table().unwrap();
+        assert_eq!(symbols.len(), 6);
+        let start = symbols.by_name("_start").unwrap();
+        assert_eq!(start.value(), BASE);
+        assert_eq!(start.size(), 16);
+        assert_eq!(start.typ(), SymbolTyp::Func);
+        assert_eq!(start.binding(), SymbolBinding::Global);
+        assert_eq!(start.section_index(), 1);
+    }
+
+    #[test]
+    fn parse_elf32_little_endian() {
+        let bytes = sample(Class::Elf32, Data::LittleEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+        check_sample(&elf, Class::Elf32, Data::LittleEndian);
+    }
+
+    #[test]
+    fn parse_elf64_big_endian() {
+        let bytes = sample(Class::Elf64, Data::BigEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+        check_sample(&elf, Class::Elf64, Data::BigEndian);
+
+        // NOTE(patrik): Big endian files can be inspected but not run
+        assert!(matches!(elf.check_hart(2 << 62),
+                         Err(ElfError::UnsupportedByteOrder)));
+    }
+
+    #[test]
+    fn parse_elf32_big_endian() {
+        let bytes = sample(Class::Elf32, Data::BigEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+        check_sample(&elf, Class::Elf32, Data::BigEndian);
+    }
+
+    #[test]
+    fn parse_rejects_truncated_tables() {
+        let bytes = sample(Class::Elf64, Data::LittleEndian);
+        assert!(matches!(Elf::parse(&bytes[..40]),
+                         Err(ElfError::TruncatedHeader)));
+        assert!(matches!(Elf::parse(&bytes[..bytes.len() - 1]),
+                         Err(ElfError::SectionHeaderTableOutOfBounds)));
+        assert!(matches!(Elf::parse(b"\x7fELF\x03\x01\x01\0\0\0\0\0\0\0\0\0"),
+                         Err(ElfError::InvalidClass(3))));
+    }
+
+    #[test]
+    fn section_lookup_by_name() {
+        let bytes = sample(Class::Elf64, Data::LittleEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.section_by_name(".text"),
+                   Some(&[0x13, 0, 0, 0].repeat(4)[..]));
+        assert_eq!(elf.section_by_name(".strtab"), Some(STRINGS));
+        assert!(elf.section_by_name(".data").is_none());
+        assert!(elf.section_by_name("").is_some());
+
+        let names = elf.section_header_iter()
+            .map(|header| elf.section_name(header).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["", ".text", ".symtab", ".strtab",
+                           ".riscv.attributes", ".shstrtab"]);
+    }
+
+    #[test]
+    fn symbol_lookup_by_name() {
+        let bytes = sample(Class::Elf32, Data::LittleEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+        let symbols = elf.symbol_table().unwrap();
+
+        let local = symbols.by_name("loop").unwrap();
+        assert_eq!(local.value(), BASE + 4);
+        assert_eq!(local.size(), 8);
+        assert_eq!(local.binding(), SymbolBinding::Local);
+
+        // The global symbol wins over the local one with the same name
+        let dup = symbols.by_name("dup").unwrap();
+        assert_eq!(dup.value(), BASE + 12);
+        assert_eq!(dup.typ(), SymbolTyp::Object);
+
+        // Undefined symbols can't be looked up
+        assert!(symbols.by_name("ext").is_none());
+        assert!(symbols.by_name("missing").is_none());
+
+        assert_eq!(elf.symbol_address("_start"), Some(BASE));
+    }
+
+    #[test]
+    fn symbol_lookup_by_address() {
+        let bytes = sample(Class::Elf64, Data::BigEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+        let symbols = elf.symbol_table().unwrap();
+
+        // The global symbol is preferred at an address with several
+        assert_eq!(symbols.format_address(BASE), "_start");
+        assert_eq!(symbols.format_address(BASE + 2), "_start+0x2");
+        assert_eq!(symbols.format_address(BASE + 6), "loop+0x2");
+        assert_eq!(symbols.format_address(BASE + 10), "dup+0x2");
+        assert_eq!(symbols.format_address(BASE + 0x40), "dup+0x34");
+        assert_eq!(symbols.format_address(BASE - 1), "0x7fffffff");
+
+        let (symbol, offset) = symbols.lookup(BASE + 12).unwrap();
+        assert_eq!(symbol.binding(), SymbolBinding::Global);
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn riscv_attributes() {
+        let bytes = sample(Class::Elf32, Data::LittleEndian);
+        let elf = Elf::parse(&bytes).unwrap();
+        let attributes = elf.riscv_attributes().unwrap().unwrap();
+
+        assert_eq!(attributes.arch.as_deref(),
+                   Some("rv32i2p1_m2p0_c2p0_zicsr2p0"));
+        assert_eq!(attributes.stack_align, Some(16));
+        assert_eq!(attributes.unaligned_access, Some(true));
+        assert_eq!(attributes.priv_spec, Some((1, 11, 0)));
+        assert_eq!(attributes.extensions(),
+                   ["i", "m", "c", "zicsr", "e", "zmmul", "zca"]);
+
+        assert!(matches!(RiscVAttributes::parse(b"B"),
+                         Err(ElfError::InvalidAttributesVersion(b'B'))));
+        assert!(matches!(RiscVAttributes::parse(b"A\x20\0\0\0riscv\0"),
+                         Err(ElfError::TruncatedAttributes)));
+    }
+
+    #[test]
+    fn riscv_attributes_missing() {
+        let mut elf = TestElf::new(Class::Elf64, Data::LittleEndian, ET_EXEC);
+        elf.sections.push(Section {
+            name: ".text",
+            typ: SHT_PROGBITS,
+            ..Default::default()
+        });
+
+        let bytes = elf.build();
+        let elf = Elf::parse(&bytes).unwrap();
+        assert!(elf.riscv_attributes().unwrap().is_none());
+    }
+
+    #[test]
+    fn isa_string_extensions() {
+        assert_eq!(isa_extensions("rv64gc"),
+                   ["c", "i", "m", "a", "f", "d", "zicsr", "zifencei",
+                    "zca", "e", "zmmul", "zaamo", "zalrsc"]);
+        assert_eq!(isa_extensions("RV32IMA_Zba1p0_xfoo"),
+                   ["i", "m", "a", "zba", "xfoo", "e", "zmmul", "zaamo",
+                    "zalrsc"]);
+        assert_eq!(isa_extensions("rv64i2p1_zicsr2p0_zifencei2"),
+                   ["i", "zicsr", "zifencei", "e"]);
+        assert!(isa_extensions("x86_64").is_empty());
+
+        assert_eq!(strip_version("zicsr2p0"), "zicsr");
+        assert_eq!(strip_version("zve32x"), "zve32x");
+        assert_eq!(strip_version("zve32x1p0"), "zve32x");
+    }
+
+    #[test]
+    fn dynamic_relocations_elf32() {
+        let mut elf = TestElf::new(Class::Elf32, Data::LittleEndian, ET_DYN);
+
+        // NOTE(patrik): The tables are found through their addresses, so
+        // they are in the loaded segment
+        let mut image = elf.writer();
+        image.pad_to(0x20);
+        image.rela(0x10, 0, 3, -8);
+        image.rela(0x14, 1, 1, 4);
+        image.pad_to(0x40);
+        image.symbol(0, 0, 0, 0, 0);
+        image.symbol(1, 0x12, 1, 0x10, 0);
+        image.bytes.extend_from_slice(b"\0f\0");
+
+        let mut dynamic = elf.writer();
+        dynamic.dynamic(DT_RELA, 0x20);
+        dynamic.dynamic(DT_RELASZ, 24);
+        dynamic.dynamic(DT_SYMTAB, 0x40);
+        dynamic.dynamic(DT_STRTAB, 0x60);
+        dynamic.dynamic(DT_NULL, 0);
+
+        elf.segments = vec![
+            Segment {
+                typ: PT_LOAD,
+                vaddr: 0,
+                memory_size: image.bytes.len() as u64,
+                data: image.bytes,
+            },
+            Segment {
+                typ: PT_DYNAMIC,
+                vaddr: 0x100,
+                memory_size: dynamic.bytes.len() as u64,
+                data: dynamic.bytes,
+            },
+        ];
+
+        let bytes = elf.build();
+        let elf = Elf::parse(&bytes).unwrap();
+        assert!(elf.is_position_independent());
+
+        let relocations = elf.dynamic_relocations().unwrap();
+        assert_eq!(relocations.len(), 2);
+        assert_eq!(relocations[0].offset, 0x10);
+        assert_eq!(relocations[0].typ, 3);
+        assert_eq!(relocations[0].symbol, 0);
+        assert_eq!(relocations[0].addend, -8);
+        assert_eq!(relocations[1].symbol, 1);
+        assert_eq!(relocations[1].typ, 1);
+        assert_eq!(relocations[1].addend, 4);
+
+        let symbol = elf.dynamic_symbol(1).unwrap();
+        assert_eq!(symbol.name(), "f");
+        assert_eq!(symbol.value(), 0x10);
     }
 }
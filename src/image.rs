@@ -0,0 +1,116 @@
+//! Module to parse RISC-V Linux kernel ´Image´ files
+//!
+//! The layout of the header is described in
+//! Documentation/arch/riscv/boot-image-header.rst in the Linux tree
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// The file is smaller than the 64 byte header
+    TooSmall,
+
+    /// Neither the magic (0x30) nor the magic2 (0x38) field is valid
+    InvalidMagic,
+
+    /// ´text_offset´ is not aligned to a page
+    UnalignedTextOffset(u64),
+}
+
+type Result<T> = std::result::Result<T, ImageError>;
+
+/// Size of the Image header
+const HEADER_SIZE: usize = 64;
+
+/// "RISCV\0\0\0", deprecated since version 0.2 of the header but still
+/// written by the kernel
+const MAGIC: u64 = 0x5643534952;
+
+/// "RSC\x05"
+const MAGIC2: u32 = 0x05435352;
+
+pub struct LinuxImage<'a> {
+    bytes: &'a [u8],
+
+    /// Offset from the start of the RAM the image is loaded at
+    text_offset: u64,
+
+    /// Size of the kernel in memory, including the BSS
+    image_size: u64,
+
+    flags: u64,
+    version: u32,
+}
+
+impl<'a> std::fmt::Debug for LinuxImage<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LinuxImage")
+            .field("text_offset", &self.text_offset)
+            .field("image_size", &self.image_size)
+            .field("flags", &self.flags)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> LinuxImage<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ImageError::TooSmall);
+        }
+
+        let text_offset = read_u64(bytes, 0x08);
+        let image_size = read_u64(bytes, 0x10);
+        let flags = read_u64(bytes, 0x18);
+        let version = read_u32(bytes, 0x20);
+        let magic = read_u64(bytes, 0x30);
+        let magic2 = read_u32(bytes, 0x38);
+
+        if magic != MAGIC && magic2 != MAGIC2 {
+            return Err(ImageError::InvalidMagic);
+        }
+
+        if text_offset & 0xfff != 0 {
+            return Err(ImageError::UnalignedTextOffset(text_offset));
+        }
+
+        Ok(Self {
+            bytes,
+            text_offset,
+            image_size,
+            flags,
+            version,
+        })
+    }
+
+    /// The whole file, it's loaded as is
+    pub fn data(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn text_offset(&self) -> u64 {
+        self.text_offset
+    }
+
+    /// Size the kernel occupies in memory
+    pub fn image_size(&self) -> u64 {
+        // NOTE(patrik): Kernels before version 0.2 of the header leave the
+        // size as 0, the file size is the best guess then
+        self.image_size.max(self.bytes.len() as u64)
+    }
+
+    /// Bit 0 of the flags is set for big endian kernels
+    pub fn is_big_endian(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    pub fn version(&self) -> (u16, u16) {
+        ((self.version >> 16) as u16, self.version as u16)
+    }
+}
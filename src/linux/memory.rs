@@ -0,0 +1,285 @@
+//! Address space of a user-mode process
+//!
+//! Ranges are mapped by the loader, ´brk´ and ´mmap´, but only the pages
+//! the guest touches get memory, so large mappings cost nothing until
+//! they are used. The hart runs with translation off, so the guest
+//! addresses are used as they are. An access outside the mapped ranges
+//! raises an access fault, the runner stops the process at it like a
+//! SIGSEGV would.
+
+use std::collections::{ BTreeMap, HashMap };
+
+use crate::memory::{ Mmu, TypeWidth };
+use super::process::{ PAGE_SIZE, USER_END };
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// What a mapped page that was never written to reads as
+static ZERO_PAGE: [u8; PAGE_SIZE as usize] = [0; PAGE_SIZE as usize];
+
+pub struct UserMemory {
+    /// Mapped ranges, end by start. The ranges are page aligned, they
+    /// don't overlap or touch each other
+    ranges: BTreeMap<u64, u64>,
+
+    /// Pages of the mapped ranges that have been written to, by page
+    /// number
+    pages: HashMap<u64, Page>,
+
+    /// Address of the load reservation from LR
+    reservation: Option<u64>,
+}
+
+fn page_number(addr: u64) -> u64 {
+    addr / PAGE_SIZE
+}
+
+fn page_offset(addr: u64) -> usize {
+    (addr % PAGE_SIZE) as usize
+}
+
+/// The page aligned range [start, end) covering [addr, addr + len), None
+/// if it goes past the end of the address space
+fn page_range(addr: u64, len: u64) -> Option<(u64, u64)> {
+    let end = addr.checked_add(len)?.checked_next_multiple_of(PAGE_SIZE)?;
+    if end > USER_END {
+        return None;
+    }
+
+    Some((addr - addr % PAGE_SIZE, end))
+}
+
+impl UserMemory {
+    pub fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+            pages: HashMap::new(),
+            reservation: None,
+        }
+    }
+
+    /// Map [addr, addr + len), pages that aren't mapped yet read as
+    /// zero, the ones that are keep their contents. False if the range
+    /// goes past the end of the address space
+    pub fn map(&mut self, addr: u64, len: u64) -> bool {
+        let Some((mut start, mut end)) = page_range(addr, len) else {
+            return false;
+        };
+
+        // NOTE(patrik): Ranges overlapping or touching the new one are
+        // merged into it
+        let merged = self.ranges.range(..=end).rev()
+            .take_while(|(_, &range_end)| range_end >= start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect::<Vec<_>>();
+        for (range_start, range_end) in merged {
+            self.ranges.remove(&range_start);
+            start = start.min(range_start);
+            end = end.max(range_end);
+        }
+
+        self.ranges.insert(start, end);
+        true
+    }
+
+    /// Unmap [addr, addr + len), the contents of the pages are dropped
+    pub fn unmap(&mut self, addr: u64, len: u64) {
+        if addr >= USER_END {
+            return;
+        }
+
+        let start = addr - addr % PAGE_SIZE;
+        let end = addr.saturating_add(len).min(USER_END)
+            .next_multiple_of(PAGE_SIZE);
+
+        let overlapping = self.ranges.range(..end).rev()
+            .take_while(|(_, &range_end)| range_end > start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect::<Vec<_>>();
+        for (range_start, range_end) in overlapping {
+            self.ranges.remove(&range_start);
+            if range_start < start {
+                self.ranges.insert(range_start, start);
+            }
+
+            if range_end > end {
+                self.ranges.insert(end, range_end);
+            }
+        }
+
+        // NOTE(patrik): Going through the touched pages instead of the
+        // range, the range may cover far more pages than exist
+        let (first, last) = (page_number(start), page_number(end));
+        self.pages.retain(|&page, _| page < first || page >= last);
+    }
+
+    /// Check if every page of [addr, addr + len) is mapped
+    pub fn is_mapped(&self, addr: u64, len: u64) -> bool {
+        let Some((start, end)) = page_range(addr, len) else {
+            return false;
+        };
+
+        if start == end {
+            return true;
+        }
+
+        // NOTE(patrik): Ranges never touch, so one range has to cover it
+        self.ranges.range(..=start).next_back()
+            .is_some_and(|(_, &range_end)| range_end >= end)
+    }
+
+    /// Check if no page of [addr, addr + len) is mapped, and that it's
+    /// inside the address space so it can be
+    pub fn is_unmapped(&self, addr: u64, len: u64) -> bool {
+        let Some((start, end)) = page_range(addr, len) else {
+            return false;
+        };
+
+        start == end || self.ranges.range(..end).next_back()
+            .is_none_or(|(_, &range_end)| range_end <= start)
+    }
+
+    /// Find the highest unmapped hole of ´len´ bytes in [bottom, top)
+    pub fn find_unmapped(&self, bottom: u64, top: u64, len: u64)
+        -> Option<u64>
+    {
+        let mut top = top;
+        for (&range_start, &range_end) in self.ranges.range(..top).rev() {
+            let addr = top.checked_sub(len)?;
+            if range_end <= addr {
+                break;
+            }
+
+            top = top.min(range_start);
+        }
+
+        top.checked_sub(len).filter(|&addr| addr >= bottom)
+    }
+
+    /// The page at ´addr´, None if it isn't mapped
+    fn page(&self, addr: u64) -> Option<&[u8; PAGE_SIZE as usize]> {
+        match self.pages.get(&page_number(addr)) {
+            Some(page) => Some(page),
+            None if self.is_mapped(addr, 1) => Some(&ZERO_PAGE),
+            None => None,
+        }
+    }
+
+    /// The page at ´addr´ to write to, it gets memory the first time.
+    /// None if it isn't mapped
+    fn page_mut(&mut self, addr: u64) -> Option<&mut Page> {
+        let number = page_number(addr);
+        if !self.pages.contains_key(&number) && !self.is_mapped(addr, 1) {
+            return None;
+        }
+
+        Some(self.pages.entry(number)
+             .or_insert_with(|| Box::new([0; PAGE_SIZE as usize])))
+    }
+
+    /// Copy [addr, addr + len) out of memory, None if a page isn't mapped
+    pub fn read_vec(&self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+        let mut addr = addr;
+        let end = addr.checked_add(len)?;
+        while addr < end {
+            let page = self.page(addr)?;
+            let offset = page_offset(addr);
+            let count = (PAGE_SIZE as usize - offset)
+                .min((end - addr) as usize);
+
+            data.extend_from_slice(&page[offset..offset + count]);
+            addr += count as u64;
+        }
+
+        Some(data)
+    }
+
+    /// Copy ´data´ into memory at ´addr´, false if a page isn't mapped.
+    /// Nothing is written then
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> bool {
+        if !self.is_mapped(addr, data.len() as u64) {
+            return false;
+        }
+
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let page = self.page_mut(addr).unwrap();
+            let offset = page_offset(addr);
+            let count = (PAGE_SIZE as usize - offset).min(data.len());
+
+            page[offset..offset + count].copy_from_slice(&data[..count]);
+            addr += count as u64;
+            data = &data[count..];
+        }
+
+        true
+    }
+
+    /// Read the NUL terminated string at ´addr´, None if it runs into an
+    /// unmapped page
+    pub fn read_string(&self, addr: u64) -> Option<Vec<u8>> {
+        let mut string = Vec::new();
+        let mut addr = addr;
+        loop {
+            let page = self.page(addr)?;
+            let rest = &page[page_offset(addr)..];
+            match rest.iter().position(|&c| c == 0) {
+                Some(length) => {
+                    string.extend_from_slice(&rest[..length]);
+                    return Some(string);
+                }
+
+                None => {
+                    string.extend_from_slice(rest);
+                    addr += rest.len() as u64;
+                }
+            }
+        }
+    }
+}
+
+impl Mmu for UserMemory {
+    fn read(&mut self, addr: u64, width: TypeWidth) -> Option<u64> {
+        let size = width.size() as usize;
+
+        // NOTE(patrik): Misaligned accesses are allowed, only the ones
+        // crossing a page need the slow path
+        let bytes = if page_offset(addr) + size <= PAGE_SIZE as usize {
+            self.page(addr).map(|page| {
+                let offset = page_offset(addr);
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&page[offset..offset + size]);
+                bytes
+            })
+        } else {
+            self.read_vec(addr, size as u64).map(|data| {
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&data);
+                bytes
+            })
+        };
+
+        bytes.map(u64::from_le_bytes)
+    }
+
+    fn write(&mut self, addr: u64, value: u64, width: TypeWidth) -> bool {
+        let size = width.size() as usize;
+        if self.reservation.is_some_and(|reserved| {
+            addr < reserved + 8 && reserved < addr + size as u64
+        }) {
+            self.reservation = None;
+        }
+
+        self.write_bytes(addr, &value.to_le_bytes()[..size])
+    }
+
+    fn reserve(&mut self, _hartid: u64, addr: u64) {
+        self.reservation = Some(addr);
+    }
+
+    fn take_reservation(&mut self, _hartid: u64, addr: u64) -> bool {
+        self.reservation.take() == Some(addr)
+    }
+}
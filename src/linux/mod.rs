@@ -0,0 +1,120 @@
+//! Linux user-mode emulation, running ´riscv64-linux´ programs without a
+//! kernel
+//!
+//! The process runs in U-mode with translation off. Its system calls are
+//! done on the host, like ´qemu-riscv64´ does.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::cpu::{ SimpleHart, Hart, Reg };
+use crate::devices::{ Clock, ExitSignal };
+use crate::coredump;
+use crate::elf::Elf;
+use crate::symbolize::Symbolizer;
+
+use memory::UserMemory;
+use process::{ Process, STACK_TOP };
+use syscall::{ Linux, STACK_SIZE };
+
+pub mod memory;
+pub mod process;
+pub mod syscall;
+
+const SIGSEGV: u16 = 11;
+
+/// Run the static or dynamically linked ´program´ with the arguments
+/// ´args´ and return its exit status. Absolute paths, the dynamic linker
+/// included, are looked up in ´sysroot´ first
+pub fn run(program: &Path, args: &[String], sysroot: Option<&Path>) -> u64 {
+    let bytes = std::fs::read(program).unwrap_or_else(|e| {
+        eprintln!("Failed to read '{}': {}", program.display(), e);
+        std::process::exit(1);
+    });
+
+    let process = Process::load(program, &bytes, sysroot)
+        .unwrap_or_else(|e| {
+            eprintln!("Can't run '{}': {:?}", program.display(), e);
+            std::process::exit(1);
+        });
+
+    let memory = Rc::new(RefCell::new(UserMemory::new()));
+    for image in process.images() {
+        let mut memory = memory.borrow_mut();
+        for chunk in &image.chunks {
+            memory.map(chunk.addr, chunk.size);
+            memory.write_bytes(chunk.addr, &chunk.data);
+        }
+    }
+
+    let mut random = [0u8; 16];
+    if let Err(e) = File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+    {
+        eprintln!("Failed to read /dev/urandom: {}", e);
+        std::process::exit(1);
+    }
+
+    let execfn = program.display().to_string();
+    let argv: Vec<String> = std::iter::once(execfn.clone())
+        .chain(args.iter().cloned())
+        .collect();
+    let envp: Vec<String> = std::env::vars()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+
+    let stack = process::build_stack(STACK_TOP, &argv, &envp, &process.auxv,
+                                     &execfn, random);
+    if stack.data.len() as u64 > STACK_SIZE {
+        eprintln!("The arguments and the environment don't fit on the \
+                   stack");
+        std::process::exit(1);
+    }
+
+    memory.borrow_mut().map(STACK_TOP - STACK_SIZE, STACK_SIZE);
+    memory.borrow_mut().write_bytes(stack.sp, &stack.data);
+
+    let exit = ExitSignal::new();
+    let linux = Linux::new(memory.clone(), exit.clone(), program,
+                           process.program_end, sysroot);
+
+    let mut hart = SimpleHart::new(Box::new(memory.clone()));
+    hart.set_clock(Clock::host());
+    hart.set_linux(linux);
+    hart.enter_user(process.entry);
+    hart.set_reg(Reg::X2, stack.sp);
+
+    loop {
+        hart.step();
+
+        if let Some(code) = exit.code() {
+            return code;
+        }
+
+        if let Some(fault) = hart.fault() {
+            let signal = coredump::signal_for_cause(fault.cause);
+            if signal == SIGSEGV {
+                eprintln!("Segmentation fault: {:#x} is not mapped, pc {:#x}",
+                          fault.tval, fault.epc);
+            } else {
+                eprintln!("Unhandled trap: cause {:#x} epc {:#x} tval {:#x}",
+                          fault.cause, fault.epc, fault.tval);
+            }
+
+            // NOTE(patrik): Only the program is described, not the
+            // dynamic linker
+            let symbolizer = Elf::parse(&bytes).ok()
+                .and_then(|elf| Symbolizer::new(&elf, &process.program));
+            if let Some(location) = symbolizer.as_ref()
+                .and_then(|symbolizer| symbolizer.describe(fault.epc))
+            {
+                eprintln!("    at {}", location);
+            }
+
+            return coredump::signal_status(signal);
+        }
+    }
+}
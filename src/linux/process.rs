@@ -0,0 +1,283 @@
+//! Setting up the address space of a new process, like ´execve´ does
+//!
+//! The program and the dynamic linker it asks for with ´PT_INTERP´ are
+//! loaded, and the initial stack gets the arguments, the environment and
+//! the auxiliary vector. The stack at ´sp´ looks like:
+//!   argc
+//!   argv[0] .. argv[argc - 1], NULL
+//!   envp[0] .. envp[n - 1], NULL
+//!   auxv pairs, ending with AT_NULL
+//!   padding, the AT_RANDOM bytes and the strings
+
+use std::path::{ Path, PathBuf };
+
+use crate::elf::{ Elf, ElfError, Typ, ProgramHeaderTyp };
+use crate::loader::{ self, LoadedImage, LoadError, SegmentAddress };
+use crate::cpu::MISA;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Where position independent programs are loaded, Linux uses 2/3 of the
+/// Sv39 user address space
+pub const PROGRAM_BASE: u64 = 0x2a_aaaa_a000;
+
+/// Where the dynamic linker is loaded
+pub const INTERPRETER_BASE: u64 = 0x3f_c000_0000;
+
+/// The initial stack grows down from here, the top of the Sv39 user
+/// address space
+pub const STACK_TOP: u64 = 0x3f_ffff_f000;
+
+/// End of the Sv39 user address space, nothing is mapped at or above it
+pub const USER_END: u64 = 0x40_0000_0000;
+
+/// Ticks per second of the ´times´ clock
+const CLOCK_TICKS: u64 = 100;
+
+#[derive(Debug)]
+pub enum ProcessError {
+    /// Failed to read a file
+    Io(PathBuf, std::io::Error),
+
+    /// Failed to parse the program or the dynamic linker
+    Elf(PathBuf, ElfError),
+
+    /// Failed to load the program or the dynamic linker
+    Load(PathBuf, LoadError),
+
+    /// The dynamic linker is not a shared object
+    InvalidInterpreter(PathBuf),
+}
+
+pub type Result<T> = std::result::Result<T, ProcessError>;
+
+/// The loaded program and the dynamic linker, ready to get a stack
+#[derive(Debug)]
+pub struct Process {
+    pub program: LoadedImage,
+
+    /// The dynamic linker from ´PT_INTERP´, None for static programs
+    pub interpreter: Option<LoadedImage>,
+
+    /// Where the hart starts, the entry of the dynamic linker if there is
+    /// one
+    pub entry: u64,
+
+    /// End of the program, the heap of ´brk´ starts at the next page
+    pub program_end: u64,
+
+    /// The auxiliary vector without the entries that point into the
+    /// stack, those are added by ´build_stack´
+    pub auxv: Vec<(u64, u64)>,
+}
+
+/// The initial stack, ´data´ is placed at [sp, STACK_TOP)
+#[derive(Debug)]
+pub struct Stack {
+    pub sp: u64,
+    pub data: Vec<u8>,
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|e| ProcessError::Io(path.to_path_buf(), e))
+}
+
+/// Parse the ELF in ´bytes´ and make sure the hart can run it, like
+/// ´check_elf´ does for the machine
+fn parse_elf<'a>(path: &Path, bytes: &'a [u8]) -> Result<Elf<'a>> {
+    let elf = Elf::parse(bytes)
+        .map_err(|e| ProcessError::Elf(path.to_path_buf(), e))?;
+    elf.validate().and_then(|_| elf.check_hart(MISA))
+        .map_err(|e| ProcessError::Elf(path.to_path_buf(), e))?;
+
+    Ok(elf)
+}
+
+/// Find the dynamic linker ´path´ inside ´sysroot´, or on the host when
+/// there is no sysroot
+pub fn interpreter_path(path: &str, sysroot: Option<&Path>) -> PathBuf {
+    match sysroot {
+        Some(sysroot) => sysroot.join(path.trim_start_matches('/')),
+        None => PathBuf::from(path),
+    }
+}
+
+impl Process {
+    /// Load the program in ´bytes´, read from ´path´, and its dynamic
+    /// linker from ´sysroot´
+    pub fn load(path: &Path, bytes: &[u8], sysroot: Option<&Path>)
+        -> Result<Self>
+    {
+        let elf = parse_elf(path, bytes)?;
+
+        let program = loader::load_elf(&elf, SegmentAddress::Virtual,
+                                       PROGRAM_BASE)
+            .map_err(|e| ProcessError::Load(path.to_path_buf(), e))?;
+
+        let program_end = program.chunks.iter()
+            .map(|chunk| chunk.addr + chunk.size)
+            .max()
+            .unwrap_or(program.bias);
+
+        // NOTE(patrik): Without ´PT_PHDR´ the table is found through the
+        // segment that covers it, some linkers don't load it at all
+        let phdr = elf.program_header_vaddr()
+            .map_or(0, |vaddr| vaddr.wrapping_add(program.bias));
+
+        let mut auxv = vec![
+            (AT_PHDR, phdr),
+            (AT_PHENT, elf.program_header_entry_size() as u64),
+            (AT_PHNUM, elf.num_program_headers() as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, program.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, MISA & 0x3ffffff),
+            (AT_CLKTCK, CLOCK_TICKS),
+            (AT_SECURE, 0),
+        ];
+
+        let interpreter = elf.interpreter()
+            .map_err(|e| ProcessError::Elf(path.to_path_buf(), e))?;
+        let Some(interpreter) = interpreter else {
+            return Ok(Self {
+                entry: program.entry,
+                program,
+                interpreter: None,
+                program_end,
+                auxv,
+            });
+        };
+
+        let interpreter_path = interpreter_path(interpreter, sysroot);
+        let interpreter = Self::load_interpreter(&interpreter_path)?;
+        for (key, value) in auxv.iter_mut() {
+            if *key == AT_BASE {
+                *value = interpreter.bias;
+            }
+        }
+
+        Ok(Self {
+            entry: interpreter.entry,
+            program,
+            interpreter: Some(interpreter),
+            program_end,
+            auxv,
+        })
+    }
+
+    fn load_interpreter(path: &Path) -> Result<LoadedImage> {
+        let bytes = read_file(path)?;
+        let elf = parse_elf(path, &bytes)?;
+
+        // NOTE(patrik): A dynamic linker asking for another one would
+        // never end
+        let has_interpreter = elf.program_header_iter()
+            .any(|header| header.typ() == ProgramHeaderTyp::Interp);
+        if elf.typ() != Typ::Shared || has_interpreter {
+            return Err(ProcessError::InvalidInterpreter(path.to_path_buf()));
+        }
+
+        loader::load_elf(&elf, SegmentAddress::Virtual, INTERPRETER_BASE)
+            .map_err(|e| ProcessError::Load(path.to_path_buf(), e))
+    }
+
+    /// Every loaded segment, the program's first
+    pub fn images(&self) -> impl Iterator<Item = &LoadedImage> {
+        std::iter::once(&self.program).chain(self.interpreter.as_ref())
+    }
+}
+
+/// Build the initial stack below ´top´ for the arguments ´argv´ and the
+/// environment ´envp´, ´random´ is what AT_RANDOM points to and ´execfn´
+/// the path the program was run as
+pub fn build_stack(top: u64, argv: &[String], envp: &[String],
+                   auxv: &[(u64, u64)], execfn: &str, random: [u8; 16])
+    -> Stack
+{
+    // NOTE(patrik): The strings go at the top, ´strings´ is built from
+    // ´strings_start´ up
+    let strings_size: usize = std::iter::once(execfn)
+        .chain(argv.iter().map(|arg| arg.as_str()))
+        .chain(envp.iter().map(|var| var.as_str()))
+        .map(|s| s.len() + 1)
+        .sum();
+    let strings_start = top - strings_size as u64;
+
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut push_string = |s: &str| {
+        let addr = strings_start + strings.len() as u64;
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+        addr
+    };
+
+    let execfn_addr = push_string(execfn);
+    let argv_addrs: Vec<u64> = argv.iter()
+        .map(|arg| push_string(arg))
+        .collect();
+    let envp_addrs: Vec<u64> = envp.iter()
+        .map(|var| push_string(var))
+        .collect();
+
+    let random_addr = (strings_start - random.len() as u64) & !0xf;
+
+    let mut auxv = auxv.to_vec();
+    auxv.push((AT_RANDOM, random_addr));
+    auxv.push((AT_EXECFN, execfn_addr));
+    auxv.push((AT_NULL, 0));
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(&argv_addrs);
+    words.push(0);
+    words.extend(&envp_addrs);
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    // NOTE(patrik): The ABI wants ´sp´ 16 byte aligned
+    let sp = (random_addr - words.len() as u64 * 8) & !0xf;
+
+    let mut data = vec![0; (top - sp) as usize];
+    for (index, word) in words.iter().enumerate() {
+        data[index * 8..index * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+
+    let random_offset = (random_addr - sp) as usize;
+    data[random_offset..random_offset + random.len()]
+        .copy_from_slice(&random);
+
+    let strings_offset = (strings_start - sp) as usize;
+    data[strings_offset..].copy_from_slice(&strings);
+
+    Stack {
+        sp,
+        data,
+    }
+}
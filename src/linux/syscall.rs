@@ -0,0 +1,946 @@
+//! Linux system calls of a user-mode process
+//!
+//! The guest makes a system call with ECALL, the number in a7 and the
+//! arguments in a0 to a5. The result goes back in a0, errors as a negative
+//! errno. The calls are done with the host's file system and clocks, the
+//! numbers and structures are the ones of riscv64 Linux.
+
+use std::cell::RefCell;
+use std::collections::{ HashMap, HashSet };
+use std::fs::{ File, OpenOptions, Metadata };
+use std::io::{ Read, Write, Seek, SeekFrom, IsTerminal };
+use std::os::unix::fs::{ FileExt, MetadataExt, OpenOptionsExt };
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+use crate::devices::ExitSignal;
+use super::memory::UserMemory;
+use super::process::{ PAGE_SIZE, INTERPRETER_BASE, USER_END };
+
+const SYS_FACCESSAT: u64 = 48;
+const SYS_IOCTL: u64 = 29;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_CLOCK_GETRES: u64 = 114;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EAGAIN: i64 = 11;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const ENOTDIR: i64 = 20;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_DIRECTORY: u64 = 0o200000;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+
+const TCGETS: u64 = 0x5401;
+const TIOCGWINSZ: u64 = 0x5413;
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_CMD_MASK: u64 = 0x7f;
+
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Size of the initial stack, also what ´getrlimit´ reports
+pub const STACK_SIZE: u64 = 8 << 20;
+
+/// Largest read or write done at once, the guest gets a short count for
+/// bigger ones
+const MAX_TRANSFER: u64 = 1 << 20;
+
+/// Size of ´struct stat´
+const STAT_SIZE: usize = 128;
+
+/// Size of one field of ´struct utsname´
+const UTSNAME_FIELD: usize = 65;
+
+/// Size of ´struct termios´, without the speeds like the kernel has it
+const TERMIOS_SIZE: usize = 36;
+
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// An open file descriptor of the guest
+struct OpenFile {
+    stream: Stream,
+
+    /// Host path the file was opened at, ´openat´ resolves paths relative
+    /// to directories with it
+    path: PathBuf,
+}
+
+impl OpenFile {
+    fn is_terminal(&self) -> bool {
+        match &self.stream {
+            Stream::Stdin => std::io::stdin().is_terminal(),
+            Stream::Stdout => std::io::stdout().is_terminal(),
+            Stream::Stderr => std::io::stderr().is_terminal(),
+            Stream::File(file) => file.is_terminal(),
+        }
+    }
+
+    fn metadata(&self) -> std::io::Result<Metadata> {
+        match &self.stream {
+            Stream::File(file) => file.metadata(),
+
+            // NOTE(patrik): The standard streams have no ´File´, the
+            // link in /proc goes to whatever they are
+            _ => std::fs::metadata(&self.path),
+        }
+    }
+}
+
+/// Negative errno of a host error, the host is expected to be Linux so
+/// the numbers are the same as the guest's
+fn errno(e: std::io::Error) -> i64 {
+    -(e.raw_os_error().unwrap_or(EIO as i32) as i64)
+}
+
+/// State of the process the system calls work on
+pub struct Linux {
+    memory: Rc<RefCell<UserMemory>>,
+    exit: ExitSignal,
+
+    files: HashMap<u64, OpenFile>,
+
+    /// Absolute paths are looked up here first, like the dynamic linker
+    sysroot: Option<PathBuf>,
+
+    /// Host path of the program, for ´/proc/self/exe´
+    program: PathBuf,
+
+    /// Start of the heap of ´brk´, right after the program
+    heap_start: u64,
+    heap_end: u64,
+
+    /// ´mmap´ without an address places the mapping below this
+    mmap_top: u64,
+
+    /// Start of ´CLOCK_MONOTONIC´
+    start: Instant,
+
+    /// System calls already warned about
+    unsupported: HashSet<u64>,
+}
+
+impl Linux {
+    /// Set up the system calls of a process in ´memory´, ´program_end´ is
+    /// where the heap starts
+    pub fn new(memory: Rc<RefCell<UserMemory>>, exit: ExitSignal,
+               program: &Path, program_end: u64, sysroot: Option<&Path>)
+        -> Self
+    {
+        let mut files = HashMap::new();
+        let streams = [Stream::Stdin, Stream::Stdout, Stream::Stderr];
+        for (fd, stream) in streams.into_iter().enumerate() {
+            files.insert(fd as u64, OpenFile {
+                stream,
+                path: PathBuf::from(format!("/proc/self/fd/{}", fd)),
+            });
+        }
+
+        let heap_start = program_end.next_multiple_of(PAGE_SIZE);
+
+        Self {
+            memory,
+            exit,
+            files,
+            sysroot: sysroot.map(|path| path.to_path_buf()),
+            program: std::fs::canonicalize(program)
+                .unwrap_or_else(|_| program.to_path_buf()),
+            heap_start,
+            heap_end: heap_start,
+            mmap_top: INTERPRETER_BASE,
+            start: Instant::now(),
+            unsupported: HashSet::new(),
+        }
+    }
+
+    /// Do the system call ´number´, returns the value for a0
+    pub fn syscall(&mut self, number: u64, args: [u64; 6]) -> u64 {
+        let result = match number {
+            SYS_READ => self.read(args[0], args[1], args[2]),
+            SYS_WRITE => self.write(args[0], args[1], args[2]),
+            SYS_READV => self.readv(args[0], args[1], args[2]),
+            SYS_WRITEV => self.writev(args[0], args[1], args[2]),
+            SYS_OPENAT => {
+                self.openat(args[0] as i64, args[1], args[2], args[3])
+            }
+
+            SYS_CLOSE => self.close(args[0]),
+            SYS_LSEEK => self.lseek(args[0], args[1] as i64, args[2]),
+            SYS_FSTAT => self.fstat(args[0], args[1]),
+            SYS_NEWFSTATAT => {
+                self.newfstatat(args[0] as i64, args[1], args[2], args[3])
+            }
+
+            SYS_FACCESSAT => self.faccessat(args[0] as i64, args[1]),
+            SYS_READLINKAT => {
+                self.readlinkat(args[0] as i64, args[1], args[2], args[3])
+            }
+
+            SYS_IOCTL => self.ioctl(args[0], args[1], args[2]),
+            SYS_BRK => self.brk(args[0]),
+            SYS_MMAP => {
+                self.mmap(args[0], args[1], args[3], args[4] as i64, args[5])
+            }
+
+            SYS_MUNMAP => self.munmap(args[0], args[1]),
+            SYS_MPROTECT => self.mprotect(args[0], args[1]),
+            SYS_MADVISE => 0,
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit.exit(args[0] & 0xff);
+                0
+            }
+
+            SYS_CLOCK_GETTIME => self.clock_gettime(args[0], args[1]),
+            SYS_CLOCK_GETRES => self.clock_getres(args[1]),
+            SYS_GETRANDOM => self.getrandom(args[0], args[1]),
+            SYS_UNAME => self.uname(args[0]),
+
+            // NOTE(patrik): There is only one thread and signals are never
+            // delivered, the C libraries only need these to succeed
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => {
+                std::process::id() as i64
+            }
+
+            SYS_GETPPID => std::os::unix::process::parent_id() as i64,
+            SYS_SET_ROBUST_LIST => 0,
+            SYS_RT_SIGACTION => self.zero_fill(args[2], 24),
+            SYS_RT_SIGPROCMASK => self.zero_fill(args[2], args[3]),
+            SYS_FUTEX => {
+                if args[1] & FUTEX_CMD_MASK == FUTEX_WAIT {
+                    -EAGAIN
+                } else {
+                    0
+                }
+            }
+
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => {
+                // NOTE(patrik): The owner of /proc/self is the user the
+                // emulator runs as
+                let metadata = std::fs::metadata("/proc/self");
+                match (number, metadata) {
+                    (SYS_GETUID | SYS_GETEUID, Ok(m)) => m.uid() as i64,
+                    (_, Ok(m)) => m.gid() as i64,
+                    (_, Err(_)) => 0,
+                }
+            }
+
+            SYS_PRLIMIT64 => self.prlimit64(args[1], args[3]),
+
+            _ => {
+                if self.unsupported.insert(number) {
+                    eprintln!("Unsupported Linux system call {}", number);
+                }
+
+                -ENOSYS
+            }
+        };
+
+        result as u64
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut OpenFile, i64> {
+        self.files.get_mut(&fd).ok_or(-EBADF)
+    }
+
+    /// Write zeros over [addr, addr + len) if ´addr´ isn't NULL
+    fn zero_fill(&mut self, addr: u64, len: u64) -> i64 {
+        if addr == 0 {
+            return 0;
+        }
+
+        let zeros = vec![0; len.min(MAX_TRANSFER) as usize];
+        if !self.memory.borrow_mut().write_bytes(addr, &zeros) {
+            return -EFAULT;
+        }
+
+        0
+    }
+
+    /// Read the path at ´addr´ and find it on the host, relative paths
+    /// start at the directory ´dirfd´
+    fn host_path(&self, dirfd: i64, addr: u64) -> Result<PathBuf, i64> {
+        let path = self.memory.borrow().read_string(addr).ok_or(-EFAULT)?;
+        let path = String::from_utf8(path).map_err(|_| -ENOENT)?;
+
+        if path.starts_with('/') {
+            // NOTE(patrik): The sysroot goes first so the libraries of the
+            // guest are found instead of the host's
+            if let Some(sysroot) = &self.sysroot {
+                let inside = sysroot.join(path.trim_start_matches('/'));
+                if inside.exists() {
+                    return Ok(inside);
+                }
+            }
+
+            return Ok(PathBuf::from(path));
+        }
+
+        if dirfd == AT_FDCWD {
+            return Ok(PathBuf::from(path));
+        }
+
+        let directory = self.files.get(&(dirfd as u64)).ok_or(-EBADF)?;
+        Ok(directory.path.join(path))
+    }
+
+    fn read(&mut self, fd: u64, buf: u64, count: u64) -> i64 {
+        let count = count.min(MAX_TRANSFER);
+        if !self.memory.borrow().is_mapped(buf, count) {
+            return -EFAULT;
+        }
+
+        let mut data = vec![0; count as usize];
+        let result = match self.file(fd) {
+            Ok(file) => match &mut file.stream {
+                Stream::Stdin => std::io::stdin().read(&mut data),
+                Stream::File(file) => file.read(&mut data),
+                _ => return -EBADF,
+            },
+
+            Err(e) => return e,
+        };
+
+        match result {
+            Ok(length) => {
+                self.memory.borrow_mut().write_bytes(buf, &data[..length]);
+                length as i64
+            }
+
+            Err(e) => errno(e),
+        }
+    }
+
+    /// Write ´data´ to the file ´fd´
+    fn write_data(&mut self, fd: u64, data: &[u8]) -> i64 {
+        let file = match self.file(fd) {
+            Ok(file) => file,
+            Err(e) => return e,
+        };
+
+        // NOTE(patrik): The guest's C library does its own buffering, the
+        // host streams are flushed so the output isn't delayed twice
+        let result = match &mut file.stream {
+            Stream::Stdin => return -EBADF,
+            Stream::Stdout => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(data).and_then(|_| stdout.flush())
+            }
+
+            Stream::Stderr => std::io::stderr().write_all(data),
+            Stream::File(file) => file.write_all(data),
+        };
+
+        match result {
+            Ok(()) => data.len() as i64,
+            Err(e) => errno(e),
+        }
+    }
+
+    fn write(&mut self, fd: u64, buf: u64, count: u64) -> i64 {
+        let data = self.memory.borrow().read_vec(buf, count.min(MAX_TRANSFER));
+        match data {
+            Some(data) => self.write_data(fd, &data),
+            None => -EFAULT,
+        }
+    }
+
+    /// Read the ´struct iovec´ array at ´iov´ as (base, length)
+    fn iovecs(&self, iov: u64, count: u64) -> Result<Vec<(u64, u64)>, i64> {
+        // NOTE(patrik): Linux allows at most 1024 entries (UIO_MAXIOV)
+        if count > 1024 {
+            return Err(-EINVAL);
+        }
+
+        let data = self.memory.borrow().read_vec(iov, count * 16)
+            .ok_or(-EFAULT)?;
+
+        Ok(data.chunks_exact(16)
+            .map(|entry| {
+                let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+                let len = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+                (base, len)
+            })
+            .collect())
+    }
+
+    fn writev(&mut self, fd: u64, iov: u64, count: u64) -> i64 {
+        let iovecs = match self.iovecs(iov, count) {
+            Ok(iovecs) => iovecs,
+            Err(e) => return e,
+        };
+
+        let mut data = Vec::new();
+        for (base, len) in iovecs {
+            let len = len.min(MAX_TRANSFER);
+            match self.memory.borrow().read_vec(base, len) {
+                Some(bytes) => data.extend_from_slice(&bytes),
+                None => return -EFAULT,
+            }
+        }
+
+        self.write_data(fd, &data)
+    }
+
+    fn readv(&mut self, fd: u64, iov: u64, count: u64) -> i64 {
+        let iovecs = match self.iovecs(iov, count) {
+            Ok(iovecs) => iovecs,
+            Err(e) => return e,
+        };
+
+        let mut total = 0;
+        for (base, len) in iovecs {
+            let result = self.read(fd, base, len);
+            if result < 0 {
+                return if total > 0 { total } else { result };
+            }
+
+            total += result;
+            if (result as u64) < len {
+                break;
+            }
+        }
+
+        total
+    }
+
+    fn openat(&mut self, dirfd: i64, pathname: u64, flags: u64, mode: u64)
+        -> i64
+    {
+        let path = match self.host_path(dirfd, pathname) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+
+        let access = flags & O_ACCMODE;
+        let mut options = OpenOptions::new();
+        options.read(access != O_WRONLY)
+            .write(access == O_WRONLY || access == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode as u32);
+
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+
+        let file = match options.open(&path) {
+            Ok(file) => file,
+            Err(e) => return errno(e),
+        };
+
+        if flags & O_DIRECTORY != 0 &&
+            !file.metadata().is_ok_and(|metadata| metadata.is_dir())
+        {
+            return -ENOTDIR;
+        }
+
+        // NOTE(patrik): Linux hands out the lowest free descriptor
+        let fd = (0..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, OpenFile {
+            stream: Stream::File(file),
+            path,
+        });
+
+        fd as i64
+    }
+
+    fn close(&mut self, fd: u64) -> i64 {
+        match self.files.remove(&fd) {
+            Some(_) => 0,
+            None => -EBADF,
+        }
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> i64 {
+        let position = match whence {
+            SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CUR => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+
+        let file = match self.file(fd) {
+            Ok(file) => file,
+            Err(e) => return e,
+        };
+
+        let Stream::File(file) = &mut file.stream else {
+            return -ESPIPE;
+        };
+
+        match file.seek(position) {
+            Ok(position) => position as i64,
+            Err(e) => errno(e),
+        }
+    }
+
+    /// Write ´metadata´ as a ´struct stat´ at ´statbuf´
+    fn write_stat(&mut self, statbuf: u64, metadata: &Metadata) -> i64 {
+        let mut stat = [0u8; STAT_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        put(0, &metadata.dev().to_le_bytes());
+        put(8, &metadata.ino().to_le_bytes());
+        put(16, &metadata.mode().to_le_bytes());
+        put(20, &(metadata.nlink() as u32).to_le_bytes());
+        put(24, &metadata.uid().to_le_bytes());
+        put(28, &metadata.gid().to_le_bytes());
+        put(32, &metadata.rdev().to_le_bytes());
+        put(48, &metadata.size().to_le_bytes());
+        put(56, &(metadata.blksize() as u32).to_le_bytes());
+        put(64, &metadata.blocks().to_le_bytes());
+        put(72, &metadata.atime().to_le_bytes());
+        put(80, &metadata.atime_nsec().to_le_bytes());
+        put(88, &metadata.mtime().to_le_bytes());
+        put(96, &metadata.mtime_nsec().to_le_bytes());
+        put(104, &metadata.ctime().to_le_bytes());
+        put(112, &metadata.ctime_nsec().to_le_bytes());
+
+        if !self.memory.borrow_mut().write_bytes(statbuf, &stat) {
+            return -EFAULT;
+        }
+
+        0
+    }
+
+    fn fstat(&mut self, fd: u64, statbuf: u64) -> i64 {
+        let metadata = match self.file(fd) {
+            Ok(file) => file.metadata(),
+            Err(e) => return e,
+        };
+
+        match metadata {
+            Ok(metadata) => self.write_stat(statbuf, &metadata),
+            Err(e) => errno(e),
+        }
+    }
+
+    fn newfstatat(&mut self, dirfd: i64, pathname: u64, statbuf: u64,
+                  flags: u64)
+        -> i64
+    {
+        // NOTE(patrik): An empty path with AT_EMPTY_PATH is how the C
+        // libraries do ´fstat´
+        let empty = self.memory.borrow().read_string(pathname)
+            .is_some_and(|path| path.is_empty());
+        if flags & AT_EMPTY_PATH != 0 && empty {
+            if dirfd == AT_FDCWD {
+                return self.stat_path(Path::new("."), statbuf, true);
+            }
+
+            return self.fstat(dirfd as u64, statbuf);
+        }
+
+        let path = match self.host_path(dirfd, pathname) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+
+        let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+        self.stat_path(&path, statbuf, follow)
+    }
+
+    fn stat_path(&mut self, path: &Path, statbuf: u64, follow: bool) -> i64 {
+        let metadata = if follow {
+            std::fs::metadata(path)
+        } else {
+            std::fs::symlink_metadata(path)
+        };
+
+        match metadata {
+            Ok(metadata) => self.write_stat(statbuf, &metadata),
+            Err(e) => errno(e),
+        }
+    }
+
+    fn faccessat(&mut self, dirfd: i64, pathname: u64) -> i64 {
+        // TODO(patrik): Only checks that the file exists, not the
+        // permissions asked for
+        match self.host_path(dirfd, pathname) {
+            Ok(path) if path.exists() => 0,
+            Ok(_) => -ENOENT,
+            Err(e) => e,
+        }
+    }
+
+    fn readlinkat(&mut self, dirfd: i64, pathname: u64, buf: u64,
+                  size: u64)
+        -> i64
+    {
+        let path = match self.host_path(dirfd, pathname) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+
+        // NOTE(patrik): /proc/self/exe of the host is the emulator, the
+        // guest gets its own program
+        let target = if path == Path::new("/proc/self/exe") {
+            self.program.clone()
+        } else {
+            match std::fs::read_link(&path) {
+                Ok(target) => target,
+                Err(e) => return errno(e),
+            }
+        };
+
+        let target = target.as_os_str().as_encoded_bytes();
+        let length = target.len().min(size as usize);
+        if !self.memory.borrow_mut().write_bytes(buf, &target[..length]) {
+            return -EFAULT;
+        }
+
+        length as i64
+    }
+
+    fn ioctl(&mut self, fd: u64, request: u64, arg: u64) -> i64 {
+        let file = match self.file(fd) {
+            Ok(file) => file,
+            Err(e) => return e,
+        };
+
+        // NOTE(patrik): Only enough for the C libraries to tell if a
+        // stream is a terminal, the settings are made up
+        if !file.is_terminal() {
+            return -ENOTTY;
+        }
+
+        let data = match request {
+            TCGETS => {
+                let mut termios = [0u8; TERMIOS_SIZE];
+                // ICRNL | IXON, OPOST | ONLCR, B38400 | CS8 | CREAD and
+                // ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE |
+                // IEXTEN
+                termios[0..4].copy_from_slice(&0x0500u32.to_le_bytes());
+                termios[4..8].copy_from_slice(&0x0005u32.to_le_bytes());
+                termios[8..12].copy_from_slice(&0x00bfu32.to_le_bytes());
+                termios[12..16].copy_from_slice(&0x8a3bu32.to_le_bytes());
+                termios.to_vec()
+            }
+
+            TIOCGWINSZ => {
+                let mut winsize = Vec::new();
+                winsize.extend_from_slice(&24u16.to_le_bytes());
+                winsize.extend_from_slice(&80u16.to_le_bytes());
+                winsize.extend_from_slice(&[0; 4]);
+                winsize
+            }
+
+            _ => return -EINVAL,
+        };
+
+        if !self.memory.borrow_mut().write_bytes(arg, &data) {
+            return -EFAULT;
+        }
+
+        0
+    }
+
+    fn brk(&mut self, addr: u64) -> i64 {
+        // NOTE(patrik): A failed ´brk´ returns the current end, that's how
+        // the guest asks for it too
+        if addr < self.heap_start {
+            return self.heap_end as i64;
+        }
+
+        let old_end = self.heap_end.next_multiple_of(PAGE_SIZE);
+        let Some(new_end) = addr.checked_next_multiple_of(PAGE_SIZE) else {
+            return self.heap_end as i64;
+        };
+
+        let mut memory = self.memory.borrow_mut();
+        if new_end > old_end {
+            if !memory.is_unmapped(old_end, new_end - old_end) {
+                return self.heap_end as i64;
+            }
+
+            memory.map(old_end, new_end - old_end);
+        } else {
+            memory.unmap(new_end, old_end - new_end);
+        }
+
+        self.heap_end = addr;
+        addr as i64
+    }
+
+    fn mmap(&mut self, addr: u64, length: u64, flags: u64, fd: i64,
+            offset: u64)
+        -> i64
+    {
+        if length == 0 || !addr.is_multiple_of(PAGE_SIZE) ||
+            !offset.is_multiple_of(PAGE_SIZE)
+        {
+            return -EINVAL;
+        }
+
+        let length = match length.checked_next_multiple_of(PAGE_SIZE) {
+            Some(length) if length <= USER_END => length,
+            _ => return -ENOMEM,
+        };
+
+        let file = if flags & MAP_ANONYMOUS == 0 {
+            match self.files.get(&(fd as u64)) {
+                Some(OpenFile { stream: Stream::File(file), .. }) => Some(file),
+                Some(_) => return -EPERM,
+                None => return -EBADF,
+            }
+        } else {
+            None
+        };
+
+        let mut memory = self.memory.borrow_mut();
+        let fixed = flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0;
+        if fixed && addr > USER_END - length {
+            return -ENOMEM;
+        }
+
+        let addr = if flags & MAP_FIXED != 0 {
+            memory.unmap(addr, length);
+            addr
+        } else if flags & MAP_FIXED_NOREPLACE != 0 {
+            if !memory.is_unmapped(addr, length) {
+                return -EEXIST;
+            }
+
+            addr
+        } else if addr != 0 && memory.is_unmapped(addr, length) {
+            addr
+        } else {
+            // NOTE(patrik): Mappings are placed downwards from below the
+            // dynamic linker, in the first hole that fits
+            let bottom = self.heap_end.next_multiple_of(PAGE_SIZE);
+            let Some(addr) = memory.find_unmapped(bottom, self.mmap_top,
+                                                  length) else {
+                return -ENOMEM;
+            };
+
+            self.mmap_top = addr;
+            addr
+        };
+
+        memory.map(addr, length);
+
+        // TODO(patrik): Shared file mappings are private copies, writes
+        // don't go back to the file
+        if let Some(file) = file {
+            // NOTE(patrik): The file is copied in a piece at a time, the
+            // mapping may be far larger than the file
+            let mut data = vec![0; MAX_TRANSFER as usize];
+            let mut filled = 0;
+            while filled < length {
+                let size = (length - filled).min(MAX_TRANSFER) as usize;
+                let position = offset.saturating_add(filled);
+                match file.read_at(&mut data[..size], position) {
+                    Ok(0) => break,
+                    Ok(count) => {
+                        memory.write_bytes(addr + filled, &data[..count]);
+                        filled += count as u64;
+                    }
+
+                    Err(e) => {
+                        memory.unmap(addr, length);
+                        return errno(e);
+                    }
+                }
+            }
+        }
+
+        addr as i64
+    }
+
+    fn munmap(&mut self, addr: u64, length: u64) -> i64 {
+        if !addr.is_multiple_of(PAGE_SIZE) || length == 0 {
+            return -EINVAL;
+        }
+
+        self.memory.borrow_mut().unmap(addr, length);
+        0
+    }
+
+    fn mprotect(&mut self, addr: u64, length: u64) -> i64 {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return -EINVAL;
+        }
+
+        // TODO(patrik): Page permissions are not enforced, every mapped
+        // page can be read, written and executed
+        if !self.memory.borrow().is_mapped(addr, length) {
+            return -ENOMEM;
+        }
+
+        0
+    }
+
+    fn clock_gettime(&mut self, clock: u64, tp: u64) -> i64 {
+        // NOTE(patrik): Every clock but the real time one is monotonic
+        // from the start of the process
+        let time = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => {
+                SystemTime::now().duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+            }
+
+            0..=11 => self.start.elapsed(),
+            _ => return -EINVAL,
+        };
+
+        let mut timespec = Vec::with_capacity(16);
+        timespec.extend_from_slice(&time.as_secs().to_le_bytes());
+        timespec.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        if !self.memory.borrow_mut().write_bytes(tp, &timespec) {
+            return -EFAULT;
+        }
+
+        0
+    }
+
+    fn clock_getres(&mut self, res: u64) -> i64 {
+        if res == 0 {
+            return 0;
+        }
+
+        let mut timespec = [0u8; 16];
+        timespec[8] = 1;
+        if !self.memory.borrow_mut().write_bytes(res, &timespec) {
+            return -EFAULT;
+        }
+
+        0
+    }
+
+    fn getrandom(&mut self, buf: u64, length: u64) -> i64 {
+        let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+        if let Err(e) = File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(&mut data))
+        {
+            return errno(e);
+        }
+
+        if !self.memory.borrow_mut().write_bytes(buf, &data) {
+            return -EFAULT;
+        }
+
+        data.len() as i64
+    }
+
+    fn uname(&mut self, buf: u64) -> i64 {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .unwrap_or_else(|_| String::from("kira"));
+
+        let fields = [
+            "Linux",
+            hostname.trim(),
+            "6.6.0",
+            "#1 SMP kira",
+            "riscv64",
+            "(none)",
+        ];
+
+        let mut utsname = vec![0u8; fields.len() * UTSNAME_FIELD];
+        for (index, field) in fields.iter().enumerate() {
+            let field = &field.as_bytes()[..field.len().min(UTSNAME_FIELD - 1)];
+            let offset = index * UTSNAME_FIELD;
+            utsname[offset..offset + field.len()].copy_from_slice(field);
+        }
+
+        if !self.memory.borrow_mut().write_bytes(buf, &utsname) {
+            return -EFAULT;
+        }
+
+        0
+    }
+
+    fn prlimit64(&mut self, resource: u64, old_limit: u64) -> i64 {
+        if old_limit == 0 {
+            return 0;
+        }
+
+        let limit = if resource == RLIMIT_STACK {
+            STACK_SIZE
+        } else {
+            RLIM_INFINITY
+        };
+
+        let mut rlimit = Vec::with_capacity(16);
+        rlimit.extend_from_slice(&limit.to_le_bytes());
+        rlimit.extend_from_slice(&limit.to_le_bytes());
+        if !self.memory.borrow_mut().write_bytes(old_limit, &rlimit) {
+            return -EFAULT;
+        }
+
+        0
+    }
+}
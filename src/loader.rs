@@ -0,0 +1,676 @@
+//! Loaders for the program images the emulator runs, every format is
+//! turned into the same list of chunks to copy into memory and an entry
+//! point
+//!
+//! Supported formats are ELF, flat binaries, Intel HEX and Motorola
+//! S-records
+
+use std::path::Path;
+use std::ops::Range;
+
+use crate::elf::{ Elf, ElfError, ProgramHeaderTyp, Class, Rela };
+use crate::elf::SymbolBinding;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+
+    /// Line of a text image is not a valid record
+    InvalidRecord(usize),
+
+    /// Checksum of the record on the line doesn't match its contents
+    InvalidChecksum(usize),
+
+    /// Record type on the line is not known
+    UnknownRecordType(usize, u8),
+
+    /// Intel HEX image without an end-of-file record
+    MissingEndRecord,
+
+    /// Segment at the address has more data in the file than in memory
+    InvalidSegment(u64),
+
+    /// Segments at the two addresses share memory
+    OverlappingSegments(u64, u64),
+
+    /// [addr, addr + size) is outside the RAM
+    DoesNotFit {
+        addr: u64,
+        size: u64,
+    },
+
+    /// Position independent executables have to be loaded at a page
+    /// aligned address
+    UnalignedBase(u64),
+
+    /// The relocation type is not supported
+    UnsupportedRelocation(u32),
+
+    /// The place a relocation at the address patches is outside the data
+    /// of the segments, the linker never places relocations in the BSS
+    RelocationOutOfBounds(u64),
+
+    /// A relocation refers to a symbol that is not defined in the program,
+    /// it would need a dynamic linker
+    UndefinedSymbol(String),
+}
+
+type Result<T> = std::result::Result<T, LoadError>;
+
+/// Format of a program image
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImageFormat {
+    Elf,
+
+    /// Flat binary, loaded as is at an address given by the user
+    Binary,
+
+    IntelHex,
+    SRecord,
+}
+
+/// Which address of an ELF segment it's loaded at
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SegmentAddress {
+    /// ´paddr´, where the segment is in physical memory, for programs
+    /// running from reset
+    Physical,
+
+    /// ´vaddr´, for programs running on top of an operating system
+    Virtual,
+}
+
+/// Contiguous bytes to place at ´addr´
+#[derive(Debug)]
+pub struct Chunk {
+    pub addr: u64,
+    pub data: Vec<u8>,
+
+    /// Size in memory, the bytes after ´data´ are zeroed
+    pub size: u64,
+}
+
+impl Chunk {
+    pub fn range(&self) -> Range<u64> {
+        self.addr..self.addr + self.size
+    }
+}
+
+/// What a loader found in an image
+#[derive(Debug)]
+pub struct LoadedImage {
+    /// The loaded ranges, sorted in the order they appear in the image
+    pub chunks: Vec<Chunk>,
+
+    pub entry: u64,
+
+    /// Added to the addresses in a position independent executable, the
+    /// symbols of the ELF have to be moved by it too
+    pub bias: u64,
+}
+
+impl ImageFormat {
+    /// Format from the name used on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "elf" => Some(ImageFormat::Elf),
+            "bin" => Some(ImageFormat::Binary),
+            "ihex" => Some(ImageFormat::IntelHex),
+            "srec" => Some(ImageFormat::SRecord),
+
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the contents and the extension of ´path´,
+    /// anything that isn't recognized is a flat binary
+    pub fn detect(path: &Path, bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"\x7fELF") {
+            return ImageFormat::Elf;
+        }
+
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hex" | "ihex") => ImageFormat::IntelHex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") =>
+                ImageFormat::SRecord,
+
+            _ => ImageFormat::Binary,
+        }
+    }
+
+    /// Load ´bytes´ as an image in this format, ´base´ is where a flat
+    /// binary or a position independent executable is placed
+    pub fn load(&self, bytes: &[u8], base: u64) -> Result<LoadedImage> {
+        match self {
+            ImageFormat::Elf => {
+                let elf = Elf::parse(bytes).map_err(LoadError::Elf)?;
+                load_elf(&elf, SegmentAddress::Physical, base)
+            }
+
+            ImageFormat::Binary => Ok(load_binary(bytes, base)),
+            ImageFormat::IntelHex => load_intel_hex(bytes),
+            ImageFormat::SRecord => load_srecord(bytes),
+        }
+    }
+}
+
+impl LoadedImage {
+    fn new() -> Self {
+        Self {
+            chunks: Vec::new(),
+            entry: 0,
+            bias: 0,
+        }
+    }
+
+    /// Add ´data´ at ´addr´, merged with the previous chunk when it
+    /// continues right after it
+    fn push(&mut self, addr: u64, data: &[u8]) {
+        if let Some(last) = self.chunks.last_mut() {
+            let is_filled = last.size == last.data.len() as u64;
+            if is_filled && last.addr + last.size == addr {
+                last.data.extend_from_slice(data);
+                last.size += data.len() as u64;
+                return;
+            }
+        }
+
+        self.chunks.push(Chunk {
+            addr,
+            data: data.to_vec(),
+            size: data.len() as u64,
+        });
+    }
+
+    /// Check that every chunk is inside ´ram´
+    pub fn check_fits(&self, ram: &Range<u64>) -> Result<()> {
+        for chunk in &self.chunks {
+            let fits = chunk.addr >= ram.start &&
+                chunk.addr.checked_add(chunk.size)
+                    .is_some_and(|end| end <= ram.end);
+            if !fits {
+                return Err(LoadError::DoesNotFit {
+                    addr: chunk.addr,
+                    size: chunk.size,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that no two chunks share memory
+    fn check_overlaps(&self) -> Result<()> {
+        let mut ranges = self.chunks.iter()
+            .map(|chunk| chunk.range())
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|range| range.start);
+
+        for pair in ranges.windows(2) {
+            if pair[0].end > pair[1].start {
+                return Err(LoadError::OverlappingSegments(pair[0].start,
+                                                          pair[1].start));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lowest loaded address, used as the entry of images that don't have
+    /// one
+    fn lowest_addr(&self) -> u64 {
+        self.chunks.iter()
+            .map(|chunk| chunk.addr)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Page size position independent executables are aligned to
+const PAGE_SIZE: u64 = 0x1000;
+
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_32: u32 = 1;
+const R_RISCV_64: u32 = 2;
+const R_RISCV_RELATIVE: u32 = 3;
+const R_RISCV_JUMP_SLOT: u32 = 5;
+
+/// Load the ´PT_LOAD´ segments of ´elf´, the part of a segment past the
+/// data in the file is the BSS and gets zeroed
+///
+/// A position independent executable is moved so its first segment starts
+/// at ´base´ and its dynamic relocations are applied, ´base´ is not used
+/// for other executables
+pub fn load_elf(elf: &Elf, address: SegmentAddress, base: u64)
+    -> Result<LoadedImage>
+{
+    let mut image = LoadedImage::new();
+
+    if elf.is_position_independent() {
+        if !base.is_multiple_of(PAGE_SIZE) {
+            return Err(LoadError::UnalignedBase(base));
+        }
+
+        let lowest = elf.program_header_iter()
+            .filter(|header| header.typ() == ProgramHeaderTyp::Load)
+            .map(|header| header.vaddr())
+            .min()
+            .unwrap_or(0);
+        image.bias = base.wrapping_sub(lowest & !(PAGE_SIZE - 1));
+    }
+
+    // Virtual address of every chunk, the relocations refer to them
+    let mut vaddrs = Vec::new();
+
+    for program_header in elf.program_header_iter() {
+        if program_header.typ() != ProgramHeaderTyp::Load {
+            continue;
+        }
+
+        let addr = match address {
+            SegmentAddress::Physical => program_header.paddr(),
+            SegmentAddress::Virtual => program_header.vaddr(),
+        };
+        let addr = addr.wrapping_add(image.bias);
+
+        let size = program_header.memory_size();
+        if program_header.file_size() as u64 > size {
+            return Err(LoadError::InvalidSegment(addr));
+        }
+
+        if addr.checked_add(size).is_none() {
+            return Err(LoadError::DoesNotFit { addr, size });
+        }
+
+        // NOTE(patrik): Empty segments don't load anything
+        if size == 0 {
+            continue;
+        }
+
+        let data = elf.program_header_data(program_header)
+            .map_err(LoadError::Elf)?;
+        image.chunks.push(Chunk {
+            addr,
+            data: data.to_vec(),
+            size,
+        });
+        vaddrs.push(program_header.vaddr());
+    }
+
+    image.check_overlaps()?;
+    image.entry = elf.entry().wrapping_add(image.bias);
+
+    if elf.is_position_independent() {
+        let relocations = elf.dynamic_relocations()
+            .map_err(LoadError::Elf)?;
+        for relocation in relocations {
+            image.relocate(elf, &vaddrs, &relocation)?;
+        }
+    }
+
+    Ok(image)
+}
+
+impl LoadedImage {
+    /// Apply a dynamic relocation, ´vaddrs´ has the virtual address of
+    /// every chunk
+    fn relocate(&mut self, elf: &Elf, vaddrs: &[u64], relocation: &Rela)
+        -> Result<()>
+    {
+        let symbol_value = || {
+            let symbol = elf.dynamic_symbol(relocation.symbol)
+                .map_err(LoadError::Elf)?;
+            // NOTE(patrik): Undefined weak symbols resolve to zero
+            if !symbol.is_defined() {
+                if symbol.binding() == SymbolBinding::Weak {
+                    return Ok(0);
+                }
+
+                return Err(LoadError::UndefinedSymbol(
+                        symbol.name().to_string()));
+            }
+
+            Ok(symbol.value().wrapping_add(self.bias))
+        };
+
+        // NOTE(patrik): The places are words of the class of the file
+        let (value, width) = match relocation.typ {
+            R_RISCV_NONE => return Ok(()),
+            R_RISCV_RELATIVE => {
+                (self.bias.wrapping_add(relocation.addend as u64), None)
+            }
+
+            R_RISCV_64 | R_RISCV_JUMP_SLOT => {
+                let value = symbol_value()?
+                    .wrapping_add(relocation.addend as u64);
+                (value, None)
+            }
+
+            R_RISCV_32 => {
+                let value = symbol_value()?
+                    .wrapping_add(relocation.addend as u64);
+                (value, Some(4))
+            }
+
+            typ => return Err(LoadError::UnsupportedRelocation(typ)),
+        };
+
+        let width = width.unwrap_or(match elf.class() {
+            Class::Elf32 => 4,
+            Class::Elf64 => 8,
+        });
+
+        let place = relocation.offset;
+        let out_of_bounds = LoadError::RelocationOutOfBounds(place);
+        let index = vaddrs.iter()
+            .zip(&self.chunks)
+            .position(|(&vaddr, chunk)| {
+                place.checked_sub(vaddr)
+                    .and_then(|offset| offset.checked_add(width))
+                    .is_some_and(|end| end <= chunk.data.len() as u64)
+            })
+            .ok_or(out_of_bounds)?;
+
+        let chunk = &mut self.chunks[index];
+        let offset = (place - vaddrs[index]) as usize;
+        let bytes = value.to_le_bytes();
+        chunk.data[offset..offset + width as usize]
+            .copy_from_slice(&bytes[..width as usize]);
+
+        Ok(())
+    }
+}
+
+pub fn load_binary(bytes: &[u8], base: u64) -> LoadedImage {
+    let mut image = LoadedImage::new();
+    image.push(base, bytes);
+    image.entry = base;
+
+    image
+}
+
+/// Decode the hex digits of a record into bytes
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(digits.get(index..index + 2)?, 16).ok()
+        })
+        .collect()
+}
+
+/// Big endian value of the bytes of an address field
+fn be_value(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+/// Lines of a text image with their line number, empty lines are skipped
+fn records(bytes: &[u8]) -> impl Iterator<Item = (usize, &str)> {
+    bytes.split(|&c| c == b'\n')
+        .enumerate()
+        .map(|(index, line)| {
+            let line = std::str::from_utf8(line).unwrap_or("");
+            (index + 1, line.trim())
+        })
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Load an Intel HEX image
+///
+/// Record layout: ´:LLAAAATT<data>CC´, where the checksum is the two's
+/// complement of the sum of the other bytes
+pub fn load_intel_hex(bytes: &[u8]) -> Result<LoadedImage> {
+    const DATA: u8 = 0x00;
+    const END_OF_FILE: u8 = 0x01;
+    const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+    const START_SEGMENT_ADDRESS: u8 = 0x03;
+    const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+    const START_LINEAR_ADDRESS: u8 = 0x05;
+
+    let mut image = LoadedImage::new();
+    let mut entry = None;
+
+    // Added to the 16-bit address of the data records
+    let mut base = 0u64;
+
+    for (line_number, line) in records(bytes) {
+        let record = line.strip_prefix(':')
+            .and_then(decode_hex)
+            .ok_or(LoadError::InvalidRecord(line_number))?;
+
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(LoadError::InvalidRecord(line_number));
+        }
+
+        let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0 {
+            return Err(LoadError::InvalidChecksum(line_number));
+        }
+
+        let addr = be_value(&record[1..3]);
+        let typ = record[3];
+        let data = &record[4..record.len() - 1];
+
+        match typ {
+            DATA => image.push(base + addr, data),
+            END_OF_FILE => {
+                image.entry = entry.unwrap_or_else(|| image.lowest_addr());
+                return Ok(image);
+            }
+
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = be_value(data) << 4;
+            }
+
+            START_SEGMENT_ADDRESS if data.len() == 4 => {
+                let cs = be_value(&data[0..2]);
+                let ip = be_value(&data[2..4]);
+                entry = Some((cs << 4) + ip);
+            }
+
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = be_value(data) << 16;
+            }
+
+            START_LINEAR_ADDRESS if data.len() == 4 => {
+                entry = Some(be_value(data));
+            }
+
+            EXTENDED_SEGMENT_ADDRESS | START_SEGMENT_ADDRESS |
+                EXTENDED_LINEAR_ADDRESS | START_LINEAR_ADDRESS =>
+            {
+                return Err(LoadError::InvalidRecord(line_number));
+            }
+
+            _ => return Err(LoadError::UnknownRecordType(line_number, typ)),
+        }
+    }
+
+    Err(LoadError::MissingEndRecord)
+}
+
+/// Load a Motorola S-record image
+///
+/// Record layout: ´STCC<address><data>SS´, where the count covers the
+/// address, the data and the checksum, and the checksum is the ones'
+/// complement of the sum of the count, address and data bytes
+pub fn load_srecord(bytes: &[u8]) -> Result<LoadedImage> {
+    let mut image = LoadedImage::new();
+    let mut entry = None;
+
+    for (line_number, line) in records(bytes) {
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            return Err(LoadError::InvalidRecord(line_number));
+        }
+
+        let typ = chars.next()
+            .and_then(|typ| typ.to_digit(10))
+            .ok_or(LoadError::InvalidRecord(line_number))? as u8;
+        let record = decode_hex(chars.as_str())
+            .ok_or(LoadError::InvalidRecord(line_number))?;
+
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(LoadError::InvalidRecord(line_number));
+        }
+
+        let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0xff {
+            return Err(LoadError::InvalidChecksum(line_number));
+        }
+
+        let address_size = match typ {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+
+            _ => return Err(LoadError::UnknownRecordType(line_number, typ)),
+        };
+
+        // Count, address and checksum
+        if record.len() < address_size + 2 {
+            return Err(LoadError::InvalidRecord(line_number));
+        }
+
+        let addr = be_value(&record[1..1 + address_size]);
+        let data = &record[1 + address_size..record.len() - 1];
+
+        match typ {
+            1..=3 => image.push(addr, data),
+            7..=9 => entry = Some(addr),
+
+            // NOTE(patrik): The header and the record counts carry nothing
+            // the emulator needs
+            _ => {}
+        }
+    }
+
+    image.entry = entry.unwrap_or_else(|| image.lowest_addr());
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{ Data, DT_RELA, DT_RELASZ, DT_RELAENT, DT_SYMTAB };
+    use crate::elf::{ DT_STRTAB, DT_NULL };
+    use crate::elf::tests::{ TestElf, Segment, PT_LOAD, PT_DYNAMIC, ET_DYN };
+
+    /// Position independent executable with one segment at 0, the places
+    /// at 0x00 to 0x28 are patched by the relocations of ´relocations´,
+    /// given as (offset, symbol, type, addend). Symbol 1 is ´func´ at 0x40
+    /// and symbol 2 the weak undefined ´weak´, symbol 3 is undefined
+    fn pie(relocations: &[(u64, u32, u32, i64)]) -> Vec<u8> {
+        let mut elf = TestElf::new(Class::Elf64, Data::LittleEndian, ET_DYN);
+        elf.entry = 0x40;
+
+        let mut image = elf.writer();
+        image.bytes.extend_from_slice(&[0xaa; 0x30]);
+        image.pad_to(0x80);
+        for &(offset, symbol, typ, addend) in relocations {
+            image.rela(offset, symbol, typ, addend);
+        }
+        let relocations_size = image.bytes.len() as u64 - 0x80;
+
+        image.pad_to(0x100);
+        image.symbol(0, 0, 0, 0, 0);
+        image.symbol(1, 0x12, 1, 0x40, 0x10); // func
+        image.symbol(6, 0x20, 0, 0, 0); // weak
+        image.symbol(11, 0x10, 0, 0, 0); // missing
+        image.pad_to(0x180);
+        image.bytes.extend_from_slice(b"\0func\0weak\0missing\0");
+
+        let mut dynamic = elf.writer();
+        dynamic.dynamic(DT_RELA, 0x80);
+        dynamic.dynamic(DT_RELASZ, relocations_size);
+        dynamic.dynamic(DT_RELAENT, 24);
+        dynamic.dynamic(DT_SYMTAB, 0x100);
+        dynamic.dynamic(DT_STRTAB, 0x180);
+        dynamic.dynamic(DT_NULL, 0);
+
+        elf.segments = vec![
+            Segment {
+                typ: PT_LOAD,
+                vaddr: 0,
+                memory_size: 0x1000,
+                data: image.bytes,
+            },
+            Segment {
+                typ: PT_DYNAMIC,
+                vaddr: 0x200,
+                memory_size: dynamic.bytes.len() as u64,
+                data: dynamic.bytes,
+            },
+        ];
+
+        elf.build()
+    }
+
+    fn load(bytes: &[u8], base: u64) -> Result<LoadedImage> {
+        let elf = Elf::parse(bytes).unwrap();
+        load_elf(&elf, SegmentAddress::Virtual, base)
+    }
+
+    fn u64_at(image: &LoadedImage, offset: usize) -> u64 {
+        let bytes = &image.chunks[0].data[offset..offset + 8];
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn relocations_are_applied() {
+        let bytes = pie(&[
+            (0x00, 0, R_RISCV_RELATIVE, 0x100),
+            (0x08, 1, R_RISCV_64, 4),
+            (0x10, 1, R_RISCV_JUMP_SLOT, 0),
+            (0x18, 1, R_RISCV_32, 0),
+            (0x20, 2, R_RISCV_64, 0),
+            (0x28, 0, R_RISCV_NONE, 0),
+        ]);
+        let image = load(&bytes, 0x80200000).unwrap();
+
+        assert_eq!(image.bias, 0x80200000);
+        assert_eq!(image.entry, 0x80200040);
+        assert_eq!(image.chunks[0].addr, 0x80200000);
+        assert_eq!(image.chunks[0].size, 0x1000);
+
+        assert_eq!(u64_at(&image, 0x00), 0x80200100);
+        assert_eq!(u64_at(&image, 0x08), 0x80200044);
+        assert_eq!(u64_at(&image, 0x10), 0x80200040);
+
+        // R_RISCV_32 only patches 4 bytes
+        assert_eq!(u64_at(&image, 0x18), 0xaaaaaaaa80200040);
+
+        // An undefined weak symbol is zero, R_RISCV_NONE does nothing
+        assert_eq!(u64_at(&image, 0x20), 0);
+        assert_eq!(u64_at(&image, 0x28), 0xaaaaaaaaaaaaaaaa);
+    }
+
+    #[test]
+    fn relocation_errors() {
+        let bytes = pie(&[(0x00, 0, R_RISCV_RELATIVE, 0)]);
+        assert!(matches!(load(&bytes, 0x80200800),
+                         Err(LoadError::UnalignedBase(0x80200800))));
+
+        let bytes = pie(&[(0x00, 3, R_RISCV_64, 0)]);
+        assert!(matches!(load(&bytes, 0x80200000),
+                         Err(LoadError::UndefinedSymbol(name))
+                         if name == "missing"));
+
+        let bytes = pie(&[(0x00, 0, 4, 0)]);
+        assert!(matches!(load(&bytes, 0x80200000),
+                         Err(LoadError::UnsupportedRelocation(4))));
+
+        // NOTE(patrik): The BSS of the segment isn't in the file
+        let bytes = pie(&[(0x800, 0, R_RISCV_RELATIVE, 0)]);
+        assert!(matches!(load(&bytes, 0x80200000),
+                         Err(LoadError::RelocationOutOfBounds(0x800))));
+    }
+}
@@ -0,0 +1,155 @@
+//! Machine models, where the RAM and the devices are placed in the
+//! physical address space of the emulated board
+
+use crate::dtb::{ IrqDevice, PcieDescription };
+
+/// Board the emulator models
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Machine {
+    /// Same memory map as the QEMU ´virt´ board, programs and device trees
+    /// made for it run without changes
+    #[default]
+    Virt,
+
+    /// Same memory map as the Spike ISA simulator, the harts start in a
+    /// boot ROM that jumps to the program
+    Spike,
+}
+
+/// Memory map of a machine, the devices the machine doesn't have are None
+#[derive(Clone, Debug)]
+pub struct MachineLayout {
+    /// Value of ´compatible´ of the root node of the device tree
+    pub compatible: &'static str,
+
+    /// Boot ROM with the reset vector and the device tree, the harts start
+    /// at the program when None
+    pub boot_rom: Option<u64>,
+
+    pub ram_base: u64,
+
+    /// Size of the RAM
+    pub ram_size: u64,
+
+    /// SiFive test device used to power off the machine
+    pub test_finisher: Option<u64>,
+
+    pub clint: Option<u64>,
+
+    /// Base and number of interrupt sources
+    pub plic: Option<(u64, u32)>,
+
+    pub uart: Option<IrqDevice>,
+    pub rtc: Option<IrqDevice>,
+
+    /// First virtio-mmio slot, the other slots and their interrupts follow
+    pub virtio_mmio: Option<IrqDevice>,
+    pub virtio_mmio_count: usize,
+
+    /// Addresses of the flash banks
+    pub pflash: Vec<u64>,
+
+    pub framebuffer: Option<u64>,
+    pub pcie: Option<PcieDescription>,
+}
+
+impl Machine {
+    /// Machine from the name used on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "virt" => Some(Machine::Virt),
+            "spike" => Some(Machine::Spike),
+
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Machine::Virt => "virt",
+            Machine::Spike => "spike",
+        }
+    }
+
+    pub fn layout(&self) -> MachineLayout {
+        match self {
+            Machine::Virt => virt_layout(),
+            Machine::Spike => spike_layout(),
+        }
+    }
+}
+
+/// Layout of the QEMU ´virt´ board, see hw/riscv/virt.c in the QEMU tree
+fn virt_layout() -> MachineLayout {
+    MachineLayout {
+        compatible: "riscv-virtio",
+        boot_rom: None,
+
+        ram_base: 0x80000000,
+        ram_size: 128 * 1024 * 1024,
+
+        test_finisher: Some(0x100000),
+        clint: Some(0x2000000),
+        plic: Some((0xc000000, 95)),
+        uart: Some(IrqDevice {
+            base: 0x10000000,
+            irq: 10,
+        }),
+        rtc: Some(IrqDevice {
+            base: 0x101000,
+            irq: 11,
+        }),
+        virtio_mmio: Some(IrqDevice {
+            base: 0x10001000,
+            irq: 1,
+        }),
+        virtio_mmio_count: 8,
+        pflash: vec![0x20000000, 0x22000000],
+
+        // NOTE(patrik): QEMU has no framebuffer here, it's placed in a hole
+        // in the memory map
+        framebuffer: Some(0x28000000),
+        pcie: Some(PcieDescription {
+            ecam_base: 0x30000000,
+            ecam_size: 0x10000000,
+            mmio_base: 0x40000000,
+            mmio_size: 0x40000000,
+            pio_base: 0x03000000,
+            pio_size: 0x10000,
+            irq: 32,
+
+            // NOTE(patrik): QEMU uses an IMSIC or no MSI at all, the
+            // doorbell is placed in a hole below the ECAM
+            msi_base: 0x2ff00000,
+            msi_irq: 64,
+            msi_count: 32,
+        }),
+    }
+}
+
+/// Layout of Spike, see riscv/platform.h in the Spike tree
+fn spike_layout() -> MachineLayout {
+    MachineLayout {
+        compatible: "ucb,spike-bare-dev",
+        boot_rom: Some(0x1000),
+
+        // NOTE(patrik): Same as the default of ´spike -m´, the host only
+        // backs the pages the guest touches
+        ram_base: 0x80000000,
+        ram_size: 2048 * 1024 * 1024,
+
+        test_finisher: None,
+        clint: Some(0x2000000),
+        plic: Some((0xc000000, 31)),
+        uart: Some(IrqDevice {
+            base: 0x10000000,
+            irq: 1,
+        }),
+        rtc: None,
+        virtio_mmio: None,
+        virtio_mmio_count: 0,
+        pflash: Vec::new(),
+        framebuffer: None,
+        pcie: None,
+    }
+}
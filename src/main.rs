@@ -3,13 +3,48 @@
 use std::path::{ Path, PathBuf };
 use std::fs::File;
 use std::io::Read;
+use std::rc::Rc;
+use std::cell::RefCell;
 
-use memory::{ TestingMemory, TestingMmu, Mmu };
-use cpu::{ SimpleHart, Hart, Reg };
+use memory::{ TestingMemory, TestingMmu, MsiDoorbell, Mmu };
+use cpu::{ SimpleHart, Hart, Reg, Fault };
+use dtb::{ MachineDescription, IrqDevice, PcieDescription };
+use sbi::Sbi;
+use devices::{ ExitSignal, Htif, Semihosting, Clock, TIMEBASE_FREQUENCY };
+use devices::{ GoldfishRtc, GOLDFISH_RTC_SIZE };
+use devices::{ Framebuffer, FramebufferConfig, PixelFormat };
+use devices::{ Pflash, PFLASH_SIZE };
+use devices::{ PciBus, PciRegion, PciWindow };
+use devices::{ VirtioBlock, VirtioMmio, VirtioPci, VIRTIO_MMIO_SIZE };
+use devices::{ Clint, CLINT_SIZE, Plic, Uart16550, UART_SIZE };
+use devices::{ SifiveTest, SIFIVE_TEST_SIZE, BootRom, BOOT_ROM_DTB_OFFSET };
+use machine::{ Machine, MachineLayout };
+use loader::{ ImageFormat, LoadedImage, LoadError };
+use symbolize::Symbolizer;
 
 mod elf;
+mod dwarf;
+mod coredump;
+mod symbolize;
+mod image;
+mod loader;
+mod dtb;
+mod machine;
+mod sbi;
 mod memory;
 mod cpu;
+mod devices;
+mod linux;
+
+/// Most harts the machine can have, xv6 is built for at most 8 CPUs
+const MAX_HARTS: usize = 8;
+
+/// How much the deterministic clock advances for every instruction
+const NS_PER_INSTRUCTION: u64 = 10;
+
+/// Largest width and height of the framebuffer, the pixels have to fit in
+/// the hole of the memory map it's placed in
+const MAX_FRAMEBUFFER_SIDE: u64 = 4096;
 
 fn read_file_to_vec<P>(path: P) -> Vec<u8>
     where P: AsRef<Path>
@@ -22,90 +57,925 @@ fn read_file_to_vec<P>(path: P) -> Vec<u8>
     result
 }
 
-fn run_test(test: &str) -> Result<(), u32> {
+/// Options from the command line
+#[derive(Default, Debug)]
+struct Options {
+    /// Program to run, when None the riscv-tests are run
+    program: Option<PathBuf>,
+
+    /// Format of the program, guessed from the file when None
+    format: Option<ImageFormat>,
+
+    /// Address a flat binary program is loaded at, the start of the RAM
+    /// when None
+    load_addr: Option<u64>,
+
+    /// Host directory the guest can access through semihosting, semihosting
+    /// is disabled when None
+    semihosting_root: Option<PathBuf>,
+
+    /// Command line the guest gets from SYS_GET_CMDLINE
+    cmdline: String,
+
+    /// Fixed time in seconds since the UNIX epoch the RTC starts at, when
+    /// set the emulator runs with a deterministic clock
+    rtc_epoch: Option<u64>,
+
+    /// Framebuffer resolution and pixel format, no framebuffer is added
+    /// when None
+    framebuffer: Option<(u64, u64, PixelFormat)>,
+
+    /// Path prefix for the framebuffer snapshots
+    fb_output: Option<PathBuf>,
+
+    /// Take a framebuffer snapshot every N frames
+    fb_every: u64,
+
+    /// Files backing the flash banks
+    pflash: Vec<PathBuf>,
+
+    /// Disk images for the virtio block devices on virtio-mmio
+    virtio_blk: Vec<PathBuf>,
+
+    /// Disk images for the virtio block devices on the PCIe bus
+    virtio_blk_pci: Vec<PathBuf>,
+
+    /// Write the generated device tree to this file
+    dump_dtb: Option<PathBuf>,
+
+    /// Run the program in S-mode on top of the built-in SBI firmware
+    sbi: bool,
+
+    /// Linux kernel ´Image´ to boot, the program is the firmware that
+    /// jumps to it
+    kernel: Option<PathBuf>,
+
+    /// Initramfs loaded next to the kernel
+    initrd: Option<PathBuf>,
+
+    /// Number of harts, one when None
+    smp: Option<usize>,
+
+    /// Board to emulate
+    machine: Machine,
+
+    /// Device tree to give the guest instead of the generated one
+    dtb: Option<PathBuf>,
+
+    /// Write an ELF core file here when a hart faults fatally
+    core: Option<PathBuf>,
+
+    /// Also write the core file when the guest exits
+    core_on_exit: bool,
+
+    /// Run the program as a riscv64 Linux process instead of on a machine
+    user: bool,
+
+    /// Arguments of the program in user mode, everything after it on the
+    /// command line
+    args: Vec<String>,
+
+    /// Directory the user-mode program finds its dynamic linker and the
+    /// absolute paths it opens in
+    sysroot: Option<PathBuf>,
+}
+
+impl Options {
+    fn harts(&self) -> usize {
+        self.smp.unwrap_or(1)
+    }
+
+    fn framebuffer_config(&self) -> Option<FramebufferConfig> {
+        let (width, height, format) = self.framebuffer?;
+
+        Some(FramebufferConfig {
+            width,
+            height,
+            format,
+
+            output: self.fb_output.clone()
+                .unwrap_or_else(|| PathBuf::from("framebuffer")),
+            every: self.fb_every,
+        })
+    }
+
+    /// Describe the machine these options creates, used for the device
+    /// tree
+    fn machine_description(&self, layout: &MachineLayout, htif: bool,
+                           initrd: Option<(u64, u64)>)
+        -> MachineDescription
+    {
+        let virtio_mmio = (0..self.virtio_blk.len())
+            .map(|index| virtio_mmio_slot(layout, index))
+            .collect();
+
+        let pcie = layout.pcie.clone()
+            .filter(|_| !self.virtio_blk_pci.is_empty());
+
+        MachineDescription {
+            compatible: layout.compatible.to_string(),
+
+            memory_base: layout.ram_base,
+            memory_size: layout.ram_size,
+
+            harts: self.harts(),
+            isa: cpu::ISA.to_string(),
+            mmu_type: Some(cpu::MMU_TYPE.to_string()),
+            timebase_frequency: TIMEBASE_FREQUENCY,
+
+            test_finisher: layout.test_finisher,
+            clint: layout.clint,
+            plic: layout.plic,
+            uart: layout.uart,
+            virtio_mmio,
+            rtc: layout.rtc,
+            pflash: layout.pflash[..self.pflash.len()].to_vec(),
+            framebuffer: layout.framebuffer
+                .zip(self.framebuffer_config()),
+            pcie,
+            htif,
+
+            bootargs: self.cmdline.clone(),
+            initrd,
+        }
+    }
+}
+
+/// Parse a framebuffer mode written as ´WIDTHxHEIGHT[:FORMAT]´
+fn parse_framebuffer_mode(mode: &str)
+    -> Result<(u64, u64, PixelFormat), String>
+{
+    let (resolution, format) = match mode.split_once(':') {
+        Some((resolution, format)) => {
+            let format = PixelFormat::parse(format)
+                .ok_or_else(|| format!("unknown pixel format '{}'", format))?;
+            (resolution, format)
+        }
+        None => (mode, PixelFormat::X8r8g8b8),
+    };
+
+    let invalid = || format!("expected WIDTHxHEIGHT, got '{}'", resolution);
+    let (width, height) = resolution.split_once('x').ok_or_else(invalid)?;
+    let width: u64 = width.parse().map_err(|_| invalid())?;
+    let height: u64 = height.parse().map_err(|_| invalid())?;
+
+    let sides = 1..=MAX_FRAMEBUFFER_SIDE;
+    if !sides.contains(&width) || !sides.contains(&height) {
+        return Err(format!("the width and height have to be 1 to {}",
+                           MAX_FRAMEBUFFER_SIDE));
+    }
+
+    Ok((width, height, format))
+}
+
+/// Parse an address given in decimal or in hex with a 0x prefix
+fn parse_address(addr: &str) -> Option<u64> {
+    match addr.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => addr.parse().ok(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: kira [OPTIONS] [PROGRAM]");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --format <FORMAT>    Format of the program: elf, bin, ihex or srec,");
+    eprintln!("                       guessed from the file by default");
+    eprintln!("  --load-addr <ADDR>   Address to load a flat binary or a PIE program at");
+    eprintln!("  --semihosting <DIR>  Enable semihosting with DIR as the guest root");
+    eprintln!("  --cmdline <ARGS>     Command line passed to the guest");
+    eprintln!("  --rtc-epoch <SECS>   Start the RTC at SECS and use a deterministic clock");
+    eprintln!("  --framebuffer <MODE> Add a framebuffer, MODE is WIDTHxHEIGHT[:FORMAT]");
+    eprintln!("  --fb-output <PREFIX> Path prefix for framebuffer snapshots");
+    eprintln!("  --fb-every <N>       Write a framebuffer snapshot every N frames");
+    eprintln!("  --pflash <FILE>      Add a flash bank backed by FILE, can be given twice");
+    eprintln!("  --virtio-blk <FILE>  Add a virtio-mmio block device backed by FILE");
+    eprintln!("  --virtio-blk-pci <FILE>");
+    eprintln!("                       Add a virtio-pci block device backed by FILE");
+    eprintln!("  --dump-dtb <FILE>    Write the generated device tree to FILE");
+    eprintln!("  --sbi                Run the program in S-mode on the built-in SBI");
+    eprintln!("  --kernel <IMAGE>     Boot a Linux kernel Image, the program is the");
+    eprintln!("                       firmware, e.g. OpenSBI fw_jump, or use --sbi");
+    eprintln!("  --initrd <FILE>      Load FILE as the initramfs of the kernel");
+    eprintln!("  --smp <N>            Number of harts, at most {}", MAX_HARTS);
+    eprintln!("  --machine <NAME>     Board to emulate: virt (default) or spike");
+    eprintln!("  --dtb <FILE>         Give the guest FILE instead of the generated");
+    eprintln!("                       device tree");
+    eprintln!("  --core <FILE>        Write an ELF core file to FILE when a hart faults");
+    eprintln!("  --core-on-exit       Also write the core file when the guest exits");
+    eprintln!("  --user               Run PROGRAM as a riscv64 Linux process, the");
+    eprintln!("                       arguments after it are passed to it");
+    eprintln!("  --sysroot <DIR>      Look up the dynamic linker and absolute paths of");
+    eprintln!("                       the --user program in DIR first");
+    std::process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                options.format = Some(ImageFormat::parse(&name)
+                    .unwrap_or_else(|| usage()));
+            }
+
+            "--load-addr" => {
+                let addr = args.next().unwrap_or_else(|| usage());
+                options.load_addr = Some(parse_address(&addr)
+                    .unwrap_or_else(|| usage()));
+            }
+
+            "--semihosting" => {
+                let root = args.next().unwrap_or_else(|| usage());
+                options.semihosting_root = Some(PathBuf::from(root));
+            }
+
+            "--cmdline" => {
+                options.cmdline = args.next().unwrap_or_else(|| usage());
+            }
+
+            "--rtc-epoch" => {
+                let epoch = args.next().unwrap_or_else(|| usage());
+                let epoch = epoch.parse().unwrap_or_else(|_| usage());
+                options.rtc_epoch = Some(epoch);
+            }
+
+            "--framebuffer" => {
+                let mode = args.next().unwrap_or_else(|| usage());
+                let mode = parse_framebuffer_mode(&mode)
+                    .unwrap_or_else(|error| {
+                        eprintln!("Invalid framebuffer mode: {}", error);
+                        usage()
+                    });
+                options.framebuffer = Some(mode);
+            }
+
+            "--fb-output" => {
+                let output = args.next().unwrap_or_else(|| usage());
+                options.fb_output = Some(PathBuf::from(output));
+            }
+
+            "--fb-every" => {
+                let every = args.next().unwrap_or_else(|| usage());
+                options.fb_every = every.parse().unwrap_or_else(|_| usage());
+            }
+
+            "--pflash" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.pflash.push(PathBuf::from(path));
+            }
+
+            "--virtio-blk" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.virtio_blk.push(PathBuf::from(path));
+            }
+
+            "--virtio-blk-pci" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.virtio_blk_pci.push(PathBuf::from(path));
+            }
+
+            "--dump-dtb" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.dump_dtb = Some(PathBuf::from(path));
+            }
+
+            "--sbi" => options.sbi = true,
+
+            "--core" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.core = Some(PathBuf::from(path));
+            }
+
+            "--core-on-exit" => options.core_on_exit = true,
+
+            "--user" => options.user = true,
+
+            "--sysroot" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.sysroot = Some(PathBuf::from(path));
+            }
+
+            "--kernel" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.kernel = Some(PathBuf::from(path));
+            }
+
+            "--initrd" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.initrd = Some(PathBuf::from(path));
+            }
+
+            "--smp" => {
+                let harts = args.next().unwrap_or_else(|| usage());
+                let harts = harts.parse().unwrap_or_else(|_| usage());
+                if harts == 0 || harts > MAX_HARTS {
+                    usage();
+                }
+
+                options.smp = Some(harts);
+            }
+
+            "--machine" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                options.machine = Machine::parse(&name)
+                    .unwrap_or_else(|| usage());
+            }
+
+            "--dtb" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.dtb = Some(PathBuf::from(path));
+            }
+
+            "-h" | "--help" => usage(),
+
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            }
+
+            _ => {
+                if options.program.is_some() {
+                    usage();
+                }
+
+                options.program = Some(PathBuf::from(arg));
+
+                // NOTE(patrik): The options of a user-mode program are its
+                // own, so they end the options of the emulator
+                if options.user {
+                    options.args.extend(args.by_ref());
+                }
+            }
+        }
+    }
+
+    // NOTE(patrik): What the devices need depends on the machine, so they
+    // are checked once all options are known
+    let layout = options.machine.layout();
+    if options.pflash.len() > layout.pflash.len() {
+        eprintln!("The {} machine has {} flash banks", options.machine.name(),
+                  layout.pflash.len());
+        usage();
+    }
+
+    let virtio_mmio_count = layout.virtio_mmio
+        .map_or(0, |_| layout.virtio_mmio_count);
+    if options.virtio_blk.len() > virtio_mmio_count {
+        eprintln!("The {} machine has {} virtio-mmio slots",
+                  options.machine.name(), virtio_mmio_count);
+        usage();
+    }
+
+    let unsupported = [
+        ("--framebuffer", options.framebuffer.is_some(),
+         layout.framebuffer.is_some()),
+        ("--virtio-blk-pci", !options.virtio_blk_pci.is_empty(),
+         layout.pcie.is_some()),
+    ];
+
+    for (option, used, supported) in unsupported {
+        if used && !supported {
+            eprintln!("The {} machine doesn't support {}",
+                      options.machine.name(), option);
+            usage();
+        }
+    }
+
+    if options.user && options.program.is_none() {
+        eprintln!("--user needs a program");
+        usage();
+    }
+
+    if options.sysroot.is_some() && !options.user {
+        eprintln!("--sysroot is only used with --user");
+        usage();
+    }
+
+    if options.initrd.is_some() && options.kernel.is_none() {
+        eprintln!("--initrd needs a kernel");
+        usage();
+    }
+
+    // NOTE(patrik): The location of the initramfs is only written to the
+    // generated device tree
+    if options.initrd.is_some() && options.dtb.is_some() {
+        eprintln!("--initrd can't be used with --dtb");
+        usage();
+    }
+
+    // NOTE(patrik): A kernel runs on top of either the firmware given as
+    // the program or the built-in SBI, not both
+    if options.kernel.is_some() && options.program.is_some() == options.sbi {
+        eprintln!("--kernel needs either a firmware program or --sbi");
+        usage();
+    }
+
+    options
+}
+
+fn run_test(test: &str) -> Result<(), u64> {
     let mut path = PathBuf::from("/opt/riscv/target/share/riscv-tests/isa/"); 
     path.push(test);
-    let file_data = read_file_to_vec(path);
 
-    let e = elf::Elf::parse(&file_data).unwrap();
+    match run_elf(path, &Options::default()) {
+        0 => Ok(()),
+        testnum => Err(testnum),
+    }
+}
+
+/// Run an ELF program until it exits through HTIF or semihosting and
+/// return the exit code the program reported
+fn run_elf<P>(path: P, options: &Options) -> u64
+    where P: AsRef<Path>
+{
+    run_machine(Some(path.as_ref()), options)
+}
+
+/// Make sure the harts can run the program in ´e´
+fn check_elf(path: &Path, e: &elf::Elf) {
+    if let Err(error) = e.validate().and_then(|_| e.check_hart(cpu::MISA)) {
+        eprintln!("Can't run '{}': {:?}", path.display(), error);
+        std::process::exit(1);
+    }
+
+    // NOTE(patrik): The ISA string in the attributes is what the program
+    // was built for, the program may still run if it avoids the missing
+    // extensions, so this is only a warning
+    let attributes = e.riscv_attributes().ok().flatten()
+        .filter(|attributes| attributes.arch.is_some());
+    if let Some(attributes) = attributes {
+        let supported = elf::isa_extensions(cpu::ISA);
+        let missing = attributes.extensions().into_iter()
+            .filter(|extension| !supported.contains(extension))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            eprintln!("Warning: '{}' was built for {}, the hart doesn't \
+                       implement {}", path.display(),
+                      attributes.arch.as_deref().unwrap_or_default(),
+                      missing.join(", "));
+        }
+
+        return;
+    }
+
+    // NOTE(patrik): Freestanding programs like xv6 are often built for the
+    // default hard float ABI of the toolchain without using any floating
+    // point, so this is only a warning
+    let extension = e.riscv_flags().float_extension()
+        .filter(|&extension| !elf::misa_has_extension(cpu::MISA, extension));
+    if let Some(extension) = extension {
+        eprintln!("Warning: '{}' uses a float ABI that needs the {} \
+                   extension, the hart doesn't implement it",
+                  path.display(), extension);
+    }
+}
+
+/// Where the kernel and the initramfs were placed in RAM
+struct KernelLayout {
+    entry: u64,
+    initrd: Option<(u64, u64)>,
+}
+
+/// Build the machine, load the ´program´ and the kernel from the options
+/// and run until the guest exits. A fatal fault of the guest returns the
+/// status of a process killed by the signal for it
+fn run_machine(program: Option<&Path>, options: &Options) -> u64 {
+    let layout = options.machine.layout();
+
+    let file_data = program.map(|path| (path, read_file_to_vec(path)));
+    let format = file_data.as_ref().map(|(path, data)| {
+        options.format.unwrap_or_else(|| ImageFormat::detect(path, data))
+    });
+
+    // NOTE(patrik): Only ELF programs have the symbols the HTIF is found
+    // through
+    let e = file_data.as_ref()
+        .filter(|_| format == Some(ImageFormat::Elf))
+        .map(|(path, data)| {
+            let e = elf::Elf::parse(data)
+                .unwrap_or_else(|e| panic!("Failed to parse ELF '{}': {:?}",
+                                           path.display(), e));
+            check_elf(path, &e);
+            e
+        });
     // println!("Elf: {:#?}", e);
 
-    let memory = TestingMemory::new(100 * 1024 * 1024);
-    let mut mmu = TestingMmu::new(memory);
+    let program_image = file_data.as_ref().zip(format)
+        .map(|((path, data), format)| {
+            let base = options.load_addr.unwrap_or(layout.ram_base);
+            format.load(data, base)
+                .unwrap_or_else(|e| panic!("Failed to load program '{}': {:?}",
+                                           path.display(), e))
+        });
 
-    for program_header in e.program_header_iter() {
-        if program_header.typ() == elf::ProgramHeaderTyp::Load {
-            let data = e.program_header_data(program_header)
-                .expect("Failed to get program header data");
-            // println!("{:#x?}: {:#x}", program_header, data.len());
+    let exit = ExitSignal::new();
+    let bias = program_image.as_ref().map_or(0, |image| image.bias);
+    let htif = e.as_ref()
+        .and_then(|e| Htif::from_elf(e, bias, exit.clone()));
 
-            for index in 0..data.len() {
-                let addr = program_header.vaddr() + index as u64;
-                let value = data[index];
-                mmu.write_u8(addr, value);
-            }
+    // NOTE(patrik): The instruction clock counts the executed instructions,
+    // the framebuffer frames always follow it so the periodic snapshots
+    // are taken at the same points on every run
+    let instruction_clock = Clock::deterministic();
+    let clock = if options.rtc_epoch.is_some() {
+        instruction_clock.clone()
+    } else {
+        Clock::host()
+    };
+
+    let harts = options.harts();
+
+    let memory = TestingMemory::new(layout.ram_size as usize);
+    let mut mmu = TestingMmu::new(layout.ram_base, memory);
+
+    if let Some(base) = layout.test_finisher {
+        mmu.add_device(base, SIFIVE_TEST_SIZE,
+                       Box::new(SifiveTest::new(exit.clone())));
+    }
+
+    if let Some(base) = layout.clint {
+        mmu.add_device(base, CLINT_SIZE,
+                       Box::new(Clint::new(harts, clock.clone())));
+    }
+
+    if let Some((base, sources)) = layout.plic {
+        mmu.set_plic(base, Plic::new(sources, harts));
+    }
+
+    if let Some(uart) = layout.uart {
+        mmu.add_device_irq(uart.base, UART_SIZE, uart.irq,
+                           Box::new(Uart16550::new()));
+    }
+
+    if let Some(rtc) = layout.rtc {
+        let device = GoldfishRtc::new(clock.clone(), options.rtc_epoch);
+        mmu.add_device_irq(rtc.base, GOLDFISH_RTC_SIZE, rtc.irq,
+                           Box::new(device));
+    }
+
+    let framebuffer = layout.framebuffer.zip(options.framebuffer_config());
+    if let Some((base, config)) = framebuffer {
+        let size = config.region_size();
+        let framebuffer = Framebuffer::new(config, instruction_clock.clone());
+        mmu.add_device(base, size, Box::new(framebuffer));
+    }
+
+    for (path, base) in options.pflash.iter().zip(&layout.pflash) {
+        let pflash = Pflash::open(path, PFLASH_SIZE)
+            .unwrap_or_else(|e| panic!("Failed to open flash image '{}': {}",
+                                       path.display(), e));
+        mmu.add_device(*base, PFLASH_SIZE, Box::new(pflash));
+    }
+
+    for (index, path) in options.virtio_blk.iter().enumerate() {
+        let block = open_disk(path);
+        let slot = virtio_mmio_slot(&layout, index);
+        mmu.add_device_irq(slot.base, VIRTIO_MMIO_SIZE, slot.irq,
+                           Box::new(VirtioMmio::new(block)));
+    }
+
+    if let Some(pcie) = layout.pcie.as_ref()
+        .filter(|_| !options.virtio_blk_pci.is_empty())
+    {
+        add_pcie(&mut mmu, pcie, &options.virtio_blk_pci);
+    }
+
+    if let Some(image) = &program_image {
+        load_image(&mut mmu, image)
+            .unwrap_or_else(|e| panic!("Failed to load program '{}': {:?}",
+                                       program.unwrap().display(), e));
+    }
+
+    let user_dtb = options.dtb.as_ref().map(read_file_to_vec);
+
+    // NOTE(patrik): The size of the device tree doesn't depend on where the
+    // initramfs ends up, so generate it once to find its place
+    let dtb_size = match &user_dtb {
+        Some(dtb) => dtb.len() as u64,
+        None => {
+            let machine = options.machine_description(&layout, htif.is_some(),
+                                                      None);
+            dtb::generate(&machine).len() as u64
+        }
+    };
+
+    // NOTE(patrik): The device tree goes in the boot ROM when there is one,
+    // otherwise at the end of the RAM where it's out of the way of the
+    // program
+    let ram_end = layout.ram_base + layout.ram_size;
+    let (dtb_addr, ram_limit) = match layout.boot_rom {
+        Some(base) => (base + BOOT_ROM_DTB_OFFSET, ram_end),
+        None => {
+            let dtb_addr = (ram_end - dtb_size) & !0xfff;
+            (dtb_addr, dtb_addr)
         }
+    };
+
+    let kernel = options.kernel.as_ref()
+        .map(|path| load_kernel(&mut mmu, layout.ram_base, path,
+                                options.initrd.as_deref(), ram_limit));
+
+    let initrd = kernel.as_ref().and_then(|kernel| kernel.initrd);
+    let dtb = user_dtb.unwrap_or_else(|| {
+        let machine = options.machine_description(&layout, htif.is_some(),
+                                                  initrd);
+        dtb::generate(&machine)
+    });
+    if let Some(path) = &options.dump_dtb {
+        std::fs::write(path, &dtb)
+            .unwrap_or_else(|e| panic!("Failed to write the device tree to '{}': {}",
+                                       path.display(), e));
     }
 
-    let mut hart = SimpleHart::new(Box::new(mmu));
-    hart.set_reg(Reg::Pc, e.entry());
+    // Where the harts start, the firmware or the program. The kernel is
+    // only the entry on its own with the built-in SBI
+    let entry = match (&program_image, &kernel) {
+        (Some(image), _) => image.entry,
+        (None, Some(kernel)) => kernel.entry,
+        (None, None) => unreachable!(),
+    };
+
+    let reset_pc = match layout.boot_rom {
+        Some(base) => {
+            let rom = BootRom::spike(entry, &dtb);
+            mmu.add_device(base, rom.size(), Box::new(rom));
+            base
+        }
+
+        None => {
+            load_bytes(&mut mmu, dtb_addr, &dtb);
+            entry
+        }
+    };
+
+    // NOTE(patrik): Attach the HTIF after loading so the initial contents
+    // of ´tohost´ isn't treated as a command
+    if let Some(htif) = htif {
+        mmu.set_htif(htif);
+    }
+
+    // NOTE(patrik): The harts share the bus, they run one instruction each
+    // in turn
+    let bus = Rc::new(RefCell::new(mmu));
+    let sbi = options.sbi.then(|| Sbi::new(harts, exit.clone()));
+
+    let mut harts = (0..harts as u64)
+        .map(|hartid| {
+            // Boot protocol: a0 is the hartid and a1 the address of the
+            // device tree, the firmware finds the kernel at the address it
+            // was built for
+            let mut hart = SimpleHart::new(Box::new(bus.clone()));
+            hart.set_hartid(hartid);
+            hart.set_reg(Reg::X10, hartid);
+            hart.set_reg(Reg::X11, dtb_addr);
+            hart.set_reg(Reg::Pc, reset_pc);
+            hart.set_clock(clock.clone());
+
+            if let Some(sbi) = &sbi {
+                hart.set_sbi(sbi.clone());
+            }
+
+            hart
+        })
+        .collect::<Vec<_>>();
+
+    if sbi.is_some() {
+        // NOTE(patrik): With the built-in SBI there is no firmware to run,
+        // the kernel or the program starts directly in S-mode on the boot
+        // hart
+        let entry = kernel.as_ref().map_or(entry, |kernel| kernel.entry);
+        harts[0].enter_supervisor(entry);
+    }
+
+    if let Some(root) = &options.semihosting_root {
+        let mut semihosting = Semihosting::new(root, exit.clone());
+        semihosting.set_cmdline(&options.cmdline);
+        harts[0].set_semihosting(semihosting);
+    }
     // hart.dump();
 
     loop {
-        hart.step();
+        for hart in harts.iter_mut() {
+            hart.step();
+        }
 
-        let value = hart.mmu.read_u32(0x80001000);
-        let success = (value & 0x1) == 1;
-        let testnum = value >> 1;
+        if let Some(fault) = harts.iter().find_map(|hart| hart.fault()) {
+            bus.borrow_mut().shutdown();
+            if let Some(path) = &options.core {
+                write_core(path, &harts, &bus.borrow(), Some(fault));
+            }
+
+            eprintln!("Unhandled trap: cause {:#x} epc {:#x} tval {:#x}",
+                      fault.cause, fault.epc, fault.tval);
+            let symbolizer = e.as_ref().zip(program_image.as_ref())
+                .and_then(|(e, image)| Symbolizer::new(e, image));
+            if let Some(location) = symbolizer.as_ref()
+                .and_then(|symbolizer| symbolizer.describe(fault.epc))
+            {
+                eprintln!("    at {}", location);
+            }
+
+            let signal = coredump::signal_for_cause(fault.cause);
+            return coredump::signal_status(signal);
+        }
 
-        if success {
-            if testnum == 0 {
-                return Ok(());
-            } 
+        bus.borrow_mut().tick();
+        instruction_clock.advance(NS_PER_INSTRUCTION);
 
-            if testnum > 0 {
-                return Err(testnum);
+        if let Some(code) = exit.code() {
+            bus.borrow_mut().shutdown();
+            if let Some(path) = options.core.as_ref()
+                .filter(|_| options.core_on_exit)
+            {
+                write_core(path, &harts, &bus.borrow(), None);
             }
+
+            return code;
         }
     }
+}
 
+/// Write the harts and the RAM to the core file at ´path´, ´fault´ is the
+/// trap that stopped the guest
+fn write_core(path: &Path, harts: &[SimpleHart], mmu: &TestingMmu,
+              fault: Option<Fault>)
+{
+    let states: Vec<_> = harts.iter()
+        .enumerate()
+        .map(|(hartid, hart)| {
+            let mut registers = [0; 32];
+            for (index, value) in registers.iter_mut().enumerate() {
+                *value = hart.reg(Reg::from(index as u32));
+            }
+
+            // NOTE(patrik): The faulting hart is shown at the instruction
+            // that trapped
+            let pc = hart.fault()
+                .map_or(hart.reg(Reg::Pc), |fault| fault.epc);
+
+            coredump::HartState {
+                hartid: hartid as u64,
+                pc,
+                registers,
+            }
+        })
+        .collect();
+
+    let signal = fault.map_or(0, |fault| {
+        coredump::signal_for_cause(fault.cause)
+    });
+    let result = File::create(path)
+        .and_then(|mut out| {
+            coredump::write(&mut out, &states, signal, mmu.ram().start,
+                            mmu.ram_data())
+        });
+
+    match result {
+        Ok(()) => eprintln!("Wrote core file '{}'", path.display()),
+        Err(e) => eprintln!("Failed to write core file '{}': {}",
+                            path.display(), e),
+    }
 }
 
-fn run_program() {
-    let mut path = PathBuf::from("./test/a.out"); 
-    let file_data = read_file_to_vec(path);
+fn load_bytes(mmu: &mut TestingMmu, addr: u64, data: &[u8]) {
+    if !mmu.write_ram(addr, data) {
+        panic!("[{:#x}, {:#x}) is outside the RAM", addr,
+               addr + data.len() as u64);
+    }
+}
 
-    let e = elf::Elf::parse(&file_data).unwrap();
-    // println!("Elf: {:#?}", e);
+/// Copy the chunks of ´image´ into the RAM and zero what's past their data
+fn load_image(mmu: &mut TestingMmu, image: &LoadedImage)
+    -> Result<(), LoadError>
+{
+    image.check_fits(&mmu.ram())?;
 
-    let memory = TestingMemory::new(100 * 1024 * 1024);
-    let mut mmu = TestingMmu::new(memory);
+    for chunk in &image.chunks {
+        let data_end = chunk.addr + chunk.data.len() as u64;
+        mmu.write_ram(chunk.addr, &chunk.data);
+        mmu.zero_ram(data_end, chunk.range().end - data_end);
+    }
 
-    for program_header in e.program_header_iter() {
-        if program_header.typ() == elf::ProgramHeaderTyp::Load {
-            let data = e.program_header_data(program_header)
-                .expect("Failed to get program header data");
-            // println!("{:#x?}: {:#x}", program_header, data.len());
+    Ok(())
+}
 
-            for index in 0..data.len() {
-                let addr = program_header.vaddr() + index as u64;
-                let value = data[index];
-                mmu.write_u8(addr, value);
-            }
+/// Load a Linux kernel ´Image´ at ´text_offset´ from ´ram_base´ and the
+/// initramfs after it, everything has to fit below ´limit´
+fn load_kernel(mmu: &mut TestingMmu, ram_base: u64, path: &Path,
+               initrd: Option<&Path>, limit: u64)
+    -> KernelLayout
+{
+    let data = read_file_to_vec(path);
+    let image = image::LinuxImage::parse(&data)
+        .unwrap_or_else(|e| panic!("Failed to parse kernel image '{}': {:?}",
+                                   path.display(), e));
+
+    if image.is_big_endian() {
+        panic!("Big endian kernels are not supported");
+    }
+
+    let entry = ram_base + image.text_offset();
+    let kernel_end = entry + image.image_size();
+    if kernel_end > limit {
+        panic!("Kernel image '{}' doesn't fit in RAM", path.display());
+    }
+
+    load_bytes(mmu, entry, image.data());
+
+    let initrd = initrd.map(|path| {
+        let data = read_file_to_vec(path);
+
+        let start = (kernel_end + 0xfff) & !0xfff;
+        let end = start + data.len() as u64;
+        if end > limit {
+            panic!("Initramfs '{}' doesn't fit in RAM", path.display());
         }
+
+        load_bytes(mmu, start, &data);
+        (start, end)
+    });
+
+    KernelLayout {
+        entry,
+        initrd,
     }
+}
 
-    let mut hart = SimpleHart::new(Box::new(mmu));
-    hart.set_reg(Reg::Pc, e.entry());
-    // hart.dump();
+fn open_disk(path: &Path) -> VirtioBlock {
+    VirtioBlock::open(path)
+        .unwrap_or_else(|e| panic!("Failed to open disk image '{}': {}",
+                                   path.display(), e))
+}
 
-    loop {
-        // hart.dump();
-        hart.step();
+/// Virtio-mmio slot ´index´ of the machine, the slots follow each other
+/// and so does their interrupts
+fn virtio_mmio_slot(layout: &MachineLayout, index: usize) -> IrqDevice {
+    let first = layout.virtio_mmio
+        .expect("The machine doesn't have virtio-mmio slots");
+
+    IrqDevice {
+        base: first.base + index as u64 * VIRTIO_MMIO_SIZE,
+        irq: first.irq + index as u32,
     }
 }
 
+/// Add the PCIe host bridge with a virtio block device for every disk
+fn add_pcie(mmu: &mut TestingMmu, pcie: &PcieDescription, disks: &[PathBuf]) {
+    let mut bus = PciBus::new(pcie.mmio_base, pcie.mmio_size, pcie.pio_size);
+    for path in disks {
+        bus.add_device(Box::new(VirtioPci::new(open_disk(path))))
+            .expect("No room for more devices on the PCIe bus");
+    }
+
+    let bus = Rc::new(RefCell::new(bus));
+    let regions = [
+        (pcie.ecam_base, pcie.ecam_size, PciWindow::Ecam),
+        (pcie.mmio_base, pcie.mmio_size, PciWindow::Memory),
+        (pcie.pio_base, pcie.pio_size, PciWindow::Io),
+    ];
+
+    mmu.set_msi_doorbell(MsiDoorbell {
+        base: pcie.msi_base,
+        sources: pcie.msi_irq..pcie.msi_irq + pcie.msi_count,
+    });
+
+    // NOTE(patrik): The four INTx lines of the bridge go to the PLIC
+    // sources from ´pcie.irq´, the device tree has the same swizzle
+    for (base, size, window) in regions {
+        let region = Box::new(PciRegion::new(bus.clone(), window));
+        if window == PciWindow::Ecam {
+            mmu.add_device_irq(base, size, pcie.irq, region);
+        } else {
+            mmu.add_device(base, size, region);
+        }
+    }
+}
+
+fn run_program() {
+    // NOTE(patrik): The program writes to the UART of the virt machine
+    let path = PathBuf::from("./test/a.out");
+    run_elf(path, &Options::default());
+}
+
 fn main() {
+    let options = parse_args();
+    if let Some(program) = options.program.as_deref().filter(|_| options.user) {
+        let code = linux::run(program, &options.args,
+                              options.sysroot.as_deref());
+        std::process::exit(code as i32);
+    }
+
+    if options.program.is_some() || options.kernel.is_some() {
+        let code = run_machine(options.program.as_deref(), &options);
+        std::process::exit(code as i32);
+    }
+
     let tests = [
         "rv64ui-p-add",
         "rv64ui-p-addi",
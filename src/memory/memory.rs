@@ -1,3 +1,7 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TypeWidth {
     // u8
     Byte,
@@ -9,27 +13,85 @@ pub enum TypeWidth {
     DoubleWord
 }
 
+impl TypeWidth {
+    /// Size of the type in bytes
+    pub fn size(&self) -> u64 {
+        match self {
+            TypeWidth::Byte => 1,
+            TypeWidth::HalfWord => 2,
+            TypeWidth::Word => 4,
+            TypeWidth::DoubleWord => 8,
+        }
+    }
+}
+
 pub trait Mmu {
-    /// Read from memory
-    fn read(&self, addr: u64, width: TypeWidth) -> u64;
+    /// Read from memory, None if nothing is mapped at ´addr´
+    fn read(&mut self, addr: u64, width: TypeWidth) -> Option<u64>;
+
+    /// Write to memory, false if nothing is mapped at ´addr´
+    fn write(&mut self, addr: u64, value: u64, width: TypeWidth) -> bool;
+
+    /// Bits of ´mip´ the devices raise for the hart ´hartid´
+    fn interrupts(&mut self, _hartid: u64) -> u64 {
+        0
+    }
+
+    /// Make a load reservation for ´hartid´ on ´addr´, used by LR
+    fn reserve(&mut self, hartid: u64, addr: u64);
+
+    /// Take the reservation of ´hartid´, returns true if it was still held
+    /// on ´addr´. Used by SC
+    fn take_reservation(&mut self, hartid: u64, addr: u64) -> bool;
 
-    /// Write to memory
-    fn write(&mut self, addr: u64, value: u64, width: TypeWidth);
+    /// Let the devices do work over time, called after every instruction
+    fn tick(&mut self) {}
 
-    fn read_u8(&self, addr: u64) -> u8 {
-        self.read(addr, TypeWidth::Byte) as u8
+    /// Tell the devices that the emulator is exiting
+    fn shutdown(&mut self) {}
+
+    /// Copy [addr, addr + data.len()) into ´data´, false if some of it
+    /// isn't mapped. Unmapped bytes read as zero
+    fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        let mut mapped = true;
+        for (index, byte) in data.iter_mut().enumerate() {
+            let addr = addr.wrapping_add(index as u64);
+            let value = self.read(addr, TypeWidth::Byte);
+            mapped &= value.is_some();
+            *byte = value.unwrap_or(0) as u8;
+        }
+
+        mapped
     }
 
-    fn read_u16(&self, addr: u64) -> u16 {
-        self.read(addr, TypeWidth::HalfWord) as u16
+    /// Copy ´data´ to ´addr´, false if some of it isn't mapped
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> bool {
+        let mut mapped = true;
+        for (index, byte) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(index as u64);
+            mapped &= self.write(addr, *byte as u64, TypeWidth::Byte);
+        }
+
+        mapped
     }
 
-    fn read_u32(&self, addr: u64) -> u32 {
-        self.read(addr, TypeWidth::Word) as u32
+    // NOTE(patrik): The helpers are for devices accessing the guest
+    // memory, unmapped memory reads as zero and writes to it are dropped
+
+    fn read_u8(&mut self, addr: u64) -> u8 {
+        self.read(addr, TypeWidth::Byte).unwrap_or(0) as u8
     }
 
-    fn read_u64(&self, addr: u64) -> u64 {
-        self.read(addr, TypeWidth::DoubleWord) as u64
+    fn read_u16(&mut self, addr: u64) -> u16 {
+        self.read(addr, TypeWidth::HalfWord).unwrap_or(0) as u16
+    }
+
+    fn read_u32(&mut self, addr: u64) -> u32 {
+        self.read(addr, TypeWidth::Word).unwrap_or(0) as u32
+    }
+
+    fn read_u64(&mut self, addr: u64) -> u64 {
+        self.read(addr, TypeWidth::DoubleWord).unwrap_or(0)
     }
 
     fn write_u8(&mut self, addr: u64, value: u8) {
@@ -45,6 +107,44 @@ pub trait Mmu {
     }
 
     fn write_u64(&mut self, addr: u64, value: u64) {
-        self.write(addr, value as u64, TypeWidth::DoubleWord);
+        self.write(addr, value, TypeWidth::DoubleWord);
+    }
+}
+/// A bus shared by several harts
+impl<M: Mmu> Mmu for Rc<RefCell<M>> {
+    fn read(&mut self, addr: u64, width: TypeWidth) -> Option<u64> {
+        self.borrow_mut().read(addr, width)
+    }
+
+    fn write(&mut self, addr: u64, value: u64, width: TypeWidth) -> bool {
+        self.borrow_mut().write(addr, value, width)
+    }
+
+    fn interrupts(&mut self, hartid: u64) -> u64 {
+        self.borrow_mut().interrupts(hartid)
+    }
+
+    fn reserve(&mut self, hartid: u64, addr: u64) {
+        self.borrow_mut().reserve(hartid, addr);
+    }
+
+    fn take_reservation(&mut self, hartid: u64, addr: u64) -> bool {
+        self.borrow_mut().take_reservation(hartid, addr)
     }
-}
\ No newline at end of file
+
+    fn tick(&mut self) {
+        self.borrow_mut().tick();
+    }
+
+    fn shutdown(&mut self) {
+        self.borrow_mut().shutdown();
+    }
+
+    fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        self.borrow_mut().read_bytes(addr, data)
+    }
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> bool {
+        self.borrow_mut().write_bytes(addr, data)
+    }
+}
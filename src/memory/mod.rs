@@ -2,12 +2,19 @@
 
 pub use memory::{ Mmu, TypeWidth };
 
+use std::ops::Range;
+
+use crate::devices::{ Htif, Device, Dma, Plic, PLIC_SIZE };
+use crate::cpu::MIP_MEIP;
+
+#[allow(clippy::module_inception)]
 mod memory;
 
 pub struct TestingMemory {
     memory: Vec<u8>,
 }
 
+#[allow(clippy::identity_op)]
 impl TestingMemory {
     pub fn new(size: usize) -> Self {
         Self {
@@ -23,6 +30,21 @@ impl TestingMemory {
         self.memory[addr] = value;
     }
 
+    /// Copy ´data´ into memory starting at ´addr´
+    pub fn write_bytes(&mut self, addr: usize, data: &[u8]) {
+        self.memory[addr..addr + data.len()].copy_from_slice(data);
+    }
+
+    /// Set the bytes in [addr, addr + len) to ´value´
+    pub fn fill(&mut self, addr: usize, len: usize, value: u8) {
+        self.memory[addr..addr + len].fill(value);
+    }
+
+    /// Get the bytes in [addr, addr + len)
+    pub fn read_bytes(&self, addr: usize, len: usize) -> &[u8] {
+        &self.memory[addr..addr + len]
+    }
+
     pub fn write_u16(&mut self, addr: usize, value: u16) {
         self.memory[addr + 0] = ((value >> 0)  & 0xff) as u8;
         self.memory[addr + 1] = ((value >> 8)  & 0xff) as u8;
@@ -81,31 +103,264 @@ impl TestingMemory {
     }
 }
 
-/// This is temporary, used for the tests
-pub const MEMORY_OFFSET: u64 = 0x80000000;
+/// Size of the region of the MSI doorbell
+pub const MSI_DOORBELL_SIZE: u64 = 0x1000;
+
+/// Target for message signaled interrupts, a 32-bit write of N to the
+/// doorbell raises an edge on PLIC source N
+pub struct MsiDoorbell {
+    pub base: u64,
+
+    /// PLIC sources the doorbell may raise, writes of other values are
+    /// dropped
+    pub sources: Range<u32>,
+}
+
+impl MsiDoorbell {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < MSI_DOORBELL_SIZE
+    }
+
+    /// Handle a write of ´data´ at ´addr´ in the region
+    fn ring(&self, plic: Option<&mut Plic>, addr: u64, data: &[u8]) {
+        let Ok(value) = <[u8; 4]>::try_from(data) else {
+            return;
+        };
+
+        let source = u32::from_le_bytes(value);
+        if addr == self.base && self.sources.contains(&source) {
+            if let Some(plic) = plic {
+                plic.trigger(source);
+            }
+        }
+    }
+}
+
+/// DMA view of the guest RAM
+struct RamDma<'a> {
+    base: u64,
+    memory: &'a mut TestingMemory,
+
+    /// Writes to the doorbell from the devices are MSIs
+    msi: Option<(&'a MsiDoorbell, Option<&'a mut Plic>)>,
+}
+
+impl RamDma<'_> {
+    /// Offset into the RAM for [addr, addr + len)
+    fn offset(&self, addr: u64, len: usize) -> Option<usize> {
+        let offset = addr.checked_sub(self.base)?;
+        let end = offset.checked_add(len as u64)?;
+        if end > self.memory.len() as u64 {
+            return None;
+        }
+
+        Some(offset as usize)
+    }
+}
+
+impl Dma for RamDma<'_> {
+    fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        let Some(offset) = self.offset(addr, data.len()) else {
+            return false;
+        };
+
+        data.copy_from_slice(self.memory.read_bytes(offset, data.len()));
+        true
+    }
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> bool {
+        if let Some((doorbell, plic)) = &mut self.msi {
+            if doorbell.contains(addr) {
+                doorbell.ring(plic.as_deref_mut(), addr, data);
+                return true;
+            }
+        }
+
+        let Some(offset) = self.offset(addr, data.len()) else {
+            return false;
+        };
+
+        self.memory.write_bytes(offset, data);
+        true
+    }
+}
+
+/// Device mapped at [base, base + size)
+struct MappedDevice {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+
+    /// PLIC source the first interrupt line of the device is connected to
+    irq: Option<u32>,
+}
+
+impl MappedDevice {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+}
 
 pub struct TestingMmu {
+    /// Physical address the RAM starts at
+    memory_base: u64,
     memory: TestingMemory,
+    htif: Option<Htif>,
+    devices: Vec<MappedDevice>,
+
+    /// PLIC and the address it's mapped at, without a PLIC any device
+    /// interrupt is a machine external interrupt on hart 0
+    plic: Option<(u64, Plic)>,
+
+    /// MSI doorbell in front of the PLIC
+    msi: Option<MsiDoorbell>,
+
+    /// Load reservations as (hartid, address) from LR
+    reservations: Vec<(u64, u64)>,
 }
 
+/// Size of the reservation set of LR, a store anywhere in it breaks the
+/// reservation
+const RESERVATION_SIZE: u64 = 8;
+
 impl TestingMmu {
-    pub fn new(memory: TestingMemory) -> Self {
+    /// Create a bus with ´memory´ as the RAM at ´memory_base´
+    pub fn new(memory_base: u64, memory: TestingMemory) -> Self {
         Self {
-            memory
+            memory_base,
+            memory,
+            htif: None,
+            devices: Vec::new(),
+            plic: None,
+            msi: None,
+            reservations: Vec::new(),
+        }
+    }
+
+    /// Map ´device´ at [base, base + size)
+    pub fn add_device(&mut self, base: u64, size: u64, device: Box<dyn Device>) {
+        self.devices.push(MappedDevice {
+            base,
+            size,
+            device,
+            irq: None,
+        });
+    }
+
+    /// Map ´device´ at [base, base + size) with its interrupt lines
+    /// connected to the sources from ´irq´ of the PLIC
+    pub fn add_device_irq(&mut self, base: u64, size: u64, irq: u32,
+                          device: Box<dyn Device>)
+    {
+        self.devices.push(MappedDevice {
+            base,
+            size,
+            device,
+            irq: Some(irq),
+        });
+    }
+
+    /// Map the PLIC at ´base´, the interrupt lines of the devices are then
+    /// routed through it
+    pub fn set_plic(&mut self, base: u64, plic: Plic) {
+        self.plic = Some((base, plic));
+    }
+
+    /// Map the MSI doorbell, the writes to it raise PLIC sources
+    pub fn set_msi_doorbell(&mut self, doorbell: MsiDoorbell) {
+        self.msi = Some(doorbell);
+    }
+
+    fn plic_mut(&mut self, addr: u64) -> Option<(u64, &mut Plic)> {
+        match &mut self.plic {
+            Some((base, plic)) if addr >= *base && addr - *base < PLIC_SIZE => {
+                Some((addr - *base, plic))
+            }
+
+            _ => None,
+        }
+    }
+
+    /// Offset into the RAM of [addr, addr + len), None if the range isn't
+    /// entirely in the RAM
+    fn ram_range_offset(&self, addr: u64, len: u64) -> Option<usize> {
+        let offset = addr.checked_sub(self.memory_base)?;
+        if offset.checked_add(len)? > self.memory.len() as u64 {
+            return None;
         }
+
+        Some(offset as usize)
+    }
+
+    /// Physical addresses of the RAM
+    pub fn ram(&self) -> std::ops::Range<u64> {
+        self.memory_base..self.memory_base + self.memory.len() as u64
+    }
+
+    /// Contents of the whole RAM
+    pub fn ram_data(&self) -> &[u8] {
+        self.memory.read_bytes(0, self.memory.len())
+    }
+
+    /// Copy ´data´ into the RAM at ´addr´, false if it doesn't fit
+    pub fn write_ram(&mut self, addr: u64, data: &[u8]) -> bool {
+        let Some(offset) = self.ram_range_offset(addr, data.len() as u64)
+        else {
+            return false;
+        };
+
+        self.memory.write_bytes(offset, data);
+        true
+    }
+
+    /// Zero [addr, addr + len) of the RAM, false if it doesn't fit
+    pub fn zero_ram(&mut self, addr: u64, len: u64) -> bool {
+        let Some(offset) = self.ram_range_offset(addr, len) else {
+            return false;
+        };
+
+        self.memory.fill(offset, len as usize, 0);
+        true
+    }
+
+    fn device_mut(&mut self, addr: u64) -> Option<&mut MappedDevice> {
+        self.devices.iter_mut().find(|mapped| mapped.contains(addr))
+    }
+
+    /// Attach a HTIF, writes to ´tohost´ are then handled by the HTIF
+    pub fn set_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
     }
 }
 
 impl Mmu for TestingMmu {
     /// Read from memory
-    fn read(&self, addr: u64, width: TypeWidth) -> u64 {
-        if addr >= MEMORY_OFFSET &&
-            addr < MEMORY_OFFSET + self.memory.len() as u64
-        {
-            let addr = addr.wrapping_sub(MEMORY_OFFSET);
-            let addr: usize = addr.try_into().unwrap();
+    fn read(&mut self, addr: u64, width: TypeWidth) -> Option<u64> {
+        // NOTE(patrik): The HTIF is taken out while it runs so it can use
+        // the MMU to access the guest memory
+        if let Some(mut htif) = self.htif.take() {
+            if htif.is_fromhost(addr, width) {
+                htif.fromhost_accessed(self);
+            }
+
+            self.htif = Some(htif);
+        }
+
+        if let Some((offset, plic)) = self.plic_mut(addr) {
+            return Some(plic.read(offset, width));
+        }
 
-            return match width {
+        if self.msi.as_ref().is_some_and(|msi| msi.contains(addr)) {
+            return Some(0);
+        }
+
+        if let Some(mapped) = self.device_mut(addr) {
+            let offset = addr - mapped.base;
+            return Some(mapped.device.read(offset, width));
+        }
+
+        if let Some(addr) = self.ram_range_offset(addr, width.size()) {
+            let value = match width {
                 TypeWidth::Byte =>  
                     self.memory.read_u8(addr)  as u64,
                 TypeWidth::HalfWord => 
@@ -113,41 +368,178 @@ impl Mmu for TestingMmu {
                 TypeWidth::Word => 
                     self.memory.read_u32(addr) as u64,
                 TypeWidth::DoubleWord => 
-                    self.memory.read_u64(addr) as u64,
+                    self.memory.read_u64(addr),
             };
+
+            return Some(value);
         }
 
-        panic!("Unknown addr: {:#x}", addr);
+        // NOTE(patrik): Nothing is mapped here, the hart raises an access
+        // fault
+        None
     }
 
     /// Write to memory
-    fn write(&mut self, addr: u64, value: u64, width: TypeWidth) {
-        if addr == 0x1000 {
-            print!("{}", value as u8 as char);
-            use std::io::Write;
-            std::io::stdout().flush().unwrap();
-            
-            return;
+    fn write(&mut self, addr: u64, value: u64, width: TypeWidth) -> bool {
+        let granule = addr & !(RESERVATION_SIZE - 1);
+        self.reservations.retain(|(_, reserved)| *reserved != granule);
+
+        if let Some((offset, plic)) = self.plic_mut(addr) {
+            plic.write(offset, value, width);
+            return true;
+        }
+
+        let plic = self.plic.as_mut().map(|(_, plic)| plic);
+        if let Some(msi) = self.msi.as_ref().filter(|msi| msi.contains(addr)) {
+            let data = &value.to_le_bytes()[..width.size() as usize];
+            msi.ring(plic, addr, data);
+            return true;
         }
 
-        if addr >= MEMORY_OFFSET &&
-            addr < MEMORY_OFFSET + self.memory.len() as u64
+        // NOTE(patrik): Borrow the devices and the RAM separately so the
+        // device can do DMA right after the write
+        let base = self.memory_base;
+        let memory = &mut self.memory;
+        let msi = self.msi.as_ref().map(|msi| (msi, plic));
+        if let Some(mapped) = self.devices.iter_mut()
+            .find(|mapped| mapped.contains(addr))
         {
-            let addr = addr.wrapping_sub(MEMORY_OFFSET);
-            let addr: usize = addr.try_into().unwrap();
+            let offset = addr - mapped.base;
+            mapped.device.write(offset, value, width);
+            mapped.device.dma(&mut RamDma { base, memory, msi });
+            return true;
+        }
 
-            return match width {
+        if let Some(offset) = self.ram_range_offset(addr, width.size()) {
+            match width {
                 TypeWidth::Byte => 
-                    self.memory.write_u8(addr, value as u8),
+                    self.memory.write_u8(offset, value as u8),
                 TypeWidth::HalfWord => 
-                    self.memory.write_u16(addr, value as u16),
+                    self.memory.write_u16(offset, value as u16),
                 TypeWidth::Word =>
-                    self.memory.write_u32(addr, value as u32),
+                    self.memory.write_u32(offset, value as u32),
                 TypeWidth::DoubleWord => 
-                    self.memory.write_u64(addr, value as u64),
+                    self.memory.write_u64(offset, value),
             };
+
+            if let Some(mut htif) = self.htif.take() {
+                if htif.is_tohost(addr, width) {
+                    htif.tohost_written(self);
+                } else if htif.is_fromhost(addr, width) {
+                    htif.fromhost_accessed(self);
+                }
+
+                self.htif = Some(htif);
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    fn interrupts(&mut self, hartid: u64) -> u64 {
+        let mut mip = 0;
+
+        match &mut self.plic {
+            Some((_, plic)) => {
+                for mapped in self.devices.iter_mut() {
+                    let Some(irq) = mapped.irq else {
+                        continue;
+                    };
+
+                    for line in 0..mapped.device.irq_count() {
+                        plic.set_level(irq + line,
+                                       mapped.device.irq_line(line));
+                    }
+                }
+
+                mip |= plic.interrupts(hartid);
+            }
+
+            None => {
+                if hartid == 0 &&
+                    self.devices.iter_mut().any(|mapped| mapped.device.irq())
+                {
+                    mip |= MIP_MEIP;
+                }
+            }
+        }
+
+        for mapped in self.devices.iter_mut() {
+            mip |= mapped.device.hart_interrupts(hartid);
+        }
+
+        mip
+    }
+
+    fn reserve(&mut self, hartid: u64, addr: u64) {
+        let granule = addr & !(RESERVATION_SIZE - 1);
+        self.reservations.retain(|(owner, _)| *owner != hartid);
+        self.reservations.push((hartid, granule));
+    }
+
+    fn take_reservation(&mut self, hartid: u64, addr: u64) -> bool {
+        let granule = addr & !(RESERVATION_SIZE - 1);
+        let held = self.reservations.contains(&(hartid, granule));
+        self.reservations.retain(|(owner, _)| *owner != hartid);
+
+        held
+    }
+
+    fn tick(&mut self) {
+        for mapped in self.devices.iter_mut() {
+            mapped.device.tick();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for mapped in self.devices.iter_mut() {
+            mapped.device.shutdown();
+        }
+    }
+
+    fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        // NOTE(patrik): The HTIF has to see the accesses to its registers,
+        // with it everything goes through ´read´
+        let offset = self.ram_range_offset(addr, data.len() as u64)
+            .filter(|_| self.htif.is_none());
+        if let Some(offset) = offset {
+            data.copy_from_slice(self.memory.read_bytes(offset, data.len()));
+            return true;
+        }
+
+        let mut mapped = true;
+        for (index, byte) in data.iter_mut().enumerate() {
+            let addr = addr.wrapping_add(index as u64);
+            let value = self.read(addr, TypeWidth::Byte);
+            mapped &= value.is_some();
+            *byte = value.unwrap_or(0) as u8;
         }
 
-        panic!("Unknown addr: {:#x}", addr);
+        mapped
     }
-}
\ No newline at end of file
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> bool {
+        let offset = self.ram_range_offset(addr, data.len() as u64)
+            .filter(|_| self.htif.is_none());
+        if let Some(offset) = offset {
+            let first = addr & !(RESERVATION_SIZE - 1);
+            let end = addr + data.len() as u64;
+            self.reservations.retain(|(_, reserved)| {
+                *reserved < first || *reserved >= end
+            });
+
+            self.memory.write_bytes(offset, data);
+            return true;
+        }
+
+        let mut mapped = true;
+        for (index, byte) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(index as u64);
+            mapped &= self.write(addr, *byte as u64, TypeWidth::Byte);
+        }
+
+        mapped
+    }
+}
@@ -0,0 +1,339 @@
+//! Built-in SBI firmware, handles the ECALLs from S-mode in Rust so an
+//! S-mode kernel can run without OpenSBI
+//!
+//! Implemented extensions: Base, TIME, IPI, RFENCE, HSM, SRST and the
+//! legacy set_timer, console putchar/getchar and shutdown calls. The state
+//! is shared between the harts, every hart holds a handle to it.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::devices::{ ExitSignal, HostConsole };
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// SBI specification version 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+
+/// NOTE(patrik): Not a registered implementation id, "kira" in ASCII
+const IMPL_ID: u64 = 0x6b697261;
+const IMPL_VERSION: u64 = 1;
+
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x54494d45;
+const EXT_IPI: u64 = 0x735049;
+const EXT_RFENCE: u64 = 0x52464e43;
+const EXT_HSM: u64 = 0x48534d;
+const EXT_SRST: u64 = 0x53525354;
+
+const EXTENSIONS: [u64; 10] = [
+    EXT_LEGACY_SET_TIMER, EXT_LEGACY_PUTCHAR, EXT_LEGACY_GETCHAR,
+    EXT_LEGACY_SHUTDOWN,
+    EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST,
+];
+
+const SRST_TYPE_SHUTDOWN: u64 = 0;
+const SRST_TYPE_COLD_REBOOT: u64 = 1;
+const SRST_TYPE_WARM_REBOOT: u64 = 2;
+const SRST_REASON_NONE: u64 = 0;
+
+const HSM_SUSPEND_RETENTIVE: u64 = 0;
+
+/// HSM states of a hart, the values are from the SBI specification
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HartState {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+}
+
+/// Result of an SBI call
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SbiOutcome {
+    /// Return to the caller with the error in a0 and the value in a1
+    Return { error: i64, value: u64 },
+
+    /// Legacy calls only return a0
+    Legacy(u64),
+
+    /// The calling hart stopped itself with HSM hart_stop
+    Stop,
+}
+
+impl SbiOutcome {
+    fn ok(value: u64) -> Self {
+        SbiOutcome::Return { error: SBI_SUCCESS, value }
+    }
+
+    fn error(error: i64) -> Self {
+        SbiOutcome::Return { error, value: 0 }
+    }
+}
+
+struct HartEntry {
+    state: HartState,
+
+    /// Address and opaque value from hart_start
+    start: Option<(u64, u64)>,
+
+    ipi_pending: bool,
+
+    /// Timer compare value in timebase ticks
+    timer: u64,
+}
+
+struct SbiState {
+    harts: Vec<HartEntry>,
+    console: HostConsole,
+    exit: ExitSignal,
+}
+
+#[derive(Clone)]
+pub struct Sbi {
+    state: Rc<RefCell<SbiState>>,
+}
+
+impl Sbi {
+    /// Create the firmware for ´harts´ harts, only hart 0 is started
+    pub fn new(harts: usize, exit: ExitSignal) -> Self {
+        let harts = (0..harts)
+            .map(|hartid| HartEntry {
+                state: if hartid == 0 {
+                    HartState::Started
+                } else {
+                    HartState::Stopped
+                },
+                start: None,
+                ipi_pending: false,
+                timer: u64::MAX,
+            })
+            .collect();
+
+        Self {
+            state: Rc::new(RefCell::new(SbiState {
+                harts,
+                console: HostConsole::new(),
+                exit,
+            })),
+        }
+    }
+
+    /// Check if ´hartid´ is waiting to be started through HSM
+    pub fn is_stopped(&self, hartid: usize) -> bool {
+        let state = self.state.borrow();
+        state.harts.get(hartid)
+            .is_some_and(|hart| hart.state == HartState::Stopped)
+    }
+
+    /// Check if the S-mode timer of ´hartid´ has fired
+    pub fn timer_pending(&self, hartid: usize, time: u64) -> bool {
+        let state = self.state.borrow();
+        state.harts.get(hartid).is_some_and(|hart| time >= hart.timer)
+    }
+
+    /// Take a pending IPI for ´hartid´
+    pub fn take_ipi(&self, hartid: usize) -> bool {
+        let mut state = self.state.borrow_mut();
+        match state.harts.get_mut(hartid) {
+            Some(hart) => std::mem::take(&mut hart.ipi_pending),
+            None => false,
+        }
+    }
+
+    /// Take a pending hart_start request for ´hartid´, returns the start
+    /// address and the opaque value
+    pub fn take_start(&self, hartid: usize) -> Option<(u64, u64)> {
+        let mut state = self.state.borrow_mut();
+        let hart = state.harts.get_mut(hartid)?;
+
+        let start = hart.start.take()?;
+        hart.state = HartState::Started;
+        Some(start)
+    }
+
+    /// Handle an ECALL from S-mode, ´args´ is a0 to a7
+    pub fn call(&self, hartid: usize, args: [u64; 8]) -> SbiOutcome {
+        let extension = args[7];
+        let function = args[6];
+
+        let mut state = self.state.borrow_mut();
+        match extension {
+            EXT_LEGACY_SET_TIMER => {
+                state.set_timer(hartid, args[0]);
+                SbiOutcome::Legacy(0)
+            }
+
+            EXT_LEGACY_PUTCHAR => {
+                state.console.putchar(args[0] as u8);
+                SbiOutcome::Legacy(0)
+            }
+
+            EXT_LEGACY_GETCHAR => {
+                let value = match state.console.getchar() {
+                    Some(value) => value as u64,
+                    None => -1i64 as u64,
+                };
+
+                SbiOutcome::Legacy(value)
+            }
+
+            EXT_LEGACY_SHUTDOWN => {
+                state.exit.exit(0);
+                SbiOutcome::Legacy(0)
+            }
+
+            EXT_BASE => state.base(function, args[0]),
+            EXT_TIME if function == 0 => {
+                state.set_timer(hartid, args[0]);
+                SbiOutcome::ok(0)
+            }
+            EXT_IPI if function == 0 => state.send_ipi(args[0], args[1]),
+            EXT_RFENCE => state.rfence(function, args[0], args[1]),
+            EXT_HSM => state.hsm(hartid, function, args),
+            EXT_SRST if function == 0 => state.system_reset(args[0], args[1]),
+
+            _ => SbiOutcome::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+}
+
+impl SbiState {
+    fn set_timer(&mut self, hartid: usize, value: u64) {
+        if let Some(hart) = self.harts.get_mut(hartid) {
+            hart.timer = value;
+        }
+    }
+
+    /// Harts selected by ´mask´ and ´base´, None if a hart doesn't exist
+    fn selected_harts(&self, mask: u64, base: u64) -> Option<Vec<usize>> {
+        // NOTE(patrik): A base of -1 selects all the harts
+        if base == u64::MAX {
+            return Some((0..self.harts.len()).collect());
+        }
+
+        let mut harts = Vec::new();
+        for bit in 0..64 {
+            if mask & (1 << bit) == 0 {
+                continue;
+            }
+
+            let hartid = base.checked_add(bit)? as usize;
+            if hartid >= self.harts.len() {
+                return None;
+            }
+
+            harts.push(hartid);
+        }
+
+        Some(harts)
+    }
+
+    fn base(&mut self, function: u64, arg: u64) -> SbiOutcome {
+        match function {
+            0 => SbiOutcome::ok(SPEC_VERSION),
+            1 => SbiOutcome::ok(IMPL_ID),
+            2 => SbiOutcome::ok(IMPL_VERSION),
+            3 => SbiOutcome::ok(EXTENSIONS.contains(&arg) as u64),
+            // mvendorid, marchid and mimpid
+            4..=6 => SbiOutcome::ok(0),
+
+            _ => SbiOutcome::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn send_ipi(&mut self, mask: u64, base: u64) -> SbiOutcome {
+        let Some(harts) = self.selected_harts(mask, base) else {
+            return SbiOutcome::error(SBI_ERR_INVALID_PARAM);
+        };
+
+        for hartid in harts {
+            self.harts[hartid].ipi_pending = true;
+        }
+
+        SbiOutcome::ok(0)
+    }
+
+    fn rfence(&mut self, function: u64, mask: u64, base: u64) -> SbiOutcome {
+        // NOTE(patrik): Only FENCE.I, SFENCE.VMA and SFENCE.VMA with ASID,
+        // the hypervisor fences aren't supported
+        if function > 2 {
+            return SbiOutcome::error(SBI_ERR_NOT_SUPPORTED);
+        }
+
+        if self.selected_harts(mask, base).is_none() {
+            return SbiOutcome::error(SBI_ERR_INVALID_PARAM);
+        }
+
+        // TODO(patrik): Flush the harts when instructions or address
+        // translations are cached, nothing is cached right now
+        SbiOutcome::ok(0)
+    }
+
+    fn hsm(&mut self, hartid: usize, function: u64, args: [u64; 8])
+        -> SbiOutcome
+    {
+        match function {
+            // hart_start
+            0 => {
+                let Some(hart) = self.harts.get_mut(args[0] as usize) else {
+                    return SbiOutcome::error(SBI_ERR_INVALID_PARAM);
+                };
+
+                if hart.state != HartState::Stopped {
+                    return SbiOutcome::error(SBI_ERR_ALREADY_AVAILABLE);
+                }
+
+                hart.state = HartState::StartPending;
+                hart.start = Some((args[1], args[2]));
+                SbiOutcome::ok(0)
+            }
+
+            // hart_stop
+            1 => {
+                self.harts[hartid].state = HartState::Stopped;
+                self.harts[hartid].timer = u64::MAX;
+                SbiOutcome::Stop
+            }
+
+            // hart_get_status
+            2 => {
+                match self.harts.get(args[0] as usize) {
+                    Some(hart) => SbiOutcome::ok(hart.state as u64),
+                    None => SbiOutcome::error(SBI_ERR_INVALID_PARAM),
+                }
+            }
+
+            // hart_suspend, a retentive suspend works like WFI
+            3 if args[0] as u32 as u64 == HSM_SUSPEND_RETENTIVE => {
+                SbiOutcome::ok(0)
+            }
+
+            _ => SbiOutcome::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn system_reset(&mut self, kind: u64, reason: u64) -> SbiOutcome {
+        let kind = kind as u32 as u64;
+        let reason = reason as u32 as u64;
+
+        match kind {
+            // NOTE(patrik): A reboot stops the emulator too, there is no
+            // way to reset the machine yet
+            SRST_TYPE_SHUTDOWN | SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT => {
+                let code = if reason == SRST_REASON_NONE { 0 } else { 1 };
+                self.exit.exit(code);
+                SbiOutcome::ok(0)
+            }
+
+            _ => SbiOutcome::error(SBI_ERR_INVALID_PARAM),
+        }
+    }
+}
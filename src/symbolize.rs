@@ -0,0 +1,63 @@
+//! Naming the code at a guest address for the fault reports
+//!
+//! Addresses are looked up in the symbols and the DWARF line table of the
+//! ELF the program was loaded from, so only the ranges of that image are
+//! described. The image may have been moved by a load bias, the lookups
+//! undo it.
+
+use std::ops::Range;
+
+use crate::elf::{ Elf, SymbolTable };
+use crate::dwarf::LineTable;
+use crate::loader::LoadedImage;
+
+pub struct Symbolizer<'a> {
+    symbols: Option<SymbolTable<'a>>,
+    lines: Option<LineTable>,
+
+    /// Ranges of the loaded image, with the bias applied
+    ranges: Vec<Range<u64>>,
+    bias: u64,
+}
+
+impl<'a> Symbolizer<'a> {
+    /// Symbolizer for ´image´, loaded from ´elf´. None if the ELF has
+    /// neither symbols nor a line table
+    pub fn new(elf: &Elf<'a>, image: &LoadedImage) -> Option<Self> {
+        let symbols = elf.symbol_table().ok()
+            .filter(|symbols| !symbols.is_empty());
+        let lines = LineTable::parse(elf).ok()
+            .filter(|lines| !lines.is_empty());
+        if symbols.is_none() && lines.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            symbols,
+            lines,
+            ranges: image.chunks.iter().map(|chunk| chunk.range()).collect(),
+            bias: image.bias,
+        })
+    }
+
+    /// Describe ´addr´ as ´symbol+offset (file:line)´, None if it's outside
+    /// the image
+    pub fn describe(&self, addr: u64) -> Option<String> {
+        if !self.ranges.iter().any(|range| range.contains(&addr)) {
+            return None;
+        }
+
+        let addr = addr.wrapping_sub(self.bias);
+        let symbol = match &self.symbols {
+            Some(symbols) => symbols.format_address(addr),
+            None => format!("{:#x}", addr),
+        };
+
+        let location = self.lines.as_ref()
+            .and_then(|lines| lines.lookup(addr));
+        match location {
+            Some(location) => Some(format!("{} ({})", symbol, location)),
+            None => Some(symbol),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #include <stdint.h>
 
-static volatile uint64_t* PRINT_OUT = (uint64_t *)0x1000;
+/* Transmit register of the UART on the QEMU virt machine */
+static volatile uint8_t* PRINT_OUT = (uint8_t *)0x10000000;
 
 void write_str(const char *str) {
     while(*str) {
@@ -0,0 +1,199 @@
+//! Check that a fatal fault is reported with the symbol and the source
+//! line of the pc
+//!
+//! The program is built here, a RISC-V ELF with a ´.symtab´ and a DWARF 4
+//! ´.debug_line´, the same as an assembler would make for
+//!   3  _start: li a0, 1
+//!   4          li a1, 2
+//!   5
+//!   6          sd zero, 0(zero)
+//! Nothing is mapped at address 0 so the store faults.
+
+use std::process::Command;
+
+const BASE: u64 = 0x80000000;
+const TEXT_OFFSET: usize = 0x1000;
+
+const TEXT: [u32; 3] = [
+    0x00100513, // li a0, 1
+    0x00200593, // li a1, 2
+    0x00003023, // sd zero, 0(zero)
+];
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+
+/// Line number program for ´TEXT´, in the file ´fault.S´
+fn debug_line() -> Vec<u8> {
+    let mut header = vec![
+        1, // minimum_instruction_length
+        1, // maximum_operations_per_instruction
+        1, // default_is_stmt
+        -5i8 as u8, // line_base
+        14, // line_range
+        13, // opcode_base
+    ];
+    header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    header.push(0); // no include directories
+    header.extend_from_slice(b"fault.S\0");
+    header.extend_from_slice(&[0, 0, 0]); // directory, time and size
+    header.push(0);
+
+    let mut program = vec![0, 9, 2]; // DW_LNE_set_address
+    program.extend_from_slice(&BASE.to_le_bytes());
+    program.extend_from_slice(&[
+        3, 2, 1, // line 3, copy
+        2, 4, 3, 1, 1, // pc + 4, line 4, copy
+        2, 4, 3, 2, 1, // pc + 4, line 6, copy
+        2, 4, 0, 1, 1, // pc + 4, DW_LNE_end_sequence
+    ]);
+
+    let mut unit = Vec::new();
+    unit.extend_from_slice(&4u16.to_le_bytes());
+    unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    unit.extend_from_slice(&header);
+    unit.extend_from_slice(&program);
+
+    let mut section = (unit.len() as u32).to_le_bytes().to_vec();
+    section.extend_from_slice(&unit);
+    section
+}
+
+fn symtab() -> Vec<u8> {
+    let mut symtab = vec![0; 24];
+    symtab.extend_from_slice(&1u32.to_le_bytes()); // "_start"
+    symtab.push(0x12); // STB_GLOBAL, STT_FUNC
+    symtab.push(0);
+    symtab.extend_from_slice(&1u16.to_le_bytes()); // .text
+    symtab.extend_from_slice(&BASE.to_le_bytes());
+    symtab.extend_from_slice(&(TEXT.len() as u64 * 4).to_le_bytes());
+    symtab
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    entsize: u64,
+}
+
+impl SectionHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&self.name.to_le_bytes());
+        header.extend_from_slice(&self.typ.to_le_bytes());
+        header.extend_from_slice(&self.flags.to_le_bytes());
+        header.extend_from_slice(&self.addr.to_le_bytes());
+        header.extend_from_slice(&(self.offset as u64).to_le_bytes());
+        header.extend_from_slice(&(self.size as u64).to_le_bytes());
+        header.extend_from_slice(&self.link.to_le_bytes());
+        header.extend_from_slice(&self.info.to_le_bytes());
+        header.extend_from_slice(&1u64.to_le_bytes());
+        header.extend_from_slice(&self.entsize.to_le_bytes());
+        header
+    }
+}
+
+/// Build the ELF, the sections follow the text in the order of ´sections´
+fn build_elf() -> Vec<u8> {
+    let text = TEXT.iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect::<Vec<_>>();
+    let shstrtab = b"\0.text\0.debug_line\0.symtab\0.strtab\0.shstrtab\0";
+    let sections = [
+        (7, SHT_PROGBITS, 0, debug_line()),
+        (19, SHT_SYMTAB, 24, symtab()),
+        (27, SHT_STRTAB, 0, b"\0_start\0".to_vec()),
+        (35, SHT_STRTAB, 0, shstrtab.to_vec()),
+    ];
+
+    let mut file = vec![0; TEXT_OFFSET];
+    file.extend_from_slice(&text);
+
+    let mut headers = vec![0; 64];
+    headers.extend(SectionHeader {
+        name: 1,
+        typ: SHT_PROGBITS,
+        flags: 6, // SHF_ALLOC | SHF_EXECINSTR
+        addr: BASE,
+        offset: TEXT_OFFSET,
+        size: text.len(),
+        ..Default::default()
+    }.to_bytes());
+    for (name, typ, entsize, data) in sections {
+        // NOTE(patrik): The symbol table links to ´.strtab´, the section
+        // after it, and its first global is symbol 1
+        let (link, info) = if typ == SHT_SYMTAB { (4, 1) } else { (0, 0) };
+        headers.extend(SectionHeader {
+            name,
+            typ,
+            offset: file.len(),
+            size: data.len(),
+            link,
+            info,
+            entsize,
+            ..Default::default()
+        }.to_bytes());
+        file.extend_from_slice(&data);
+    }
+
+    let section_headers = file.len().next_multiple_of(8);
+    file.resize(section_headers, 0);
+    file.extend_from_slice(&headers);
+
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7fELF");
+    elf.extend_from_slice(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&0xf3u16.to_le_bytes()); // EM_RISCV
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&BASE.to_le_bytes()); // entry
+    elf.extend_from_slice(&64u64.to_le_bytes()); // program headers
+    elf.extend_from_slice(&(section_headers as u64).to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+    elf.extend_from_slice(&64u16.to_le_bytes());
+    elf.extend_from_slice(&56u16.to_le_bytes());
+    elf.extend_from_slice(&1u16.to_le_bytes());
+    elf.extend_from_slice(&64u16.to_le_bytes());
+    elf.extend_from_slice(&6u16.to_le_bytes());
+    elf.extend_from_slice(&5u16.to_le_bytes()); // .shstrtab
+
+    // PT_LOAD of the text, readable and executable
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&5u32.to_le_bytes());
+    elf.extend_from_slice(&(TEXT_OFFSET as u64).to_le_bytes());
+    elf.extend_from_slice(&BASE.to_le_bytes());
+    elf.extend_from_slice(&BASE.to_le_bytes());
+    elf.extend_from_slice(&(text.len() as u64).to_le_bytes());
+    elf.extend_from_slice(&(text.len() as u64).to_le_bytes());
+    elf.extend_from_slice(&0x1000u64.to_le_bytes());
+
+    file[..elf.len()].copy_from_slice(&elf);
+    file
+}
+
+#[test]
+fn fault_report_has_source_line() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("fault.elf");
+    std::fs::write(&path, build_elf()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_kira"))
+        .arg(&path)
+        .output()
+        .expect("Failed to start kira");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(stderr.contains("epc 0x80000008"), "{}", stderr);
+    assert!(stderr.contains("at _start+0x8 (fault.S:6)"), "{}", stderr);
+
+    // NOTE(patrik): A store access fault is a SIGSEGV
+    assert_eq!(output.status.code(), Some(128 + 11));
+}
@@ -0,0 +1,96 @@
+//! Boot xv6-riscv to the shell and run ´usertests´
+//!
+//! The test needs a built xv6-riscv tree, it's ignored by default. Point
+//! XV6_DIR at the tree and run it with
+//! ´XV6_DIR=../xv6-riscv cargo test --release -- --ignored xv6´
+
+use std::io::{ Read, Write };
+use std::path::PathBuf;
+use std::process::{ Command, Stdio };
+use std::sync::mpsc;
+use std::time::{ Duration, Instant };
+
+/// Same number of harts as the xv6 Makefile gives QEMU
+const HARTS: &str = "3";
+
+/// How long booting and ´usertests´ may take before kira is considered
+/// hung, usertests takes a few minutes in a release build
+const TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[test]
+#[ignore]
+fn xv6_usertests() {
+    let dir = std::env::var_os("XV6_DIR")
+        .map(PathBuf::from)
+        .expect("XV6_DIR has to point at a built xv6-riscv tree");
+
+    let kernel = dir.join("kernel").join("kernel");
+    let fs = dir.join("fs.img");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kira"))
+        .arg("--smp").arg(HARTS)
+        .arg("--virtio-blk").arg(&fs)
+        .arg(&kernel)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start kira");
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    // NOTE(patrik): The console output is read on another thread so the
+    // test sees it as it comes
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        while let Ok(count) = stdout.read(&mut buffer) {
+            if count == 0 || sender.send(buffer[..count].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut output = String::new();
+    let mut started = false;
+    let mut timed_out = false;
+    let passed = loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let data = match receiver.recv_timeout(timeout) {
+            Ok(data) => data,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break false;
+            }
+
+            Err(mpsc::RecvTimeoutError::Disconnected) => break false,
+        };
+
+        let text = String::from_utf8_lossy(&data);
+        print!("{}", text);
+        output.push_str(&text);
+
+        if !started && output.contains("$ ") {
+            stdin.write_all(b"usertests\n").unwrap();
+            stdin.flush().unwrap();
+            started = true;
+        }
+
+        if output.contains("ALL TESTS PASSED") {
+            break true;
+        }
+
+        if output.contains("SOME TESTS FAILED") ||
+            output.contains("panic: ")
+        {
+            break false;
+        }
+    };
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(!timed_out, "usertests didn't finish in {:?}", TIMEOUT);
+    assert!(passed, "usertests didn't pass");
+}